@@ -69,6 +69,47 @@ class DatabaseLoginsStorage(private val dbPath: String) : AutoCloseable, LoginsS
         }
     }
 
+    /**
+     * Like [unlock], but for a database that isn't encrypted with SQLCipher, and instead
+     * encrypts the username and password of each login with [fieldEncryptionKey], which
+     * comes from [createFieldEncryptionKey].
+     */
+    @Synchronized
+    @Throws(LoginsStorageException::class)
+    fun unlockWithFieldEncryption(fieldEncryptionKey: String) {
+        return rustCall {
+            if (!isLocked()) {
+                throw MismatchedLockException("Unlock called when we are already unlocked")
+            }
+            raw.set(PasswordSyncAdapter.INSTANCE.sync15_passwords_state_new_with_field_encryption(
+                    dbPath,
+                    fieldEncryptionKey,
+                    it))
+        }
+    }
+
+    /**
+     * Migrates the SQLCipher database at [sqlcipherPath] to a new database at this
+     * storage's path, which must not exist yet, and which encrypts fields with
+     * [fieldEncryptionKey], then unlocks it. The SQLCipher database is left as-is, so
+     * it should be deleted once this succeeds.
+     */
+    @Synchronized
+    @Throws(LoginsStorageException::class)
+    fun migrateFromSqlcipher(sqlcipherPath: String, sqlcipherKey: String, fieldEncryptionKey: String) {
+        return rustCall {
+            if (!isLocked()) {
+                throw MismatchedLockException("Migrate called when we are already unlocked")
+            }
+            raw.set(PasswordSyncAdapter.INSTANCE.sync15_passwords_state_migrate_from_sqlcipher(
+                    sqlcipherPath,
+                    sqlcipherKey,
+                    dbPath,
+                    fieldEncryptionKey,
+                    it))
+        }
+    }
+
     @Synchronized
     @Throws(LoginsStorageException::class)
     override fun ensureUnlocked(encryptionKey: String) {
@@ -186,6 +227,26 @@ class DatabaseLoginsStorage(private val dbPath: String) : AutoCloseable, LoginsS
         }
     }
 
+    companion object {
+        /**
+         * Returns a new random key for [unlockWithFieldEncryption], which should be
+         * stored somewhere secure, like the Android keystore.
+         */
+        @Throws(LoginsStorageException::class)
+        fun createFieldEncryptionKey(): String {
+            val e = RustError.ByReference()
+            try {
+                val key = PasswordSyncAdapter.INSTANCE.sync15_passwords_create_field_encryption_key(e)
+                if (e.isFailure()) {
+                    throw e.intoException()
+                }
+                return key!!.getAndConsumeRustString()
+            } finally {
+                e.ensureConsumed()
+            }
+        }
+    }
+
     // In practice we usually need to be synchronized to call this safely, so it doesn't
     // synchronize itself
     private inline fun <U> nullableRustCall(callback: (RustError.ByReference) -> U?): U? {
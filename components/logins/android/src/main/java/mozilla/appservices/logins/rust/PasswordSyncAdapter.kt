@@ -31,8 +31,33 @@ internal interface PasswordSyncAdapter : Library {
         error: RustError.ByReference
     ): LoginsDbHandle
 
+    fun sync15_passwords_state_new_with_field_encryption(
+        db_path: String,
+        field_encryption_key: String,
+        error: RustError.ByReference
+    ): LoginsDbHandle
+
+    fun sync15_passwords_state_migrate_from_sqlcipher(
+        sqlcipher_path: String,
+        sqlcipher_key: String,
+        db_path: String,
+        field_encryption_key: String,
+        error: RustError.ByReference
+    ): LoginsDbHandle
+
+    fun sync15_passwords_create_field_encryption_key(error: RustError.ByReference): Pointer?
+
     fun sync15_passwords_state_destroy(handle: LoginsDbHandle, error: RustError.ByReference)
 
+    fun sync15_passwords_rekey(handle: LoginsDbHandle, new_encryption_key: String, error: RustError.ByReference)
+
+    fun sync15_passwords_rekey_with_hex_key(
+        handle: LoginsDbHandle,
+        new_encryption_key_bytes: ByteArray,
+        new_encryption_key_len: Int,
+        error: RustError.ByReference
+    )
+
     // Important: strings returned from rust as *char must be Pointers on this end, returning a
     // String will work but either force us to leak them, or cause us to corrupt the heap (when we
     // free them).
@@ -43,6 +68,36 @@ internal interface PasswordSyncAdapter : Library {
     // return json array
     fun sync15_passwords_get_all(handle: LoginsDbHandle, error: RustError.ByReference): Pointer?
 
+    // return json array
+    fun sync15_passwords_get_by_base_domain(handle: LoginsDbHandle, origin: String, error: RustError.ByReference): Pointer?
+
+    // return json array. formActionOrigin and httpRealm may be null.
+    fun sync15_passwords_find_for_form(
+        handle: LoginsDbHandle,
+        origin: String,
+        formActionOrigin: String?,
+        httpRealm: String?,
+        error: RustError.ByReference
+    ): Pointer?
+
+    // return json array of earlier passwords, newest first.
+    fun sync15_passwords_get_password_history(handle: LoginsDbHandle, id: String, error: RustError.ByReference): Pointer?
+    fun sync15_passwords_restore_password(handle: LoginsDbHandle, id: String, history_id: Long, error: RustError.ByReference)
+
+    // takes and returns json arrays.
+    fun sync15_passwords_get_breach_alerts(handle: LoginsDbHandle, breaches_json: String, error: RustError.ByReference): Pointer?
+    fun sync15_passwords_dismiss_breach_alert(handle: LoginsDbHandle, id: String, error: RustError.ByReference)
+
+    // return json array of duplicate groups.
+    fun sync15_passwords_find_duplicates(handle: LoginsDbHandle, error: RustError.ByReference): Pointer?
+    // takes a json array of ids, returns the merged login as json.
+    fun sync15_passwords_merge_duplicates(
+        handle: LoginsDbHandle,
+        survivor_id: String,
+        duplicate_ids_json: String,
+        error: RustError.ByReference
+    ): Pointer?
+
     // Returns a JSON string containing a sync ping.
     fun sync15_passwords_sync(
         handle: LoginsDbHandle,
@@ -65,6 +120,11 @@ internal interface PasswordSyncAdapter : Library {
     fun sync15_passwords_add(handle: LoginsDbHandle, new_login_json: String, error: RustError.ByReference): Pointer?
     fun sync15_passwords_update(handle: LoginsDbHandle, existing_login_json: String, error: RustError.ByReference)
 
+    // These take json arrays, and return a json array with an `id` or an `error` for each record.
+    fun sync15_passwords_add_many(handle: LoginsDbHandle, new_logins_json: String, error: RustError.ByReference): Pointer?
+    fun sync15_passwords_update_many(handle: LoginsDbHandle, existing_logins_json: String, error: RustError.ByReference): Pointer?
+    fun sync15_passwords_delete_many(handle: LoginsDbHandle, ids_json: String, error: RustError.ByReference): Pointer?
+
     fun sync15_passwords_destroy_string(p: Pointer)
 
     fun sync15_passwords_new_interrupt_handle(handle: LoginsDbHandle, error: RustError.ByReference): RawLoginsInterruptHandle?
@@ -4,6 +4,7 @@
 package mozilla.appservices.logins
 
 import mozilla.appservices.Megazord
+import org.junit.Assert.assertEquals
 import org.junit.Assert.assertNotNull
 import org.junit.Assert.fail
 import org.junit.Rule
@@ -12,6 +13,7 @@ import org.junit.rules.TemporaryFolder
 import org.junit.runner.RunWith
 import org.robolectric.RobolectricTestRunner
 import org.robolectric.annotation.Config
+import java.io.File
 
 @RunWith(RobolectricTestRunner::class)
 @Config(manifest = Config.NONE)
@@ -74,6 +76,41 @@ class DatabaseLoginsStorageTest : LoginsStorageTest() {
         finishAndClose(store)
     }
 
+    @Test
+    fun testFieldEncryption() {
+        Megazord.init()
+        val sqlcipherPath = dbFolder.newFile().absolutePath
+        val sqlcipherStore = DatabaseLoginsStorage(dbPath = sqlcipherPath)
+        sqlcipherStore.unlock("sqlcipher key")
+        val id = sqlcipherStore.add(ServerPassword(
+                id = "",
+                hostname = "https://www.example.com",
+                httpRealm = "Something",
+                username = "Foobar2000",
+                password = "hunter2",
+                usernameField = "users_name",
+                passwordField = "users_password"
+        ))
+        sqlcipherStore.close()
+
+        val key = DatabaseLoginsStorage.createFieldEncryptionKey()
+        // The new database must not exist yet.
+        val path = File(dbFolder.root, "encrypted.db").absolutePath
+        val store = DatabaseLoginsStorage(dbPath = path)
+        store.migrateFromSqlcipher(sqlcipherPath, "sqlcipher key", key)
+        assertEquals("hunter2", store.get(id)!!.password)
+
+        store.lock()
+        expectException(InvalidKeyException::class.java) {
+            store.unlockWithFieldEncryption(DatabaseLoginsStorage.createFieldEncryptionKey())
+        }
+        assert(store.isLocked())
+        store.unlockWithFieldEncryption(key)
+        assertEquals("hunter2", store.get(id)!!.password)
+
+        store.close()
+    }
+
     @Test
     fun testSyncException() {
         val test = getTestStore()
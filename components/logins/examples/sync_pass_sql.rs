@@ -302,7 +302,7 @@ fn main() -> Result<()> {
     }
 
     loop {
-        match prompt_chars("[A]dd, [D]elete, [U]pdate, [S]ync, [V]iew, [R]eset, [W]ipe, [T]ouch, E[x]ecute SQL Query, or [Q]uit").unwrap_or('?') {
+        match prompt_chars("[A]dd, [D]elete, [U]pdate, [S]ync, Dry-ru[N] sync, [V]iew, [R]eset, [W]ipe, [T]ouch, E[x]ecute SQL Query, or [Q]uit").unwrap_or('?') {
             'A' | 'a' => {
                 log::info!("Adding new record");
                 let record = read_login();
@@ -375,6 +375,19 @@ fn main() -> Result<()> {
                     }
                 }
             }
+            'N' | 'n' => {
+                log::info!("Dry-run syncing!");
+                match engine.dry_run_sync(&cli_fxa.client_init, &cli_fxa.root_sync_key, true) {
+                    Err(e) => {
+                        log::warn!("Dry run failed! {}", e);
+                        log::warn!("BT: {:?}", e.backtrace());
+                    },
+                    Ok(planned) => {
+                        log::info!("Dry run was successful!");
+                        println!("Planned changes: {}", serde_json::to_string_pretty(&planned).unwrap());
+                    }
+                }
+            }
             'V' | 'v' => {
                 if let Err(e) = show_all(&engine) {
                     log::warn!("Failed to dump passwords? This is probably bad! {}", e);
@@ -12,11 +12,12 @@ use ffi_support::ConcurrentHandleMap;
 use ffi_support::{
     define_box_destructor, define_handle_map_deleter, define_string_destructor, ExternError, FfiStr,
 };
-use logins::{Login, PasswordEngine, Result};
+use logins::{BatchResult, Breach, ErrorKind, InvalidLogin, Login, PasswordEngine, Result};
 use std::os::raw::c_char;
 
 lazy_static::lazy_static! {
-    static ref ENGINES: ConcurrentHandleMap<PasswordEngine> = ConcurrentHandleMap::new();
+    // The sync manager also syncs engines through this map, by handle.
+    pub static ref ENGINES: ConcurrentHandleMap<PasswordEngine> = ConcurrentHandleMap::new();
 }
 
 #[no_mangle]
@@ -74,6 +75,91 @@ pub unsafe extern "C" fn sync15_passwords_state_new_with_hex_key(
     })
 }
 
+/// Returns a new random key for `sync15_passwords_state_new_with_field_encryption`.
+/// The app should store it somewhere secure, like the Android keystore.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_create_field_encryption_key(
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_create_field_encryption_key");
+    ffi_support::call_with_result(error, logins::create_field_encryption_key)
+}
+
+/// Opens a database that isn't encrypted with SQLCipher, and instead encrypts
+/// the username and password of each login with `field_encryption_key`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_state_new_with_field_encryption(
+    db_path: FfiStr<'_>,
+    field_encryption_key: FfiStr<'_>,
+    error: &mut ExternError,
+) -> u64 {
+    log::debug!("sync15_passwords_state_new_with_field_encryption");
+    ENGINES.insert_with_result(error, || {
+        PasswordEngine::new_with_field_encryption(db_path.as_str(), field_encryption_key.as_str())
+    })
+}
+
+/// Migrates the SQLCipher database at `sqlcipher_path` to a new database at
+/// `db_path` that uses field-level encryption, and opens it. The SQLCipher
+/// database is left as-is, so the app should delete it once this succeeds.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_state_migrate_from_sqlcipher(
+    sqlcipher_path: FfiStr<'_>,
+    sqlcipher_key: FfiStr<'_>,
+    db_path: FfiStr<'_>,
+    field_encryption_key: FfiStr<'_>,
+    error: &mut ExternError,
+) -> u64 {
+    log::debug!("sync15_passwords_state_migrate_from_sqlcipher");
+    ENGINES.insert_with_result(error, || {
+        PasswordEngine::migrate_from_sqlcipher(
+            sqlcipher_path.as_str(),
+            sqlcipher_key.as_str(),
+            db_path.as_str(),
+            field_encryption_key.as_str(),
+        )
+    })
+}
+
+/// Re-encrypts the database with a new key. For SQLCipher databases, this is
+/// the new SQLCipher key; for databases that use field-level encryption, it's
+/// the new field encryption key. If this fails, the database is left
+/// encrypted with the old key.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_rekey(
+    handle: u64,
+    new_encryption_key: FfiStr<'_>,
+    error: &mut ExternError,
+) {
+    log::debug!("sync15_passwords_rekey");
+    ENGINES.call_with_result_mut(error, handle, |state| -> Result<()> {
+        state.rekey(new_encryption_key.as_str())
+    })
+}
+
+/// Same as sync15_passwords_rekey, but automatically hex-encodes the string,
+/// like sync15_passwords_state_new_with_hex_key.
+///
+/// Unlike sync15_passwords_state_new_with_hex_key, a key_len of 0, or a null
+/// key, is an `INVALID_KEY` error, since rekeying can't decrypt the database.
+#[no_mangle]
+pub unsafe extern "C" fn sync15_passwords_rekey_with_hex_key(
+    handle: u64,
+    new_encryption_key: *const u8,
+    new_encryption_key_len: u32,
+    error: &mut ExternError,
+) {
+    log::debug!("sync15_passwords_rekey_with_hex_key");
+    ENGINES.call_with_result_mut(error, handle, |state| -> Result<()> {
+        if new_encryption_key.is_null() || new_encryption_key_len == 0 {
+            return Err(ErrorKind::CannotRekey("The new key is empty").into());
+        }
+        let byte_slice =
+            std::slice::from_raw_parts(new_encryption_key, new_encryption_key_len as usize);
+        state.rekey(&base16::encode_lower(byte_slice))
+    })
+}
+
 // indirection to help `?` figure out the target error type
 fn parse_url(url: &str) -> sync15::Result<url::Url> {
     Ok(url::Url::parse(url)?)
@@ -182,6 +268,131 @@ pub extern "C" fn sync15_passwords_get_by_id(
     ENGINES.call_with_result(error, handle, |state| state.get(id.as_str()))
 }
 
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_by_base_domain(
+    handle: u64,
+    origin: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_get_by_base_domain");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let logins = state.get_by_base_domain(origin.as_str())?;
+        let result = serde_json::to_string(&logins)?;
+        Ok(result)
+    })
+}
+
+/// `form_action_origin` and `http_realm` may be null.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_find_for_form(
+    handle: u64,
+    origin: FfiStr<'_>,
+    form_action_origin: FfiStr<'_>,
+    http_realm: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_find_for_form");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let logins = state.find_for_form(
+            origin.as_str(),
+            form_action_origin.as_opt_str(),
+            http_realm.as_opt_str(),
+        )?;
+        let result = serde_json::to_string(&logins)?;
+        Ok(result)
+    })
+}
+
+/// Returns the earlier passwords of a login as a JSON array, newest first.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_password_history(
+    handle: u64,
+    id: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_get_password_history");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let history = state.get_password_history(id.as_str())?;
+        let result = serde_json::to_string(&history)?;
+        Ok(result)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn sync15_passwords_restore_password(
+    handle: u64,
+    id: FfiStr<'_>,
+    history_id: i64,
+    error: &mut ExternError,
+) {
+    log::debug!("sync15_passwords_restore_password");
+    ENGINES.call_with_result(error, handle, |state| {
+        state.restore_password(id.as_str(), history_id)
+    })
+}
+
+/// `breaches_json` is a JSON array of breaches. Returns a JSON array of
+/// `{ login, breach }` alerts.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_breach_alerts(
+    handle: u64,
+    breaches_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_get_breach_alerts");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let breaches: Vec<Breach> = serde_json::from_str(breaches_json.as_str())?;
+        let alerts = state.get_breach_alerts(&breaches)?;
+        let result = serde_json::to_string(&alerts)?;
+        Ok(result)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn sync15_passwords_dismiss_breach_alert(
+    handle: u64,
+    id: FfiStr<'_>,
+    error: &mut ExternError,
+) {
+    log::debug!("sync15_passwords_dismiss_breach_alert");
+    ENGINES.call_with_result(error, handle, |state| {
+        state.dismiss_breach_alert(id.as_str())
+    })
+}
+
+/// Returns a JSON array of `{ survivor, duplicates }` groups.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_find_duplicates(
+    handle: u64,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_find_duplicates");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let groups = state.find_duplicates()?;
+        let result = serde_json::to_string(&groups)?;
+        Ok(result)
+    })
+}
+
+/// `duplicate_ids_json` is a JSON array of GUIDs. Returns the merged login as
+/// JSON.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_merge_duplicates(
+    handle: u64,
+    survivor_id: FfiStr<'_>,
+    duplicate_ids_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_merge_duplicates");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let duplicate_ids: Vec<String> = serde_json::from_str(duplicate_ids_json.as_str())?;
+        let duplicate_ids = duplicate_ids.iter().map(String::as_str).collect::<Vec<_>>();
+        let merged = state.merge_duplicates(survivor_id.as_str(), &duplicate_ids)?;
+        let result = serde_json::to_string(&merged)?;
+        Ok(result)
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sync15_passwords_add(
     handle: u64,
@@ -213,6 +424,133 @@ pub extern "C" fn sync15_passwords_update(
     });
 }
 
+/// Parses each record of the `records_json` array separately, so that a
+/// malformed record only fails its own `BatchResult` instead of the whole
+/// batch. `prepare` is called on each record before parsing it. Returns the
+/// logins that parsed, and the error for each record that didn't, in order.
+fn parse_batch(
+    records_json: &str,
+    prepare: impl Fn(&mut serde_json::Value),
+) -> Result<(Vec<Login>, Vec<Option<BatchResult>>)> {
+    let records: Vec<serde_json::Value> = serde_json::from_str(records_json)?;
+    let mut logins = Vec::with_capacity(records.len());
+    let errors = records
+        .into_iter()
+        .map(|mut record| {
+            prepare(&mut record);
+            match serde_json::from_value(record) {
+                Ok(login) => {
+                    logins.push(login);
+                    None
+                }
+                Err(e) => {
+                    let err = ErrorKind::InvalidLogin(InvalidLogin::Malformed(e.to_string()));
+                    Some(BatchResult::from(Err(err.into())))
+                }
+            }
+        })
+        .collect();
+    Ok((logins, errors))
+}
+
+/// Puts the `results` for the logins that `parse_batch` returned back in
+/// place among the `errors` for the records that didn't parse.
+fn merge_batch(errors: Vec<Option<BatchResult>>, results: Vec<BatchResult>) -> Vec<BatchResult> {
+    let mut results = results.into_iter();
+    errors
+        .into_iter()
+        .filter_map(|error| error.or_else(|| results.next()))
+        .collect()
+}
+
+/// `records_json` is a JSON array of logins, which are added in one
+/// transaction. Returns a JSON array with a `BatchResult` for each login: the
+/// GUID of the new login, or a coded error. A login that isn't valid JSON for
+/// a login gets an `INVALID_LOGIN` error, without failing the others.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_add_many(
+    handle: u64,
+    records_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_add_many");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let (logins, errors) = parse_batch(records_json.as_str(), |record| {
+            if record.get("id").is_none() {
+                // Note: we replace this with a real guid in `db.rs`.
+                record["id"] = serde_json::Value::String(String::default());
+            }
+        })?;
+        let results = state
+            .add_many(logins)?
+            .into_iter()
+            .map(BatchResult::from)
+            .collect::<Vec<_>>();
+        let results = merge_batch(errors, results);
+        let result = serde_json::to_string(&results)?;
+        Ok(result)
+    })
+}
+
+/// Like `sync15_passwords_add_many`, but updates existing logins. The
+/// `BatchResult` for each login has its GUID, or a coded error.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_update_many(
+    handle: u64,
+    records_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_update_many");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let (logins, errors) = parse_batch(records_json.as_str(), |_| {})?;
+        let ids = logins
+            .iter()
+            .map(|login| login.guid.to_string())
+            .collect::<Vec<_>>();
+        let results = state
+            .update_many(logins)?
+            .into_iter()
+            .zip(ids)
+            .map(|(result, id)| BatchResult::from(result.map(|_| id)))
+            .collect::<Vec<_>>();
+        let results = merge_batch(errors, results);
+        let result = serde_json::to_string(&results)?;
+        Ok(result)
+    })
+}
+
+/// `ids_json` is a JSON array of GUIDs to delete in one transaction. The
+/// `BatchResult` for each has its GUID, or a coded error. GUIDs that don't
+/// exist get a `NoSuchRecord` error.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_delete_many(
+    handle: u64,
+    ids_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_delete_many");
+    ENGINES.call_with_result(error, handle, |state| -> Result<String> {
+        let ids: Vec<String> = serde_json::from_str(ids_json.as_str())?;
+        let id_strs = ids.iter().map(String::as_str).collect::<Vec<_>>();
+        let results = state
+            .delete_many(&id_strs)?
+            .into_iter()
+            .zip(ids.iter())
+            .map(|(result, id)| {
+                BatchResult::from(result.and_then(|existed| {
+                    if existed {
+                        Ok(id.clone())
+                    } else {
+                        Err(ErrorKind::NoSuchRecord(id.clone()).into())
+                    }
+                }))
+            })
+            .collect::<Vec<_>>();
+        let result = serde_json::to_string(&results)?;
+        Ok(result)
+    })
+}
+
 define_string_destructor!(sync15_passwords_destroy_string);
 define_handle_map_deleter!(ENGINES, sync15_passwords_state_destroy);
 define_box_destructor!(
@@ -41,6 +41,15 @@ Sync15PasswordEngineHandle sync15_passwords_state_new_with_hex_key(char const *_
 void sync15_passwords_state_destroy(Sync15PasswordEngineHandle handle,
                                     Sync15PasswordsError *_Nonnull error_out);
 
+void sync15_passwords_rekey(Sync15PasswordEngineHandle handle,
+                            char const *_Nonnull new_encryption_key,
+                            Sync15PasswordsError *_Nonnull error_out);
+
+void sync15_passwords_rekey_with_hex_key(Sync15PasswordEngineHandle handle,
+                                         uint8_t const *new_encryption_key_bytes,
+                                         uint32_t new_encryption_key_len,
+                                         Sync15PasswordsError *_Nonnull error_out);
+
 char *_Nullable sync15_passwords_get_by_id(Sync15PasswordEngineHandle handle,
                                           char const *_Nonnull id,
                                           Sync15PasswordsError *_Nonnull error_out);
@@ -52,6 +61,41 @@ char *_Nullable sync15_passwords_get_by_id(Sync15PasswordEngineHandle handle,
 char *_Nullable sync15_passwords_get_all(Sync15PasswordEngineHandle handle,
                                          Sync15PasswordsError *_Nonnull error_out);
 
+char *_Nullable sync15_passwords_get_by_base_domain(Sync15PasswordEngineHandle handle,
+                                                    char const *_Nonnull origin,
+                                                    Sync15PasswordsError *_Nonnull error_out);
+
+char *_Nullable sync15_passwords_find_for_form(Sync15PasswordEngineHandle handle,
+                                               char const *_Nonnull origin,
+                                               char const *_Nullable form_action_origin,
+                                               char const *_Nullable http_realm,
+                                               Sync15PasswordsError *_Nonnull error_out);
+
+char *_Nullable sync15_passwords_get_password_history(Sync15PasswordEngineHandle handle,
+                                                      char const *_Nonnull id,
+                                                      Sync15PasswordsError *_Nonnull error_out);
+
+void sync15_passwords_restore_password(Sync15PasswordEngineHandle handle,
+                                       char const *_Nonnull id,
+                                       int64_t history_id,
+                                       Sync15PasswordsError *_Nonnull error_out);
+
+char *_Nullable sync15_passwords_get_breach_alerts(Sync15PasswordEngineHandle handle,
+                                                   char const *_Nonnull breaches_json,
+                                                   Sync15PasswordsError *_Nonnull error_out);
+
+void sync15_passwords_dismiss_breach_alert(Sync15PasswordEngineHandle handle,
+                                           char const *_Nonnull id,
+                                           Sync15PasswordsError *_Nonnull error_out);
+
+char *_Nullable sync15_passwords_find_duplicates(Sync15PasswordEngineHandle handle,
+                                                 Sync15PasswordsError *_Nonnull error_out);
+
+char *_Nullable sync15_passwords_merge_duplicates(Sync15PasswordEngineHandle handle,
+                                                  char const *_Nonnull survivor_id,
+                                                  char const *_Nonnull duplicate_ids_json,
+                                                  Sync15PasswordsError *_Nonnull error_out);
+
 char *_Nullable sync15_passwords_sync(Sync15PasswordEngineHandle handle,
                                       char const *_Nonnull key_id,
                                       char const *_Nonnull access_token,
@@ -87,6 +131,18 @@ void sync15_passwords_update(Sync15PasswordEngineHandle handle,
                              char const *_Nonnull json,
                              Sync15PasswordsError *_Nonnull error);
 
+char *_Nullable sync15_passwords_add_many(Sync15PasswordEngineHandle handle,
+                                          char const *_Nonnull json,
+                                          Sync15PasswordsError *_Nonnull error);
+
+char *_Nullable sync15_passwords_update_many(Sync15PasswordEngineHandle handle,
+                                             char const *_Nonnull json,
+                                             Sync15PasswordsError *_Nonnull error);
+
+char *_Nullable sync15_passwords_delete_many(Sync15PasswordEngineHandle handle,
+                                             char const *_Nonnull ids_json,
+                                             Sync15PasswordsError *_Nonnull error);
+
 void sync15_passwords_destroy_string(char const *_Nonnull str);
 
 Sync15PasswordsInterruptHandle *_Nullable
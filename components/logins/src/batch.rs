@@ -0,0 +1,200 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Batch versions of `add`, `update`, and `delete`.
+//!
+//! Each batch runs in one transaction, but a record that fails doesn't stop
+//! the others: we run each record in its own savepoint, roll back just that
+//! record if it fails, and return its error in its place. Only interruption,
+//! or an error that leaves the transaction unusable, fails (and rolls back)
+//! the whole batch.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use sql_support::{ConnExt, SqlInterruptScope};
+
+impl LoginDb {
+    /// Runs `f` in a savepoint, which is rolled back if `f` fails. Must be
+    /// called inside a transaction.
+    fn with_savepoint<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<Result<T>> {
+        self.execute_batch("SAVEPOINT batch_record")?;
+        let result = f();
+        if result.is_err() {
+            self.execute_batch("ROLLBACK TO batch_record")?;
+        }
+        self.execute_batch("RELEASE batch_record")?;
+        Ok(result)
+    }
+
+    /// Adds each of `logins`, returning the added login, or the error, for
+    /// each.
+    pub fn add_many(
+        &self,
+        logins: Vec<Login>,
+        scope: &SqlInterruptScope,
+    ) -> Result<Vec<Result<Login>>> {
+        let tx = self.unchecked_transaction()?;
+        let mut results = Vec::with_capacity(logins.len());
+        for login in logins {
+            scope.err_if_interrupted()?;
+            results.push(self.with_savepoint(|| self.insert_login(login.fixup()?))?);
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    /// Updates each of `logins`, returning the result for each.
+    pub fn update_many(
+        &self,
+        logins: Vec<Login>,
+        scope: &SqlInterruptScope,
+    ) -> Result<Vec<Result<()>>> {
+        let tx = self.unchecked_transaction()?;
+        let mut results = Vec::with_capacity(logins.len());
+        for login in logins {
+            scope.err_if_interrupted()?;
+            results.push(self.with_savepoint(|| self.update_login(login.fixup()?))?);
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    /// Deletes the logins with `ids`, returning whether each existed.
+    pub fn delete_many(
+        &self,
+        ids: &[&str],
+        scope: &SqlInterruptScope,
+    ) -> Result<Vec<Result<bool>>> {
+        let tx = self.unchecked_transaction()?;
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            scope.err_if_interrupted()?;
+            results.push(self.with_savepoint(|| self.delete_login(id))?);
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{error_codes, BatchResult};
+
+    fn login(guid: &str, hostname: &str) -> Login {
+        Login {
+            guid: guid.into(),
+            hostname: hostname.into(),
+            http_realm: Some("realm".into()),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        }
+    }
+
+    #[test]
+    fn test_add_many() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        let scope = db.begin_interrupt_scope();
+        let results = db
+            .add_many(
+                vec![
+                    login("", "https://www.example.org"),
+                    // Duplicate GUID.
+                    login("dummy_000001", "https://www.example.net"),
+                    // Invalid.
+                    login("dummy_000002", ""),
+                    login("dummy_000003", "https://www.example.net"),
+                ],
+                &scope,
+            )
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        let added = results[0].as_ref().unwrap();
+        assert!(!added.guid.is_empty());
+        match results[1].as_ref().unwrap_err().kind() {
+            ErrorKind::DuplicateGuid(guid) => assert_eq!(guid, "dummy_000001"),
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        match results[2].as_ref().unwrap_err().kind() {
+            ErrorKind::InvalidLogin(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        assert_eq!(results[3].as_ref().unwrap().guid, "dummy_000003");
+
+        // Errors are returned over the FFI with the same codes as for single
+        // records.
+        let batch_results = results
+            .into_iter()
+            .map(|result| BatchResult::from(result.map(|login| login.guid.into_string())))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            serde_json::to_value(&batch_results[1..4]).unwrap(),
+            serde_json::json!([
+                { "error": {
+                    "code": error_codes::DUPLICATE_GUID,
+                    "message": "A duplicate GUID is present: \"dummy_000001\"",
+                } },
+                { "error": {
+                    "code": error_codes::INVALID_LOGIN,
+                    "message": "Invalid login: Hostname is empty",
+                } },
+                { "id": "dummy_000003" },
+            ])
+        );
+
+        assert_eq!(db.get_all().unwrap().len(), 3);
+        assert_eq!(
+            db.get_by_id("dummy_000001").unwrap().unwrap().hostname,
+            "https://www.example.com"
+        );
+    }
+
+    #[test]
+    fn test_update_and_delete_many() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        db.add(login("dummy_000002", "https://www.example.org"))
+            .unwrap();
+        let scope = db.begin_interrupt_scope();
+
+        let mut changed = login("dummy_000001", "https://www.example.com");
+        changed.password = "new password".into();
+        let results = db
+            .update_many(
+                vec![
+                    changed,
+                    login("dummy_000003", "https://www.example.net"),
+                    login("dummy_000002", ""),
+                ],
+                &scope,
+            )
+            .unwrap();
+        assert!(results[0].is_ok());
+        match results[1].as_ref().unwrap_err().kind() {
+            ErrorKind::NoSuchRecord(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        assert!(results[2].is_err());
+        assert_eq!(
+            db.get_by_id("dummy_000001").unwrap().unwrap().password,
+            "new password"
+        );
+        assert_eq!(
+            db.get_by_id("dummy_000002").unwrap().unwrap().hostname,
+            "https://www.example.org"
+        );
+
+        let results = db
+            .delete_many(&["dummy_000001", "dummy_000003"], &scope)
+            .unwrap();
+        assert!(results[0].as_ref().unwrap());
+        assert!(!results[1].as_ref().unwrap());
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,277 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Breach alerts for saved logins, following what Desktop does in
+//! `LoginBreaches.getPotentialBreachesByLoginGUID`.
+//!
+//! Callers supply the breaches, usually from a bundled or separately
+//! downloaded copy of the Firefox Monitor dataset; we never fetch them
+//! ourselves. A login is considered breached if:
+//!
+//! - The breach exposed passwords (its `data_classes` include `"Passwords"`).
+//! - The login's host is the breached domain, or one of its subdomains.
+//! - The login's password was last changed before the breach.
+//! - The user hasn't dismissed the alert for the login since the breach.
+//!
+//! Dismissals are stored in the local-only `loginsBreachAlertDismissals`
+//! table, and are removed with the login, or when the store is wiped. If an
+//! incoming record replaces a login with a different GUID, the dismissal
+//! moves to the new GUID.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::matching;
+use crate::util;
+use rusqlite::{named_params, Connection};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// The data class for breaches that exposed passwords.
+pub const PASSWORDS_DATA_CLASS: &str = "Passwords";
+
+/// A breach of a site's user data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Breach {
+    /// The breached domain, like `example.com`.
+    pub domain: String,
+    /// When the breach happened, in milliseconds.
+    pub breach_date: i64,
+    /// The kinds of data exposed by the breach, like `"Email addresses"` or
+    /// `"Passwords"`.
+    #[serde(default)]
+    pub data_classes: Vec<String>,
+}
+
+impl Breach {
+    fn exposed_passwords(&self) -> bool {
+        self.data_classes
+            .iter()
+            .any(|class| class == PASSWORDS_DATA_CLASS)
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        let domain = self.domain.to_ascii_lowercase();
+        let host = host.to_ascii_lowercase();
+        !domain.is_empty()
+            && (host == domain
+                || (host.ends_with(&domain) && host[..host.len() - domain.len()].ends_with('.')))
+    }
+}
+
+/// A saved login that was exposed by a breach.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreachAlert {
+    pub login: Login,
+    pub breach: Breach,
+}
+
+impl LoginDb {
+    /// Returns an alert for each login that was exposed by one of `breaches`,
+    /// and whose alert hasn't been dismissed. If several breaches match a
+    /// login, the alert is for the most recent one.
+    pub fn get_breach_alerts(&self, breaches: &[Breach]) -> Result<Vec<BreachAlert>> {
+        let breaches = breaches
+            .iter()
+            .filter(|breach| breach.exposed_passwords())
+            .collect::<Vec<_>>();
+        if breaches.is_empty() {
+            return Ok(Vec::new());
+        }
+        let dismissals = self
+            .query_rows_and_then_named(
+                "SELECT guid, timeDismissed FROM loginsBreachAlertDismissals",
+                &[],
+                |row| -> Result<(String, i64)> { Ok((row.get(0)?, row.get(1)?)) },
+            )?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
+        let mut alerts = Vec::new();
+        for login in self.get_all()? {
+            let host = match matching::host_for_origin(&login.hostname) {
+                Some(host) => host,
+                None => continue,
+            };
+            let dismissed = dismissals.get(login.guid.as_str()).cloned();
+            let breach = breaches
+                .iter()
+                .filter(|breach| {
+                    breach.matches_host(&host)
+                        && breach.breach_date > login.time_password_changed
+                        && dismissed.map_or(true, |time| breach.breach_date > time)
+                })
+                .max_by_key(|breach| breach.breach_date);
+            if let Some(breach) = breach {
+                alerts.push(BreachAlert {
+                    login,
+                    breach: (*breach).clone(),
+                });
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Dismisses the breach alert for the login with `id`. It will only be
+    /// alerted again for breaches that happen after now.
+    pub fn dismiss_breach_alert(&self, id: &str) -> Result<()> {
+        if !self.exists(id)? {
+            throw!(ErrorKind::NoSuchRecord(id.to_owned()));
+        }
+        self.execute_named_cached(
+            "REPLACE INTO loginsBreachAlertDismissals (guid, timeDismissed)
+             VALUES (:guid, :now_millis)",
+            named_params! {
+                ":guid": id,
+                ":now_millis": util::system_time_ms_i64(SystemTime::now()),
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Moves a dismissed breach alert to a new GUID, along with the login's
+/// password history, when we dedupe an incoming record against a local login
+/// with a different GUID.
+pub(crate) fn move_breach_dismissal(conn: &Connection, from: &str, to: &str) -> Result<()> {
+    conn.execute_named_cached(
+        "UPDATE OR REPLACE loginsBreachAlertDismissals SET guid = :to WHERE guid = :from",
+        named_params! { ":from": from, ":to": to },
+    )?;
+    Ok(())
+}
+
+/// Forgets the dismissed breach alert for the login with `guid`, when the
+/// login is deleted locally or by an incoming tombstone.
+pub(crate) fn delete_breach_dismissal(conn: &Connection, guid: &str) -> Result<()> {
+    conn.execute_named_cached(
+        "DELETE FROM loginsBreachAlertDismissals WHERE guid = :guid",
+        named_params! { ":guid": guid },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add_login;
+
+    fn breach(domain: &str, breach_date: i64) -> Breach {
+        Breach {
+            domain: domain.into(),
+            breach_date,
+            data_classes: vec!["Email addresses".into(), PASSWORDS_DATA_CLASS.into()],
+        }
+    }
+
+    fn alerted_hostnames(db: &LoginDb, breaches: &[Breach]) -> Vec<String> {
+        let mut hostnames = db
+            .get_breach_alerts(breaches)
+            .unwrap()
+            .into_iter()
+            .map(|alert| alert.login.hostname)
+            .collect::<Vec<_>>();
+        hostnames.sort();
+        hostnames
+    }
+
+    #[test]
+    fn test_breach_matches_host() {
+        let breach = breach("example.com", 0);
+        assert!(breach.matches_host("example.com"));
+        assert!(breach.matches_host("www.EXAMPLE.com"));
+        assert!(!breach.matches_host("notexample.com"));
+        assert!(!breach.matches_host("example.com.au"));
+        assert!(!Breach {
+            domain: "".into(),
+            ..breach
+        }
+        .matches_host("example.com"));
+    }
+
+    #[test]
+    fn test_get_breach_alerts() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        add_login(&db, "https://example.org", None, Some("realm"));
+        add_login(&db, "https://unbreached.com", None, Some("realm"));
+        db.execute_batch("UPDATE loginsL SET timePasswordChanged = 1000")
+            .unwrap();
+        let after = 2000;
+        let before = 500;
+
+        assert_eq!(
+            alerted_hostnames(
+                &db,
+                &[
+                    breach("example.com", after),
+                    breach("example.org", after),
+                    breach("unbreached.com", before),
+                ]
+            ),
+            vec!["https://example.org", "https://www.example.com"]
+        );
+
+        // Breaches that didn't expose passwords don't count.
+        let mut no_passwords = breach("example.com", after);
+        no_passwords.data_classes = vec!["Email addresses".into()];
+        assert!(db.get_breach_alerts(&[no_passwords]).unwrap().is_empty());
+
+        // The most recent breach wins.
+        let alerts = db
+            .get_breach_alerts(&[
+                breach("example.com", after),
+                breach("example.com", after + 5),
+            ])
+            .unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].login.guid.as_str(), guid);
+        assert_eq!(alerts[0].breach.breach_date, after + 5);
+    }
+
+    #[test]
+    fn test_dismiss_breach_alert() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        let login = db.get_by_id(&guid).unwrap().unwrap();
+        db.execute_batch("UPDATE loginsL SET timePasswordChanged = 1000")
+            .unwrap();
+        // Long before we dismiss it.
+        let old_breach = breach("example.com", 2000);
+        assert_eq!(
+            db.get_breach_alerts(&[old_breach.clone()]).unwrap().len(),
+            1
+        );
+
+        db.dismiss_breach_alert(login.guid.as_str()).unwrap();
+        assert!(db
+            .get_breach_alerts(&[old_breach.clone()])
+            .unwrap()
+            .is_empty());
+
+        // Breaches after the dismissal are alerted again.
+        let dismissed: i64 = db
+            .query_one("SELECT timeDismissed FROM loginsBreachAlertDismissals")
+            .unwrap();
+        let new_breach = breach("example.com", dismissed + 1);
+        assert_eq!(db.get_breach_alerts(&[new_breach]).unwrap().len(), 1);
+
+        match db.dismiss_breach_alert("nonexistent").unwrap_err().kind() {
+            ErrorKind::NoSuchRecord(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+
+        // Dismissals are removed with their login.
+        db.delete(login.guid.as_str()).unwrap();
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsBreachAlertDismissals")
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -2,22 +2,27 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::breaches;
+use crate::encryption::{self, EncryptorDecryptor};
 use crate::error::*;
+use crate::history;
 use crate::login::{LocalLogin, Login, MirrorLogin, SyncLoginData, SyncStatus};
+use crate::matching;
 use crate::schema;
 use crate::update_plan::UpdatePlan;
 use crate::util;
 use lazy_static::lazy_static;
 use rusqlite::{
     named_params,
-    types::{FromSql, ToSql},
-    Connection, NO_PARAMS,
+    types::{FromSql, ToSql, Value},
+    Connection, OpenFlags, Row, NO_PARAMS,
 };
 use sql_support::{self, ConnExt};
 use sql_support::{SqlInterruptHandle, SqlInterruptScope};
 use std::collections::HashSet;
+use std::fs;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{atomic::AtomicUsize, Arc};
 use std::time::SystemTime;
@@ -27,31 +32,68 @@ use sync15::{
 };
 use sync_guid::Guid;
 
+// Sets the SQLCipher key for `db`, along with the settings that SQLCipher
+// used before 4.0.0, which our databases were created with.
+fn set_sqlcipher_key(db: &Connection, key: &str) -> Result<()> {
+    db.set_pragma("key", key)?;
+    // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
+    // is less than ideal, but should be fine. Real uses of
+    // this (lockwise, etc) use a real random string for the
+    // encryption key, so the reduced KDF iteration count
+    // is fine.
+    db.set_pragma("cipher_page_size", 1024)?
+        .set_pragma("kdf_iter", 64000)?
+        .set_pragma("cipher_hmac_algorithm", "HMAC_SHA1")?
+        .set_pragma("cipher_kdf_algorithm", "PBKDF2_HMAC_SHA1")?;
+    Ok(())
+}
+
+// Returns the names of the columns in `table`, which are empty if there's no
+// such table.
+fn table_columns(db: &Connection, table: &str) -> Result<Vec<String>> {
+    db.query_rows_and_then_named(&format!("PRAGMA table_info({})", table), &[], |row| {
+        row.get::<_, String>("name")
+    })
+}
+
 pub struct LoginDb {
     pub db: Connection,
     interrupt_counter: Arc<AtomicUsize>,
+    // Set if the database uses field-level encryption instead of SQLCipher.
+    encdec: Option<EncryptorDecryptor>,
+    // Set if the database was opened with a SQLCipher key.
+    sqlcipher_encrypted: bool,
 }
 
 impl LoginDb {
     pub fn with_connection(db: Connection, encryption_key: Option<&str>) -> Result<Self> {
+        Self::init(db, encryption_key, None)
+    }
+
+    /// Like `with_connection`, but for a database that isn't encrypted with
+    /// SQLCipher, and instead encrypts the `username` and `password` of each
+    /// login with `field_encryption_key`.
+    pub fn with_connection_and_field_encryption(
+        db: Connection,
+        field_encryption_key: &str,
+    ) -> Result<Self> {
+        let encdec = EncryptorDecryptor::new(field_encryption_key)?;
+        Self::init(db, None, Some(encdec))
+    }
+
+    fn init(
+        db: Connection,
+        encryption_key: Option<&str>,
+        encdec: Option<EncryptorDecryptor>,
+    ) -> Result<Self> {
         #[cfg(test)]
         {
             util::init_test_logging();
         }
 
         if let Some(key) = encryption_key {
-            db.set_pragma("key", key)?
-                .set_pragma("secure_delete", true)?;
-
-            // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
-            // is less than ideal, but should be fine. Real uses of
-            // this (lockwise, etc) use a real random string for the
-            // encryption key, so the reduced KDF iteration count
-            // is fine.
-            db.set_pragma("cipher_page_size", 1024)?
-                .set_pragma("kdf_iter", 64000)?
-                .set_pragma("cipher_hmac_algorithm", "HMAC_SHA1")?
-                .set_pragma("cipher_kdf_algorithm", "PBKDF2_HMAC_SHA1")?;
+            set_sqlcipher_key(&db, key)?;
+            db.set_pragma("secure_delete", true)?;
         }
 
         // `temp_store = 2` is required on Android to force the DB to keep temp
@@ -59,17 +101,53 @@ impl LoginDb {
         // https://github.com/mozilla/mentat/issues/505. Ideally we'd only
         // do this on Android, or allow caller to configure it.
         db.set_pragma("temp_store", 2)?;
+        define_functions(&db)?;
 
         let mut logins = Self {
             db,
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
+            encdec,
+            // SQLCipher doesn't encrypt databases opened with an empty key.
+            sqlcipher_encrypted: encryption_key.map_or(false, |key| !key.is_empty()),
         };
         let tx = logins.db.transaction()?;
         schema::init(&tx)?;
         tx.commit()?;
+        schema::create_temp_triggers(&logins.db)?;
+        logins.check_field_encryption()?;
         Ok(logins)
     }
 
+    // Makes sure that we're opening a field-encrypted database with the
+    // right key, and other databases without one.
+    fn check_field_encryption(&self) -> Result<()> {
+        let canary = self.get_meta::<String>(schema::FIELD_ENCRYPTION_CANARY_META_KEY)?;
+        match (&self.encdec, canary) {
+            (None, None) => {}
+            (None, Some(_)) => throw!(ErrorKind::FieldEncryptionKeyRequired),
+            (Some(encdec), Some(canary)) => {
+                if !encdec.check_canary(&canary) {
+                    throw!(ErrorKind::IncorrectFieldEncryptionKey);
+                }
+            }
+            (Some(encdec), None) => {
+                // Either a new database, or one that stores every field in
+                // plaintext, which needs to be migrated first.
+                let has_logins = self.query_one::<bool>(
+                    "SELECT EXISTS(SELECT 1 FROM loginsL) OR EXISTS(SELECT 1 FROM loginsM)",
+                )?;
+                if has_logins {
+                    throw!(ErrorKind::NotFieldEncrypted);
+                }
+                self.put_meta(
+                    schema::FIELD_ENCRYPTION_CANARY_META_KEY,
+                    &encdec.encrypt(encryption::CANARY_TEXT)?,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn open(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
         Ok(Self::with_connection(
             Connection::open(path)?,
@@ -84,6 +162,209 @@ impl LoginDb {
         )?)
     }
 
+    pub fn open_with_field_encryption(
+        path: impl AsRef<Path>,
+        field_encryption_key: &str,
+    ) -> Result<Self> {
+        Self::with_connection_and_field_encryption(Connection::open(path)?, field_encryption_key)
+    }
+
+    pub fn open_in_memory_with_field_encryption(field_encryption_key: &str) -> Result<Self> {
+        Self::with_connection_and_field_encryption(
+            Connection::open_in_memory()?,
+            field_encryption_key,
+        )
+    }
+
+    /// Migrates a database encrypted with SQLCipher to a new database at
+    /// `path`, which must not exist yet, that uses field-level encryption.
+    /// The SQLCipher database is only read, not upgraded, so it's left as-is,
+    /// and callers should delete it once this succeeds.
+    ///
+    /// Usernames and passwords are encrypted before they're written, so they
+    /// never reach the disk in plaintext. The new database is built in a
+    /// temporary file next to `path`, and only moved into place once
+    /// everything's been copied; if we fail, the temporary file is deleted.
+    pub fn migrate_from_sqlcipher(
+        sqlcipher_path: impl AsRef<Path>,
+        sqlcipher_key: &str,
+        path: impl AsRef<Path>,
+        field_encryption_key: &str,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            throw!(ErrorKind::MigrationTargetExists(
+                path.to_string_lossy().into_owned()
+            ));
+        }
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".migrating");
+        let tmp_path = PathBuf::from(tmp_path);
+        // Left behind by a migration that crashed.
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        let result = Self::copy_from_sqlcipher(
+            sqlcipher_path.as_ref(),
+            sqlcipher_key,
+            &tmp_path,
+            field_encryption_key,
+        )
+        .and_then(|_| Ok(fs::rename(&tmp_path, path)?));
+        if let Err(e) = result {
+            if let Err(remove_err) = fs::remove_file(&tmp_path) {
+                log::warn!("Failed to remove {:?}: {}", tmp_path, remove_err);
+            }
+            return Err(e);
+        }
+        Self::open_with_field_encryption(path, field_encryption_key)
+    }
+
+    // Copies every row from the SQLCipher database into a new field-encrypted
+    // database at `path`, encrypting usernames and passwords as we go.
+    fn copy_from_sqlcipher(
+        sqlcipher_path: &Path,
+        sqlcipher_key: &str,
+        path: &Path,
+        field_encryption_key: &str,
+    ) -> Result<()> {
+        let conn = Connection::open(path)?;
+        // Overwrite anything we delete, so it doesn't linger in free pages.
+        conn.set_pragma("secure_delete", true)?;
+        let new = Self::with_connection_and_field_encryption(conn, field_encryption_key)?;
+        let encdec = new
+            .encdec
+            .as_ref()
+            .expect("Should have a field encryption key");
+        // We don't use `Self::open`, because that would upgrade the old
+        // database's schema. Instead, we copy the columns that both databases
+        // have, and bring the copied rows up to date afterward.
+        let old = Connection::open_with_flags(sqlcipher_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        set_sqlcipher_key(&old, sqlcipher_key)?;
+        let old_version = old.query_one::<i64>("PRAGMA user_version")?;
+        if old_version == 0 {
+            // Either an empty database, or one so old that `schema::init`
+            // would drop its tables, so there's nothing to copy.
+            return Ok(());
+        }
+        let tx = new.db.unchecked_transaction()?;
+        for &(table, encrypted_cols) in &[
+            ("loginsL", &["username", "password"][..]),
+            ("loginsM", &["username", "password"][..]),
+            ("loginsHistory", &["password"][..]),
+            ("loginsBreachAlertDismissals", &[][..]),
+            ("loginsSyncMeta", &[][..]),
+        ] {
+            let old_cols = table_columns(&old, table)?;
+            let cols = table_columns(&tx, table)?
+                .into_iter()
+                .filter(|col| old_cols.contains(col))
+                .collect::<Vec<_>>();
+            if cols.is_empty() {
+                // The old database is from before this table was added.
+                continue;
+            }
+            let rows = old.query_rows_and_then_named(
+                &format!("SELECT {} FROM {}", cols.join(", "), table),
+                &[],
+                |row| {
+                    (0..cols.len())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<rusqlite::Result<Vec<_>>>()
+                },
+            )?;
+            let insert_sql = format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                table,
+                cols.join(", "),
+                sql_support::repeat_sql_vars(cols.len()),
+            );
+            for mut values in rows {
+                for (col, value) in cols.iter().zip(values.iter_mut()) {
+                    if !encrypted_cols.contains(&col.as_str()) {
+                        continue;
+                    }
+                    // Tombstones don't have usernames.
+                    let plaintext = match value {
+                        Value::Text(text) => text.as_str(),
+                        _ => "",
+                    };
+                    *value = Value::Text(encdec.encrypt(plaintext)?);
+                }
+                tx.execute_cached(&insert_sql, &values)?;
+            }
+        }
+        schema::upgrade_copied_rows(&tx, old_version)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Changes the key that the database is encrypted with. For SQLCipher
+    /// databases, `new_key` is the new SQLCipher key, and SQLCipher re-encrypts
+    /// every page in a single transaction. For field-encrypted databases,
+    /// `new_key` is a new field encryption key, and we re-encrypt every login,
+    /// and its password history, in a single transaction. Either way, if we're
+    /// interrupted, the database is left encrypted with the old key.
+    pub fn rekey(&mut self, new_key: &str) -> Result<()> {
+        if let Some(encdec) = &self.encdec {
+            let new_encdec = EncryptorDecryptor::new(new_key)?;
+            let tx = self.db.unchecked_transaction()?;
+            for table in &["loginsL", "loginsM"] {
+                let logins = tx.query_rows_and_then_named(
+                    &format!("SELECT guid, username, password FROM {}", table),
+                    &[],
+                    |row| -> Result<(String, Option<String>, String)> {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    },
+                )?;
+                for (guid, username, password) in logins {
+                    let username = encdec.decrypt(&username.unwrap_or_default())?;
+                    let password = encdec.decrypt(&password)?;
+                    tx.execute_named_cached(
+                        &format!(
+                            "UPDATE {} SET username = :username, password = :password
+                             WHERE guid = :guid",
+                            table
+                        ),
+                        named_params! {
+                            ":guid": guid,
+                            ":username": new_encdec.encrypt(&username)?,
+                            ":password": new_encdec.encrypt(&password)?,
+                        },
+                    )?;
+                }
+            }
+            let history = tx.query_rows_and_then_named(
+                "SELECT id, password FROM loginsHistory",
+                &[],
+                |row| -> Result<(i64, String)> { Ok((row.get(0)?, row.get(1)?)) },
+            )?;
+            for (id, password) in history {
+                tx.execute_named_cached(
+                    "UPDATE loginsHistory SET password = :password WHERE id = :id",
+                    named_params! {
+                        ":id": id,
+                        ":password": new_encdec.encrypt(&encdec.decrypt(&password)?)?,
+                    },
+                )?;
+            }
+            self.put_meta(
+                schema::FIELD_ENCRYPTION_CANARY_META_KEY,
+                &new_encdec.encrypt(encryption::CANARY_TEXT)?,
+            )?;
+            tx.commit()?;
+            self.encdec = Some(new_encdec);
+        } else if self.sqlcipher_encrypted {
+            if new_key.is_empty() {
+                throw!(ErrorKind::CannotRekey("The new key is empty"));
+            }
+            self.db.set_pragma("rekey", new_key)?;
+        } else {
+            throw!(ErrorKind::CannotRekey("The database isn't encrypted"));
+        }
+        Ok(())
+    }
+
     pub fn disable_mem_security(&self) -> Result<()> {
         self.conn().set_pragma("cipher_memory_security", false)?;
         Ok(())
@@ -120,6 +401,29 @@ impl Deref for LoginDb {
 // login specific stuff.
 
 impl LoginDb {
+    /// Reads a login from a row, decrypting its fields if needed.
+    fn login_from_row(&self, row: &Row<'_>) -> Result<Login> {
+        let mut login = Login::from_row(row)?;
+        encryption::decrypt_login(self.encdec.as_ref(), &mut login)?;
+        Ok(login)
+    }
+
+    /// Returns the field encryptor for databases that use field-level
+    /// encryption.
+    pub(crate) fn encdec(&self) -> Option<&EncryptorDecryptor> {
+        self.encdec.as_ref()
+    }
+
+    /// Encrypts a field before writing it, if needed.
+    fn encrypt_field(&self, value: &str) -> Result<String> {
+        encryption::encrypt_field(self.encdec.as_ref(), value)
+    }
+
+    /// Decrypts a field that we just read, if needed.
+    fn decrypt_field(&self, value: String) -> Result<String> {
+        encryption::decrypt_field(self.encdec.as_ref(), value)
+    }
+
     fn mark_as_synchronized(
         &self,
         guids: &[&str],
@@ -186,7 +490,23 @@ impl LoginDb {
                 }
                 seen_ids.insert(incoming.0.id.clone());
                 match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
-                    Ok(v) => sync_data.push(v),
+                    Ok(mut v) => {
+                        if let Some(login) = v.inbound.0.take() {
+                            match login.fixed_up() {
+                                Ok(login) => v.inbound.0 = Some(login),
+                                Err(reason) => {
+                                    log::warn!(
+                                        "Skipping invalid record {:?}: {}",
+                                        incoming.0.id,
+                                        reason
+                                    );
+                                    telem.failed(1);
+                                    continue;
+                                }
+                            }
+                        }
+                        sync_data.push(v)
+                    }
                     Err(e) => {
                         log::error!("Failed to deserialize record {:?}: {}", incoming.0.id, e);
                         // Ideally we'd track new_failed, but it's unclear how
@@ -198,9 +518,16 @@ impl LoginDb {
         }
         scope.err_if_interrupted()?;
 
+        // We skip records that we can't use, so we look up local and mirror
+        // rows by the guids that we kept, to keep the indexes lined up with
+        // `sync_data`.
+        let guids = sync_data
+            .iter()
+            .map(|data| data.guid.clone())
+            .collect::<Vec<_>>();
         sql_support::each_chunk_mapped(
-            &records,
-            |r| r.0.id.as_str(),
+            &guids,
+            |guid| guid.as_str(),
             |chunk, offset| -> Result<()> {
                 // pairs the bound parameter for the guid with an integer index.
                 let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
@@ -250,9 +577,13 @@ impl LoginDb {
                     let guid_idx = guid_idx_i as usize;
                     let is_mirror: bool = row.get("is_mirror")?;
                     if is_mirror {
-                        sync_data[guid_idx].set_mirror(MirrorLogin::from_row(row)?)?;
+                        let mut mirror = MirrorLogin::from_row(row)?;
+                        encryption::decrypt_login(self.encdec.as_ref(), &mut mirror.login)?;
+                        sync_data[guid_idx].set_mirror(mirror)?;
                     } else {
-                        sync_data[guid_idx].set_local(LocalLogin::from_row(row)?)?;
+                        let mut local = LocalLogin::from_row(row)?;
+                        encryption::decrypt_login(self.encdec.as_ref(), &mut local.login)?;
+                        sync_data[guid_idx].set_local(local)?;
                     }
                     scope.err_if_interrupted()?;
                     Ok(())
@@ -268,6 +599,20 @@ impl LoginDb {
     // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
     // for each one if they exist)... I can't think of how to write that query, though.
     fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
+        self.find_dupe_in(l, "loginsL", "")
+    }
+
+    /// Like `find_dupe`, but ignores deleted local logins, and also looks for
+    /// dupes in the mirror, so that importing a login we've already synced
+    /// doesn't add it again.
+    pub(crate) fn find_live_dupe(&self, l: &Login) -> Result<Option<Login>> {
+        if let Some(dupe) = self.find_dupe_in(l, "loginsL", "AND is_deleted = 0")? {
+            return Ok(Some(dupe));
+        }
+        self.find_dupe_in(l, "loginsM", "AND is_overridden = 0")
+    }
+
+    fn find_dupe_in(&self, l: &Login, table: &str, filter: &str) -> Result<Option<Login>> {
         let form_submit_host_port = l
             .form_submit_url
             .as_ref()
@@ -275,16 +620,19 @@ impl LoginDb {
         let args = named_params! {
             ":hostname": l.hostname,
             ":http_realm": l.http_realm,
-            ":username": l.username,
             ":form_submit": form_submit_host_port,
         };
+        // We compare usernames after reading the rows, since they might be
+        // encrypted.
         let mut query = format!(
             "SELECT {common}
-             FROM loginsL
+             FROM {table}
              WHERE hostname IS :hostname
                AND httpRealm IS :http_realm
-               AND username IS :username",
+               {filter}",
             common = schema::COMMON_COLS,
+            table = table,
+            filter = filter,
         );
         if form_submit_host_port.is_some() {
             // Stolen from iOS
@@ -292,12 +640,16 @@ impl LoginDb {
         } else {
             query += " AND formSubmitURL IS :form_submit"
         }
-        Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
+        let candidates =
+            self.query_rows_and_then_named(&query, args, |row| self.login_from_row(row))?;
+        Ok(candidates
+            .into_iter()
+            .find(|candidate| candidate.username == l.username))
     }
 
     pub fn get_all(&self) -> Result<Vec<Login>> {
         let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
-        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| self.login_from_row(row))?;
         rows.collect::<Result<_>>()
     }
 
@@ -305,11 +657,70 @@ impl LoginDb {
         self.try_query_row(
             &GET_BY_GUID_SQL,
             &[(":guid", &id as &dyn ToSql)],
-            Login::from_row,
+            |row| self.login_from_row(row),
             true,
         )
     }
 
+    /// Returns all logins for origins on the same base domain (eTLD+1) as
+    /// `origin`, which can be an origin like `https://www.example.com`, or a
+    /// bare host.
+    pub fn get_by_base_domain(&self, origin: &str) -> Result<Vec<Login>> {
+        let base_domain = match matching::base_domain_for_origin(origin) {
+            Some(base_domain) => base_domain,
+            None => return Ok(Vec::new()),
+        };
+        let mut stmt = self.db.prepare_cached(&GET_BY_BASE_DOMAIN_SQL)?;
+        let logins: Vec<Login> = stmt
+            .query_and_then_named(&[(":base_domain", &base_domain as &dyn ToSql)], |row| {
+                self.login_from_row(row)
+            })?
+            .collect::<Result<_>>()?;
+        Ok(logins)
+    }
+
+    /// Returns the logins that can fill in a form on `origin`. If
+    /// `form_action_origin` is given, only form logins that submit to it, or
+    /// to any origin, are returned. If `http_realm` is given, only HTTP auth
+    /// logins for that realm are returned. Logins saved for `origin` itself
+    /// come before ones saved for its `http` version.
+    pub fn find_for_form(
+        &self,
+        origin: &str,
+        form_action_origin: Option<&str>,
+        http_realm: Option<&str>,
+    ) -> Result<Vec<Login>> {
+        let origins = matching::lookup_origins(origin);
+        let mut logins = self.get_by_hostnames(&origins)?;
+        logins.retain(|login| {
+            let action_matches = form_action_origin.map_or(true, |action| {
+                login
+                    .form_submit_url
+                    .as_ref()
+                    .map_or(false, |url| matching::is_origin_matching(url, action, true))
+            });
+            let realm_matches = http_realm.map_or(true, |realm| {
+                login.http_realm.as_ref().map_or(false, |r| r == realm)
+            });
+            action_matches && realm_matches
+        });
+        logins.sort_by_key(|login| login.hostname != origins[0]);
+        Ok(logins)
+    }
+
+    pub(crate) fn get_by_hostnames(&self, hostnames: &[String]) -> Result<Vec<Login>> {
+        let mut logins = Vec::with_capacity(hostnames.len());
+        sql_support::each_chunk(hostnames, |chunk, _| -> Result<()> {
+            let mut stmt = self.db.prepare(&get_by_hostnames_sql(chunk.len()))?;
+            let rows = stmt.query_and_then(chunk, |row| self.login_from_row(row))?;
+            for login in rows {
+                logins.push(login?);
+            }
+            Ok(())
+        })?;
+        Ok(logins)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         let tx = self.unchecked_transaction()?;
         self.ensure_local_overlay_exists(id)?;
@@ -333,10 +744,17 @@ impl LoginDb {
         Ok(())
     }
 
-    pub fn add(&self, mut login: Login) -> Result<Login> {
-        login.check_valid()?;
-
+    pub fn add(&self, login: Login) -> Result<Login> {
+        let login = login.fixup()?;
         let tx = self.unchecked_transaction()?;
+        let login = self.insert_login(login)?;
+        tx.commit()?;
+        Ok(login)
+    }
+
+    /// Inserts a new, already fixed up, login. Must be called inside a
+    /// transaction.
+    pub(crate) fn insert_login(&self, mut login: Login) -> Result<Login> {
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
         // Allow an empty GUID to be passed to indicate that we should generate
@@ -398,8 +816,8 @@ impl LoginDb {
                 ":form_submit_url": login.form_submit_url,
                 ":username_field": login.username_field,
                 ":password_field": login.password_field,
-                ":username": login.username,
-                ":password": login.password,
+                ":username": self.encrypt_field(&login.username)?,
+                ":password": self.encrypt_field(&login.password)?,
                 ":guid": login.guid,
                 ":time_created": login.time_created,
                 ":times_used": login.times_used,
@@ -415,18 +833,42 @@ impl LoginDb {
             );
             throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
         }
-        tx.commit()?;
         Ok(login)
     }
 
     pub fn update(&self, login: Login) -> Result<()> {
-        login.check_valid()?;
+        let login = login.fixup()?;
         let tx = self.unchecked_transaction()?;
+        self.update_login(login)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Updates an existing, already fixed up, login. Must be called inside a
+    /// transaction.
+    pub(crate) fn update_login(&self, login: Login) -> Result<()> {
         // Note: These fail with DuplicateGuid if the record doesn't exist.
         self.ensure_local_overlay_exists(login.guid_str())?;
         self.mark_mirror_overridden(login.guid_str())?;
 
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        // We can't compare passwords in SQL, since they might be encrypted.
+        let old_password = self.db.query_row_named(
+            "SELECT password FROM loginsL WHERE guid = :guid",
+            named_params! { ":guid": login.guid },
+            |row| row.get::<_, String>(0),
+        )?;
+        let old_password = self.decrypt_field(old_password)?;
+        let password_changed = old_password != login.password;
+        if password_changed {
+            history::record_password_change(
+                &self.db,
+                self.encdec(),
+                login.guid_str(),
+                &old_password,
+                now_ms,
+            )?;
+        }
 
         let sql = format!(
             "UPDATE loginsL
@@ -434,9 +876,9 @@ impl LoginDb {
                  timeLastUsed        = :now_millis,
                  -- Only update timePasswordChanged if, well, the password changed.
                  timePasswordChanged = (CASE
-                     WHEN password = :password
-                     THEN timePasswordChanged
-                     ELSE :now_millis
+                     WHEN :password_changed
+                     THEN :now_millis
+                     ELSE timePasswordChanged
                  END),
                  httpRealm           = :http_realm,
                  formSubmitURL       = :form_submit_url,
@@ -456,8 +898,9 @@ impl LoginDb {
             &sql,
             named_params! {
                 ":hostname": login.hostname,
-                ":username": login.username,
-                ":password": login.password,
+                ":username": self.encrypt_field(&login.username)?,
+                ":password": self.encrypt_field(&login.password)?,
+                ":password_changed": password_changed,
                 ":http_realm": login.http_realm,
                 ":form_submit_url": login.form_submit_url,
                 ":username_field": login.username_field,
@@ -466,7 +909,6 @@ impl LoginDb {
                 ":now_millis": now_ms,
             },
         )?;
-        tx.commit()?;
         Ok(())
     }
 
@@ -488,6 +930,14 @@ impl LoginDb {
     /// existed already.
     pub fn delete(&self, id: &str) -> Result<bool> {
         let tx = self.unchecked_transaction_imm()?;
+        let exists = self.delete_login(id)?;
+        tx.commit()?;
+        Ok(exists)
+    }
+
+    /// Deletes a login, leaving a tombstone if it was synced. Must be called
+    /// inside a transaction.
+    pub(crate) fn delete_login(&self, id: &str) -> Result<bool> {
         let exists = self.exists(id)?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
@@ -534,11 +984,13 @@ impl LoginDb {
             WHERE guid = :guid",
             changed = SyncStatus::Changed as u8),
             named_params! { ":now_ms": now_ms, ":guid": id })?;
-        tx.commit()?;
+
+        history::delete_password_history(&self.db, id)?;
+        breaches::delete_breach_dismissal(&self.db, id)?;
         Ok(exists)
     }
 
-    fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
+    pub(crate) fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
         self.execute_named_cached(
             "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
             named_params! { ":guid": guid },
@@ -546,7 +998,7 @@ impl LoginDb {
         Ok(())
     }
 
-    fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
+    pub(crate) fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
         let already_have_local: bool = self.db.query_row_named(
             "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid)",
             named_params! { ":guid": guid },
@@ -636,6 +1088,11 @@ impl LoginDb {
                 changed = SyncStatus::Changed as u8),
             named_params! { ":now_ms": now_ms })?;
         scope.err_if_interrupted()?;
+
+        self.execute_all(&[
+            "DELETE FROM loginsHistory",
+            "DELETE FROM loginsBreachAlertDismissals",
+        ])?;
         tx.commit()?;
         Ok(())
     }
@@ -646,7 +1103,14 @@ impl LoginDb {
         self.execute_all(&[
             "DELETE FROM loginsL",
             "DELETE FROM loginsM",
-            "DELETE FROM loginsSyncMeta",
+            "DELETE FROM loginsHistory",
+            "DELETE FROM loginsBreachAlertDismissals",
+            // Keep the canary, so that we still know if the database uses
+            // field-level encryption.
+            &format!(
+                "DELETE FROM loginsSyncMeta WHERE key <> '{}'",
+                schema::FIELD_ENCRYPTION_CANARY_META_KEY
+            ),
         ])?;
         tx.commit()?;
         Ok(())
@@ -678,8 +1142,9 @@ impl LoginDb {
                     plan.plan_three_way_merge(local, mirror, upstream, upstream_time, server_now);
                     telem.reconciled(1);
                 }
-                (Some(_mirror), None) => {
+                (Some(mirror), None) => {
                     log::debug!("  Forwarding mirror to remote");
+                    plan.plan_password_change(&mirror.login, &upstream);
                     plan.plan_mirror_update(upstream, upstream_time);
                     telem.applied(1);
                 }
@@ -712,7 +1177,7 @@ impl LoginDb {
         // (as a way to save us from ourselves), we side-step that by creating
         // it manually.
         let tx = self.db.unchecked_transaction()?;
-        plan.execute(&tx, scope)?;
+        plan.execute(&tx, scope, self.encdec.as_ref())?;
         tx.commit()?;
         Ok(())
     }
@@ -737,7 +1202,7 @@ impl LoginDb {
                 Payload::new_tombstone(row.get::<_, String>("guid")?)
                     .with_sortindex(TOMBSTONE_SORTINDEX)
             } else {
-                let login = Login::from_row(row)?;
+                let login = self.login_from_row(row)?;
                 Payload::from_record(login)?.with_sortindex(DEFAULT_SORTINDEX)
             })
         })?;
@@ -746,23 +1211,46 @@ impl LoginDb {
         Ok(outgoing)
     }
 
+    fn plan_incoming(
+        &self,
+        inbound: &IncomingChangeset,
+        telem: &mut telemetry::Engine,
+        scope: &SqlInterruptScope,
+    ) -> Result<UpdatePlan> {
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let data = self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
+        let result = self.reconcile(data, inbound.timestamp, &mut incoming_telemetry, scope);
+        telem.incoming(incoming_telemetry);
+        result
+    }
+
     fn do_apply_incoming(
         &self,
         inbound: IncomingChangeset,
         telem: &mut telemetry::Engine,
         scope: &SqlInterruptScope,
     ) -> Result<OutgoingChangeset> {
-        let mut incoming_telemetry = telemetry::EngineIncoming::new();
-        let data = self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
-        let plan = {
-            let result = self.reconcile(data, inbound.timestamp, &mut incoming_telemetry, scope);
-            telem.incoming(incoming_telemetry);
-            result
-        }?;
+        let plan = self.plan_incoming(&inbound, telem, scope)?;
         self.execute_plan(plan, scope)?;
         Ok(self.fetch_outgoing(inbound.timestamp, scope)?)
     }
 
+    /// Like `do_apply_incoming`, but rolls back the changes after fetching
+    /// the outgoing records, for a dry-run sync.
+    fn do_apply_incoming_dry_run(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+        scope: &SqlInterruptScope,
+    ) -> Result<OutgoingChangeset> {
+        let plan = self.plan_incoming(&inbound, telem, scope)?;
+        let tx = self.db.unchecked_transaction()?;
+        plan.execute(&tx, scope, self.encdec.as_ref())?;
+        let outgoing = self.fetch_outgoing(inbound.timestamp, scope)?;
+        tx.rollback()?;
+        Ok(outgoing)
+    }
+
     fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
         self.execute_named_cached(
             "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
@@ -833,7 +1321,8 @@ impl LoginDb {
     }
 }
 
-pub(crate) struct LoginStore<'a> {
+/// The `Store` that syncs logins.
+pub struct LoginStore<'a> {
     pub db: &'a LoginDb,
     pub scope: sql_support::SqlInterruptScope,
 }
@@ -860,6 +1349,16 @@ impl<'a> Store for LoginStore<'a> {
         Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
     }
 
+    fn apply_incoming_dry_run(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self
+            .db
+            .do_apply_incoming_dry_run(inbound, telem, &self.scope)?)
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -931,11 +1430,235 @@ lazy_static! {
     );
     static ref CLONE_SINGLE_MIRROR_SQL: String =
         format!("{} WHERE guid = :guid", &*CLONE_ENTIRE_MIRROR_SQL,);
+    // Both halves of this query use the `(is_deleted, baseDomain)` and
+    // `(is_overridden, baseDomain)` indexes.
+    static ref GET_BY_BASE_DOMAIN_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0 AND baseDomain = :base_domain
+
+         UNION ALL
+
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0 AND baseDomain = :base_domain",
+        common_cols = schema::COMMON_COLS,
+    );
+}
+
+// Defines the SQL functions that the triggers in `schema` use.
+fn define_functions(c: &Connection) -> Result<()> {
+    c.create_scalar_function("base_domain", 1, true, |ctx| {
+        Ok(matching::base_domain_for_origin(&ctx.get::<String>(0)?))
+    })?;
+    Ok(())
+}
+
+// Both halves of this query use the `(is_deleted, hostname)` and
+// `(is_overridden, hostname)` indexes.
+fn get_by_hostnames_sql(count: usize) -> String {
+    format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0 AND hostname IN ({vars})
+
+         UNION ALL
+
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0 AND hostname IN ({vars})",
+        common_cols = schema::COMMON_COLS,
+        // Numbered, so that both halves can share the same parameters.
+        vars = sql_support::repeat_display(count, ",", |i, f| write!(f, "?{}", i + 1)),
+    )
+}
+
+/// Adds a login with the username `user` and password `password` for tests,
+/// and returns its GUID.
+#[cfg(test)]
+pub(crate) fn add_login(
+    db: &LoginDb,
+    hostname: &str,
+    form_submit_url: Option<&str>,
+    http_realm: Option<&str>,
+) -> String {
+    db.add(Login {
+        hostname: hostname.into(),
+        form_submit_url: form_submit_url.map(Into::into),
+        http_realm: http_realm.map(Into::into),
+        username: "user".into(),
+        password: "password".into(),
+        ..Login::default()
+    })
+    .expect("should add login")
+    .guid
+    .into_string()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn guids(logins: &[Login]) -> Vec<&str> {
+        logins.iter().map(Login::guid_str).collect()
+    }
+
+    #[test]
+    fn test_get_by_base_domain() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let www = add_login(&db, "https://www.example.com", Some(""), None);
+        let accounts = add_login(&db, "http://accounts.example.com", Some(""), None);
+        let bare = add_login(&db, "https://example.com:8443", None, Some("realm"));
+        add_login(&db, "https://example.org", Some(""), None);
+        add_login(&db, "https://notexample.com", Some(""), None);
+
+        let mut expected = vec![www.as_str(), accounts.as_str(), bare.as_str()];
+        expected.sort();
+        for origin in &[
+            "https://example.com",
+            "https://a.b.example.com/path",
+            "example.com",
+        ] {
+            let logins = db.get_by_base_domain(origin).unwrap();
+            let mut found = guids(&logins);
+            found.sort();
+            assert_eq!(found, expected, "Wrong logins for {}", origin);
+        }
+
+        assert!(db
+            .get_by_base_domain("https://example.net")
+            .unwrap()
+            .is_empty());
+        assert!(db.get_by_base_domain("").unwrap().is_empty());
+
+        // Changing a login's hostname moves it to the new base domain.
+        let mut login = db.get_by_id(&www).unwrap().unwrap();
+        login.hostname = "https://www.example.org".into();
+        db.update(login).unwrap();
+        assert_eq!(
+            db.get_by_base_domain("https://example.com").unwrap().len(),
+            2
+        );
+        assert_eq!(
+            db.get_by_base_domain("https://example.org").unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_get_by_base_domain_uses_index() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let mut stmt = db
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", &*GET_BY_BASE_DOMAIN_SQL))
+            .unwrap();
+        let details = stmt
+            .query_and_then_named(&[(":base_domain", &"example.com" as &dyn ToSql)], |row| {
+                row.get::<_, String>(3)
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert!(
+            details
+                .iter()
+                .any(|d| d.contains("idx_loginsL_is_deleted_baseDomain")),
+            "Should use the local base domain index: {:?}",
+            details
+        );
+        assert!(
+            details
+                .iter()
+                .any(|d| d.contains("idx_loginsM_is_overridden_baseDomain")),
+            "Should use the mirror base domain index: {:?}",
+            details
+        );
+    }
+
+    #[test]
+    fn test_find_for_form() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let https = add_login(
+            &db,
+            "https://example.com",
+            Some("https://example.com"),
+            None,
+        );
+        let http = add_login(&db, "http://example.com", Some("http://example.com"), None);
+        let wildcard = add_login(&db, "https://example.com", Some(""), None);
+        let other_action = add_login(&db, "https://example.com", Some("https://other.com"), None);
+        let realm = add_login(&db, "https://example.com", None, Some("My Realm"));
+        add_login(&db, "https://www.example.com", Some(""), None);
+
+        // Scheme upgrades apply to both the origin and the form action
+        // origin. Logins for the exact origin come first.
+        let logins = db
+            .find_for_form("https://example.com", Some("https://example.com"), None)
+            .unwrap();
+        let mut found = guids(&logins);
+        assert_eq!(found.pop(), Some(http.as_str()));
+        found.sort();
+        let mut expected = vec![https.as_str(), wildcard.as_str()];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // ...But we never downgrade.
+        let logins = db
+            .find_for_form("http://example.com", Some("http://example.com"), None)
+            .unwrap();
+        assert_eq!(guids(&logins), vec![http.as_str()]);
+
+        let logins = db
+            .find_for_form("https://example.com", Some("https://other.com"), None)
+            .unwrap();
+        let mut found = guids(&logins);
+        found.sort();
+        let mut expected = vec![wildcard.as_str(), other_action.as_str()];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let logins = db
+            .find_for_form("https://example.com", None, Some("My Realm"))
+            .unwrap();
+        assert_eq!(guids(&logins), vec![realm.as_str()]);
+        assert!(db
+            .find_for_form("https://example.com", None, Some("Other Realm"))
+            .unwrap()
+            .is_empty());
+
+        // Without a form action origin or realm, we return everything for the
+        // origin.
+        assert_eq!(
+            db.find_for_form("https://example.com", None, None)
+                .unwrap()
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_get_by_hostnames_uses_index() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let mut stmt = db
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", get_by_hostnames_sql(2)))
+            .unwrap();
+        let details = stmt
+            .query_and_then(&["https://a.com", "https://b.com"], |row| {
+                row.get::<_, String>(3)
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert!(
+            details
+                .iter()
+                .any(|d| d.contains("idx_loginsL_is_deleted_hostname")),
+            "Should use the local hostname index: {:?}",
+            details
+        );
+        assert!(
+            details
+                .iter()
+                .any(|d| d.contains("idx_loginsM_is_overridden_hostname")),
+            "Should use the mirror hostname index: {:?}",
+            details
+        );
+    }
     #[test]
     fn test_bad_record() {
         let db = LoginDb::open_in_memory(Some("testing")).unwrap();
@@ -981,4 +1704,538 @@ mod tests {
         assert_eq!(res[0].guid, "dummy_000001");
         assert_eq!(res[1].guid, "dummy_000003");
     }
+
+    #[test]
+    fn test_password_history_incoming() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", Some(""), None);
+        let scope = db.begin_interrupt_scope();
+        db.mark_as_synchronized(&[guid.as_str()], ServerTimestamp(1000), &scope)
+            .unwrap();
+
+        let mut telem = telemetry::Engine::new("passwords");
+        let incoming = |payload: serde_json::Value| IncomingChangeset {
+            changes: vec![(Payload::from_json(payload).unwrap(), ServerTimestamp(2000))],
+            timestamp: ServerTimestamp(2000),
+            collection: "passwords".into(),
+            next_offset: None,
+        };
+        db.do_apply_incoming(
+            incoming(serde_json::json!({
+                "id": guid,
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "",
+                "username": "user",
+                "password": "new password",
+                "timePasswordChanged": 1500,
+            })),
+            &mut telem,
+            &scope,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_by_id(&guid).unwrap().unwrap().password,
+            "new password"
+        );
+        let history = db.get_password_history(&guid).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].password, "password");
+        assert_eq!(history[0].time_changed, 1500);
+
+        let mut telem = telemetry::Engine::new("passwords");
+        db.do_apply_incoming(
+            incoming(serde_json::json!({ "id": guid, "deleted": true })),
+            &mut telem,
+            &scope,
+        )
+        .unwrap();
+        assert!(db.get_password_history(&guid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_breach_dismissal_incoming_tombstone() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", Some(""), None);
+        let scope = db.begin_interrupt_scope();
+        db.mark_as_synchronized(&[guid.as_str()], ServerTimestamp(1000), &scope)
+            .unwrap();
+        db.dismiss_breach_alert(&guid).unwrap();
+
+        let mut telem = telemetry::Engine::new("passwords");
+        db.do_apply_incoming(
+            IncomingChangeset {
+                changes: vec![(Payload::new_tombstone(guid.clone()), ServerTimestamp(2000))],
+                timestamp: ServerTimestamp(2000),
+                collection: "passwords".into(),
+                next_offset: None,
+            },
+            &mut telem,
+            &scope,
+        )
+        .unwrap();
+        assert!(db.get_by_id(&guid).unwrap().is_none());
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsBreachAlertDismissals")
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_breach_dismissal_incoming_dupe() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        db.dismiss_breach_alert(&guid).unwrap();
+
+        // An incoming record for the same login, with a different GUID and a
+        // newer password, replaces our local login.
+        let scope = db.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        db.do_apply_incoming(
+            IncomingChangeset {
+                changes: vec![(
+                    Payload::from_json(serde_json::json!({
+                        "id": "dummy_000001",
+                        "hostname": "https://www.example.com",
+                        "httpRealm": "realm",
+                        "username": "user",
+                        "password": "new password",
+                        "timePasswordChanged": util::system_time_ms_i64(SystemTime::now()) + 60_000,
+                    }))
+                    .unwrap(),
+                    ServerTimestamp(2000),
+                )],
+                timestamp: ServerTimestamp(2000),
+                collection: "passwords".into(),
+                next_offset: None,
+            },
+            &mut telem,
+            &scope,
+        )
+        .unwrap();
+        assert!(db.get_by_id(&guid).unwrap().is_none());
+        // The dismissal moves along with the password history.
+        assert_eq!(
+            db.query_one::<String>("SELECT guid FROM loginsBreachAlertDismissals")
+                .unwrap(),
+            "dummy_000001"
+        );
+        assert_eq!(db.get_password_history("dummy_000001").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_apply_incoming_dry_run() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", Some(""), None);
+        let scope = db.begin_interrupt_scope();
+        db.mark_as_synchronized(&[guid.as_str()], ServerTimestamp(1000), &scope)
+            .unwrap();
+        add_login(&db, "https://www.example.org", Some(""), None);
+
+        let mut telem = telemetry::Engine::new("passwords");
+        let inbound = IncomingChangeset {
+            changes: vec![(
+                Payload::from_json(serde_json::json!({ "id": guid, "deleted": true })).unwrap(),
+                ServerTimestamp(2000),
+            )],
+            timestamp: ServerTimestamp(2000),
+            collection: "passwords".into(),
+            next_offset: None,
+        };
+        let outgoing = db
+            .do_apply_incoming_dry_run(inbound, &mut telem, &scope)
+            .unwrap();
+        // We'd upload the new login, but the deleted one is gone.
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_ne!(outgoing.changes[0].id(), guid);
+        // ...but nothing actually changed.
+        assert!(db.get_by_id(&guid).unwrap().is_some());
+        assert_eq!(db.get_all().unwrap().len(), 2);
+        let outgoing = db.fetch_outgoing(ServerTimestamp(2000), &scope).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+    }
+
+    #[test]
+    fn test_fixup_incoming() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(Login {
+            guid: "dummy_000003".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        let scope = db.begin_interrupt_scope();
+        let mut telem = sync15::telemetry::EngineIncoming::new();
+        let res = db
+            .fetch_login_data(
+                &[
+                    // fixable
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000001",
+                            "formSubmitURL": "https://www.example.com/submit",
+                            "httpRealm": "",
+                            "hostname": "https://www.example.com/login",
+                            "username": "test",
+                            "password": "test",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                    // unfixable
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000002",
+                            "hostname": "https://www.example.com",
+                            "username": "test",
+                            "password": "test",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                    // valid, and exists locally
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000003",
+                            "formSubmitURL": "https://www.example.com",
+                            "hostname": "https://www.example.com",
+                            "username": "test",
+                            "password": "new password",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                ],
+                &mut telem,
+                &scope,
+            )
+            .unwrap();
+        assert_eq!(telem.get_failed(), 1);
+        assert_eq!(res.len(), 2);
+
+        assert_eq!(res[0].guid, "dummy_000001");
+        let fixed = res[0].inbound.0.as_ref().unwrap();
+        assert_eq!(fixed.hostname, "https://www.example.com");
+        assert_eq!(fixed.http_realm, None);
+        assert!(res[0].local.is_none());
+
+        // The skipped record shouldn't throw off which local record goes
+        // with which incoming record.
+        assert_eq!(res[1].guid, "dummy_000003");
+        assert_eq!(res[1].local.as_ref().unwrap().login.password, "test");
+    }
+
+    fn raw_secrets(db: &LoginDb, guid: &str) -> (String, String) {
+        db.query_row_named(
+            "SELECT username, password FROM loginsL WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_field_encryption() {
+        let key = encryption::create_key().unwrap();
+        let db = LoginDb::open_in_memory_with_field_encryption(&key).unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "test".into(),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        let (username, password) = raw_secrets(&db, login.guid_str());
+        assert_ne!(username, "test");
+        assert_ne!(password, "hunter2");
+        let stored = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(stored.username, "test");
+        assert_eq!(stored.password, "hunter2");
+
+        // Dupes are still found, even though the usernames are encrypted.
+        let dupe = db.find_live_dupe(&login).unwrap().unwrap();
+        assert_eq!(dupe.guid, login.guid);
+
+        // Updating with the same password doesn't count as a change, even
+        // though the encrypted password is different.
+        db.update(stored.clone()).unwrap();
+        let updated = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(updated.time_password_changed, stored.time_password_changed);
+        assert_ne!(raw_secrets(&db, login.guid_str()).1, password);
+
+        // Outgoing records aren't encrypted.
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        let record: Login = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(record.password, "hunter2");
+    }
+
+    #[test]
+    fn test_field_encryption_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = encryption::create_key().unwrap();
+        let encrypted_path = dir.path().join("encrypted.db");
+        LoginDb::open_with_field_encryption(&encrypted_path, &key)
+            .unwrap()
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("realm".into()),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        let other_key = encryption::create_key().unwrap();
+        let check_err = |result: Result<LoginDb>, expected: &str| match result {
+            Err(e) => assert_eq!(e.to_string(), expected),
+            Ok(_) => panic!("Should fail with {:?}", expected),
+        };
+        check_err(
+            LoginDb::open_with_field_encryption(&encrypted_path, &other_key),
+            "The database was encrypted with a different field encryption key",
+        );
+        check_err(
+            LoginDb::open(&encrypted_path, None),
+            "The database uses field-level encryption, and needs a field encryption key",
+        );
+        check_err(
+            LoginDb::open_with_field_encryption(&encrypted_path, "not a key"),
+            "The field encryption key isn't 32 bytes of URL-safe base64",
+        );
+        // Wiping shouldn't forget that the database is encrypted.
+        LoginDb::open_with_field_encryption(&encrypted_path, &key)
+            .unwrap()
+            .wipe_local()
+            .unwrap();
+        check_err(
+            LoginDb::open(&encrypted_path, None),
+            "The database uses field-level encryption, and needs a field encryption key",
+        );
+
+        let plaintext_path = dir.path().join("plaintext.db");
+        LoginDb::open(&plaintext_path, None)
+            .unwrap()
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("realm".into()),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        check_err(
+            LoginDb::open_with_field_encryption(&plaintext_path, &key),
+            "The database doesn't use field-level encryption",
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_sqlcipher() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlcipher_path = dir.path().join("sqlcipher.db");
+        let path = dir.path().join("encrypted.db");
+        let (guid, deleted_guid) = {
+            let db = LoginDb::open(&sqlcipher_path, Some("sqlcipher key")).unwrap();
+            let guid = add_login(&db, "https://www.example.com", Some(""), None);
+            let deleted_guid = add_login(&db, "https://www.example.org", Some(""), None);
+            // Pretend we synced, so that deleting leaves a tombstone.
+            db.execute_batch("UPDATE loginsL SET sync_status = 0")
+                .unwrap();
+            db.delete(&deleted_guid).unwrap();
+            db.set_last_sync(ServerTimestamp(1234)).unwrap();
+            (guid, deleted_guid)
+        };
+
+        let sqlcipher_contents = std::fs::read(&sqlcipher_path).unwrap();
+
+        let key = encryption::create_key().unwrap();
+        let db =
+            LoginDb::migrate_from_sqlcipher(&sqlcipher_path, "sqlcipher key", &path, &key).unwrap();
+        assert_eq!(std::fs::read(&sqlcipher_path).unwrap(), sqlcipher_contents);
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0].guid, guid);
+        assert_eq!(logins[0].username, "user");
+        assert_eq!(logins[0].password, "password");
+        assert_ne!(raw_secrets(&db, &guid).1, "password");
+        assert_eq!(raw_secrets(&db, &deleted_guid), ("".into(), "".into()));
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(1234)));
+        drop(db);
+        assert!(!dir.path().join("encrypted.db.migrating").exists());
+
+        // The new database doesn't use SQLCipher.
+        let db = LoginDb::open_with_field_encryption(&path, &key).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_from_older_sqlcipher_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlcipher_path = dir.path().join("sqlcipher.db");
+        let path = dir.path().join("encrypted.db");
+        let guid = {
+            let db = LoginDb::open(&sqlcipher_path, Some("sqlcipher key")).unwrap();
+            let guid = add_login(&db, "https://www.example.com", Some(""), None);
+            // Make it look like a v3 database, which stored timestamps in
+            // microseconds, and didn't have the history or dismissals tables.
+            db.execute_batch(
+                "UPDATE loginsL SET timeCreated = 1234000,
+                                    timeLastUsed = 1234000,
+                                    timePasswordChanged = 1234000;
+                 DROP TABLE loginsHistory;
+                 DROP TABLE loginsBreachAlertDismissals;
+                 PRAGMA user_version = 3;",
+            )
+            .unwrap();
+            guid
+        };
+
+        let key = encryption::create_key().unwrap();
+        let db =
+            LoginDb::migrate_from_sqlcipher(&sqlcipher_path, "sqlcipher key", &path, &key).unwrap();
+        let login = db.get_by_id(&guid).unwrap().unwrap();
+        assert_eq!(login.time_created, 1234);
+        assert_eq!(login.time_last_used, 1234);
+        assert_eq!(login.time_password_changed, 1234);
+        assert_eq!(
+            db.get_by_base_domain("https://example.com").unwrap().len(),
+            1
+        );
+        drop(db);
+
+        // The old database keeps its schema.
+        let old = Connection::open(&sqlcipher_path).unwrap();
+        set_sqlcipher_key(&old, "sqlcipher key").unwrap();
+        assert_eq!(old.query_one::<i64>("PRAGMA user_version").unwrap(), 3);
+        assert!(table_columns(&old, "loginsHistory").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_from_sqlcipher_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let sqlcipher_path = dir.path().join("sqlcipher.db");
+        std::fs::write(&sqlcipher_path, b"not a database").unwrap();
+        let path = dir.path().join("encrypted.db");
+        let tmp_path = dir.path().join("encrypted.db.migrating");
+        // Left over from an earlier migration.
+        std::fs::write(&tmp_path, b"").unwrap();
+        let key = encryption::create_key().unwrap();
+        assert!(LoginDb::migrate_from_sqlcipher(&sqlcipher_path, "key", &path, &key).is_err());
+        assert!(!path.exists());
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_migrate_from_sqlcipher_target_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("encrypted.db");
+        std::fs::write(&path, b"").unwrap();
+        let key = encryption::create_key().unwrap();
+        match LoginDb::migrate_from_sqlcipher(dir.path().join("sqlcipher.db"), "key", &path, &key) {
+            Err(e) => match e.kind() {
+                ErrorKind::MigrationTargetExists(_) => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            },
+            Ok(_) => panic!("Should refuse to overwrite an existing database"),
+        }
+    }
+
+    #[test]
+    fn test_rekey_sqlcipher() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sqlcipher.db");
+        let guid = {
+            let mut db = LoginDb::open(&path, Some("old key")).unwrap();
+            let guid = add_login(&db, "https://www.example.com", Some(""), None);
+            match db.rekey("").unwrap_err().kind() {
+                ErrorKind::CannotRekey(_) => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            }
+            db.rekey("new key").unwrap();
+            // The connection keeps working after rekeying.
+            assert!(db.get_by_id(&guid).unwrap().is_some());
+            guid
+        };
+
+        assert!(LoginDb::open(&path, Some("old key")).is_err());
+        let db = LoginDb::open(&path, Some("new key")).unwrap();
+        let login = db.get_by_id(&guid).unwrap().unwrap();
+        assert_eq!(login.password, "password");
+
+        let mut plaintext = LoginDb::open_in_memory(None).unwrap();
+        match plaintext.rekey("new key").unwrap_err().kind() {
+            ErrorKind::CannotRekey(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_rekey_empty_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plaintext.db");
+        let guid = {
+            // An empty key doesn't encrypt the database, so there's nothing
+            // to rekey.
+            let mut db = LoginDb::open(&path, Some("")).unwrap();
+            let guid = add_login(&db, "https://www.example.com", Some(""), None);
+            match db.rekey("new key").unwrap_err().kind() {
+                ErrorKind::CannotRekey(_) => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            }
+            guid
+        };
+
+        // The database is still unencrypted.
+        assert!(LoginDb::open(&path, Some("new key")).is_err());
+        let db = LoginDb::open(&path, None).unwrap();
+        assert!(db.get_by_id(&guid).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_rekey_field_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("encrypted.db");
+        let old_key = encryption::create_key().unwrap();
+        let new_key = encryption::create_key().unwrap();
+        let (guid, deleted_guid) = {
+            let mut db = LoginDb::open_with_field_encryption(&path, &old_key).unwrap();
+            let guid = add_login(&db, "https://www.example.com", Some(""), None);
+            let deleted_guid = add_login(&db, "https://www.example.org", Some(""), None);
+            db.execute_batch("UPDATE loginsL SET sync_status = 0")
+                .unwrap();
+            db.delete(&deleted_guid).unwrap();
+            let old_secrets = raw_secrets(&db, &guid);
+
+            // A bad key shouldn't change anything.
+            assert!(db.rekey("not a key").is_err());
+            assert_eq!(raw_secrets(&db, &guid), old_secrets);
+
+            db.rekey(&new_key).unwrap();
+            assert_ne!(raw_secrets(&db, &guid), old_secrets);
+            assert_eq!(raw_secrets(&db, &deleted_guid), ("".into(), "".into()));
+            let login = db.get_by_id(&guid).unwrap().unwrap();
+            assert_eq!(login.username, "user");
+            assert_eq!(login.password, "password");
+            (guid, deleted_guid)
+        };
+
+        match LoginDb::open_with_field_encryption(&path, &old_key) {
+            Err(e) => match e.kind() {
+                ErrorKind::IncorrectFieldEncryptionKey => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            },
+            Ok(_) => panic!("Shouldn't open with the old key"),
+        }
+        let db = LoginDb::open_with_field_encryption(&path, &new_key).unwrap();
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0].guid, guid);
+        assert_eq!(logins[0].password, "password");
+        assert!(db.get_by_id(&deleted_guid).unwrap().is_none());
+    }
 }
@@ -0,0 +1,317 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Finding and merging duplicate logins.
+//!
+//! `find_dupe` only catches duplicates as they're added or synced, so old
+//! imports and sync bugs can leave many copies of the same login behind.
+//! Logins are considered duplicates if they have the same origin, form action
+//! origin (ignoring paths and default ports for both), HTTP auth realm,
+//! username, and password.
+//!
+//! For each group of duplicates, we propose the most recently, then most
+//! frequently, used login as the survivor. Merging a group keeps the survivor,
+//! with the usage stats of the whole group, and deletes the others, leaving
+//! tombstones for the ones we've synced.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::{Login, SyncStatus};
+use crate::matching;
+use crate::util;
+use rusqlite::named_params;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+/// A group of logins that are likely duplicates of each other.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateGroup {
+    /// The login that we propose to keep.
+    pub survivor: Login,
+    /// The other logins, which merging deletes.
+    pub duplicates: Vec<Login>,
+}
+
+// Logins with the same key are duplicates.
+fn dupe_key(login: &Login) -> (String, Option<String>, Option<&str>, &str, &str) {
+    (
+        matching::normalize_origin(&login.hostname),
+        login
+            .form_submit_url
+            .as_ref()
+            .map(|url| matching::normalize_origin(url)),
+        login.http_realm.as_ref().map(String::as_str),
+        &login.username,
+        &login.password,
+    )
+}
+
+impl LoginDb {
+    /// Groups every login with its likely duplicates. Logins without any
+    /// duplicates aren't returned.
+    pub fn find_duplicates(&self) -> Result<Vec<DuplicateGroup>> {
+        let logins = self.get_all()?;
+        let mut groups: HashMap<_, Vec<&Login>> = HashMap::new();
+        for login in &logins {
+            groups.entry(dupe_key(login)).or_default().push(login);
+        }
+        let mut result = groups
+            .into_iter()
+            .filter(|(_, group)| group.len() > 1)
+            .map(|(_, mut group)| {
+                // Most recently used first, then most used. The GUID makes
+                // the order stable.
+                group.sort_by(|a, b| {
+                    b.time_last_used
+                        .cmp(&a.time_last_used)
+                        .then(b.times_used.cmp(&a.times_used))
+                        .then(a.guid.cmp(&b.guid))
+                });
+                let mut group = group.into_iter().cloned();
+                DuplicateGroup {
+                    survivor: group.next().unwrap(),
+                    duplicates: group.collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.survivor.guid.cmp(&b.survivor.guid));
+        Ok(result)
+    }
+
+    /// Merges the logins with `duplicate_ids` into the login with
+    /// `survivor_id`, in one transaction. The survivor's usage stats become
+    /// the totals for the whole group, and the duplicates are deleted. Returns
+    /// the merged survivor. Repeated IDs, and `survivor_id`, in
+    /// `duplicate_ids` are ignored.
+    pub fn merge_duplicates(&self, survivor_id: &str, duplicate_ids: &[&str]) -> Result<Login> {
+        let tx = self.unchecked_transaction()?;
+        let mut survivor = match self.get_by_id(survivor_id)? {
+            Some(login) => login,
+            None => throw!(ErrorKind::NoSuchRecord(survivor_id.to_owned())),
+        };
+        // Merging the same duplicate twice would double its usage stats.
+        let duplicate_ids = duplicate_ids
+            .iter()
+            .cloned()
+            .filter(|&id| id != survivor_id)
+            .collect::<HashSet<_>>();
+        let mut duplicates = Vec::with_capacity(duplicate_ids.len());
+        for id in duplicate_ids {
+            let login = match self.get_by_id(id)? {
+                Some(login) => login,
+                None => throw!(ErrorKind::NoSuchRecord(id.to_owned())),
+            };
+            if dupe_key(&login) != dupe_key(&survivor) {
+                throw!(ErrorKind::NotADuplicate(id.to_owned()));
+            }
+            duplicates.push(login);
+        }
+
+        for login in &duplicates {
+            survivor.times_used += login.times_used;
+            survivor.time_last_used = survivor.time_last_used.max(login.time_last_used);
+            survivor.time_created = survivor.time_created.min(login.time_created);
+            self.delete_login(login.guid_str())?;
+        }
+
+        self.ensure_local_overlay_exists(survivor_id)?;
+        self.mark_mirror_overridden(survivor_id)?;
+        self.execute_named_cached(
+            &format!(
+                "UPDATE loginsL
+                 SET local_modified = :now_millis,
+                     timesUsed      = :times_used,
+                     timeLastUsed   = :time_last_used,
+                     timeCreated    = :time_created,
+                     -- leave New records as they are, otherwise update them to `changed`
+                     sync_status    = max(sync_status, {changed})
+                 WHERE guid = :guid",
+                changed = SyncStatus::Changed as u8
+            ),
+            named_params! {
+                ":now_millis": util::system_time_ms_i64(SystemTime::now()),
+                ":times_used": survivor.times_used,
+                ":time_last_used": survivor.time_last_used,
+                ":time_created": survivor.time_created,
+                ":guid": survivor_id,
+            },
+        )?;
+        tx.commit()?;
+        Ok(survivor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::add_login;
+
+    fn add_used_login(db: &LoginDb, hostname: &str, times_used: i64) -> String {
+        let guid = add_login(db, hostname, None, Some("realm"));
+        db.execute_named(
+            "UPDATE loginsL SET timesUsed = :times_used, timeLastUsed = :times_used
+             WHERE guid = :guid",
+            named_params! { ":times_used": times_used, ":guid": guid },
+        )
+        .unwrap();
+        guid
+    }
+
+    fn add_other_user_login(db: &LoginDb, hostname: &str) -> String {
+        let guid = add_login(db, hostname, None, Some("realm"));
+        let mut login = db.get_by_id(&guid).unwrap().unwrap();
+        login.username = "other user".into();
+        db.update(login).unwrap();
+        guid
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let a = add_used_login(&db, "https://www.example.com", 3);
+        let b = add_used_login(&db, "https://www.example.com/login", 10);
+        let c = add_used_login(&db, "https://www.example.com:443", 1);
+        add_other_user_login(&db, "https://www.example.com");
+        add_used_login(&db, "http://www.example.com", 1);
+
+        let groups = db.find_duplicates().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].survivor.guid, b);
+        let duplicates = groups[0]
+            .duplicates
+            .iter()
+            .map(|login| login.guid.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(duplicates, vec![a.as_str(), c.as_str()]);
+    }
+
+    fn duplicate_groups(db: &LoginDb) -> Vec<Vec<String>> {
+        db.find_duplicates()
+            .unwrap()
+            .into_iter()
+            .map(|group| {
+                let mut guids = std::iter::once(group.survivor)
+                    .chain(group.duplicates)
+                    .map(|login| login.guid.to_string())
+                    .collect::<Vec<_>>();
+                guids.sort();
+                guids
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_duplicates_realm() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let a = add_login(&db, "https://www.example.com", None, Some("realm"));
+        let b = add_login(&db, "https://www.example.com", None, Some("realm"));
+        add_login(&db, "https://www.example.com", None, Some("other realm"));
+        add_login(&db, "https://www.example.com", Some(""), None);
+
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(duplicate_groups(&db), vec![expected]);
+    }
+
+    #[test]
+    fn test_find_duplicates_form_submit_url() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let a = add_login(
+            &db,
+            "https://www.example.com",
+            Some("https://login.example.com/submit"),
+            None,
+        );
+        let b = add_login(
+            &db,
+            "https://www.example.com",
+            Some("https://login.example.com:443/other"),
+            None,
+        );
+        add_login(
+            &db,
+            "https://www.example.com",
+            Some("https://www.example.com/submit"),
+            None,
+        );
+        add_login(&db, "https://www.example.com", Some(""), None);
+
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(duplicate_groups(&db), vec![expected]);
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let survivor = add_used_login(&db, "https://www.example.com", 3);
+        let synced = add_used_login(&db, "https://www.example.com", 4);
+        let local = add_used_login(&db, "https://www.example.com", 5);
+        // Pretend we synced one of them, so that merging leaves a tombstone.
+        db.execute_named(
+            "UPDATE loginsL SET sync_status = 0 WHERE guid = :guid",
+            named_params! { ":guid": synced },
+        )
+        .unwrap();
+
+        let merged = db
+            .merge_duplicates(&survivor, &[&synced, &local, &local, &survivor])
+            .unwrap();
+        assert_eq!(merged.times_used, 12);
+        assert_eq!(merged.time_last_used, 5);
+        assert_eq!(db.get_by_id(&survivor).unwrap().unwrap(), merged);
+        assert_eq!(db.get_all().unwrap().len(), 1);
+
+        let is_tombstone = |guid: &str| {
+            db.try_query_row(
+                "SELECT is_deleted FROM loginsL WHERE guid = :guid",
+                named_params! { ":guid": guid },
+                |row| row.get::<_, bool>(0),
+                false,
+            )
+            .unwrap()
+        };
+        assert_eq!(is_tombstone(&synced), Some(true));
+        assert_eq!(is_tombstone(&local), None);
+    }
+
+    #[test]
+    fn test_merge_non_duplicates() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let survivor = add_used_login(&db, "https://www.example.com", 1);
+        let other = add_other_user_login(&db, "https://www.example.com");
+        match db
+            .merge_duplicates(&survivor, &[&other])
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NotADuplicate(guid) => assert_eq!(guid, &other),
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        match db
+            .merge_duplicates(&survivor, &["nonexistent"])
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NoSuchRecord(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        let form = add_login(
+            &db,
+            "https://www.example.com",
+            Some("https://www.example.com"),
+            None,
+        );
+        match db.merge_duplicates(&survivor, &[&form]).unwrap_err().kind() {
+            ErrorKind::NotADuplicate(guid) => assert_eq!(guid, &form),
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        // Nothing was deleted.
+        assert_eq!(db.get_all().unwrap().len(), 3);
+    }
+}
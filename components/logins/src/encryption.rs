@@ -0,0 +1,171 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Field-level encryption for logins.
+//!
+//! Instead of encrypting the whole database with SQLCipher, a database can be
+//! opened with a field encryption key, which we use to encrypt the `username`
+//! and `password` of each login with AES-256-GCM. Everything else, like the
+//! hostname and timestamps, stays in plaintext, so that it can be queried
+//! without the key.
+//!
+//! Keys are 32 random bytes, encoded as URL-safe base64 without padding; use
+//! `create_key` to make one. Encrypted fields are stored as URL-safe base64 of
+//! a random 12-byte nonce followed by the ciphertext and tag. Empty fields,
+//! like the username and password of tombstones, are stored as empty strings.
+
+use crate::error::*;
+use crate::login::Login;
+use rc_crypto::{aead, rand};
+
+// Stored in the meta table of field-encrypted databases, so that we can tell
+// whether a database uses field-level encryption, and check that we're using
+// the right key.
+pub(crate) static CANARY_TEXT: &str = "a string for checking that the key is correct";
+
+/// Returns a new random field encryption key.
+pub fn create_key() -> Result<String> {
+    let mut key = vec![0u8; aead::AES_256_GCM.key_len()];
+    rand::fill(&mut key)?;
+    Ok(base64::encode_config(&key, base64::URL_SAFE_NO_PAD))
+}
+
+/// Encrypts and decrypts login fields with a field encryption key.
+pub struct EncryptorDecryptor {
+    sealing_key: aead::SealingKey,
+    opening_key: aead::OpeningKey,
+}
+
+impl EncryptorDecryptor {
+    pub fn new(key: &str) -> Result<Self> {
+        let key_bytes = base64::decode_config(key, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| ErrorKind::InvalidFieldEncryptionKey)?;
+        if key_bytes.len() != aead::AES_256_GCM.key_len() {
+            throw!(ErrorKind::InvalidFieldEncryptionKey);
+        }
+        Ok(Self {
+            sealing_key: aead::SealingKey::new(&aead::AES_256_GCM, &key_bytes)?,
+            opening_key: aead::OpeningKey::new(&aead::AES_256_GCM, &key_bytes)?,
+        })
+    }
+
+    pub fn encrypt(&self, cleartext: &str) -> Result<String> {
+        if cleartext.is_empty() {
+            return Ok(String::new());
+        }
+        let mut nonce_bytes = vec![0u8; aead::AES_256_GCM.nonce_len()];
+        rand::fill(&mut nonce_bytes)?;
+        let nonce = aead::Nonce::try_assume_unique_for_key(&aead::AES_256_GCM, &nonce_bytes)?;
+        let ciphertext = aead::seal(
+            &self.sealing_key,
+            nonce,
+            aead::Aad::empty(),
+            cleartext.as_bytes(),
+        )?;
+        nonce_bytes.extend(ciphertext);
+        Ok(base64::encode_config(&nonce_bytes, base64::URL_SAFE_NO_PAD))
+    }
+
+    pub fn decrypt(&self, ciphertext: &str) -> Result<String> {
+        if ciphertext.is_empty() {
+            return Ok(String::new());
+        }
+        let bytes = base64::decode_config(ciphertext, base64::URL_SAFE_NO_PAD)?;
+        let nonce_len = aead::AES_256_GCM.nonce_len();
+        if bytes.len() < nonce_len {
+            throw!(ErrorKind::InvalidEncryptedField);
+        }
+        let (nonce_bytes, ciphertext_and_tag) = bytes.split_at(nonce_len);
+        let nonce = aead::Nonce::try_assume_unique_for_key(&aead::AES_256_GCM, nonce_bytes)?;
+        let cleartext = aead::open(
+            &self.opening_key,
+            nonce,
+            aead::Aad::empty(),
+            ciphertext_and_tag,
+        )?;
+        Ok(String::from_utf8(cleartext).map_err(|_| ErrorKind::InvalidEncryptedField)?)
+    }
+
+    /// Returns `true` if `canary` is `CANARY_TEXT` encrypted with our key.
+    pub(crate) fn check_canary(&self, canary: &str) -> bool {
+        match self.decrypt(canary) {
+            Ok(text) => text == CANARY_TEXT,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Encrypts a field before writing it to the database, if the database uses
+/// field-level encryption.
+pub(crate) fn encrypt_field(encdec: Option<&EncryptorDecryptor>, value: &str) -> Result<String> {
+    match encdec {
+        Some(encdec) => encdec.encrypt(value),
+        None => Ok(value.to_owned()),
+    }
+}
+
+/// Decrypts a field that we just read from the database, if the database uses
+/// field-level encryption.
+pub(crate) fn decrypt_field(encdec: Option<&EncryptorDecryptor>, value: String) -> Result<String> {
+    match encdec {
+        Some(encdec) => encdec.decrypt(&value),
+        None => Ok(value),
+    }
+}
+
+/// Decrypts the fields of a login that we just read from the database.
+pub(crate) fn decrypt_login(encdec: Option<&EncryptorDecryptor>, login: &mut Login) -> Result<()> {
+    if let Some(encdec) = encdec {
+        login.username = encdec.decrypt(&login.username)?;
+        login.password = encdec.decrypt(&login.password)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let encdec = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        let ciphertext = encdec.encrypt("hunter2").unwrap();
+        assert_ne!(ciphertext, "hunter2");
+        assert_eq!(encdec.decrypt(&ciphertext).unwrap(), "hunter2");
+        // Each encryption uses a new nonce.
+        assert_ne!(encdec.encrypt("hunter2").unwrap(), ciphertext);
+
+        assert_eq!(encdec.encrypt("").unwrap(), "");
+        assert_eq!(encdec.decrypt("").unwrap(), "");
+
+        let other = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        assert!(other.decrypt(&ciphertext).is_err());
+        assert!(encdec.decrypt("not base64!").is_err());
+        assert!(encdec.decrypt("AAAA").is_err());
+    }
+
+    #[test]
+    fn test_check_canary() {
+        let encdec = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        let canary = encdec.encrypt(CANARY_TEXT).unwrap();
+        assert!(encdec.check_canary(&canary));
+        assert!(!encdec.check_canary(&encdec.encrypt("something else").unwrap()));
+
+        let other = EncryptorDecryptor::new(&create_key().unwrap()).unwrap();
+        assert!(!other.check_canary(&canary));
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        for key in &["", "not base64!", "AAAA"] {
+            match EncryptorDecryptor::new(key) {
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidFieldEncryptionKey => {}
+                    kind => panic!("Wrong error: {:?}", kind),
+                },
+                Ok(_) => panic!("Should reject key {:?}", key),
+            }
+        }
+    }
+}
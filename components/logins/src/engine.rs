@@ -1,14 +1,19 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use crate::breaches::{Breach, BreachAlert};
 use crate::db::{LoginDb, LoginStore};
+use crate::dedupe::DuplicateGroup;
 use crate::error::*;
+use crate::history::PasswordHistoryEntry;
+use crate::import_export::ImportResult;
 use crate::login::Login;
 use std::cell::Cell;
+use std::io::{Read, Write};
 use std::path::Path;
 use sync15::{
-    sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
-    Sync15StorageClientInit,
+    sync_multiple, telemetry, KeyBundle, MemoryCachedState, PlannedChanges, StoreSyncAssociation,
+    Sync15StorageClientInit, SyncMode, SyncOptions, SyncResult,
 };
 
 // This isn't really an engine in the firefox sync15 desktop sense -- it's
@@ -36,6 +41,54 @@ impl PasswordEngine {
         })
     }
 
+    /// Opens a database that encrypts the `username` and `password` of each
+    /// login with `field_encryption_key`, instead of using SQLCipher. See
+    /// `create_field_encryption_key`.
+    pub fn new_with_field_encryption(
+        path: impl AsRef<Path>,
+        field_encryption_key: &str,
+    ) -> Result<Self> {
+        let db = LoginDb::open_with_field_encryption(path, field_encryption_key)?;
+        Ok(Self {
+            db,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
+    pub fn new_in_memory_with_field_encryption(field_encryption_key: &str) -> Result<Self> {
+        let db = LoginDb::open_in_memory_with_field_encryption(field_encryption_key)?;
+        Ok(Self {
+            db,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
+    /// Migrates a SQLCipher database to a new database at `path` that uses
+    /// field-level encryption, and opens it. See
+    /// `LoginDb::migrate_from_sqlcipher`.
+    pub fn migrate_from_sqlcipher(
+        sqlcipher_path: impl AsRef<Path>,
+        sqlcipher_key: &str,
+        path: impl AsRef<Path>,
+        field_encryption_key: &str,
+    ) -> Result<Self> {
+        let db = LoginDb::migrate_from_sqlcipher(
+            sqlcipher_path,
+            sqlcipher_key,
+            path,
+            field_encryption_key,
+        )?;
+        Ok(Self {
+            db,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
+    /// Re-encrypts the database with `new_key`. See `LoginDb::rekey`.
+    pub fn rekey(&mut self, new_key: &str) -> Result<()> {
+        self.db.rekey(new_key)
+    }
+
     pub fn list(&self) -> Result<Vec<Login>> {
         self.db.get_all()
     }
@@ -44,6 +97,44 @@ impl PasswordEngine {
         self.db.get_by_id(id)
     }
 
+    pub fn get_by_base_domain(&self, origin: &str) -> Result<Vec<Login>> {
+        self.db.get_by_base_domain(origin)
+    }
+
+    pub fn find_for_form(
+        &self,
+        origin: &str,
+        form_action_origin: Option<&str>,
+        http_realm: Option<&str>,
+    ) -> Result<Vec<Login>> {
+        self.db
+            .find_for_form(origin, form_action_origin, http_realm)
+    }
+
+    pub fn get_password_history(&self, id: &str) -> Result<Vec<PasswordHistoryEntry>> {
+        self.db.get_password_history(id)
+    }
+
+    pub fn restore_password(&self, id: &str, history_id: i64) -> Result<()> {
+        self.db.restore_password(id, history_id)
+    }
+
+    pub fn get_breach_alerts(&self, breaches: &[Breach]) -> Result<Vec<BreachAlert>> {
+        self.db.get_breach_alerts(breaches)
+    }
+
+    pub fn dismiss_breach_alert(&self, id: &str) -> Result<()> {
+        self.db.dismiss_breach_alert(id)
+    }
+
+    pub fn find_duplicates(&self) -> Result<Vec<DuplicateGroup>> {
+        self.db.find_duplicates()
+    }
+
+    pub fn merge_duplicates(&self, survivor_id: &str, duplicate_ids: &[&str]) -> Result<Login> {
+        self.db.merge_duplicates(survivor_id, duplicate_ids)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         self.db.touch(id)
     }
@@ -77,6 +168,40 @@ impl PasswordEngine {
         self.db.add(login).map(|record| record.guid.into_string())
     }
 
+    /// Adds each of `logins` in one transaction, returning the ID of each
+    /// added login, or why it couldn't be added.
+    pub fn add_many(&self, logins: Vec<Login>) -> Result<Vec<Result<String>>> {
+        let scope = self.db.begin_interrupt_scope();
+        Ok(self
+            .db
+            .add_many(logins, &scope)?
+            .into_iter()
+            .map(|result| result.map(|login| login.guid.into_string()))
+            .collect())
+    }
+
+    /// Updates each of `logins` in one transaction, returning the result for
+    /// each.
+    pub fn update_many(&self, logins: Vec<Login>) -> Result<Vec<Result<()>>> {
+        let scope = self.db.begin_interrupt_scope();
+        self.db.update_many(logins, &scope)
+    }
+
+    /// Deletes the logins with `ids` in one transaction, returning whether
+    /// each existed.
+    pub fn delete_many(&self, ids: &[&str]) -> Result<Vec<Result<bool>>> {
+        let scope = self.db.begin_interrupt_scope();
+        self.db.delete_many(ids, &scope)
+    }
+
+    pub fn import_csv(&self, reader: impl Read) -> Result<Vec<ImportResult>> {
+        self.db.import_csv(reader)
+    }
+
+    pub fn export_csv(&self, writer: impl Write, confirm_plaintext_export: bool) -> Result<usize> {
+        self.db.export_csv(writer, confirm_plaintext_export)
+    }
+
     pub fn disable_mem_security(&self) -> Result<()> {
         self.db.disable_mem_security()
     }
@@ -91,12 +216,50 @@ impl PasswordEngine {
         self.db.new_interrupt_handle()
     }
 
+    /// Returns the `Store` that syncs logins, for callers that sync it
+    /// alongside other engines, like the sync manager. Unlike `sync`, the
+    /// caller owns the global sync state.
+    pub fn sync_store(&self) -> Result<LoginStore<'_>> {
+        // migrate our V1 state - this needn't live for long.
+        self.db.migrate_global_state()?;
+        Ok(LoginStore::new(&self.db))
+    }
+
     /// A convenience wrapper around sync_multiple.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
     ) -> Result<telemetry::SyncTelemetryPing> {
+        let result = self.do_sync(storage_init, root_sync_key, SyncMode::Normal)?;
+        Ok(result.telemetry)
+    }
+
+    /// Like `sync`, but doesn't change any local logins or upload anything.
+    /// Returns the changes that a sync would make instead.
+    pub fn dry_run_sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        include_ids: bool,
+    ) -> Result<PlannedChanges> {
+        let mut result = self.do_sync(
+            storage_init,
+            root_sync_key,
+            SyncMode::DryRun { include_ids },
+        )?;
+        Ok(result
+            .planned_changes
+            .remove("passwords")
+            .unwrap_or_default())
+    }
+
+    fn do_sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        mode: SyncMode,
+    ) -> Result<SyncResult> {
         // migrate our V1 state - this needn't live for long.
         self.db.migrate_global_state()?;
 
@@ -110,11 +273,19 @@ impl PasswordEngine {
             &mut mem_cached_state,
             storage_init,
             root_sync_key,
+            &SyncOptions {
+                mode,
+                ..SyncOptions::default()
+            },
             &store.scope,
         );
-        // We always update the state - sync_multiple does the right thing
-        // if it needs to be dropped (ie, they will be None or contain Nones etc)
-        self.db.set_global_state(&disk_cached_state)?;
+        // We always update the state after a real sync - sync_multiple does
+        // the right thing if it needs to be dropped (ie, they will be None or
+        // contain Nones etc). A dry run mustn't change anything, including
+        // our sync IDs, or when we can sync next.
+        if mode == SyncMode::Normal {
+            self.db.set_global_state(&disk_cached_state)?;
+        }
 
         // for b/w compat reasons, we do some dances with the result.
         // XXX - note that this means telemetry isn't going to be reported back
@@ -124,7 +295,7 @@ impl PasswordEngine {
             return Err(e.into());
         }
         match result.engine_results.remove("passwords") {
-            None | Some(Ok(())) => Ok(result.telemetry),
+            None | Some(Ok(())) => Ok(result),
             Some(Err(e)) => Err(e.into()),
         }
     }
@@ -46,6 +46,54 @@ pub enum ErrorKind {
 
     #[fail(display = "{}", _0)]
     Interrupted(#[fail(cause)] interrupt::Interrupted),
+
+    #[fail(display = "Error reading or writing CSV: {}", _0)]
+    CsvError(#[fail(cause)] csv::Error),
+
+    #[fail(display = "CSV file has no {:?} column", _0)]
+    MissingCsvColumn(&'static str),
+
+    #[fail(display = "Exporting logins as plaintext CSV wasn't confirmed")]
+    ExportNotConfirmed,
+
+    #[fail(display = "Crypto error: {}", _0)]
+    CryptoError(#[fail(cause)] rc_crypto::Error),
+
+    #[fail(display = "Error decoding base64: {}", _0)]
+    Base64Decode(#[fail(cause)] base64::DecodeError),
+
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
+
+    #[fail(display = "The field encryption key isn't 32 bytes of URL-safe base64")]
+    InvalidFieldEncryptionKey,
+
+    #[fail(display = "The database was encrypted with a different field encryption key")]
+    IncorrectFieldEncryptionKey,
+
+    #[fail(display = "The database uses field-level encryption, and needs a field encryption key")]
+    FieldEncryptionKeyRequired,
+
+    #[fail(display = "The database doesn't use field-level encryption")]
+    NotFieldEncrypted,
+
+    #[fail(display = "An encrypted field in the database is malformed")]
+    InvalidEncryptedField,
+
+    #[fail(display = "Can't migrate to {:?}, since it already exists", _0)]
+    MigrationTargetExists(String),
+
+    #[fail(display = "Can't rekey the database: {}", _0)]
+    CannotRekey(&'static str),
+
+    #[fail(display = "Can't generate a password that follows the rules: {}", _0)]
+    UnsatisfiablePasswordRules(&'static str),
+
+    #[fail(
+        display = "Login {:?} isn't a duplicate of the login to merge into",
+        _0
+    )]
+    NotADuplicate(String),
 }
 
 error_support::define_error! {
@@ -56,6 +104,10 @@ error_support::define_error! {
         (SqlError, rusqlite::Error),
         (InvalidLogin, InvalidLogin),
         (Interrupted, interrupt::Interrupted),
+        (CsvError, csv::Error),
+        (CryptoError, rc_crypto::Error),
+        (Base64Decode, base64::DecodeError),
+        (IoError, std::io::Error),
     }
 }
 
@@ -69,4 +121,6 @@ pub enum InvalidLogin {
     BothTargets,
     #[fail(display = "Neither `formSubmitUrl` and `httpRealm` are present")]
     NoTarget,
+    #[fail(display = "Malformed login: {}", _0)]
+    Malformed(String),
 }
@@ -4,8 +4,9 @@
 
 // This module implement the traits that make the FFI code easier to manage.
 
-use crate::{Error, ErrorKind, Login};
+use crate::{Error, ErrorKind, Login, Result};
 use ffi_support::{implement_into_ffi_by_json, ErrorCode, ExternError};
+use serde_derive::*;
 use sync15::ErrorKind as Sync15ErrorKind;
 
 pub mod error_codes {
@@ -29,7 +30,9 @@ pub mod error_codes {
     pub const INVALID_LOGIN: i32 = 4;
 
     /// Either the file is not a database, or it is not encrypted with the
-    /// provided encryption key.
+    /// provided encryption key. Also returned if a field encryption key is
+    /// malformed, or doesn't match how the database is encrypted, and if the
+    /// database can't be rekeyed with the new key.
     pub const INVALID_KEY: i32 = 5;
 
     /// A request to the sync server failed.
@@ -84,6 +87,19 @@ fn get_code(err: &Error) -> ErrorCode {
             ErrorCode::new(error_codes::INTERRUPTED)
         }
 
+        ErrorKind::InvalidFieldEncryptionKey
+        | ErrorKind::IncorrectFieldEncryptionKey
+        | ErrorKind::FieldEncryptionKeyRequired
+        | ErrorKind::NotFieldEncrypted => {
+            log::error!("Invalid field encryption key: {}", err);
+            ErrorCode::new(error_codes::INVALID_KEY)
+        }
+
+        ErrorKind::CannotRekey(reason) => {
+            log::error!("Can't rekey the database: {}", reason);
+            ErrorCode::new(error_codes::INVALID_KEY)
+        }
+
         err => {
             log::error!("Unexpected error: {:?}", err);
             ErrorCode::new(error_codes::UNEXPECTED)
@@ -97,4 +113,37 @@ impl From<Error> for ExternError {
     }
 }
 
+/// The result for one record of a batch operation, which serializes to
+/// `{"id": "..."}` on success, or `{"error": {"code": ..., "message": "..."}}`
+/// with one of the `error_codes` on failure.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BatchResult {
+    Id(String),
+    Error { code: i32, message: String },
+}
+
+impl From<Result<String>> for BatchResult {
+    fn from(result: Result<String>) -> BatchResult {
+        match result {
+            Ok(id) => BatchResult::Id(id),
+            Err(e) => BatchResult::Error {
+                code: get_code(&e).code(),
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
 implement_into_ffi_by_json!(Login);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cannot_rekey_code() {
+        let err: Error = ErrorKind::CannotRekey("The new key is empty").into();
+        assert_eq!(get_code(&err).code(), error_codes::INVALID_KEY);
+    }
+}
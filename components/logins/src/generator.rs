@@ -0,0 +1,476 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Password generation.
+//!
+//! Passwords are generated from a set of [PasswordRules], which can be built
+//! directly, or parsed from the `passwordrules` syntax that Apple proposed,
+//! and that sites publish in the `passwordrules` attribute of their password
+//! fields. For example:
+//!
+//! ```text
+//! required: lower; required: upper; required: digit;
+//! allowed: [-().&@?'#,/"+]; max-consecutive: 2; minlength: 8; maxlength: 20;
+//! ```
+//!
+//! Like Safari, we only generate ASCII passwords, so the `unicode` class is
+//! treated like `ascii-printable`, and non-ASCII characters in custom classes
+//! are ignored. Properties and classes we don't understand are ignored too,
+//! so that a typo in a site's rules doesn't keep us from generating a
+//! password.
+//!
+//! All randomness comes from `rc_crypto::rand`.
+
+use crate::error::*;
+use rc_crypto::rand;
+use std::collections::BTreeSet;
+
+/// The length of generated passwords, unless the rules say otherwise. This is
+/// the same as Desktop's password generator.
+pub const DEFAULT_LENGTH: usize = 15;
+
+// Generating a password that has too many consecutive identical characters is
+// rare, so we retry instead of trying to avoid them.
+const MAX_ATTEMPTS: usize = 100;
+
+const UPPER: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const LOWER: &str = "abcdefghijklmnopqrstuvwxyz";
+const DIGIT: &str = "0123456789";
+const SPECIAL: &str = "-~!@#$%^&*_+=`|(){}[:;\"'<>,.? ]";
+
+/// Constraints for generated passwords.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PasswordRules {
+    /// Each of these sets must contribute at least one character.
+    pub required: Vec<BTreeSet<char>>,
+    /// Characters that may be used in addition to the required ones. If
+    /// there are no required or allowed characters, we use upper and lower
+    /// case letters and digits, and require one of each.
+    pub allowed: BTreeSet<char>,
+    /// Characters that must never be used, even if they're required or
+    /// allowed.
+    pub forbidden: BTreeSet<char>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    /// The maximum number of times the same character can appear in a row.
+    pub max_consecutive: Option<usize>,
+}
+
+impl PasswordRules {
+    /// Parses rules in the `passwordrules` syntax.
+    pub fn parse(rules: &str) -> Self {
+        let mut parsed = Self::default();
+        let mut parser = Parser {
+            chars: rules.chars().collect(),
+            pos: 0,
+        };
+        while let Some((name, value)) = parser.next_property() {
+            match name.as_str() {
+                "required" => {
+                    let set = parse_classes(&value);
+                    if !set.is_empty() {
+                        parsed.required.push(set);
+                    }
+                }
+                "allowed" => parsed.allowed.extend(parse_classes(&value)),
+                "minlength" => {
+                    if let Ok(n) = value.trim().parse::<usize>() {
+                        parsed.min_length = Some(parsed.min_length.map_or(n, |m| m.max(n)));
+                    }
+                }
+                "maxlength" => {
+                    if let Ok(n) = value.trim().parse::<usize>() {
+                        parsed.max_length = Some(parsed.max_length.map_or(n, |m| m.min(n)));
+                    }
+                }
+                "max-consecutive" => {
+                    if let Ok(n) = value.trim().parse::<usize>() {
+                        parsed.max_consecutive =
+                            Some(parsed.max_consecutive.map_or(n, |m| m.min(n)));
+                    }
+                }
+                _ => log::debug!("Ignoring unknown password rule {:?}", name),
+            }
+        }
+        parsed
+    }
+
+    /// Returns the sets that must each contribute a character, and every
+    /// character that can be used, without any forbidden characters.
+    fn character_sets(&self) -> Result<(Vec<Vec<char>>, Vec<char>)> {
+        let (required, allowed) = if self.required.is_empty() && self.allowed.is_empty() {
+            let defaults = [UPPER, LOWER, DIGIT]
+                .iter()
+                .map(|class| class.chars().collect())
+                .collect();
+            (defaults, BTreeSet::new())
+        } else {
+            (self.required.clone(), self.allowed.clone())
+        };
+        let mut all = allowed;
+        let mut required_sets = Vec::with_capacity(required.len());
+        for set in required {
+            all.extend(&set);
+            let set = set.difference(&self.forbidden).cloned().collect::<Vec<_>>();
+            if set.is_empty() {
+                throw!(ErrorKind::UnsatisfiablePasswordRules(
+                    "Every character of a required class is forbidden"
+                ));
+            }
+            required_sets.push(set);
+        }
+        let all = all.difference(&self.forbidden).cloned().collect::<Vec<_>>();
+        if all.is_empty() {
+            throw!(ErrorKind::UnsatisfiablePasswordRules(
+                "Every allowed character is forbidden"
+            ));
+        }
+        Ok((required_sets, all))
+    }
+
+    fn length(&self, required_count: usize) -> Result<usize> {
+        let min_length = self.min_length.unwrap_or(0).max(required_count);
+        let max_length = self.max_length.unwrap_or(usize::max_value());
+        if min_length > max_length {
+            throw!(ErrorKind::UnsatisfiablePasswordRules(
+                "The password can't be long enough to include every required class"
+            ));
+        }
+        Ok(DEFAULT_LENGTH.max(min_length).min(max_length))
+    }
+}
+
+/// Generates a password that follows `rules`.
+pub fn generate(rules: &PasswordRules) -> Result<String> {
+    let (required, all) = rules.character_sets()?;
+    let length = rules.length(required.len())?;
+    if length == 0 {
+        return Ok(String::new());
+    }
+    if rules.max_consecutive == Some(0) {
+        throw!(ErrorKind::UnsatisfiablePasswordRules(
+            "No character can be used even once"
+        ));
+    }
+    for _ in 0..MAX_ATTEMPTS {
+        let mut password = Vec::with_capacity(length);
+        for set in &required {
+            password.push(set[random_index(set.len())?]);
+        }
+        while password.len() < length {
+            password.push(all[random_index(all.len())?]);
+        }
+        shuffle(&mut password)?;
+        if let Some(max) = rules.max_consecutive {
+            if has_consecutive_run(&password, max + 1) {
+                continue;
+            }
+        }
+        return Ok(password.into_iter().collect());
+    }
+    throw!(ErrorKind::UnsatisfiablePasswordRules(
+        "Couldn't avoid repeating characters too many times"
+    ))
+}
+
+/// Returns `true` if `password` has the same character `run` times in a row.
+fn has_consecutive_run(password: &[char], run: usize) -> bool {
+    password
+        .windows(run)
+        .any(|window| window.iter().all(|&c| c == window[0]))
+}
+
+/// Returns a uniformly random index less than `len`, which must be non-zero.
+fn random_index(len: usize) -> Result<usize> {
+    assert!(len > 0 && len <= u32::max_value() as usize);
+    let len = len as u32;
+    // Reject values from the last, incomplete, multiple of `len`, so that we
+    // don't favor small indices.
+    let limit = u32::max_value() - u32::max_value() % len;
+    loop {
+        let mut bytes = [0u8; 4];
+        rand::fill(&mut bytes)?;
+        let value = u32::from_le_bytes(bytes);
+        if value < limit {
+            return Ok((value % len) as usize);
+        }
+    }
+}
+
+/// Shuffles `chars` in place, with a Fisher-Yates shuffle.
+fn shuffle(chars: &mut [char]) -> Result<()> {
+    for i in (1..chars.len()).rev() {
+        let j = random_index(i + 1)?;
+        chars.swap(i, j);
+    }
+    Ok(())
+}
+
+// A tiny parser for the `passwordrules` syntax, which splits the rules into
+// properties. Custom character classes can contain `;`, so we can't just
+// split on it.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Returns the next property's lowercased name and raw value.
+    fn next_property(&mut self) -> Option<(String, String)> {
+        loop {
+            while self.pos < self.chars.len()
+                && (self.chars[self.pos].is_whitespace() || self.chars[self.pos] == ';')
+            {
+                self.pos += 1;
+            }
+            if self.pos >= self.chars.len() {
+                return None;
+            }
+            let mut name = String::new();
+            while self.pos < self.chars.len() && !":;".contains(self.chars[self.pos]) {
+                name.push(self.chars[self.pos]);
+                self.pos += 1;
+            }
+            if self.pos >= self.chars.len() || self.chars[self.pos] == ';' {
+                // A property without a value, which we ignore.
+                continue;
+            }
+            self.pos += 1;
+            let mut value = String::new();
+            let mut in_class = false;
+            while self.pos < self.chars.len() {
+                let c = self.chars[self.pos];
+                if c == ';' && !in_class {
+                    break;
+                }
+                if c == '[' && !in_class {
+                    in_class = true;
+                    // `]` is literal if it's the first character of a class.
+                    value.push(c);
+                    self.pos += 1;
+                    if self.pos < self.chars.len() && self.chars[self.pos] == ']' {
+                        value.push(']');
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                if c == ']' && in_class {
+                    in_class = false;
+                }
+                value.push(c);
+                self.pos += 1;
+            }
+            return Some((name.trim().to_ascii_lowercase(), value));
+        }
+    }
+}
+
+/// Parses a comma-separated list of character classes, like
+/// `upper, digit, [-_]`, into the set of characters in any of them.
+fn parse_classes(value: &str) -> BTreeSet<char> {
+    let mut set = BTreeSet::new();
+    let chars = value.chars().collect::<Vec<_>>();
+    let mut pos = 0;
+    while pos < chars.len() {
+        if chars[pos].is_whitespace() || chars[pos] == ',' {
+            pos += 1;
+        } else if chars[pos] == '[' {
+            pos += 1;
+            let start = pos;
+            while pos < chars.len() && (chars[pos] != ']' || pos == start) {
+                pos += 1;
+            }
+            set.extend(chars[start..pos].iter().filter(|c| is_ascii_printable(**c)));
+            pos += 1;
+        } else {
+            let start = pos;
+            while pos < chars.len() && !chars[pos].is_whitespace() && chars[pos] != ',' {
+                pos += 1;
+            }
+            let name = chars[start..pos]
+                .iter()
+                .collect::<String>()
+                .to_ascii_lowercase();
+            match name.as_str() {
+                "upper" => set.extend(UPPER.chars()),
+                "lower" => set.extend(LOWER.chars()),
+                "digit" => set.extend(DIGIT.chars()),
+                "special" => set.extend(SPECIAL.chars()),
+                "ascii-printable" | "unicode" => set.extend((0x20u8..=0x7e).map(char::from)),
+                _ => log::debug!("Ignoring unknown character class {:?}", name),
+            }
+        }
+    }
+    set
+}
+
+fn is_ascii_printable(c: char) -> bool {
+    (' '..='~').contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn chars(s: &str) -> BTreeSet<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = PasswordRules::parse(
+            "required: lower; required: upper; required: digit; \
+             allowed: [-().&@?'#,/\"+]; max-consecutive: 2; minlength: 8; maxlength: 20;",
+        );
+        assert_eq!(
+            rules,
+            PasswordRules {
+                required: vec![chars(LOWER), chars(UPPER), chars(DIGIT)],
+                allowed: chars("-().&@?'#,/\"+"),
+                forbidden: BTreeSet::new(),
+                min_length: Some(8),
+                max_length: Some(20),
+                max_consecutive: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_classes() {
+        // Classes in one `required` property are combined, and names are case
+        // insensitive.
+        let rules = PasswordRules::parse("required: UPPER, [;,]; allowed: lower");
+        let mut expected = chars(UPPER);
+        expected.extend(chars(";,"));
+        assert_eq!(rules.required, vec![expected]);
+        assert_eq!(rules.allowed, chars(LOWER));
+
+        // `]` is literal at the start of a custom class.
+        let rules = PasswordRules::parse("required: []a-]");
+        assert_eq!(rules.required, vec![chars("]a-")]);
+
+        // Non-ASCII characters are ignored.
+        let rules = PasswordRules::parse("required: [aé]");
+        assert_eq!(rules.required, vec![chars("a")]);
+
+        let rules = PasswordRules::parse("allowed: unicode");
+        assert_eq!(rules.allowed.len(), 95);
+        assert!(rules.allowed.contains(&' '));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        // Unknown properties and classes, and bad values, are ignored.
+        let rules = PasswordRules::parse(
+            "requird: lower; required: emoji, digit; minlength: eight; maxlength 4; ;;",
+        );
+        assert_eq!(
+            rules,
+            PasswordRules {
+                required: vec![chars(DIGIT)],
+                ..PasswordRules::default()
+            }
+        );
+        assert_eq!(PasswordRules::parse(""), PasswordRules::default());
+
+        // Repeated limits use the strictest value.
+        let rules =
+            PasswordRules::parse("minlength: 8; minlength: 10; maxlength: 30; maxlength: 20");
+        assert_eq!(rules.min_length, Some(10));
+        assert_eq!(rules.max_length, Some(20));
+    }
+
+    #[test]
+    fn test_generate_default() {
+        for _ in 0..100 {
+            let password = generate(&PasswordRules::default()).unwrap();
+            assert_eq!(password.len(), DEFAULT_LENGTH);
+            assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+            assert!(password.chars().any(|c| c.is_ascii_digit()));
+            assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+    }
+
+    #[test]
+    fn test_generate_with_rules() {
+        let mut rules = PasswordRules::parse(
+            "required: lower; required: [!#]; allowed: digit; \
+             max-consecutive: 1; minlength: 20; maxlength: 30",
+        );
+        rules.forbidden = chars("aeiou0#");
+        for _ in 0..100 {
+            let password = generate(&rules).unwrap().chars().collect::<Vec<_>>();
+            assert_eq!(password.len(), 20);
+            assert!(password.iter().any(|c| c.is_ascii_lowercase()));
+            assert!(password.contains(&'!'));
+            assert!(password
+                .iter()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '!'));
+            assert!(!password.iter().any(|c| rules.forbidden.contains(c)));
+            assert!(!has_consecutive_run(&password, 2));
+        }
+
+        let rules = PasswordRules::parse("maxlength: 8");
+        assert_eq!(generate(&rules).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn test_generate_unsatisfiable() {
+        let check = |rules: PasswordRules| match generate(&rules).unwrap_err().kind() {
+            ErrorKind::UnsatisfiablePasswordRules(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        };
+        check(PasswordRules::parse("minlength: 10; maxlength: 5"));
+        check(PasswordRules::parse(
+            "required: lower; required: upper; required: digit; maxlength: 2",
+        ));
+        check(PasswordRules {
+            forbidden: chars(DIGIT),
+            ..PasswordRules::parse("required: digit; allowed: lower")
+        });
+        check(PasswordRules::parse(
+            "allowed: [a]; max-consecutive: 1; minlength: 2",
+        ));
+    }
+
+    #[test]
+    fn test_distribution() {
+        const SAMPLES: usize = 10_000;
+        let mut counts = HashMap::new();
+        for _ in 0..SAMPLES {
+            *counts.entry(random_index(4).unwrap()).or_insert(0) += 1;
+        }
+        // Each index should come up about a quarter of the time. The chance of
+        // being this far off by accident is vanishingly small.
+        for i in 0..4 {
+            let count = counts[&i];
+            assert!(
+                count > 2_200 && count < 2_800,
+                "{} came up {} times",
+                i,
+                count
+            );
+        }
+
+        // Generated characters should be uniform over the allowed set, too.
+        let rules = PasswordRules::parse("allowed: [abcd]; minlength: 100; maxlength: 100");
+        let mut counts = HashMap::new();
+        for _ in 0..100 {
+            for c in generate(&rules).unwrap().chars() {
+                *counts.entry(c).or_insert(0) += 1;
+            }
+        }
+        for c in "abcd".chars() {
+            let count = counts[&c];
+            assert!(
+                count > 2_200 && count < 2_800,
+                "{} came up {} times",
+                c,
+                count
+            );
+        }
+    }
+}
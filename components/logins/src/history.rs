@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Password history for logins.
+//!
+//! Every time `update`, or a merge during sync, changes the password of a
+//! login, we save the old password in the `loginsHistory` table, so that users
+//! can get it back if a site rejects the new one. We only keep the most recent
+//! [MAX_PASSWORD_HISTORY] passwords for each login.
+//!
+//! History is local-only: it's never uploaded, and it's removed when its login
+//! is deleted, or when the store is wiped.
+
+use crate::db::LoginDb;
+use crate::encryption::{self, EncryptorDecryptor};
+use crate::error::*;
+use rusqlite::{named_params, Connection, Row};
+use serde_derive::*;
+use sql_support::ConnExt;
+
+/// The number of earlier passwords we keep for each login.
+pub const MAX_PASSWORD_HISTORY: u32 = 10;
+
+/// An earlier password of a login.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordHistoryEntry {
+    pub id: i64,
+    pub password: String,
+    /// When the password was replaced, in milliseconds.
+    pub time_changed: i64,
+}
+
+/// Saves `old_password` as an earlier password of the login with `guid`, and
+/// forgets the oldest passwords if there are too many. Must be called inside a
+/// transaction.
+pub(crate) fn record_password_change(
+    conn: &Connection,
+    encdec: Option<&EncryptorDecryptor>,
+    guid: &str,
+    old_password: &str,
+    time_changed: i64,
+) -> Result<()> {
+    // Tombstones don't have passwords.
+    if old_password.is_empty() {
+        return Ok(());
+    }
+    conn.execute_named_cached(
+        "INSERT INTO loginsHistory (guid, password, timeChanged)
+         VALUES (:guid, :password, :time_changed)",
+        named_params! {
+            ":guid": guid,
+            ":password": encryption::encrypt_field(encdec, old_password)?,
+            ":time_changed": time_changed,
+        },
+    )?;
+    conn.execute_named_cached(
+        &format!(
+            "DELETE FROM loginsHistory
+             WHERE guid = :guid AND id NOT IN (
+                 SELECT id FROM loginsHistory
+                 WHERE guid = :guid
+                 ORDER BY timeChanged DESC, id DESC
+                 LIMIT {max}
+             )",
+            max = MAX_PASSWORD_HISTORY
+        ),
+        named_params! { ":guid": guid },
+    )?;
+    Ok(())
+}
+
+/// Forgets the earlier passwords of the login with `guid`.
+pub(crate) fn delete_password_history(conn: &Connection, guid: &str) -> Result<()> {
+    conn.execute_named_cached(
+        "DELETE FROM loginsHistory WHERE guid = :guid",
+        named_params! { ":guid": guid },
+    )?;
+    Ok(())
+}
+
+/// Moves the earlier passwords of a login to a new GUID, when we dedupe an
+/// incoming record against a local login with a different GUID.
+pub(crate) fn move_password_history(conn: &Connection, from: &str, to: &str) -> Result<()> {
+    conn.execute_named_cached(
+        "UPDATE loginsHistory SET guid = :to WHERE guid = :from",
+        named_params! { ":from": from, ":to": to },
+    )?;
+    Ok(())
+}
+
+impl LoginDb {
+    fn history_entry_from_row(&self, row: &Row<'_>) -> Result<PasswordHistoryEntry> {
+        Ok(PasswordHistoryEntry {
+            id: row.get("id")?,
+            password: encryption::decrypt_field(self.encdec(), row.get("password")?)?,
+            time_changed: row.get("timeChanged")?,
+        })
+    }
+
+    /// Returns the earlier passwords of the login with `id`, newest first.
+    pub fn get_password_history(&self, id: &str) -> Result<Vec<PasswordHistoryEntry>> {
+        self.query_rows_and_then_named_cached(
+            "SELECT id, password, timeChanged FROM loginsHistory
+             WHERE guid = :guid
+             ORDER BY timeChanged DESC, id DESC",
+            named_params! { ":guid": id },
+            |row| self.history_entry_from_row(row),
+        )
+    }
+
+    /// Changes the password of the login with `id` back to the earlier
+    /// password with `history_id`. Like any other update, this saves the
+    /// current password in the history.
+    pub fn restore_password(&self, id: &str, history_id: i64) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        let entry = self.try_query_row(
+            "SELECT id, password, timeChanged FROM loginsHistory
+             WHERE guid = :guid AND id = :id",
+            named_params! { ":guid": id, ":id": history_id },
+            |row| self.history_entry_from_row(row),
+            false,
+        )?;
+        let (entry, mut login) = match (entry, self.get_by_id(id)?) {
+            (Some(entry), Some(login)) => (entry, login),
+            _ => throw!(ErrorKind::NoSuchRecord(id.to_owned())),
+        };
+        self.execute_named_cached(
+            "DELETE FROM loginsHistory WHERE id = :id",
+            named_params! { ":id": entry.id },
+        )?;
+        login.password = entry.password;
+        self.update_login(login)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add_login;
+
+    fn set_password(db: &LoginDb, guid: &str, password: &str) {
+        let mut login = db.get_by_id(guid).unwrap().unwrap();
+        login.password = password.into();
+        db.update(login).unwrap();
+    }
+
+    fn history_passwords(db: &LoginDb, guid: &str) -> Vec<String> {
+        db.get_password_history(guid)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.password)
+            .collect()
+    }
+
+    #[test]
+    fn test_history() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        assert!(db.get_password_history(&guid).unwrap().is_empty());
+
+        set_password(&db, &guid, "second");
+        set_password(&db, &guid, "third");
+        // Updates that don't change the password aren't recorded.
+        set_password(&db, &guid, "third");
+        assert_eq!(history_passwords(&db, &guid), vec!["second", "password"]);
+
+        let first = db.get_password_history(&guid).unwrap()[1].clone();
+        db.restore_password(&guid, first.id).unwrap();
+        assert_eq!(db.get_by_id(&guid).unwrap().unwrap().password, "password");
+        assert_eq!(history_passwords(&db, &guid), vec!["third", "second"]);
+
+        match db.restore_password(&guid, first.id).unwrap_err().kind() {
+            ErrorKind::NoSuchRecord(_) => {}
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+
+        db.delete(&guid).unwrap();
+        assert!(db.get_password_history(&guid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        for i in 1..=MAX_PASSWORD_HISTORY + 5 {
+            set_password(&db, &guid, &i.to_string());
+        }
+        let passwords = history_passwords(&db, &guid);
+        assert_eq!(passwords.len(), MAX_PASSWORD_HISTORY as usize);
+        assert_eq!(passwords[0], (MAX_PASSWORD_HISTORY + 4).to_string());
+    }
+
+    #[test]
+    fn test_history_wiped() {
+        let db = LoginDb::open_in_memory(None).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        set_password(&db, &guid, "second");
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert!(db.get_password_history(&guid).unwrap().is_empty());
+
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        set_password(&db, &guid, "second");
+        db.wipe_local().unwrap();
+        assert!(db.get_password_history(&guid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_history_encrypted() {
+        let key = encryption::create_key().unwrap();
+        let db = LoginDb::open_in_memory_with_field_encryption(&key).unwrap();
+        let guid = add_login(&db, "https://www.example.com", None, Some("realm"));
+        set_password(&db, &guid, "second");
+        let raw = db
+            .query_one::<String>("SELECT password FROM loginsHistory")
+            .unwrap();
+        assert_ne!(raw, "password");
+        assert_eq!(history_passwords(&db, &guid), vec!["password"]);
+    }
+}
@@ -0,0 +1,456 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Importing logins from the CSV files that other browsers and password
+//! managers export, and exporting our logins in the same format as Desktop.
+//!
+//! There's no standard layout for these files, so we look for the columns we
+//! need by name, using the names that Firefox, Chrome, LastPass, 1Password,
+//! and Bitwarden use.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::matching;
+use sql_support::ConnExt;
+use std::io::{Read, Write};
+use sync_guid::Guid;
+
+const HOSTNAME_COLUMNS: &[&str] = &["url", "origin", "hostname", "website", "login_uri"];
+const USERNAME_COLUMNS: &[&str] = &["username", "login_username", "login name", "user"];
+const PASSWORD_COLUMNS: &[&str] = &["password", "login_password"];
+const HTTP_REALM_COLUMNS: &[&str] = &["httprealm", "http realm"];
+const FORM_SUBMIT_URL_COLUMNS: &[&str] = &["formactionorigin", "formsubmiturl"];
+
+/// The columns that Desktop writes when exporting logins.
+const EXPORT_COLUMNS: &[&str] = &[
+    "url",
+    "username",
+    "password",
+    "httpRealm",
+    "formActionOrigin",
+    "guid",
+    "timeCreated",
+    "timeLastUsed",
+    "timePasswordChanged",
+];
+
+/// What happened to a row in an imported CSV file.
+#[derive(Debug)]
+pub enum ImportOutcome {
+    /// The row was a new login, which was added with this GUID.
+    Added(Guid),
+    /// The row had a new password for the existing login with this GUID, so
+    /// we changed its password.
+    Updated(Guid),
+    /// The row is identical to the existing login with this GUID.
+    Duplicate(Guid),
+    /// The row isn't a valid login, and was skipped.
+    Invalid(InvalidLogin),
+}
+
+#[derive(Debug)]
+pub struct ImportResult {
+    /// The line in the CSV file where the row starts, counting from 1.
+    pub line: u64,
+    pub outcome: ImportOutcome,
+}
+
+/// The indexes of the columns we import.
+struct CsvColumns {
+    hostname: usize,
+    password: usize,
+    username: Option<usize>,
+    http_realm: Option<usize>,
+    form_submit_url: Option<usize>,
+}
+
+impl CsvColumns {
+    fn from_headers(headers: &csv::StringRecord) -> Result<Self> {
+        let find = |names: &[&str]| {
+            headers.iter().position(|header| {
+                let header = header.trim_start_matches('\u{feff}').trim();
+                names.iter().any(|name| header.eq_ignore_ascii_case(name))
+            })
+        };
+        Ok(CsvColumns {
+            hostname: find(HOSTNAME_COLUMNS)
+                .ok_or_else(|| ErrorKind::MissingCsvColumn(HOSTNAME_COLUMNS[0]))?,
+            password: find(PASSWORD_COLUMNS)
+                .ok_or_else(|| ErrorKind::MissingCsvColumn(PASSWORD_COLUMNS[0]))?,
+            username: find(USERNAME_COLUMNS),
+            http_realm: find(HTTP_REALM_COLUMNS),
+            form_submit_url: find(FORM_SUBMIT_URL_COLUMNS),
+        })
+    }
+
+    fn login_from_record(&self, record: &csv::StringRecord) -> Login {
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .unwrap_or_default()
+        };
+        let non_empty = |index: Option<usize>| match field(index).trim() {
+            "" => None,
+            value => Some(value),
+        };
+        let http_realm = non_empty(self.http_realm).map(str::to_owned);
+        // Most password managers don't record where forms submit to, so we
+        // use the wildcard (an empty `formSubmitURL`) for those logins.
+        let form_submit_url = match non_empty(self.form_submit_url) {
+            Some(url) => Some(matching::normalize_origin(url)),
+            None if http_realm.is_none() => Some(String::new()),
+            None => None,
+        };
+        Login {
+            hostname: field(Some(self.hostname)).trim().to_owned(),
+            username: field(self.username).trim().to_owned(),
+            // Leading and trailing spaces might be part of the password.
+            password: field(Some(self.password)).to_owned(),
+            http_realm,
+            form_submit_url,
+            ..Login::default()
+        }
+    }
+}
+
+impl LoginDb {
+    /// Imports logins from a CSV file. Rows for logins that we already have
+    /// update those logins' passwords instead of adding new logins. Either
+    /// every row is imported, or, if reading the file fails, none are.
+    pub fn import_csv(&self, reader: impl Read) -> Result<Vec<ImportResult>> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+        let columns = CsvColumns::from_headers(reader.headers()?)?;
+        let tx = self.unchecked_transaction()?;
+        let mut results = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, csv::Position::line);
+            let outcome = self.import_login(columns.login_from_record(&record))?;
+            results.push(ImportResult { line, outcome });
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn import_login(&self, login: Login) -> Result<ImportOutcome> {
+        let login = match login.fixed_up() {
+            Ok(login) => login,
+            Err(reason) => return Ok(ImportOutcome::Invalid(reason)),
+        };
+        Ok(match self.find_import_dupe(&login)? {
+            Some(dupe) if dupe.password == login.password => ImportOutcome::Duplicate(dupe.guid),
+            Some(mut dupe) => {
+                dupe.password = login.password;
+                let guid = dupe.guid.clone();
+                self.update_login(dupe)?;
+                ImportOutcome::Updated(guid)
+            }
+            None => ImportOutcome::Added(self.insert_login(login)?.guid),
+        })
+    }
+
+    /// Finds an existing login that `login` duplicates. Most CSV files don't
+    /// have a form action origin, so, unlike `find_dupe`, a login with an
+    /// empty `formSubmitURL` also duplicates a form login with any form action
+    /// origin for the same hostname and username.
+    fn find_import_dupe(&self, login: &Login) -> Result<Option<Login>> {
+        if let Some(dupe) = self.find_live_dupe(login)? {
+            return Ok(Some(dupe));
+        }
+        match &login.form_submit_url {
+            Some(url) if url.is_empty() => {}
+            _ => return Ok(None),
+        }
+        Ok(self
+            .get_by_hostnames(std::slice::from_ref(&login.hostname))?
+            .into_iter()
+            .find(|candidate| {
+                candidate.form_submit_url.is_some() && candidate.username == login.username
+            }))
+    }
+
+    /// Exports all logins, including their passwords in plaintext, as a CSV
+    /// file in the same format as Desktop. Since the file isn't encrypted,
+    /// callers must confirm that the user asked for this by passing
+    /// `confirm_plaintext_export`. Returns the number of exported logins.
+    pub fn export_csv(&self, writer: impl Write, confirm_plaintext_export: bool) -> Result<usize> {
+        if !confirm_plaintext_export {
+            throw!(ErrorKind::ExportNotConfirmed);
+        }
+        let logins = self.get_all()?;
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(EXPORT_COLUMNS)?;
+        for login in &logins {
+            writer.write_record(&[
+                login.hostname.as_str(),
+                login.username.as_str(),
+                login.password.as_str(),
+                login.http_realm.as_ref().map_or("", String::as_str),
+                login.form_submit_url.as_ref().map_or("", String::as_str),
+                login.guid_str(),
+                &login.time_created.to_string(),
+                &login.time_last_used.to_string(),
+                &login.time_password_changed.to_string(),
+            ])?;
+        }
+        writer.flush().map_err(csv::Error::from)?;
+        Ok(logins.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcomes(results: &[ImportResult]) -> Vec<(u64, String)> {
+        results
+            .iter()
+            .map(|result| {
+                let outcome = match &result.outcome {
+                    ImportOutcome::Added(_) => "added".to_owned(),
+                    ImportOutcome::Updated(_) => "updated".to_owned(),
+                    ImportOutcome::Duplicate(_) => "duplicate".to_owned(),
+                    ImportOutcome::Invalid(reason) => format!("invalid: {}", reason),
+                };
+                (result.line, outcome)
+            })
+            .collect()
+    }
+
+    fn sorted_logins(
+        db: &LoginDb,
+    ) -> Vec<(String, String, String, Option<String>, Option<String>)> {
+        let mut logins = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|l| {
+                (
+                    l.hostname,
+                    l.username,
+                    l.password,
+                    l.form_submit_url,
+                    l.http_realm,
+                )
+            })
+            .collect::<Vec<_>>();
+        logins.sort();
+        logins
+    }
+
+    #[test]
+    fn test_import_chrome() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let csv = "name,url,username,password
+example.com,https://example.com/login?next=1,alice,hunter2
+example.com,https://example.com,alice,hunter2
+example.org,https://example.org,bob,
+,,carol,secret
+example.net,http://example.net:8080/,,\" spaces \"
+";
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            outcomes(&results),
+            vec![
+                (2, "added".to_owned()),
+                (3, "duplicate".to_owned()),
+                (4, "invalid: Password is empty".to_owned()),
+                (5, "invalid: Hostname is empty".to_owned()),
+                (6, "added".to_owned()),
+            ]
+        );
+        assert_eq!(
+            sorted_logins(&db),
+            vec![
+                (
+                    "http://example.net:8080".to_owned(),
+                    "".to_owned(),
+                    " spaces ".to_owned(),
+                    Some("".to_owned()),
+                    None
+                ),
+                (
+                    "https://example.com".to_owned(),
+                    "alice".to_owned(),
+                    "hunter2".to_owned(),
+                    Some("".to_owned()),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_layouts() {
+        let layouts = &[
+            // LastPass
+            "url,username,password,extra,name,grouping,fav
+https://example.com,alice,hunter2,,Example,,0",
+            // 1Password
+            "Title,Website,Username,Password,Notes
+Example,https://example.com,alice,hunter2,",
+            // Bitwarden
+            "folder,favorite,type,name,notes,fields,login_uri,login_username,login_password,login_totp
+,,login,Example,,,https://example.com,alice,hunter2,",
+            // Firefox, with a byte order mark.
+            "\u{feff}\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\"
+\"https://example.com\",\"alice\",\"hunter2\",,\"\",\"{abc}\"",
+        ];
+        for layout in layouts {
+            let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+            let results = db.import_csv(layout.as_bytes()).unwrap();
+            assert_eq!(outcomes(&results), vec![(2, "added".to_owned())]);
+            assert_eq!(
+                sorted_logins(&db),
+                vec![(
+                    "https://example.com".to_owned(),
+                    "alice".to_owned(),
+                    "hunter2".to_owned(),
+                    Some("".to_owned()),
+                    None
+                )],
+            );
+        }
+
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        match db.import_csv("name,username,password\n".as_bytes()) {
+            Err(e) => match e.kind() {
+                ErrorKind::MissingCsvColumn("url") => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            },
+            Ok(_) => panic!("Should fail without a URL column"),
+        }
+    }
+
+    #[test]
+    fn test_import_existing() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let local = db
+            .add(Login {
+                hostname: "https://example.com".into(),
+                form_submit_url: Some("https://example.com".into()),
+                username: "alice".into(),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.execute_batch(
+            "INSERT INTO loginsM (
+                guid, hostname, formSubmitURL, username, password,
+                timeCreated, timePasswordChanged, server_modified
+             ) VALUES (
+                'mirror000001', 'https://example.org', '', 'bob', 'secret',
+                1000, 1000, 1000
+             )",
+        )
+        .unwrap();
+
+        let csv = "url,username,password
+https://example.com,alice,hunter2
+https://example.com,alice,correct horse
+https://example.org,bob,secret
+https://example.org,bob,battery staple
+";
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            outcomes(&results),
+            vec![
+                (2, "duplicate".to_owned()),
+                (3, "updated".to_owned()),
+                (4, "duplicate".to_owned()),
+                (5, "updated".to_owned()),
+            ]
+        );
+        match &results[1].outcome {
+            ImportOutcome::Updated(guid) => assert_eq!(guid, &local.guid),
+            outcome => panic!("Wrong outcome: {:?}", outcome),
+        }
+        match &results[3].outcome {
+            ImportOutcome::Updated(guid) => assert_eq!(guid, "mirror000001"),
+            outcome => panic!("Wrong outcome: {:?}", outcome),
+        }
+        assert_eq!(
+            sorted_logins(&db),
+            vec![
+                (
+                    "https://example.com".to_owned(),
+                    "alice".to_owned(),
+                    "correct horse".to_owned(),
+                    Some("https://example.com".to_owned()),
+                    None
+                ),
+                (
+                    "https://example.org".to_owned(),
+                    "bob".to_owned(),
+                    "battery staple".to_owned(),
+                    Some("".to_owned()),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_is_atomic() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let mut csv = b"url,username,password\nhttps://example.com,alice,hunter2\n".to_vec();
+        // Invalid UTF-8 in the second row.
+        csv.extend_from_slice(b"https://example.org,bob,\xff\xfe\n");
+        assert!(db.import_csv(csv.as_slice()).is_err());
+        assert!(db.get_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        for (hostname, form_submit_url, http_realm, username) in &[
+            (
+                "https://example.com",
+                Some("https://example.com"),
+                None,
+                "alice",
+            ),
+            (
+                "https://example.org",
+                Some(""),
+                None,
+                "bob, \"the builder\"",
+            ),
+            ("https://example.net", None, Some("Private area"), "carol"),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                form_submit_url: form_submit_url.map(Into::into),
+                http_realm: http_realm.map(Into::into),
+                username: (*username).into(),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+
+        let mut csv = Vec::new();
+        match db.export_csv(&mut csv, false) {
+            Err(e) => match e.kind() {
+                ErrorKind::ExportNotConfirmed => {}
+                kind => panic!("Wrong error: {:?}", kind),
+            },
+            Ok(_) => panic!("Export should need confirmation"),
+        }
+        assert!(csv.is_empty());
+
+        assert_eq!(db.export_csv(&mut csv, true).unwrap(), 3);
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with(
+            "url,username,password,httpRealm,formActionOrigin,guid,timeCreated,timeLastUsed,timePasswordChanged\n"
+        ));
+
+        // Importing the export should give us the same logins.
+        let other_db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let results = other_db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(sorted_logins(&other_db), sorted_logins(&db));
+    }
+}
@@ -9,14 +9,29 @@
 mod error;
 mod login;
 
+mod batch;
+mod breaches;
 mod db;
+mod dedupe;
+mod encryption;
 mod engine;
+pub mod generator;
+mod history;
+mod import_export;
+mod matching;
 pub mod schema;
 mod update_plan;
 mod util;
 
 mod ffi;
 
+pub use crate::breaches::{Breach, BreachAlert, PASSWORDS_DATA_CLASS};
+pub use crate::db::LoginStore;
+pub use crate::dedupe::DuplicateGroup;
+pub use crate::encryption::{create_key as create_field_encryption_key, EncryptorDecryptor};
 pub use crate::engine::*;
 pub use crate::error::*;
+pub use crate::ffi::BatchResult;
+pub use crate::history::{PasswordHistoryEntry, MAX_PASSWORD_HISTORY};
+pub use crate::import_export::{ImportOutcome, ImportResult};
 pub use crate::login::*;
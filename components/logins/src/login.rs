@@ -3,12 +3,14 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::*;
+use crate::matching;
 use crate::util;
 use rusqlite::Row;
 use serde_derive::*;
 use std::time::{self, SystemTime};
 use sync15::ServerTimestamp;
 use sync_guid::Guid;
+use url::Url;
 
 #[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -84,7 +86,51 @@ impl Login {
         self.guid.as_str()
     }
 
+    /// Repairs the problems with this login that we can safely fix, and
+    /// then checks that it's valid. We fix:
+    ///
+    /// - An empty `httpRealm` on a login with a `formSubmitURL`, which we
+    ///   drop.
+    /// - A `hostname` that's a full URL instead of an origin, which we
+    ///   replace with its origin.
+    /// - Internationalized hosts in the `hostname` and `formSubmitURL`, which
+    ///   we convert to punycode.
+    pub fn fixup(self) -> Result<Login> {
+        Ok(self.fixed_up()?)
+    }
+
+    /// Like `fixup`, but returns why the login is invalid, instead of an
+    /// `Error`.
+    pub(crate) fn fixed_up(mut self) -> std::result::Result<Login, InvalidLogin> {
+        if self.form_submit_url.is_some()
+            && self.http_realm.as_ref().map_or(false, String::is_empty)
+        {
+            self.http_realm = None;
+        }
+        if !self.hostname.is_empty() {
+            self.hostname = matching::normalize_origin(&self.hostname);
+        }
+        // Unlike the hostname, the `formSubmitURL` isn't always an origin, so
+        // we only touch it if it has an internationalized host.
+        if let Some(form_submit_url) = self.form_submit_url.as_mut() {
+            if !form_submit_url.is_ascii() {
+                if let Ok(url) = Url::parse(form_submit_url) {
+                    *form_submit_url = url.into_string();
+                }
+            }
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
     pub fn check_valid(&self) -> Result<()> {
+        self.validate()?;
+        Ok(())
+    }
+
+    /// Like `check_valid`, but returns why the login is invalid, instead of
+    /// an `Error`.
+    pub(crate) fn validate(&self) -> std::result::Result<(), InvalidLogin> {
         if self.hostname.is_empty() {
             throw!(InvalidLogin::EmptyHostname);
         }
@@ -515,4 +561,87 @@ mod tests {
         assert_eq!(login.time_last_used, now64 - 50);
         assert_eq!(login.time_password_changed, now64 - 25);
     }
+
+    #[test]
+    fn test_fixup() {
+        let login = Login {
+            hostname: "https://www.example.com/login?next=/".into(),
+            form_submit_url: Some("https://www.example.com/submit".into()),
+            http_realm: Some("".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+        let fixed = login.fixup().unwrap();
+        assert_eq!(fixed.hostname, "https://www.example.com");
+        assert_eq!(
+            fixed.form_submit_url,
+            Some("https://www.example.com/submit".into())
+        );
+        assert_eq!(fixed.http_realm, None);
+
+        let login = Login {
+            hostname: "https://bücher.example/".into(),
+            form_submit_url: Some("https://bücher.example/login".into()),
+            password: "test".into(),
+            ..Login::default()
+        };
+        let fixed = login.fixup().unwrap();
+        assert_eq!(fixed.hostname, "https://xn--bcher-kva.example");
+        assert_eq!(
+            fixed.form_submit_url,
+            Some("https://xn--bcher-kva.example/login".into())
+        );
+
+        // Logins that are already valid aren't changed.
+        let login = Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("".into()),
+            password: "test".into(),
+            ..Login::default()
+        };
+        assert_eq!(login.clone().fixup().unwrap(), login);
+
+        // But logins we can't fix are still invalid.
+        let unfixable = &[
+            (
+                Login {
+                    form_submit_url: Some("https://www.example.com".into()),
+                    password: "test".into(),
+                    ..Login::default()
+                },
+                "Hostname is empty",
+            ),
+            (
+                Login {
+                    hostname: "https://www.example.com".into(),
+                    form_submit_url: Some("https://www.example.com".into()),
+                    ..Login::default()
+                },
+                "Password is empty",
+            ),
+            (
+                Login {
+                    hostname: "https://www.example.com".into(),
+                    form_submit_url: Some("https://www.example.com".into()),
+                    http_realm: Some("realm".into()),
+                    password: "test".into(),
+                    ..Login::default()
+                },
+                "Both `formSubmitUrl` and `httpRealm` are present",
+            ),
+            (
+                Login {
+                    hostname: "https://www.example.com".into(),
+                    password: "test".into(),
+                    ..Login::default()
+                },
+                "Neither `formSubmitUrl` and `httpRealm` are present",
+            ),
+        ];
+        for (login, reason) in unfixable {
+            let err = login.clone().fixed_up().unwrap_err();
+            assert_eq!(err.to_string(), *reason);
+        }
+    }
 }
@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Origin matching rules for looking up logins, following what Desktop does
+//! in `LoginHelper.isOriginMatching` and `LoginManagerStorage.searchLogins`.
+//!
+//! - Logins saved for an `http` origin can be used on the `https` origin with
+//!   the same host and port ("scheme upgrades"), but not the other way around.
+//!
+//! - An empty `formSubmitURL` is a wildcard, and matches any form action
+//!   origin.
+//!
+//! - Logins for other subdomains of the same base domain (eTLD+1), like
+//!   `accounts.example.com` and `www.example.com`, are only returned by the
+//!   base domain lookup, never by the form lookup.
+
+use crate::util;
+use lazy_static::lazy_static;
+use publicsuffix::List;
+use std::net::IpAddr;
+use url::Url;
+
+lazy_static! {
+    // The `publicsuffix` crate doesn't bundle the list, and can only fetch
+    // it over the network, which we don't want a password manager doing on
+    // the first lookup. The list changes rarely enough that we vendor a copy
+    // from https://publicsuffix.org/list/public_suffix_list.dat instead, and
+    // update it with `tools/update_public_suffix_list.sh`. It's only parsed
+    // on first use.
+    static ref PUBLIC_SUFFIX_LIST: List = List::from_str(include_str!("public_suffix_list.dat"))
+        .expect("The bundled public suffix list should be valid");
+}
+
+/// Returns the base domain (eTLD+1) for `host`. IP addresses and hosts
+/// without a registrable domain, like `localhost`, are their own base
+/// domain.
+pub(crate) fn base_domain(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return host;
+    }
+    match PUBLIC_SUFFIX_LIST.parse_domain(&host) {
+        Ok(domain) => domain.root().map(str::to_owned).unwrap_or(host),
+        Err(_) => host,
+    }
+}
+
+/// Returns the host for a login `hostname` or search origin. Bare hosts, like
+/// `example.com`, are returned as-is.
+pub(crate) fn host_for_origin(origin: &str) -> Option<String> {
+    match Url::parse(origin) {
+        Ok(url) => url.host_str().map(str::to_owned),
+        Err(_) if !origin.is_empty() && !origin.contains('/') => Some(origin.to_owned()),
+        Err(_) => None,
+    }
+}
+
+/// Returns the base domain for a login `hostname` or search origin, or `None`
+/// if it doesn't have a host. This is what we store in the `baseDomain`
+/// column.
+pub(crate) fn base_domain_for_origin(origin: &str) -> Option<String> {
+    host_for_origin(origin).map(|host| base_domain(&host))
+}
+
+/// Normalizes an origin or URL to its origin, like `https://example.com`,
+/// dropping default ports and paths. Strings that aren't valid URLs, or that
+/// have opaque origins (like `javascript:`), are returned unchanged.
+pub(crate) fn normalize_origin(origin: &str) -> String {
+    match Url::parse(origin) {
+        Ok(url) => match url.origin() {
+            o @ url::Origin::Tuple(..) => o.ascii_serialization(),
+            url::Origin::Opaque(_) => origin.to_owned(),
+        },
+        Err(_) => origin.to_owned(),
+    }
+}
+
+/// Returns the `hostname`s of the logins that can be used on `origin`: the
+/// origin itself, and, if it's `https`, the `http` origin with the same host
+/// and port.
+pub(crate) fn lookup_origins(origin: &str) -> Vec<String> {
+    let normalized = normalize_origin(origin);
+    let mut origins = vec![normalized.clone()];
+    if let Ok(url) = Url::parse(&normalized) {
+        if url.scheme() == "https" {
+            if let Some(host_port) = util::url_host_port(&normalized) {
+                origins.push(format!("http://{}", host_port));
+            }
+        }
+    }
+    origins
+}
+
+/// Returns `true` if a login saved for `login_origin` can be used on
+/// `search_origin`. If `accept_wildcard` is set, an empty `login_origin`
+/// matches any search origin.
+pub(crate) fn is_origin_matching(
+    login_origin: &str,
+    search_origin: &str,
+    accept_wildcard: bool,
+) -> bool {
+    if login_origin.is_empty() {
+        return accept_wildcard;
+    }
+    let login_origin = normalize_origin(login_origin);
+    let search_origin = normalize_origin(search_origin);
+    if login_origin == search_origin {
+        return true;
+    }
+    match (Url::parse(&login_origin), Url::parse(&search_origin)) {
+        (Ok(login_url), Ok(search_url)) => {
+            login_url.scheme() == "http"
+                && search_url.scheme() == "https"
+                && util::url_host_port(&login_origin) == util::url_host_port(&search_origin)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_domain() {
+        assert_eq!(base_domain("example.com"), "example.com");
+        assert_eq!(base_domain("www.example.com"), "example.com");
+        assert_eq!(base_domain("a.b.EXAMPLE.com"), "example.com");
+        assert_eq!(base_domain("www.example.co.uk"), "example.co.uk");
+        assert_eq!(base_domain("localhost"), "localhost");
+        assert_eq!(base_domain("127.0.0.1"), "127.0.0.1");
+    }
+
+    #[test]
+    fn test_host_for_origin() {
+        assert_eq!(
+            host_for_origin("https://www.example.com:8080"),
+            Some("www.example.com".into())
+        );
+        assert_eq!(host_for_origin("example.com"), Some("example.com".into()));
+        assert_eq!(host_for_origin(""), None);
+        assert_eq!(host_for_origin("javascript:"), None);
+    }
+
+    #[test]
+    fn test_lookup_origins() {
+        assert_eq!(
+            lookup_origins("https://example.com/login"),
+            vec!["https://example.com", "http://example.com"]
+        );
+        assert_eq!(
+            lookup_origins("https://example.com:8443"),
+            vec!["https://example.com:8443", "http://example.com:8443"]
+        );
+        assert_eq!(
+            lookup_origins("http://example.com"),
+            vec!["http://example.com"]
+        );
+    }
+
+    #[test]
+    fn test_is_origin_matching() {
+        assert!(is_origin_matching(
+            "https://example.com",
+            "https://example.com/path",
+            false
+        ));
+        // Scheme upgrades are allowed, downgrades aren't.
+        assert!(is_origin_matching(
+            "http://example.com",
+            "https://example.com",
+            false
+        ));
+        assert!(!is_origin_matching(
+            "https://example.com",
+            "http://example.com",
+            false
+        ));
+        assert!(!is_origin_matching(
+            "http://example.com:8080",
+            "https://example.com",
+            false
+        ));
+        // Subdomains don't match.
+        assert!(!is_origin_matching(
+            "https://www.example.com",
+            "https://example.com",
+            false
+        ));
+        // Wildcards.
+        assert!(is_origin_matching("", "https://example.com", true));
+        assert!(!is_origin_matching("", "https://example.com", false));
+        assert!(is_origin_matching("javascript:", "javascript:", false));
+    }
+}
@@ -2,15 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
+//! Logins Schema v7
 //! ================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are five tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsHistory`: The table used to store earlier passwords.
+//! - `loginsBreachAlertDismissals`: The table used to store dismissed breach
+//!   alerts.
 //!
 //! ## `loginsL`
 //!
@@ -46,6 +49,10 @@
 //!     - `2` (`SyncStatus::New`): Indicating that the record has never been
 //!       synced, or we have been reset since the last time it synced.
 //!
+//! - `baseDomain`: The base domain (eTLD+1) of `hostname`, used to look up
+//!   logins for autofill. It was added in version 7, and is kept up to date
+//!   by temporary triggers that we create on each connection.
+//!
 //! ## `loginsM`
 //!
 //! This stores server-side login information, also known as the "mirror".
@@ -72,13 +79,15 @@
 //! - `is_overridden`: A boolean indicating whether or not the mirror contents
 //!   are invalid, and that we should defer to the data stored in `loginsL`.
 //!
+//! - `baseDomain`: As in `loginsL`.
+//!
 //! ## `loginsSyncMeta`
 //!
 //! This is a simple key-value table based on the `moz_meta` table in places.
 //! This table was added (by this rust crate) in version 4, and so is not
 //! present in firefox-ios.
 //!
-//! Currently it is used to store two items:
+//! Currently it is used to store three items:
 //!
 //! 1. The last sync timestamp is stored under [LAST_SYNC_META_KEY], a
 //!    `sync15::ServerTimestamp` stored in integer milliseconds.
@@ -87,15 +96,52 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. For databases that use field-level encryption instead of SQLCipher, a
+//!    known string encrypted with the field encryption key is stored under
+//!    [FIELD_ENCRYPTION_CANARY_META_KEY]. In these databases, the `username`
+//!    and `password` columns of both tables hold encrypted values, as
+//!    described in the `encryption` module.
+//!
+//! ## `loginsHistory`
+//!
+//! This stores the earlier passwords of each login, so that users can restore
+//! them. It was added in version 5, and is never synced. See the `history`
+//! module for details.
+//!
+//! ### `loginsHistory` Columns
+//!
+//! - `guid`: The GUID of the login.
+//!
+//! - `password`: The earlier password. Like the `password` column of the other
+//!   tables, this is encrypted in databases that use field-level encryption.
+//!
+//! - `timeChanged`: A millisecond timestamp indicating when this password was
+//!   replaced.
+//!
+//! ## `loginsBreachAlertDismissals`
+//!
+//! This stores when the user dismissed the breach alert for a login. It was
+//! added in version 6, and is never synced. See the `breaches` module for
+//! details.
+//!
+//! ### `loginsBreachAlertDismissals` Columns
+//!
+//! - `guid`: The GUID of the login.
+//!
+//! - `timeDismissed`: A millisecond timestamp indicating when the alert was
+//!   dismissed.
+//!
 
 use crate::error::*;
 use lazy_static::lazy_static;
 use rusqlite::Connection;
 use sql_support::ConnExt;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 added a metadata
+/// table and changed timestamps to be in milliseconds, version 5 added the
+/// password history table, version 6 added the breach alert dismissals table,
+/// and version 7 (this version) adds the `baseDomain` column.
+pub const VERSION: i64 = 7;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -154,7 +200,13 @@ lazy_static! {
             local_modified INTEGER,
 
             is_deleted     TINYINT NOT NULL DEFAULT 0,
-            sync_status    TINYINT NOT NULL DEFAULT 0
+            sync_status    TINYINT NOT NULL DEFAULT 0,
+
+            -- The base domain (eTLD+1) of `hostname`, or NULL if it doesn't
+            -- have a host. This isn't in `COMMON_COLS`, since the triggers in
+            -- `CREATE_BASE_DOMAIN_TRIGGERS_SQL` always derive it from
+            -- `hostname`.
+            baseDomain     TEXT
         )",
         common_sql = COMMON_SQL
     );
@@ -164,7 +216,10 @@ lazy_static! {
             -- Milliseconds (a sync15::ServerTimestamp multiplied by
             -- 1000 and truncated)
             server_modified INTEGER NOT NULL,
-            is_overridden   TINYINT NOT NULL DEFAULT 0
+            is_overridden   TINYINT NOT NULL DEFAULT 0,
+
+            -- See `loginsL.baseDomain`.
+            baseDomain      TEXT
         )",
         common_sql = COMMON_SQL
     );
@@ -179,6 +234,29 @@ const CREATE_META_TABLE_SQL: &str = "
     )
 ";
 
+const CREATE_HISTORY_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsHistory (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        guid        TEXT NOT NULL,
+        password    TEXT NOT NULL,
+        -- Milliseconds
+        timeChanged INTEGER NOT NULL
+    )
+";
+
+const CREATE_HISTORY_GUID_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsHistory_guid
+    ON loginsHistory (guid)
+";
+
+const CREATE_BREACH_ALERT_DISMISSALS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsBreachAlertDismissals (
+        guid          TEXT PRIMARY KEY,
+        -- Milliseconds
+        timeDismissed INTEGER NOT NULL
+    )
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -189,6 +267,50 @@ const CREATE_DELETED_HOSTNAME_INDEX_SQL: &str = "
     ON loginsL (is_deleted, hostname)
 ";
 
+const CREATE_DELETED_BASE_DOMAIN_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsL_is_deleted_baseDomain
+    ON loginsL (is_deleted, baseDomain)
+";
+
+const CREATE_OVERRIDE_BASE_DOMAIN_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_baseDomain
+    ON loginsM (is_overridden, baseDomain)
+";
+
+// `base_domain` is a SQL function defined on each connection, so these
+// triggers are temporary, and created on each connection, too. Otherwise,
+// other connections to the database, like the `sqlite3` shell, couldn't
+// write to the tables.
+const CREATE_BASE_DOMAIN_TRIGGERS_SQL: &str = "
+    CREATE TEMP TRIGGER IF NOT EXISTS loginsL_afterInsert_setBaseDomain
+    AFTER INSERT ON loginsL
+    BEGIN
+        UPDATE loginsL SET baseDomain = base_domain(NEW.hostname)
+        WHERE id = NEW.id;
+    END;
+
+    CREATE TEMP TRIGGER IF NOT EXISTS loginsL_afterUpdate_setBaseDomain
+    AFTER UPDATE OF hostname ON loginsL
+    BEGIN
+        UPDATE loginsL SET baseDomain = base_domain(NEW.hostname)
+        WHERE id = NEW.id;
+    END;
+
+    CREATE TEMP TRIGGER IF NOT EXISTS loginsM_afterInsert_setBaseDomain
+    AFTER INSERT ON loginsM
+    BEGIN
+        UPDATE loginsM SET baseDomain = base_domain(NEW.hostname)
+        WHERE id = NEW.id;
+    END;
+
+    CREATE TEMP TRIGGER IF NOT EXISTS loginsM_afterUpdate_setBaseDomain
+    AFTER UPDATE OF hostname ON loginsM
+    BEGIN
+        UPDATE loginsM SET baseDomain = base_domain(NEW.hostname)
+        WHERE id = NEW.id;
+    END;
+";
+
 // As noted above, we use these when updating from schema v3 (firefox-ios's
 // last schema) to convert from microsecond timestamps to milliseconds.
 const UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL: &str = "
@@ -205,10 +327,19 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &str = "
         timePasswordChanged = timePasswordChanged / 1000
 ";
 
+// We use these when updating from schema v6, to fill in the base domains of
+// existing logins.
+const ADD_LOCAL_BASE_DOMAIN_SQL: &str = "ALTER TABLE loginsL ADD COLUMN baseDomain TEXT";
+const ADD_MIRROR_BASE_DOMAIN_SQL: &str = "ALTER TABLE loginsM ADD COLUMN baseDomain TEXT";
+const UPDATE_LOCAL_BASE_DOMAINS_SQL: &str = "UPDATE loginsL SET baseDomain = base_domain(hostname)";
+const UPDATE_MIRROR_BASE_DOMAINS_SQL: &str =
+    "UPDATE loginsM SET baseDomain = base_domain(hostname)";
+
 pub(crate) static LAST_SYNC_META_KEY: &str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &str = "global_state_v2";
 pub(crate) static GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
 pub(crate) static COLLECTION_SYNCID_META_KEY: &str = "passwords_sync_id";
+pub(crate) static FIELD_ENCRYPTION_CANARY_META_KEY: &str = "field_encryption_canary";
 
 pub(crate) fn init(db: &Connection) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
@@ -277,7 +408,40 @@ fn upgrade(db: &Connection, from: i64) -> Result<()> {
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
+        ])?;
+    }
+    if from < 5 {
+        // The password history table was added in v5.
+        db.execute_all(&[CREATE_HISTORY_TABLE_SQL, CREATE_HISTORY_GUID_INDEX_SQL])?;
+    }
+    if from < 6 {
+        // The breach alert dismissals table was added in v6.
+        db.execute_batch(CREATE_BREACH_ALERT_DISMISSALS_TABLE_SQL)?;
+    }
+    if from < 7 {
+        // The `baseDomain` column and its indexes were added in v7.
+        db.execute_all(&[
+            ADD_LOCAL_BASE_DOMAIN_SQL,
+            ADD_MIRROR_BASE_DOMAIN_SQL,
+            UPDATE_LOCAL_BASE_DOMAINS_SQL,
+            UPDATE_MIRROR_BASE_DOMAINS_SQL,
+            CREATE_DELETED_BASE_DOMAIN_INDEX_SQL,
+            CREATE_OVERRIDE_BASE_DOMAIN_INDEX_SQL,
+        ])?;
+    }
+    db.execute_batch(&*SET_VERSION_SQL)?;
+    Ok(())
+}
+
+/// Brings rows that `LoginDb::migrate_from_sqlcipher` copied from a database
+/// with schema version `from` up to date. The new database already has the
+/// tables and columns that were added since then, and the triggers fill in
+/// `baseDomain`, so we only need the upgrades that change existing data.
+pub(crate) fn upgrade_copied_rows(db: &Connection, from: i64) -> Result<()> {
+    if from < 4 {
+        db.execute_all(&[
+            UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
+            UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
         ])?;
     }
     Ok(())
@@ -290,18 +454,32 @@ pub(crate) fn create(db: &Connection) -> Result<()> {
         &*CREATE_MIRROR_TABLE_SQL,
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
+        CREATE_DELETED_BASE_DOMAIN_INDEX_SQL,
+        CREATE_OVERRIDE_BASE_DOMAIN_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_HISTORY_TABLE_SQL,
+        CREATE_HISTORY_GUID_INDEX_SQL,
+        CREATE_BREACH_ALERT_DISMISSALS_TABLE_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
 }
 
+/// Creates the temporary triggers that keep `baseDomain` up to date. This
+/// must be called on each connection, after `init`.
+pub(crate) fn create_temp_triggers(db: &Connection) -> Result<()> {
+    db.execute_batch(CREATE_BASE_DOMAIN_TRIGGERS_SQL)?;
+    Ok(())
+}
+
 pub(crate) fn drop(db: &Connection) -> Result<()> {
     log::debug!("Dropping schema");
     db.execute_all(&[
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsHistory",
+        "DROP TABLE IF EXISTS loginsBreachAlertDismissals",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
@@ -2,7 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::breaches;
+use crate::encryption::{self, EncryptorDecryptor};
 use crate::error::*;
+use crate::history;
 use crate::login::{LocalLogin, Login, MirrorLogin, SyncStatus};
 use crate::util;
 use rusqlite::{named_params, Connection};
@@ -19,16 +22,26 @@ pub(crate) struct UpdatePlan {
     // the bool is the `is_overridden` flag, the i64 is ServerTimestamp in millis
     pub mirror_inserts: Vec<(Login, i64, bool)>,
     pub mirror_updates: Vec<(Login, i64)>,
+    // (guid, old password, time changed in millis)
+    pub password_changes: Vec<(Guid, String, i64)>,
+    // (old guid, new guid), for dupes with different GUIDs.
+    pub moved_histories: Vec<(Guid, Guid)>,
+    pub delete_histories: Vec<Guid>,
 }
 
 impl UpdatePlan {
     pub fn plan_two_way_merge(&mut self, local: &Login, upstream: (Login, ServerTimestamp)) {
         let is_override = local.time_password_changed > upstream.0.time_password_changed;
-        self.mirror_inserts
-            .push((upstream.0, upstream.1.as_millis() as i64, is_override));
         if !is_override {
+            if local.guid != upstream.0.guid {
+                self.moved_histories
+                    .push((local.guid.clone(), upstream.0.guid.clone()));
+            }
+            self.plan_password_change(local, &upstream.0);
             self.delete_local.push(local.guid.clone());
         }
+        self.mirror_inserts
+            .push((upstream.0, upstream.1.as_millis() as i64, is_override));
     }
 
     pub fn plan_three_way_merge(
@@ -48,6 +61,7 @@ impl UpdatePlan {
         let upstream_delta = upstream.delta(&shared.login);
 
         let merged_delta = local_delta.merge(upstream_delta, remote_age < local_age);
+        let old_login = local.login;
 
         // Update mirror to upstream
         self.mirror_updates
@@ -56,12 +70,29 @@ impl UpdatePlan {
 
         new.login.apply_delta(merged_delta);
         new.server_modified = upstream_time;
+        self.plan_password_change(&old_login, &new.login);
         self.local_updates.push(new);
     }
 
     pub fn plan_delete(&mut self, id: Guid) {
         self.delete_local.push(id.clone());
         self.delete_mirror.push(id.clone());
+        self.delete_histories.push(id);
+    }
+
+    /// Records the old password in the history, if merging `new` changes the
+    /// password of `old`.
+    pub fn plan_password_change(&mut self, old: &Login, new: &Login) {
+        if old.password == new.password {
+            return;
+        }
+        let time_changed = if new.time_password_changed > 0 {
+            new.time_password_changed
+        } else {
+            util::system_time_ms_i64(SystemTime::now())
+        };
+        self.password_changes
+            .push((new.guid.clone(), old.password.clone(), time_changed));
     }
 
     pub fn plan_mirror_update(&mut self, login: Login, time: ServerTimestamp) {
@@ -99,7 +130,12 @@ impl UpdatePlan {
     }
 
     // These aren't batched but probably should be.
-    fn perform_mirror_updates(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    fn perform_mirror_updates(
+        &self,
+        conn: &Connection,
+        scope: &SqlInterruptScope,
+        encdec: Option<&EncryptorDecryptor>,
+    ) -> Result<()> {
         let sql = "
             UPDATE loginsM
             SET server_modified = :server_modified,
@@ -126,9 +162,9 @@ impl UpdatePlan {
                 ":form_submit_url": login.form_submit_url,
                 ":username_field": login.username_field,
                 ":password_field": login.password_field,
-                ":password": login.password,
+                ":password": encryption::encrypt_field(encdec, &login.password)?,
                 ":hostname": login.hostname,
-                ":username": login.username,
+                ":username": encryption::encrypt_field(encdec, &login.username)?,
                 ":times_used": login.times_used,
                 ":time_last_used": login.time_last_used,
                 ":time_password_changed": login.time_password_changed,
@@ -140,7 +176,12 @@ impl UpdatePlan {
         Ok(())
     }
 
-    fn perform_mirror_inserts(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    fn perform_mirror_inserts(
+        &self,
+        conn: &Connection,
+        scope: &SqlInterruptScope,
+        encdec: Option<&EncryptorDecryptor>,
+    ) -> Result<()> {
         let sql = "
             INSERT OR IGNORE INTO loginsM (
                 is_overridden,
@@ -190,9 +231,9 @@ impl UpdatePlan {
                 ":form_submit_url": login.form_submit_url,
                 ":username_field": login.username_field,
                 ":password_field": login.password_field,
-                ":password": login.password,
+                ":password": encryption::encrypt_field(encdec, &login.password)?,
                 ":hostname": login.hostname,
-                ":username": login.username,
+                ":username": encryption::encrypt_field(encdec, &login.username)?,
                 ":times_used": login.times_used,
                 ":time_last_used": login.time_last_used,
                 ":time_password_changed": login.time_password_changed,
@@ -204,7 +245,12 @@ impl UpdatePlan {
         Ok(())
     }
 
-    fn perform_local_updates(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    fn perform_local_updates(
+        &self,
+        conn: &Connection,
+        scope: &SqlInterruptScope,
+        encdec: Option<&EncryptorDecryptor>,
+    ) -> Result<()> {
         let sql = format!(
             "UPDATE loginsL
              SET local_modified      = :local_modified,
@@ -233,9 +279,9 @@ impl UpdatePlan {
                 ":form_submit_url": l.login.form_submit_url,
                 ":username_field": l.login.username_field,
                 ":password_field": l.login.password_field,
-                ":password": l.login.password,
+                ":password": encryption::encrypt_field(encdec, &l.login.password)?,
                 ":hostname": l.login.hostname,
-                ":username": l.login.username,
+                ":username": encryption::encrypt_field(encdec, &l.login.username)?,
                 ":time_last_used": l.login.time_last_used,
                 ":time_password_changed": l.login.time_password_changed,
                 ":times_used": l.login.times_used,
@@ -246,15 +292,43 @@ impl UpdatePlan {
         Ok(())
     }
 
-    pub fn execute(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    fn perform_password_history(
+        &self,
+        conn: &Connection,
+        scope: &SqlInterruptScope,
+        encdec: Option<&EncryptorDecryptor>,
+    ) -> Result<()> {
+        for guid in &self.delete_histories {
+            history::delete_password_history(conn, guid)?;
+            breaches::delete_breach_dismissal(conn, guid)?;
+        }
+        for (from, to) in &self.moved_histories {
+            history::move_password_history(conn, from, to)?;
+            breaches::move_breach_dismissal(conn, from, to)?;
+        }
+        for (guid, old_password, time_changed) in &self.password_changes {
+            history::record_password_change(conn, encdec, guid, old_password, *time_changed)?;
+            scope.err_if_interrupted()?;
+        }
+        Ok(())
+    }
+
+    pub fn execute(
+        &self,
+        conn: &Connection,
+        scope: &SqlInterruptScope,
+        encdec: Option<&EncryptorDecryptor>,
+    ) -> Result<()> {
         log::debug!("UpdatePlan: deleting records...");
         self.perform_deletes(conn, scope)?;
         log::debug!("UpdatePlan: Updating existing mirror records...");
-        self.perform_mirror_updates(conn, scope)?;
+        self.perform_mirror_updates(conn, scope, encdec)?;
         log::debug!("UpdatePlan: Inserting new mirror records...");
-        self.perform_mirror_inserts(conn, scope)?;
+        self.perform_mirror_inserts(conn, scope, encdec)?;
         log::debug!("UpdatePlan: Updating reconciled local records...");
-        self.perform_local_updates(conn, scope)?;
+        self.perform_local_updates(conn, scope, encdec)?;
+        log::debug!("UpdatePlan: Recording changed passwords...");
+        self.perform_password_history(conn, scope, encdec)?;
         Ok(())
     }
 }
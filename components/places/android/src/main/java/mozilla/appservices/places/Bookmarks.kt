@@ -253,6 +253,28 @@ interface ReadableBookmarksConnection : InterruptibleConnection {
      * has its `interrupt()` method called on another thread.
      */
     fun getRecentBookmarks(limit: Int): List<BookmarkItem>
+
+    /**
+     * Returns the results of a desktop `place:` query URL, like the ones
+     * desktop uses for "smart bookmarks" such as "Most Visited" or the
+     * contents of a tag.
+     *
+     * Only the `tag`, `!tags`, `sort`, `maxResults`, `type` and `queryType`
+     * params are supported; others are ignored. The results aren't always
+     * bookmarks in the tree: history queries return the matching pages, and
+     * tag queries return a bookmark for each tag, whose URL is the query for
+     * that tag.
+     *
+     * @param url The `place:` URL.
+     * @return A list of the query's results.
+     *
+     * @throws UrlParseFailed If `url` is not a valid URL.
+     * @throws PlacesException If `url` isn't a `place:` URL, or is a query
+     * we don't support.
+     * @throws OperationInterrupted if this database implements [InterruptibleConnection] and
+     * has its `interrupt()` method called on another thread.
+     */
+    fun getBookmarksQueryResults(url: String): List<BookmarkItem>
 }
 
 /**
@@ -162,6 +162,7 @@ internal interface LibPlacesFFI : Library {
         access_token: String,
         sync_key: String,
         tokenserver_url: String,
+        config_json: String?,
         out_err: RustError.ByReference
     ): Pointer?
 
@@ -218,6 +219,12 @@ internal interface LibPlacesFFI : Library {
         error: RustError.ByReference
     ): RustBuffer.ByValue
 
+    fun bookmarks_get_query_results(
+        handle: PlacesConnectionHandle,
+        url: String,
+        error: RustError.ByReference
+    ): RustBuffer.ByValue
+
     // Returns newly inserted guid
     fun bookmarks_insert(
         handle: PlacesConnectionHandle,
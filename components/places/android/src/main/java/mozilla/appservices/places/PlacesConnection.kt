@@ -78,7 +78,8 @@ class PlacesApi(path: String) : PlacesManager, AutoCloseable {
         }
     }
 
-    override fun syncHistory(syncInfo: SyncAuthInfo): SyncTelemetryPing {
+    override fun syncHistory(syncInfo: SyncAuthInfo, config: HistorySyncConfig?): SyncTelemetryPing {
+        val configJSON = config?.toJSON()?.toString()
         val pingJSONString = rustCallForString(this) { error ->
             LibPlacesFFI.INSTANCE.sync15_history_sync(
                     this.handle.get(),
@@ -86,6 +87,7 @@ class PlacesApi(path: String) : PlacesManager, AutoCloseable {
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    configJSON,
                     error
             )
         }
@@ -364,6 +366,19 @@ open class PlacesReaderConnection internal constructor(connHandle: Long) :
             LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(rustBuf)
         }
     }
+
+    override fun getBookmarksQueryResults(url: String): List<BookmarkItem> {
+        val rustBuf = rustCall { err ->
+            LibPlacesFFI.INSTANCE.bookmarks_get_query_results(this.handle.get(), url, err)
+        }
+
+        try {
+            val message = MsgTypes.BookmarkNodeList.parseFrom(rustBuf.asCodedInputStream()!!)
+            return unpackProtobufItemList(message)
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(rustBuf)
+        }
+    }
 }
 
 fun visitTransitionSet(l: List<VisitType>): Int {
@@ -556,8 +571,10 @@ interface PlacesManager {
      * take some time due to the network etc. Because only 1 thread can be
      * using a PlacesAPI at a time, it is recommended, but not enforced, that
      * you have all connections you intend using open before calling this.
+     *
+     * @param config limits on what's synced. Null uses the defaults.
      */
-    fun syncHistory(syncInfo: SyncAuthInfo): SyncTelemetryPing
+    fun syncHistory(syncInfo: SyncAuthInfo, config: HistorySyncConfig? = null): SyncTelemetryPing
 
     /**
      * Syncs the places bookmarks store, returning a telemetry ping.
@@ -860,6 +877,38 @@ data class VisitObservation(
     }
 }
 
+/**
+ * Limits on what [PlacesManager.syncHistory] syncs. Fields left as null use their defaults.
+ */
+data class HistorySyncConfig(
+    /** The maximum number of visits kept for each incoming record. */
+    val maxIncomingVisits: Int? = null,
+    /** The maximum number of records uploaded on each sync. */
+    val maxOutgoingRecords: Int? = null,
+    /** Records whose most recent visit is older than this many seconds aren't synced. */
+    val maxRecordAgeSecs: Long? = null,
+    /**
+     * How many seconds back to download records that were modified before the first sync.
+     * 0 disables backfilling.
+     */
+    val backfillMaxAgeSecs: Long? = null,
+    /** Whether to sync download visits. */
+    val syncDownloads: Boolean? = null,
+    /** Whether to sync visits that came from a redirect. */
+    val syncRedirects: Boolean? = null
+) {
+    fun toJSON(): JSONObject {
+        val o = JSONObject()
+        this.maxIncomingVisits?.let { o.put("maxIncomingVisits", it) }
+        this.maxOutgoingRecords?.let { o.put("maxOutgoingRecords", it) }
+        this.maxRecordAgeSecs?.let { o.put("maxRecordAgeSecs", it) }
+        this.backfillMaxAgeSecs?.let { o.put("backfillMaxAgeSecs", it) }
+        this.syncDownloads?.let { o.put("syncDownloads", it) }
+        this.syncRedirects?.let { o.put("syncRedirects", it) }
+        return o
+    }
+}
+
 fun stringOrNull(jsonObject: JSONObject, key: String): String? {
     return try {
         jsonObject.getString(key)
@@ -262,4 +262,13 @@ class PlacesConnectionTest {
         assertEquals(folder.position, 2)
         assertEquals(folder.parentGUID, BookmarkRoot.Unfiled.id)
     }
+
+    @Test
+    fun testHistorySyncConfigJSON() {
+        assertEquals("{}", HistorySyncConfig().toJSON().toString())
+        val json = HistorySyncConfig(maxRecordAgeSecs = 2592000, syncDownloads = false).toJSON()
+        assertEquals(2, json.length())
+        assertEquals(2592000L, json.getLong("maxRecordAgeSecs"))
+        assertEquals(false, json.getBoolean("syncDownloads"))
+    }
 }
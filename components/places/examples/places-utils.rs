@@ -23,7 +23,7 @@ use std::io::{BufReader, BufWriter};
 use structopt::StructOpt;
 use sync15::{
     sync_multiple, MemoryCachedState, SetupStorageClient, Store, StoreSyncAssociation,
-    Sync15StorageClient,
+    Sync15StorageClient, SyncMode, SyncOptions,
 };
 use url::Url;
 
@@ -174,7 +174,11 @@ fn sync(
     wipe_all: bool,
     wipe: bool,
     reset: bool,
+    dry_run: bool,
 ) -> Result<()> {
+    if dry_run && (wipe_all || wipe || reset) {
+        failure::bail!("Can't wipe or reset as part of a dry run");
+    }
     let conn = api.open_sync_connection()?;
 
     // interrupts are per-connection, so we need to set that up here.
@@ -244,6 +248,14 @@ fn sync(
         &mut mem_cached_state,
         &cli_fxa.client_init.clone(),
         &cli_fxa.root_sync_key,
+        &SyncOptions {
+            mode: if dry_run {
+                SyncMode::DryRun { include_ids: true }
+            } else {
+                SyncMode::Normal
+            },
+            ..SyncOptions::default()
+        },
         &interruptee,
     );
 
@@ -267,6 +279,12 @@ fn sync(
     }
 
     println!("Sync service status: {:?}", result.service_status);
+    if dry_run {
+        println!(
+            "Planned changes: {}",
+            serde_json::to_string_pretty(&result.planned_changes).unwrap()
+        );
+    }
     println!(
         "Sync telemetry: {}",
         serde_json::to_string_pretty(&result.telemetry).unwrap()
@@ -324,6 +342,11 @@ enum Command {
         /// Reset the store before syncing
         #[structopt(name = "reset", long)]
         reset: bool,
+
+        /// Report what the sync would change, without changing any local
+        /// data or uploading anything.
+        #[structopt(name = "dry-run", long)]
+        dry_run: bool,
     },
 
     #[structopt(name = "export-bookmarks")]
@@ -376,7 +399,16 @@ fn main() -> Result<()> {
             wipe_all,
             wipe,
             reset,
-        } => sync(&api, engines, credential_file, wipe_all, wipe, reset),
+            dry_run,
+        } => sync(
+            &api,
+            engines,
+            credential_file,
+            wipe_all,
+            wipe,
+            reset,
+            dry_run,
+        ),
         Command::ExportBookmarks { output_file } => run_native_export(&db, output_file),
         Command::ImportBookmarks { input_file } => run_native_import(&db, input_file),
         Command::ImportIosBookmarks { input_file } => run_ios_import(&api, input_file),
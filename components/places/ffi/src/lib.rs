@@ -30,7 +30,7 @@ fn parse_url(url: &str) -> places::Result<url::Url> {
 }
 
 lazy_static::lazy_static! {
-    static ref APIS: ConcurrentHandleMap<Arc<PlacesApi>> = ConcurrentHandleMap::new();
+    pub static ref APIS: ConcurrentHandleMap<Arc<PlacesApi>> = ConcurrentHandleMap::new();
     static ref CONNECTIONS: ConcurrentHandleMap<PlacesDb> = ConcurrentHandleMap::new();
 }
 
@@ -414,6 +414,8 @@ pub extern "C" fn places_accept_result(
     })
 }
 
+/// `config_json` is an optional `HistorySyncConfig` as JSON; null uses the
+/// default config.
 #[no_mangle]
 pub extern "C" fn sync15_history_sync(
     handle: u64,
@@ -421,10 +423,15 @@ pub extern "C" fn sync15_history_sync(
     access_token: FfiStr<'_>,
     sync_key: FfiStr<'_>,
     tokenserver_url: FfiStr<'_>,
+    config_json: FfiStr<'_>,
     error: &mut ExternError,
 ) -> *mut c_char {
     log::debug!("sync15_history_sync");
     APIS.call_with_result(error, handle, |api| -> places::Result<_> {
+        let config = match config_json.as_opt_str() {
+            Some(json) => serde_json::from_str(json)?,
+            None => places::history_sync::HistorySyncConfig::default(),
+        };
         let ping = api.sync_history(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
@@ -432,6 +439,7 @@ pub extern "C" fn sync15_history_sync(
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+            &config,
         )?;
         Ok(ping)
     })
@@ -460,6 +468,29 @@ pub extern "C" fn sync15_bookmarks_sync(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn sync15_reading_list_sync(
+    handle: u64,
+    key_id: FfiStr<'_>,
+    access_token: FfiStr<'_>,
+    sync_key: FfiStr<'_>,
+    tokenserver_url: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_reading_list_sync");
+    APIS.call_with_result(error, handle, |api| -> places::Result<_> {
+        let ping = api.sync_reading_list(
+            &sync15::Sync15StorageClientInit {
+                key_id: key_id.into_string(),
+                access_token: access_token.into_string(),
+                tokenserver_url: parse_url(tokenserver_url.as_str())?,
+            },
+            &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+        )?;
+        Ok(ping)
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn bookmarks_get_tree(
     handle: u64,
@@ -610,6 +641,21 @@ pub extern "C" fn bookmarks_get_recent(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn bookmarks_get_query_results(
+    handle: u64,
+    url: FfiStr<'_>,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("bookmarks_get_query_results");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let url = parse_url(url.as_str())?;
+        Ok(BookmarkNodeList::from(
+            storage::query::fetch_query_results_for_url(conn, &url)?,
+        ))
+    })
+}
+
 define_string_destructor!(places_destroy_string);
 define_bytebuffer_destructor!(places_destroy_bytebuffer);
 define_handle_map_deleter!(APIS, places_api_destroy);
@@ -8,6 +8,58 @@ import os.log
 internal typealias APIHandle = UInt64
 internal typealias ConnectionHandle = UInt64
 
+/**
+ * Limits on what `PlacesAPI.syncHistory` syncs. Fields left as `nil` use
+ * their defaults.
+ */
+public struct HistorySyncConfig {
+    /// The maximum number of visits kept for each incoming record.
+    public var maxIncomingVisits: Int?
+
+    /// The maximum number of records uploaded on each sync.
+    public var maxOutgoingRecords: Int?
+
+    /// Records whose most recent visit is older than this many seconds aren't
+    /// synced.
+    public var maxRecordAgeSecs: Int64?
+
+    /// How many seconds back to download records that were modified before
+    /// the first sync. 0 disables backfilling.
+    public var backfillMaxAgeSecs: Int64?
+
+    /// Whether to sync download visits.
+    public var syncDownloads: Bool?
+
+    /// Whether to sync visits that came from a redirect.
+    public var syncRedirects: Bool?
+
+    public init() {}
+
+    internal func toJSON() throws -> String {
+        var dict = [String: Any]()
+        if let maxIncomingVisits = self.maxIncomingVisits {
+            dict["maxIncomingVisits"] = maxIncomingVisits
+        }
+        if let maxOutgoingRecords = self.maxOutgoingRecords {
+            dict["maxOutgoingRecords"] = maxOutgoingRecords
+        }
+        if let maxRecordAgeSecs = self.maxRecordAgeSecs {
+            dict["maxRecordAgeSecs"] = maxRecordAgeSecs
+        }
+        if let backfillMaxAgeSecs = self.backfillMaxAgeSecs {
+            dict["backfillMaxAgeSecs"] = backfillMaxAgeSecs
+        }
+        if let syncDownloads = self.syncDownloads {
+            dict["syncDownloads"] = syncDownloads
+        }
+        if let syncRedirects = self.syncRedirects {
+            dict["syncRedirects"] = syncRedirects
+        }
+        let data: Data = try JSONSerialization.data(withJSONObject: dict)
+        return String(data: data, encoding: String.Encoding.utf8)!
+    }
+}
+
 /**
  * This is something like a places connection manager. It primarialy exists to
  * ensure that only a single write connection is active at once.
@@ -144,6 +196,40 @@ public class PlacesAPI {
         }
     }
 
+    /**
+     * Sync the history collection.
+     *
+     * - Parameter config: Limits on what's synced. `nil` uses the defaults.
+     *
+     * - Returns: A JSON string representing a telemetry ping for this sync. The
+     *            string contains the ping payload, and should be sent to the
+     *            telemetry submission endpoint.
+     *
+     * - Throws:
+     *     - `PlacesError.databaseInterrupted`: If a call is made to `interrupt()` on this
+     *                                          object from another thread.
+     *     - `PlacesError.unexpected`: When an error that has not specifically been exposed
+     *                                 to Swift is encountered (for example IO errors from
+     *                                 the database code, etc).
+     *     - `PlacesError.panic`: If the rust code panics while completing this
+     *                            operation. (If this occurs, please let us know).
+     */
+    open func syncHistory(unlockInfo: SyncUnlockInfo, config: HistorySyncConfig? = nil) throws -> String {
+        let configJSON = try config?.toJSON()
+        return try queue.sync {
+            let pingStr = try PlacesError.unwrap { err in
+                sync15_history_sync(handle,
+                                    unlockInfo.kid,
+                                    unlockInfo.fxaAccessToken,
+                                    unlockInfo.syncKey,
+                                    unlockInfo.tokenserverURL,
+                                    configJSON,
+                                    err)
+            }
+            return String(freeingPlacesString: pingStr)
+        }
+    }
+
     /**
      * Sync the bookmarks collection.
      *
@@ -487,6 +573,50 @@ public class PlacesReadConnection {
         }
     }
 
+    /**
+     * Returns the results of a desktop `place:` query URL, like the ones
+     * desktop uses for "smart bookmarks" such as "Most Visited" or the
+     * contents of a tag.
+     *
+     * Only the `tag`, `!tags`, `sort`, `maxResults`, `type` and `queryType`
+     * params are supported; others are ignored. The results aren't always
+     * bookmarks in the tree: history queries return the matching pages, and
+     * tag queries return a bookmark for each tag, whose URL is the query for
+     * that tag.
+     *
+     * - Parameter url: The `place:` URL.
+     * - Returns: A list of the query's results.
+     * - Throws:
+     *     - `PlacesError.urlParseError`: If `url` is not a valid URL.
+     *     - `PlacesError.databaseInterrupted`: If a call is made to
+     *                                          `interrupt()` on this object
+     *                                          from another thread.
+     *     - `PlacesError.connUseAfterAPIClosed`: If the PlacesAPI that returned
+     *                                            this connection object has
+     *                                            been closed. This indicates
+     *                                            API misuse.
+     *     - `PlacesError.databaseBusy`: If this query times out with a
+     *       SQLITE_BUSY error.
+     *     - `PlacesError.unexpected`: If `url` isn't a `place:` URL, or is a
+     *                                 query we don't support, or when an error
+     *                                 that has not specifically been exposed
+     *                                 to Swift is encountered.
+     *     - `PlacesError.panic`: If the rust code panics while completing this
+     *                            operation. (If this occurs, please let us
+     *                            know).
+     */
+    open func getBookmarksQueryResults(url: String) throws -> [BookmarkItem] {
+        return try queue.sync {
+            try self.checkApi()
+            let buffer = try PlacesError.unwrap { error in
+                bookmarks_get_query_results(self.handle, url, error)
+            }
+            defer { places_destroy_bytebuffer(buffer) }
+            let msg = try MsgTypes_BookmarkNodeList(serializedData: Data(placesRustBuffer: buffer))
+            return unpackProtobufItemList(msg: msg)
+        }
+    }
+
     /**
      * Attempt to interrupt a long-running operation which may be
      * happening concurrently. If the operation is interrupted,
@@ -130,6 +130,7 @@ char *_Nonnull sync15_history_sync(PlacesAPIHandle handle,
                                    char const *_Nonnull access_token,
                                    char const *_Nonnull sync_key,
                                    char const *_Nonnull tokenserver_url,
+                                   char const *_Nullable config_json,
                                    PlacesRustError *_Nonnull out_err);
 
 char *_Nonnull sync15_bookmarks_sync(PlacesAPIHandle handle,
@@ -170,6 +171,10 @@ PlacesRustBuffer bookmarks_get_recent(PlacesConnectionHandle handle,
                                       int32_t limit,
                                       PlacesRustError *_Nonnull out_err);
 
+PlacesRustBuffer bookmarks_get_query_results(PlacesConnectionHandle handle,
+                                             char const *_Nonnull url,
+                                             PlacesRustError *_Nonnull out_err);
+
 PlacesRustBuffer bookmarks_get_tree(PlacesConnectionHandle handle,
                                     char const *_Nullable root_guid,
                                     PlacesRustError *_Nonnull out_err);
@@ -218,3 +218,44 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks_synced_tag_relation(
                            ON DELETE CASCADE,
     PRIMARY KEY(itemId, tagId)
 ) WITHOUT ROWID;
+
+-- Pages saved to read later. Reading list items aren't bookmarks, so we don't
+-- store them in `moz_bookmarks`, and they don't reference `moz_places`.
+CREATE TABLE IF NOT EXISTS moz_reading_list(
+    id INTEGER PRIMARY KEY,
+    guid TEXT NOT NULL UNIQUE CHECK(length(guid) == 12),
+    url TEXT NOT NULL UNIQUE,
+    title TEXT,
+    excerpt TEXT,
+    status INTEGER NOT NULL DEFAULT 0, -- ReadingListStatus::Unread
+    -- In milliseconds.
+    dateAdded INTEGER NOT NULL,
+    lastModified INTEGER NOT NULL,
+    syncStatus INTEGER NOT NULL DEFAULT 1, -- SyncStatus::New
+    syncChangeCounter INTEGER NOT NULL DEFAULT 1
+);
+
+-- Tombstones for reading list items we've deleted locally, but haven't
+-- uploaded yet. Unlike bookmarks, these are written by the storage code, not
+-- a trigger, since we only need them for items that have been synced.
+CREATE TABLE IF NOT EXISTS moz_reading_list_deleted(
+    guid TEXT PRIMARY KEY,
+    dateRemoved INTEGER NOT NULL
+) WITHOUT ROWID;
+
+-- The last known server state for each reading list item, including
+-- tombstones. Incoming records are staged here with `needsMerge` set, and
+-- then merged into `moz_reading_list`. It's unused if Sync isn't configured.
+CREATE TABLE IF NOT EXISTS moz_reading_list_synced(
+    guid TEXT PRIMARY KEY,
+    -- The server modified time, in milliseconds.
+    serverModified INTEGER NOT NULL DEFAULT 0,
+    needsMerge BOOLEAN NOT NULL DEFAULT 0,
+    isDeleted BOOLEAN NOT NULL DEFAULT 0,
+    url TEXT,
+    title TEXT,
+    excerpt TEXT,
+    status INTEGER NOT NULL DEFAULT 0,
+    dateAdded INTEGER NOT NULL DEFAULT 0,
+    lastModified INTEGER NOT NULL DEFAULT 0
+) WITHOUT ROWID;
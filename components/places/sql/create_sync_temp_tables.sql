@@ -113,3 +113,13 @@ CREATE TEMP TABLE tagsToUpload(
     tag TEXT,
     PRIMARY KEY(id, tag)
 ) WITHOUT ROWID;
+
+-- Stores reading list items and tombstones staged for upload, and their
+-- change counters at the time we staged them.
+CREATE TEMP TABLE readingListItemsToUpload(
+    guid TEXT PRIMARY KEY,
+    syncChangeCounter INTEGER NOT NULL,
+    -- The server modified time for the uploaded record, in milliseconds.
+    uploadedAt INTEGER NOT NULL DEFAULT -1,
+    isDeleted BOOLEAN NOT NULL DEFAULT 0
+) WITHOUT ROWID;
@@ -6,6 +6,8 @@ use crate::bookmark_sync::store::BookmarksStore;
 use crate::db::db::PlacesDb;
 use crate::error::*;
 use crate::history_sync::store::HistoryStore;
+use crate::history_sync::HistorySyncConfig;
+use crate::reading_list_sync::ReadingListStore;
 use crate::storage::{delete_meta, get_meta, put_meta};
 use crate::util::normalize_path;
 use lazy_static::lazy_static;
@@ -20,7 +22,7 @@ use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc, Mutex, Weak,
 };
-use sync15::{sync_multiple, telemetry, MemoryCachedState, SyncResult};
+use sync15::{sync_multiple, telemetry, MemoryCachedState, SyncOptions, SyncResult};
 
 // Not clear if this should be here, but this is the "global sync state"
 // which is persisted to disk and reused for all engines.
@@ -229,18 +231,21 @@ impl PlacesApi {
         &self,
         client_init: &sync15::Sync15StorageClientInit,
         key_bundle: &sync15::KeyBundle,
+        config: &HistorySyncConfig,
     ) -> Result<telemetry::SyncTelemetryPing> {
         self.do_sync_one(
             "history",
             move |conn, mem_cached_state, disk_cached_state| {
                 let interruptee = conn.begin_interrupt_scope();
-                let store = HistoryStore::new(&conn, &interruptee);
+                let mut store = HistoryStore::new(&conn, &interruptee);
+                store.set_config(config.clone());
                 sync_multiple(
                     &[&store],
                     disk_cached_state,
                     mem_cached_state,
                     client_init,
                     key_bundle,
+                    &SyncOptions::default(),
                     &interruptee,
                 )
             },
@@ -263,6 +268,37 @@ impl PlacesApi {
                     mem_cached_state,
                     client_init,
                     key_bundle,
+                    &SyncOptions::default(),
+                    &interruptee,
+                )
+            },
+        )
+    }
+
+    /// Syncs the reading list. The `readinglist` engine isn't one of the
+    /// engines in a fresh `meta/global`, so this also enables it.
+    pub fn sync_reading_list(
+        &self,
+        client_init: &sync15::Sync15StorageClientInit,
+        key_bundle: &sync15::KeyBundle,
+    ) -> Result<telemetry::SyncTelemetryPing> {
+        let mut engine_updates = HashMap::new();
+        engine_updates.insert("readinglist".to_string(), true);
+        self.do_sync_one(
+            "readinglist",
+            move |conn, mem_cached_state, disk_cached_state| {
+                let interruptee = conn.begin_interrupt_scope();
+                let store = ReadingListStore::new(&conn, &interruptee);
+                sync_multiple(
+                    &[&store],
+                    disk_cached_state,
+                    mem_cached_state,
+                    client_init,
+                    key_bundle,
+                    &SyncOptions {
+                        engine_updates: Some(&engine_updates),
+                        ..SyncOptions::default()
+                    },
                     &interruptee,
                 )
             },
@@ -350,6 +386,7 @@ impl PlacesApi {
             &mut mem_cached_state,
             client_init,
             key_bundle,
+            &SyncOptions::default(),
             &interruptee,
         );
         // even on failure we set the persisted state - sync itself takes care
@@ -8,6 +8,7 @@ use super::record::{
 };
 use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::error::*;
+use crate::hash;
 use crate::storage::{
     bookmarks::maybe_truncate_title,
     tags::{validate_tag, ValidatedTag},
@@ -15,6 +16,7 @@ use crate::storage::{
 };
 use rusqlite::Connection;
 use sql_support::{self, ConnExt};
+use std::cell::Cell;
 use std::iter;
 use sync15::ServerTimestamp;
 use sync_guid::Guid as SyncGuid;
@@ -28,11 +30,21 @@ const RESULTS_AS_TAG_CONTENTS: &str = "7";
 /// and related tables.
 pub struct IncomingApplicator<'a> {
     db: &'a Connection,
+    converted_livemarks: Cell<usize>,
 }
 
 impl<'a> IncomingApplicator<'a> {
     pub fn new(db: &'a Connection) -> Self {
-        Self { db }
+        Self {
+            db,
+            converted_livemarks: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of incoming livemarks that we've converted into
+    /// folders so far. This is reported in the validation telemetry.
+    pub fn converted_livemarks(&self) -> usize {
+        self.converted_livemarks.get()
     }
 
     pub fn apply_payload(
@@ -311,33 +323,96 @@ impl<'a> IncomingApplicator<'a> {
                 }
             }
         }
-        let feed_url = validate_href(l.feed_url, &l.record_id.as_guid(), "feed");
-        let site_url = validate_href(l.site_url, &l.record_id.as_guid(), "site");
-        let validity = if feed_url.is_some() {
-            SyncedBookmarkValidity::Valid
-        } else {
-            SyncedBookmarkValidity::Replace
-        };
-        self.db.execute_named_cached(
-            "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge, kind,
-                                               dateAdded, title, feedURL, siteURL, validity)
-             VALUES(:guid, :parentGuid, :serverModified, 1, :kind,
-                    :dateAdded, :title, :feedUrl, :siteUrl, :validity)",
-            &[
-                (":guid", &l.record_id.as_guid().as_str()),
-                (
-                    ":parentGuid",
-                    &l.parent_record_id.as_ref().map(BookmarkRecordId::as_guid),
-                ),
-                (":serverModified", &(modified.as_millis() as i64)),
-                (":kind", &SyncedBookmarkKind::Livemark),
-                (":dateAdded", &l.date_added),
-                (":title", &l.title),
-                (":feedUrl", &feed_url),
-                (":siteUrl", &site_url),
-                (":validity", &validity),
-            ],
+        let guid = l.record_id.as_guid();
+        let feed_url = validate_href(l.feed_url, guid, "feed");
+        let site_url = validate_href(l.site_url, guid, "site");
+
+        // Livemarks are no longer supported anywhere, so we convert them into
+        // a plain folder holding a bookmark to the site, and another to the
+        // feed if it's different. The folder keeps the livemark's GUID, and
+        // the children get GUIDs derived from it, so every client that
+        // converts the same livemark ends up with an identical tree.
+        let mut children = Vec::with_capacity(2);
+        if let Some(site_url) = site_url.as_ref() {
+            children.push((livemark_child_guid(guid, "site"), l.title.clone(), site_url));
+        }
+        if let Some(feed_url) = feed_url.as_ref() {
+            if site_url.as_ref() != Some(feed_url) {
+                children.push((livemark_child_guid(guid, "feed"), None, feed_url));
+            }
+        }
+        if children.is_empty() {
+            // There's nothing worth keeping, so let the merger delete it.
+            self.db.execute_named_cached(
+                "REPLACE INTO moz_bookmarks_synced(guid, parentGuid, serverModified, needsMerge,
+                                                   kind, dateAdded, title, validity)
+                 VALUES(:guid, :parentGuid, :serverModified, 1,
+                        :kind, :dateAdded, :title, :validity)",
+                &[
+                    (":guid", &guid.as_str()),
+                    (
+                        ":parentGuid",
+                        &l.parent_record_id.as_ref().map(BookmarkRecordId::as_guid),
+                    ),
+                    (":serverModified", &(modified.as_millis() as i64)),
+                    (":kind", &SyncedBookmarkKind::Livemark),
+                    (":dateAdded", &l.date_added),
+                    (":title", &l.title),
+                    (":validity", &SyncedBookmarkValidity::Replace),
+                ],
+            )?;
+            return Ok(());
+        }
+
+        let mut child_record_ids = Vec::with_capacity(children.len());
+        for (child_guid, title, url) in children {
+            let record_id = BookmarkRecordId::from(child_guid);
+            self.store_incoming_bookmark(
+                modified,
+                BookmarkRecord {
+                    record_id: record_id.clone(),
+                    parent_record_id: Some(l.record_id.clone()),
+                    parent_title: l.title.clone(),
+                    date_added: l.date_added,
+                    has_dupe: false,
+                    title,
+                    url: Some(url.clone()),
+                    keyword: None,
+                    tags: Vec::new(),
+                },
+            )?;
+            child_record_ids.push(record_id);
+        }
+        self.store_incoming_folder(
+            modified,
+            FolderRecord {
+                record_id: l.record_id.clone(),
+                parent_record_id: l.parent_record_id,
+                parent_title: l.parent_title,
+                date_added: l.date_added,
+                has_dupe: l.has_dupe,
+                title: l.title,
+                children: child_record_ids.clone(),
+            },
         )?;
+
+        // The server still has a livemark, so make sure we replace it (and
+        // upload the new children) once we've merged.
+        for record_id in iter::once(&l.record_id).chain(child_record_ids.iter()) {
+            self.db.execute_named_cached(
+                "UPDATE moz_bookmarks_synced SET
+                   validity = :reupload
+                 WHERE guid = :guid AND
+                       validity = :valid",
+                &[
+                    (":guid", &record_id.as_guid().as_str()),
+                    (":reupload", &SyncedBookmarkValidity::Reupload),
+                    (":valid", &SyncedBookmarkValidity::Valid),
+                ],
+            )?;
+        }
+        self.converted_livemarks
+            .set(self.converted_livemarks.get() + 1);
         Ok(())
     }
 
@@ -390,6 +465,23 @@ impl<'a> IncomingApplicator<'a> {
     }
 }
 
+/// Returns a GUID for a bookmark that we create when converting a livemark
+/// into a folder. It's derived from the livemark's GUID and the `kind` of
+/// bookmark ("site" or "feed"), so that it's the same on every client.
+fn livemark_child_guid(livemark_guid: &SyncGuid, kind: &str) -> SyncGuid {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut bits = (u64::from(hash::hash_string(&format!("{}:{}", livemark_guid, kind))) << 32)
+        | u64::from(hash::hash_string(&format!("{}:{}", kind, livemark_guid)));
+    let guid: String = (0..12)
+        .map(|_| {
+            let c = ALPHABET[(bits & 0x3f) as usize] as char;
+            bits = bits.rotate_right(6);
+            c
+        })
+        .collect();
+    guid.into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -642,7 +734,7 @@ mod tests {
 
     #[test]
     fn test_apply_livemark() {
-        // A livemark with missing URLs
+        // A livemark with missing URLs isn't worth converting.
         assert_incoming_creates_mirror_item(
             json!({
                 "id": "livemark1___",
@@ -658,42 +750,104 @@ mod tests {
                 .feed_url(None)
                 .site_url(None),
         );
-        // Valid feed_url but invalid site_url is considered "valid", but the
-        // invalid URL is dropped.
-        assert_incoming_creates_mirror_item(
+
+        let livemark_guid = SyncGuid::from("livemark1___");
+        let site_guid = livemark_child_guid(&livemark_guid, "site");
+        let feed_guid = livemark_child_guid(&livemark_guid, "feed");
+        assert!(site_guid.is_valid_for_places());
+        assert!(feed_guid.is_valid_for_places());
+        assert_ne!(site_guid, feed_guid);
+        assert_eq!(site_guid, livemark_child_guid(&livemark_guid, "site"));
+
+        // Valid feed_url but invalid site_url converts into a folder with
+        // just the feed.
+        let api = new_mem_api();
+        let conn = apply_incoming(
+            &api,
             json!({
                 "id": "livemark1___",
                 "type": "livemark",
                 "parentid": "unfiled",
                 "parentName": "Unfiled Bookmarks",
+                "title": "News",
                 "feedUri": "http://example.com",
                 "siteUri": "foo"
             }),
-            &SyncedBookmarkItem::new()
-                .validity(SyncedBookmarkValidity::Valid)
-                .kind(SyncedBookmarkKind::Livemark)
+        );
+        assert_eq!(
+            SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Folder)
                 .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
+                .title(Some("News"))
                 .needs_merge(true)
-                .feed_url(Some("http://example.com/"))
-                .site_url(None),
+                .children(vec![feed_guid.clone()]),
+            &SyncedBookmarkItem::get(&conn, &livemark_guid)
+                .expect("should work")
+                .expect("item should exist")
         );
+        assert_eq!(
+            SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&livemark_guid))
+                .title(None)
+                .url(Some("http://example.com/"))
+                .needs_merge(true),
+            &SyncedBookmarkItem::get(&conn, &feed_guid)
+                .expect("should work")
+                .expect("item should exist")
+        );
+
         // Everything valid
-        assert_incoming_creates_mirror_item(
-            json!({
-                "id": "livemark1___",
-                "type": "livemark",
-                "parentid": "unfiled",
-                "parentName": "Unfiled Bookmarks",
-                "feedUri": "http://example.com",
-                "siteUri": "http://example.com/something"
-            }),
-            &SyncedBookmarkItem::new()
-                .validity(SyncedBookmarkValidity::Valid)
-                .kind(SyncedBookmarkKind::Livemark)
-                .parent_guid(Some(&BookmarkRootGuid::Unfiled.as_guid()))
-                .needs_merge(true)
-                .feed_url(Some("http://example.com/"))
-                .site_url(Some("http://example.com/something")),
+        let api = new_mem_api();
+        let conn = api.open_sync_connection().expect("should get a connection");
+        let applicator = IncomingApplicator::new(&conn);
+        applicator
+            .apply_payload(
+                Payload::from_json(json!({
+                    "id": "livemark1___",
+                    "type": "livemark",
+                    "parentid": "unfiled",
+                    "parentName": "Unfiled Bookmarks",
+                    "title": "News",
+                    "feedUri": "http://example.com/rss",
+                    "siteUri": "http://example.com/something"
+                }))
+                .unwrap(),
+                ServerTimestamp(0),
+            )
+            .expect("Should apply livemark");
+        assert_eq!(applicator.converted_livemarks(), 1);
+        assert_eq!(
+            SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Folder)
+                .children(vec![site_guid.clone(), feed_guid.clone()]),
+            &SyncedBookmarkItem::get(&conn, &livemark_guid)
+                .expect("should work")
+                .expect("item should exist")
+        );
+        assert_eq!(
+            SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&livemark_guid))
+                .title(Some("News"))
+                .url(Some("http://example.com/something")),
+            &SyncedBookmarkItem::get(&conn, &site_guid)
+                .expect("should work")
+                .expect("item should exist")
+        );
+        assert_eq!(
+            SyncedBookmarkItem::new()
+                .validity(SyncedBookmarkValidity::Reupload)
+                .kind(SyncedBookmarkKind::Bookmark)
+                .parent_guid(Some(&livemark_guid))
+                .url(Some("http://example.com/rss")),
+            &SyncedBookmarkItem::get(&conn, &feed_guid)
+                .expect("should work")
+                .expect("item should exist")
         );
     }
 }
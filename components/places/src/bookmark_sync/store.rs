@@ -67,11 +67,13 @@ impl<'a> BookmarksStore<'a> {
         Self { db, interruptee }
     }
 
+    /// Stages all incoming items, returning the collection timestamp and the
+    /// number of livemarks we converted into folders.
     fn stage_incoming(
         &self,
         inbound: IncomingChangeset,
         incoming_telemetry: &mut telemetry::EngineIncoming,
-    ) -> Result<ServerTimestamp> {
+    ) -> Result<(ServerTimestamp, usize)> {
         let timestamp = inbound.timestamp;
         let mut tx = self.db.begin_transaction()?;
 
@@ -84,7 +86,7 @@ impl<'a> BookmarksStore<'a> {
             self.interruptee.err_if_interrupted()?;
         }
         tx.commit()?;
-        Ok(timestamp)
+        Ok((timestamp, applicator.converted_livemarks()))
     }
 
     fn has_changes(&self) -> Result<bool> {
@@ -573,7 +575,8 @@ impl<'a> Store for BookmarksStore<'a> {
     ) -> result::Result<OutgoingChangeset, failure::Error> {
         // Stage all incoming items.
         let mut incoming_telemetry = telemetry::EngineIncoming::new();
-        let timestamp = self.stage_incoming(inbound, &mut incoming_telemetry)?;
+        let (timestamp, converted_livemarks) =
+            self.stage_incoming(inbound, &mut incoming_telemetry)?;
         telem.incoming(incoming_telemetry);
 
         // write the timestamp now, so if we are interrupted merging or
@@ -583,6 +586,7 @@ impl<'a> Store for BookmarksStore<'a> {
 
         // Merge.
         let mut merger = Merger::with_telemetry(&self, timestamp, telem);
+        merger.set_converted_livemarks(converted_livemarks);
         merger.merge()?;
 
         // Finally, stage outgoing items.
@@ -590,6 +594,20 @@ impl<'a> Store for BookmarksStore<'a> {
         Ok(outgoing)
     }
 
+    fn apply_incoming_dry_run(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        self.db
+            .with_rolled_back_transaction(|| self.apply_incoming(inbound, telem))
+    }
+
+    // We don't implement `shrink_record`, since dropping children from a
+    // folder that's too large would make other devices move or lose them.
+    // Instead, the folder is skipped, and isn't passed to `sync_finished`, so
+    // it stays changed until it's small enough to upload.
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -703,6 +721,9 @@ pub(crate) struct Merger<'a> {
     // turns it on, to avoid accidentally enabling unintentionally.
     external_transaction: bool,
     telem: Option<&'a mut telemetry::Engine>,
+    // The number of incoming livemarks converted into folders while staging,
+    // which we report alongside the other validation problems.
+    converted_livemarks: usize,
 }
 
 impl<'a> Merger<'a> {
@@ -713,6 +734,7 @@ impl<'a> Merger<'a> {
             local_time: Timestamp::now(),
             external_transaction: false,
             telem: None,
+            converted_livemarks: 0,
         }
     }
 
@@ -727,6 +749,7 @@ impl<'a> Merger<'a> {
             local_time: Timestamp::now(),
             external_transaction: false,
             telem: Some(telem),
+            converted_livemarks: 0,
         }
     }
 
@@ -738,6 +761,10 @@ impl<'a> Merger<'a> {
         self.external_transaction = v;
     }
 
+    pub(crate) fn set_converted_livemarks(&mut self, count: usize) {
+        self.converted_livemarks = count;
+    }
+
     pub(crate) fn merge(&mut self) -> Result<()> {
         use dogear::Store;
         if !self.store.has_changes()? {
@@ -750,7 +777,9 @@ impl<'a> Merger<'a> {
 
         // Record telemetry in all cases, even if the merge fails.
         if let Some(ref mut telem) = self.telem {
-            telem.validation(driver.validation.into_inner());
+            let mut validation = driver.validation.into_inner();
+            validation.problem("convertedLivemarks", self.converted_livemarks);
+            telem.validation(validation);
         }
         result
     }
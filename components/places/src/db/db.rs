@@ -7,6 +7,7 @@ use crate::api::places_api::ConnectionType;
 use crate::error::*;
 use rusqlite::Connection;
 use sql_support::{ConnExt, SqlInterruptHandle, SqlInterruptScope};
+use std::cell::Cell;
 use std::ops::Deref;
 use std::path::Path;
 
@@ -22,6 +23,9 @@ pub struct PlacesDb {
     api_id: usize,
     in_memory: bool,
     pub(super) coop_tx_lock: Arc<Mutex<()>>,
+    // Set while a dry-run sync applies incoming records, so that the
+    // transactions it begins nest inside the one it rolls back.
+    pub(super) in_dry_run: Cell<bool>,
 }
 
 impl PlacesDb {
@@ -79,6 +83,7 @@ impl PlacesDb {
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
             coop_tx_lock,
             in_memory,
+            in_dry_run: Cell::new(false),
         };
         match res.conn_type() {
             // For read-only connections, we can avoid opening a transaction,
@@ -188,6 +193,7 @@ fn define_functions(c: &Connection) -> Result<()> {
         sql_fns::strip_prefix_and_userinfo,
     )?;
     c.create_scalar_function("reverse_host", 1, true, sql_fns::reverse_host)?;
+    c.create_scalar_function("lower_unicode", 1, true, sql_fns::lower_unicode)?;
     c.create_scalar_function("autocomplete_match", 10, true, sql_fns::autocomplete_match)?;
     c.create_scalar_function("hash", -1, true, sql_fns::hash)?;
     c.create_scalar_function("now", 0, false, sql_fns::now)?;
@@ -313,6 +319,13 @@ mod sql_fns {
         Ok(rev_host)
     }
 
+    // SQLite's `lower()` only lowercases ASCII characters, so this matches
+    // what `str::to_lowercase` does in Rust.
+    #[inline(never)]
+    pub fn lower_unicode(ctx: &Context<'_>) -> Result<Option<String>> {
+        Ok(get_raw_opt_str(ctx, "lower_unicode", 0)?.map(str::to_lowercase))
+    }
+
     #[inline(never)]
     pub fn get_prefix(ctx: &Context<'_>) -> Result<String> {
         let href = get_raw_str(ctx, "get_prefix", 0)?;
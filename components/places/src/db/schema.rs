@@ -16,7 +16,7 @@ use crate::types::SyncStatus;
 use rusqlite::NO_PARAMS;
 use sql_support::ConnExt;
 
-const VERSION: i64 = 9;
+const VERSION: i64 = 10;
 
 // Shared schema and temp tables for the read-write and Sync connections.
 const CREATE_SHARED_SCHEMA_SQL: &str = include_str!("../../sql/create_shared_schema.sql");
@@ -219,6 +219,7 @@ fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
         ],
         || Ok(()),
     )?;
+    migration(db, 9, 10, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // reading list.
     // Add more migrations here...
 
     if get_current_schema_version(db)? == VERSION {
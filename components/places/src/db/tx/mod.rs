@@ -30,20 +30,69 @@ enum PlacesTransactionRepr<'conn> {
     // Note: these might seem pointless, but can allow us to ensure consistency
     // between separate reads.
     ReadOnly(UncheckedTransaction<'conn>),
+    // Used for transactions begun during a dry run.
+    Nested(NestedTransaction<'conn>),
+}
+
+/// A savepoint inside a dry run's transaction. Committing it only releases
+/// it into the dry run's transaction, which is always rolled back.
+struct NestedTransaction<'conn> {
+    conn: &'conn Connection,
+    finished: bool,
+}
+
+impl<'conn> NestedTransaction<'conn> {
+    fn new(conn: &'conn Connection) -> Result<Self> {
+        conn.execute_batch("SAVEPOINT places_nested_tx")?;
+        Ok(NestedTransaction {
+            conn,
+            finished: false,
+        })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        self.conn.execute_batch("RELEASE places_nested_tx")?;
+        Ok(())
+    }
+
+    fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.conn
+            .execute_batch("ROLLBACK TO places_nested_tx; RELEASE places_nested_tx")?;
+        Ok(())
+    }
+}
+
+impl<'conn> Drop for NestedTransaction<'conn> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self
+                .conn
+                .execute_batch("ROLLBACK TO places_nested_tx; RELEASE places_nested_tx")
+            {
+                log::warn!("Error dropping a nested transaction: {}", e);
+            }
+        }
+    }
 }
 
 impl<'conn> PlacesTransaction<'conn> {
     /// - For transactions on sync connnections: Checks to see if we have held a
     ///   transaction for longer than the requested time, and if so, commits the
     ///   current transaction and opens another.
+    /// - For transactions begun during a dry run: Does nothing, since the dry
+    ///   run's transaction is never committed.
     /// - For transactions on other connections: `debug_assert!`s, or logs a
     ///   warning and does nothing.
     #[inline]
     pub fn maybe_commit(&mut self) -> Result<()> {
-        if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.0 {
-            tx.maybe_commit()?;
-        } else {
-            debug_complaint!("maybe_commit called on a non-chunked transaction");
+        match &mut self.0 {
+            PlacesTransactionRepr::ChunkedWrite(tx) => tx.maybe_commit()?,
+            PlacesTransactionRepr::Nested(_) => {}
+            _ => {
+                debug_complaint!("maybe_commit called on a non-chunked transaction");
+            }
         }
         Ok(())
     }
@@ -54,6 +103,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::ReadOnly(t) => t.commit()?,
+            PlacesTransactionRepr::Nested(t) => t.commit()?,
         };
         Ok(())
     }
@@ -66,6 +116,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::ReadOnly(t) => t.rollback()?,
+            PlacesTransactionRepr::Nested(t) => t.rollback()?,
         };
         Ok(())
     }
@@ -77,7 +128,15 @@ impl super::PlacesDb {
     /// - For Sync connections, begins a chunked coop transaction.
     /// - for ReadWrite connections, begins a normal coop transaction
     /// - for ReadOnly connections, begins an unchecked transaction.
+    ///
+    /// During a dry run, this begins a savepoint in the dry run's transaction
+    /// instead.
     pub fn begin_transaction(&self) -> Result<PlacesTransaction<'_>> {
+        if self.in_dry_run.get() {
+            return Ok(PlacesTransaction(PlacesTransactionRepr::Nested(
+                NestedTransaction::new(self.conn())?,
+            )));
+        }
         Ok(PlacesTransaction(match self.conn_type() {
             ConnectionType::Sync => {
                 PlacesTransactionRepr::ChunkedWrite(self.chunked_coop_trransaction()?)
@@ -91,6 +150,25 @@ impl super::PlacesDb {
             }
         }))
     }
+
+    /// Runs `f` in a transaction that's always rolled back, so that none of
+    /// its changes are kept. Any transactions that `f` begins are savepoints
+    /// in this one. This is used for dry-run syncs, and can't be nested.
+    pub fn with_rolled_back_transaction<T, E>(
+        &self,
+        f: impl FnOnce() -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E>
+    where
+        E: From<Error>,
+    {
+        assert!(!self.in_dry_run.get(), "Dry runs can't be nested");
+        let tx = self.begin_transaction()?;
+        self.in_dry_run.set(true);
+        let result = f();
+        self.in_dry_run.set(false);
+        tx.rollback()?;
+        result
+    }
 }
 
 impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
@@ -101,6 +179,7 @@ impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => &t,
             PlacesTransactionRepr::UnchunkedWrite(t) => &t,
             PlacesTransactionRepr::ReadOnly(t) => &t,
+            PlacesTransactionRepr::Nested(t) => t.conn,
         }
     }
 }
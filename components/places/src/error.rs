@@ -129,6 +129,11 @@ pub enum InvalidPlaceInfo {
 
     #[fail(display = "Cannot update the bookmark root {:?}", _0)]
     CannotUpdateRoot(BookmarkRootGuid),
+
+    // Like Urls and tags, the query itself is private, so we only include
+    // the reason it's invalid.
+    #[fail(display = "Invalid or unsupported place query: {}", _0)]
+    InvalidQuery(&'static str),
 }
 
 // Error types used when we can't continue due to corruption.
@@ -2,10 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::types::Timestamp;
+use crate::types::{Timestamp, VisitTransition, VisitTransitionSet};
 use serde_derive::*;
 use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod plan;
 pub mod record;
@@ -16,6 +16,118 @@ const MAX_OUTGOING_PLACES: usize = 5000;
 const MAX_VISITS: usize = 20;
 pub const HISTORY_TTL: u32 = 5_184_000; // 60 days in milliseconds
 
+/// By default, we backfill records modified within the last 90 days.
+pub const DEFAULT_BACKFILL_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Controls how much history we download and upload when syncing. The
+/// defaults match what we've always done.
+///
+/// The FFI takes this as JSON, with camelCase field names, and the maximum
+/// record and backfill ages in seconds as `maxRecordAgeSecs` and
+/// `backfillMaxAgeSecs`. Missing fields use their defaults.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct HistorySyncConfig {
+    /// The maximum number of visits we keep for each incoming record. If we
+    /// already have this many visits for a page, incoming visits older than
+    /// all of them are ignored.
+    pub max_incoming_visits: usize,
+    /// The maximum number of records we upload on each sync. Any more are
+    /// skipped.
+    pub max_outgoing_records: usize,
+    /// Records whose most recent visit is older than this are neither applied
+    /// nor uploaded. `None` means records of any age are synced.
+    #[serde(
+        rename = "maxRecordAgeSecs",
+        deserialize_with = "deserialize_max_record_age"
+    )]
+    pub max_record_age: Option<Duration>,
+    /// How far back we download records that were modified before our first
+    /// sync. A zero duration disables backfilling. We never backfill records
+    /// older than `max_record_age`, since we wouldn't apply them anyway.
+    #[serde(
+        rename = "backfillMaxAgeSecs",
+        deserialize_with = "deserialize_backfill_max_age"
+    )]
+    pub backfill_max_age: Duration,
+    /// Whether to sync visits with the `Download` transition.
+    pub sync_downloads: bool,
+    /// Whether to sync visits with the `RedirectPermanent` and
+    /// `RedirectTemporary` transitions; that is, visits that came from a
+    /// redirect source.
+    pub sync_redirects: bool,
+}
+
+impl Default for HistorySyncConfig {
+    fn default() -> Self {
+        Self {
+            max_incoming_visits: MAX_VISITS,
+            max_outgoing_records: MAX_OUTGOING_PLACES,
+            max_record_age: None,
+            backfill_max_age: DEFAULT_BACKFILL_MAX_AGE,
+            sync_downloads: true,
+            sync_redirects: true,
+        }
+    }
+}
+
+fn deserialize_max_record_age<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let secs: Option<u64> = serde::de::Deserialize::deserialize(deserializer)?;
+    Ok(secs.map(Duration::from_secs))
+}
+
+fn deserialize_backfill_max_age<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let secs: u64 = serde::de::Deserialize::deserialize(deserializer)?;
+    Ok(Duration::from_secs(secs))
+}
+
+impl HistorySyncConfig {
+    /// Returns the visit transitions we shouldn't sync.
+    pub fn excluded_transitions(&self) -> VisitTransitionSet {
+        let mut excluded = VisitTransitionSet::empty();
+        if !self.sync_downloads {
+            excluded.insert(VisitTransition::Download);
+        }
+        if !self.sync_redirects {
+            excluded.insert(VisitTransition::RedirectPermanent);
+            excluded.insert(VisitTransition::RedirectTemporary);
+        }
+        excluded
+    }
+
+    /// Returns the time before which records are too old to sync, if any.
+    pub fn oldest_allowed(&self) -> Option<Timestamp> {
+        self.max_record_age.map(|age| {
+            let now = Timestamp::now();
+            Timestamp(now.as_millis().saturating_sub(age.as_millis() as u64))
+        })
+    }
+
+    /// Returns the time before which we don't backfill records, or `None` if
+    /// backfilling is disabled. This is the more recent of the backfill and
+    /// record age limits.
+    pub fn backfill_cutoff(&self) -> Option<Timestamp> {
+        if self.backfill_max_age == Duration::default() {
+            return None;
+        }
+        let now = Timestamp::now();
+        let cutoff = Timestamp(
+            now.as_millis()
+                .saturating_sub(self.backfill_max_age.as_millis() as u64),
+        );
+        Some(match self.oldest_allowed() {
+            Some(oldest) if oldest > cutoff => oldest,
+            _ => cutoff,
+        })
+    }
+}
+
 /// Visit timestamps on the server are *microseconds* since the epoch.
 #[derive(
     Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize, Default,
@@ -52,3 +164,59 @@ impl fmt::Display for ServerVisitTimestamp {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_from_json() {
+        let config: HistorySyncConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, HistorySyncConfig::default());
+
+        let config: HistorySyncConfig = serde_json::from_str(
+            r#"{"maxOutgoingRecords": 10, "maxRecordAgeSecs": 60, "syncDownloads": false}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            HistorySyncConfig {
+                max_outgoing_records: 10,
+                max_record_age: Some(Duration::from_secs(60)),
+                sync_downloads: false,
+                ..HistorySyncConfig::default()
+            }
+        );
+
+        let config: HistorySyncConfig =
+            serde_json::from_str(r#"{"backfillMaxAgeSecs": 0}"#).unwrap();
+        assert_eq!(config.backfill_max_age, Duration::default());
+        assert_eq!(config.backfill_cutoff(), None);
+    }
+
+    #[test]
+    fn test_backfill_cutoff() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = Timestamp::now().as_millis();
+
+        let config = HistorySyncConfig::default();
+        let cutoff = config.backfill_cutoff().expect("should backfill");
+        assert!(cutoff.as_millis() <= now - DEFAULT_BACKFILL_MAX_AGE.as_millis() as u64);
+
+        // A shorter record age limits how far back we backfill...
+        let config = HistorySyncConfig {
+            max_record_age: Some(day * 30),
+            ..HistorySyncConfig::default()
+        };
+        let cutoff = config.backfill_cutoff().expect("should backfill");
+        assert!(cutoff.as_millis() > now - (day * 31).as_millis() as u64);
+
+        // ...but a longer one doesn't extend it.
+        let config = HistorySyncConfig {
+            max_record_age: Some(day * 365),
+            ..HistorySyncConfig::default()
+        };
+        let cutoff = config.backfill_cutoff().expect("should backfill");
+        assert!(cutoff.as_millis() < now - (day * 89).as_millis() as u64);
+    }
+}
@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use super::record::{HistoryRecord, HistoryRecordVisit, HistorySyncRecord};
-use super::{HISTORY_TTL, MAX_OUTGOING_PLACES, MAX_VISITS};
+use super::{HistorySyncConfig, HISTORY_TTL, MAX_VISITS};
 use crate::api::history::can_add_url;
 use crate::db::PlacesDb;
 use crate::error::*;
@@ -57,9 +57,16 @@ pub enum IncomingPlan {
     /// subtly different from Skip as we may still need to write metadata to
     /// the local DB for reconciled items.
     Reconciled,
+    /// An entry we're ignoring because of the `HistorySyncConfig` - it's too
+    /// old, or all its visits have excluded transitions.
+    Excluded,
 }
 
-fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usize) -> IncomingPlan {
+fn plan_incoming_record(
+    conn: &PlacesDb,
+    record: HistoryRecord,
+    config: &HistorySyncConfig,
+) -> IncomingPlan {
     let url = match Url::parse(&record.hist_uri) {
         Ok(u) => u,
         Err(e) => return IncomingPlan::Invalid(e.into()),
@@ -77,6 +84,24 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
         }
         Err(e) => return IncomingPlan::Failed(e),
     }
+
+    if let Some(oldest_allowed) = config.oldest_allowed() {
+        let newest_visit = record.visits.iter().map(|v| Timestamp::from(v.date)).max();
+        if newest_visit.map_or(false, |date| date < oldest_allowed) {
+            return IncomingPlan::Excluded;
+        }
+    }
+    let excluded_transitions = config.excluded_transitions();
+    if !record.visits.is_empty()
+        && record.visits.iter().all(|v| {
+            VisitTransition::from_primitive(v.transition)
+                .map_or(false, |t| excluded_transitions.contains(t))
+        })
+    {
+        return IncomingPlan::Excluded;
+    }
+
+    let max_visits = config.max_incoming_visits;
     // Let's get what we know about it, if anything - last 20, like desktop?
     let visit_tuple = match fetch_visits(conn, &url, max_visits) {
         Ok(v) => v,
@@ -132,8 +157,8 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
     let mut to_apply = Vec::with_capacity(record.visits.len());
     for incoming_visit in record.visits {
         let transition = match VisitTransition::from_primitive(incoming_visit.transition) {
-            Some(v) => v,
-            None => continue,
+            Some(v) if !excluded_transitions.contains(v) => v,
+            _ => continue,
         };
         match clamp_visit_date(incoming_visit.date.into()) {
             Ok(timestamp) => {
@@ -173,9 +198,47 @@ fn plan_incoming_record(conn: &PlacesDb, record: HistoryRecord, max_visits: usiz
 pub fn apply_plan(
     db: &PlacesDb,
     inbound: IncomingChangeset,
+    config: &HistorySyncConfig,
     telem: &mut telemetry::EngineIncoming,
+    outgoing_telem: &mut telemetry::EngineOutgoing,
     interruptee: &impl Interruptee,
 ) -> Result<OutgoingChangeset> {
+    let timestamp = inbound.timestamp;
+    apply_incoming_plan(db, inbound, config, telem, interruptee)?;
+    // It might make sense for fetch_outgoing to manage its own
+    // begin_transaction - even though doesn't seem a large bottleneck
+    // at this time, the fact we hold a single transaction for the entire call
+    // really is used only for performance, so it's certainly a candidate.
+    let tx = db.begin_transaction()?;
+    let mut outgoing = OutgoingChangeset::new("history".into(), timestamp);
+    let (mut out_infos, num_skipped) = fetch_outgoing(db, config, MAX_VISITS)?;
+    outgoing_telem.skipped(num_skipped);
+
+    for (guid, out_record) in out_infos.drain() {
+        let payload = match out_record {
+            OutgoingInfo::Record(record) => Payload::from_record(record)?,
+            OutgoingInfo::Tombstone => {
+                Payload::new_tombstone_with_ttl(guid.as_str().to_string(), HISTORY_TTL)
+            }
+        };
+        log::trace!("outgoing {:?}", payload);
+        outgoing.changes.push(payload);
+    }
+    tx.commit()?;
+
+    log::info!("incoming: {}", serde_json::to_string(&telem).unwrap());
+    Ok(outgoing)
+}
+
+/// Applies incoming records without staging anything for upload. This is
+/// used directly when backfilling older records.
+pub fn apply_incoming_plan(
+    db: &PlacesDb,
+    inbound: IncomingChangeset,
+    config: &HistorySyncConfig,
+    telem: &mut telemetry::EngineIncoming,
+    interruptee: &impl Interruptee,
+) -> Result<()> {
     // for a first-cut, let's do this in the most naive way possible...
     let mut plans: Vec<(SyncGuid, IncomingPlan)> = Vec::with_capacity(inbound.changes.len());
     for incoming in inbound.changes {
@@ -191,7 +254,7 @@ pub fn apply_plan(
             }
         };
         let plan = match item.record {
-            Some(record) => plan_incoming_record(db, record, MAX_VISITS),
+            Some(record) => plan_incoming_record(db, record, config),
             None => IncomingPlan::Delete,
         };
         let guid = item.guid.clone();
@@ -200,7 +263,6 @@ pub fn apply_plan(
 
     let mut tx = db.begin_transaction()?;
 
-    let mut outgoing = OutgoingChangeset::new("history".into(), inbound.timestamp);
     for (guid, plan) in plans {
         interruptee.err_if_interrupted()?;
         tx.maybe_commit()?;
@@ -246,31 +308,15 @@ pub fn apply_plan(
                 log::trace!("incoming: reconciled {:?}", guid);
                 apply_synced_reconciliation(&db, &guid)?;
             }
-        };
-    }
-    finish_incoming(&db)?;
-    tx.commit()?;
-    // It might make sense for fetch_outgoing to manage its own
-    // begin_transaction - even though doesn't seem a large bottleneck
-    // at this time, the fact we hold a single transaction for the entire call
-    // really is used only for performance, so it's certainly a candidate.
-    let tx = db.begin_transaction()?;
-    let mut out_infos = fetch_outgoing(db, MAX_OUTGOING_PLACES, MAX_VISITS)?;
-
-    for (guid, out_record) in out_infos.drain() {
-        let payload = match out_record {
-            OutgoingInfo::Record(record) => Payload::from_record(record)?,
-            OutgoingInfo::Tombstone => {
-                Payload::new_tombstone_with_ttl(guid.as_str().to_string(), HISTORY_TTL)
+            IncomingPlan::Excluded => {
+                log::trace!("incoming: excluding item {:?}", guid);
+                telem.skipped(1);
             }
         };
-        log::trace!("outgoing {:?}", payload);
-        outgoing.changes.push(payload);
     }
+    finish_incoming(&db)?;
     tx.commit()?;
-
-    log::info!("incoming: {}", serde_json::to_string(&telem).unwrap());
-    Ok(outgoing)
+    Ok(())
 }
 
 pub fn finish_plan(db: &PlacesDb) -> Result<()> {
@@ -336,6 +382,13 @@ mod tests {
             .expect("should have got values")
     }
 
+    fn config_with_max_visits(max_incoming_visits: usize) -> HistorySyncConfig {
+        HistorySyncConfig {
+            max_incoming_visits,
+            ..HistorySyncConfig::default()
+        }
+    }
+
     #[test]
     fn test_invalid_guid() -> Result<()> {
         let _ = env_logger::try_init();
@@ -349,10 +402,12 @@ mod tests {
             visits: vec![],
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Invalid(_) => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, record, &config_with_max_visits(10)) {
+                IncomingPlan::Invalid(_) => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -369,8 +424,68 @@ mod tests {
             visits: vec![],
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Invalid(_) => true,
+        assert!(
+            match plan_incoming_record(&conn, record, &config_with_max_visits(10)) {
+                IncomingPlan::Invalid(_) => true,
+                _ => false,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_excluded_by_config() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let make_record = |age: Duration, transition: VisitTransition| HistoryRecord {
+            id: "aaaaaaaaaaaa".into(),
+            title: "title".into(),
+            hist_uri: "https://example.com".into(),
+            sortindex: 0,
+            ttl: 100,
+            visits: vec![HistoryRecordVisit {
+                date: (SystemTime::now() - age).into(),
+                transition: transition as u8,
+            }],
+        };
+        let one_day = Duration::from_secs(24 * 60 * 60);
+        let config = HistorySyncConfig {
+            max_record_age: Some(one_day * 30),
+            sync_downloads: false,
+            ..HistorySyncConfig::default()
+        };
+
+        assert!(match plan_incoming_record(
+            &conn,
+            make_record(one_day, VisitTransition::Link),
+            &config
+        ) {
+            IncomingPlan::Apply { .. } => true,
+            _ => false,
+        });
+        assert!(match plan_incoming_record(
+            &conn,
+            make_record(one_day * 60, VisitTransition::Link),
+            &config
+        ) {
+            IncomingPlan::Excluded => true,
+            _ => false,
+        });
+        assert!(match plan_incoming_record(
+            &conn,
+            make_record(one_day, VisitTransition::Download),
+            &config
+        ) {
+            IncomingPlan::Excluded => true,
+            _ => false,
+        });
+        // Redirects are still synced by default.
+        assert!(match plan_incoming_record(
+            &conn,
+            make_record(one_day, VisitTransition::RedirectTemporary),
+            &config
+        ) {
+            IncomingPlan::Apply { .. } => true,
             _ => false,
         });
         Ok(())
@@ -393,10 +508,12 @@ mod tests {
             visits,
         };
 
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Apply { .. } => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, record, &config_with_max_visits(10)) {
+                IncomingPlan::Apply { .. } => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -430,10 +547,12 @@ mod tests {
             visits,
         };
         // We should have reconciled it.
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Reconciled => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, record, &config_with_max_visits(10)) {
+                IncomingPlan::Reconciled => true,
+                _ => false,
+            }
+        );
         Ok(())
     }
 
@@ -462,10 +581,12 @@ mod tests {
         };
         // Even though there are no visits we should record that it will be
         // applied with the guid change.
-        assert!(match plan_incoming_record(&conn, record, 10) {
-            IncomingPlan::Apply { .. } => true,
-            _ => false,
-        });
+        assert!(
+            match plan_incoming_record(&conn, record, &config_with_max_visits(10)) {
+                IncomingPlan::Apply { .. } => true,
+                _ => false,
+            }
+        );
     }
 
     // These "dupe" tests all do the full application of the plan and checks
@@ -509,7 +630,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(
@@ -575,7 +698,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 1, "should have guid1 as outgoing");
@@ -637,7 +762,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(
@@ -673,7 +800,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             result,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 0, "nothing outgoing");
@@ -708,7 +837,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             result,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 0, "should skip the invalid entry");
@@ -730,7 +861,7 @@ mod tests {
             ttl: 100,
             visits,
         };
-        let plan = plan_incoming_record(&db, record, 10);
+        let plan = plan_incoming_record(&db, record, &config_with_max_visits(10));
         // We expect "Reconciled" because after skipping the invalid visit
         // we found nothing to apply.
         assert!(match plan {
@@ -760,7 +891,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             result,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
 
@@ -806,7 +939,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
 
@@ -848,7 +983,9 @@ mod tests {
         apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
 
@@ -894,7 +1031,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
 
@@ -947,7 +1086,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 0, "should be nothing outgoing");
@@ -970,7 +1111,9 @@ mod tests {
         apply_plan(
             &db,
             IncomingChangeset::new("history".to_string(), ServerTimestamp(0i64)),
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         // It should have changed to normal but still have the initial counter.
@@ -989,7 +1132,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             incoming,
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 0, "should be nothing outgoing");
@@ -1011,7 +1156,9 @@ mod tests {
         apply_plan(
             &db,
             IncomingChangeset::new("history".to_string(), ServerTimestamp(0i64)),
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         // It should have changed to normal but still have the initial counter.
@@ -1026,7 +1173,9 @@ mod tests {
         let outgoing = apply_plan(
             &db,
             IncomingChangeset::new("history".to_string(), ServerTimestamp(0i64)),
+            &HistorySyncConfig::default(),
             &mut telemetry::EngineIncoming::new(),
+            &mut telemetry::EngineOutgoing::new(),
             &NeverInterrupts,
         )?;
         assert_eq!(outgoing.changes.len(), 1, "tombstone should be uploaded");
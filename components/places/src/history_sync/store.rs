@@ -9,35 +9,61 @@ use crate::storage::history::history_sync::reset_storage;
 use rusqlite::types::{FromSql, ToSql};
 use rusqlite::Connection;
 use sql_support::SqlInterruptScope;
+use std::cell::Cell;
 use std::ops::Deref;
 use std::result;
 use sync15::telemetry;
 use sync15::{
     extract_v1_state, CollSyncIds, CollectionRequest, IncomingChangeset, OutgoingChangeset,
-    ServerTimestamp, Store, StoreSyncAssociation,
+    Payload, RequestOrder, ServerTimestamp, Store, StoreSyncAssociation,
 };
 use sync_guid::Guid;
 
-use super::plan::{apply_plan, finish_plan};
-use super::MAX_INCOMING_PLACES;
+use super::plan::{apply_incoming_plan, apply_plan, finish_plan};
+use super::{HistorySyncConfig, MAX_INCOMING_PLACES};
 
 const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
 const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
 const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
+// The server timestamp of the oldest record we downloaded on the first sync,
+// which we backfill the records older than. Zero means there's nothing left
+// to backfill.
+const BACKFILL_LOW_WATER_META_KEY: &str = "history_backfill_low_water";
+// The server's `X-Weave-Next-Offset` token for the next page of older
+// records, if we're partway through backfilling them. It's opaque, so we
+// store it as we got it, and send it back unchanged.
+const BACKFILL_NEXT_OFFSET_META_KEY: &str = "history_backfill_next_offset";
+
+// How many older records we fetch in each backfill request, and how many of
+// those requests we make on each sync.
+const MAX_BACKFILL_PLACES: usize = 1000;
+const MAX_BACKFILL_BATCHES_PER_SYNC: usize = 5;
 
 // A HistoryStore is short-lived and constructed each sync by something which
 // owns the connection and ClientInfo.
 pub struct HistoryStore<'a> {
     pub db: &'a PlacesDb,
     interruptee: &'a SqlInterruptScope,
+    config: HistorySyncConfig,
+    backfill_batches: Cell<usize>,
 }
 
 impl<'a> HistoryStore<'a> {
     pub fn new(db: &'a PlacesDb, interruptee: &'a SqlInterruptScope) -> Self {
         assert_eq!(db.conn_type(), ConnectionType::Sync);
-        Self { db, interruptee }
+        Self {
+            db,
+            interruptee,
+            config: HistorySyncConfig::default(),
+            backfill_batches: Cell::new(0),
+        }
+    }
+
+    /// Sets the limits on what we download and upload.
+    pub fn set_config(&mut self, config: HistorySyncConfig) {
+        self.config = config;
     }
 
     fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
@@ -58,10 +84,38 @@ impl<'a> HistoryStore<'a> {
         telem: &mut telemetry::Engine,
     ) -> Result<OutgoingChangeset> {
         let timestamp = inbound.timestamp;
+        if self
+            .get_meta::<i64>(LAST_SYNC_META_KEY)?
+            .unwrap_or_default()
+            == 0
+        {
+            // This is our first sync, which only fetched the newest records,
+            // so remember where we got up to. If we got everything, there's
+            // nothing left to backfill.
+            let low_water = if inbound.changes.len() < MAX_INCOMING_PLACES {
+                0
+            } else {
+                oldest_modified(&inbound)
+            };
+            self.put_backfill_position(low_water, None)?;
+        }
         let outgoing = {
             let mut incoming_telemetry = telemetry::EngineIncoming::new();
-            let result = apply_plan(&self.db, inbound, &mut incoming_telemetry, self.interruptee);
+            let mut outgoing_telemetry = telemetry::EngineOutgoing::new();
+            let result = apply_plan(
+                &self.db,
+                inbound,
+                &self.config,
+                &mut incoming_telemetry,
+                &mut outgoing_telemetry,
+                self.interruptee,
+            );
             telem.incoming(incoming_telemetry);
+            // This only counts the records we skipped; the ones we upload are
+            // recorded after each batch is posted.
+            if outgoing_telemetry.get_skipped() > 0 {
+                telem.outgoing(outgoing_telemetry);
+            }
             result
         }?;
         // write the timestamp now, so if we are interrupted creating outgoing
@@ -89,10 +143,74 @@ impl<'a> HistoryStore<'a> {
         Ok(())
     }
 
+    fn do_get_backfill_request(&self) -> Result<Option<CollectionRequest>> {
+        let cutoff = match self.config.backfill_cutoff() {
+            Some(cutoff) if self.backfill_batches.get() < MAX_BACKFILL_BATCHES_PER_SYNC => {
+                cutoff.as_millis() as i64
+            }
+            // Either backfilling is disabled, or we've done enough for this
+            // sync, and will pick up where we left off next time.
+            _ => return Ok(None),
+        };
+        let low_water = match self.get_meta::<i64>(BACKFILL_LOW_WATER_META_KEY)? {
+            Some(low_water) if low_water > 0 => low_water,
+            _ => return Ok(None),
+        };
+        if low_water <= cutoff {
+            log::debug!("History backfill has reached the maximum age");
+            return Ok(None);
+        }
+        // Some records at the low-water mark may not have been in the first
+        // sync, so we include the mark, and then page through the older
+        // records with the server's token.
+        let next_offset = self.get_meta::<String>(BACKFILL_NEXT_OFFSET_META_KEY)?;
+        Ok(Some(
+            CollectionRequest::new("history")
+                .full()
+                .older_than(ServerTimestamp(low_water + 1))
+                .newer_than(ServerTimestamp(cutoff))
+                .sort_by(RequestOrder::Newest)
+                .limit(MAX_BACKFILL_PLACES)
+                .offset(next_offset),
+        ))
+    }
+
+    fn do_apply_backfill(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<()> {
+        self.backfill_batches.set(self.backfill_batches.get() + 1);
+        let prev_low_water = self
+            .get_meta::<i64>(BACKFILL_LOW_WATER_META_KEY)?
+            .unwrap_or_default();
+        let (low_water, next_offset) = next_backfill_position(prev_low_water, &inbound);
+        apply_incoming_plan(
+            &self.db,
+            inbound,
+            &self.config,
+            incoming_telemetry,
+            self.interruptee,
+        )?;
+        self.put_backfill_position(low_water, next_offset.as_ref())?;
+        Ok(())
+    }
+
+    fn put_backfill_position(&self, low_water: i64, next_offset: Option<&String>) -> Result<()> {
+        self.put_meta(BACKFILL_LOW_WATER_META_KEY, &low_water)?;
+        match next_offset {
+            Some(next_offset) => self.put_meta(BACKFILL_NEXT_OFFSET_META_KEY, next_offset)?,
+            None => self.delete_meta(BACKFILL_NEXT_OFFSET_META_KEY)?,
+        }
+        Ok(())
+    }
+
     fn do_reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
         let tx = self.db.begin_transaction()?;
         reset_storage(self.db)?;
         self.put_meta(LAST_SYNC_META_KEY, &0)?;
+        self.delete_meta(BACKFILL_LOW_WATER_META_KEY)?;
+        self.delete_meta(BACKFILL_NEXT_OFFSET_META_KEY)?;
         match assoc {
             StoreSyncAssociation::Disconnected => {
                 self.delete_meta(GLOBAL_SYNCID_META_KEY)?;
@@ -137,6 +255,40 @@ impl<'a> HistoryStore<'a> {
     }
 }
 
+// Drops the older half of an outgoing record's visits, to make it small
+// enough to upload. Visits are fetched newest first, so we keep the most
+// recent ones.
+fn shrink_record(mut record: Payload) -> Option<Payload> {
+    let visits = record.data.get_mut("visits")?.as_array_mut()?;
+    if visits.len() <= 1 {
+        return None;
+    }
+    let keep = visits.len() / 2;
+    visits.truncate(keep);
+    Some(record)
+}
+
+// Returns the server timestamp of the oldest record in `inbound`.
+fn oldest_modified(inbound: &IncomingChangeset) -> i64 {
+    inbound
+        .changes
+        .iter()
+        .map(|(_, modified)| modified.as_millis())
+        .min()
+        .unwrap_or_default()
+}
+
+/// Returns the backfill position, as a low-water mark and the token for the
+/// next page, after downloading `inbound`, which was fetched below
+/// `low_water`. We keep paging through the same request until the server
+/// doesn't give us a token, which means we got the last page.
+fn next_backfill_position(low_water: i64, inbound: &IncomingChangeset) -> (i64, Option<String>) {
+    match &inbound.next_offset {
+        Some(next_offset) => (low_water, Some(next_offset.clone())),
+        None => (0, None),
+    }
+}
+
 impl<'a> Deref for HistoryStore<'a> {
     type Target = Connection;
     #[inline]
@@ -158,6 +310,26 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(self.do_apply_incoming(inbound, telem)?)
     }
 
+    fn apply_incoming_dry_run(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self
+            .db
+            .with_rolled_back_transaction(|| self.do_apply_incoming(inbound, telem))?)
+    }
+
+    fn shrink_record(&self, record: Payload) -> result::Result<Option<Payload>, failure::Error> {
+        Ok(shrink_record(record))
+    }
+
+    // Each page is uploaded on its own, so we'd rather skip a page we can't
+    // upload than fail the sync.
+    fn requires_atomic_upload(&self) -> bool {
+        false
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -171,10 +343,32 @@ impl<'a> Store for HistoryStore<'a> {
         let since = self
             .get_meta::<i64>(LAST_SYNC_META_KEY)?
             .unwrap_or_default();
-        Ok(CollectionRequest::new("history")
+        let request = CollectionRequest::new("history")
             .full()
             .newer_than(ServerTimestamp(since))
-            .limit(MAX_INCOMING_PLACES))
+            .limit(MAX_INCOMING_PLACES);
+        Ok(if since == 0 {
+            // On our first sync, we want the newest records; older ones are
+            // backfilled later.
+            request.sort_by(RequestOrder::Newest)
+        } else {
+            request
+        })
+    }
+
+    fn get_backfill_request(&self) -> result::Result<Option<CollectionRequest>, failure::Error> {
+        Ok(self.do_get_backfill_request()?)
+    }
+
+    fn apply_backfill(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<(), failure::Error> {
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let result = self.do_apply_backfill(inbound, &mut incoming_telemetry);
+        telem.add_incoming(incoming_telemetry);
+        Ok(result?)
     }
 
     fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
@@ -197,3 +391,173 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_api;
+    use crate::history_sync::DEFAULT_BACKFILL_MAX_AGE;
+    use crate::types::Timestamp;
+    use sql_support::ConnExt;
+    use std::time::Duration;
+
+    fn changeset_with_modified(modified: &[i64]) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new("history".into(), ServerTimestamp(0));
+        for (i, &m) in modified.iter().enumerate() {
+            changeset.changes.push((
+                Payload::new_tombstone(format!("backfill{:04}", i)),
+                ServerTimestamp(m),
+            ));
+        }
+        changeset
+    }
+
+    #[test]
+    fn test_next_backfill_position() {
+        // A page without a token is the last one, so we're done.
+        assert_eq!(
+            next_backfill_position(5000, &changeset_with_modified(&[4000])),
+            (0, None)
+        );
+
+        // Otherwise, we keep the low-water mark, and remember the token for
+        // the next page, whatever it looks like.
+        let mut changeset = changeset_with_modified(&[3000, 4000]);
+        changeset.next_offset = Some("opaque:token".into());
+        assert_eq!(
+            next_backfill_position(5000, &changeset),
+            (5000, Some("opaque:token".into()))
+        );
+    }
+
+    #[test]
+    fn test_shrink_record() {
+        let visits = (0..5)
+            .map(|i| serde_json::json!({ "date": 5000 - i, "type": 1 }))
+            .collect::<Vec<_>>();
+        let record = Payload::from_json(serde_json::json!({
+            "id": "aaaaaaaaaaaa",
+            "histUri": "https://example.com/",
+            "visits": visits,
+        }))
+        .unwrap();
+
+        let record = shrink_record(record).expect("Should drop visits");
+        assert_eq!(
+            record.data["visits"],
+            serde_json::json!([{ "date": 5000, "type": 1 }, { "date": 4999, "type": 1 }])
+        );
+        let record = shrink_record(record).expect("Should drop visits");
+        assert_eq!(record.data["visits"].as_array().unwrap().len(), 1);
+        // We can't drop the last visit.
+        assert!(shrink_record(record).is_none());
+
+        // Or shrink tombstones.
+        assert!(shrink_record(Payload::new_tombstone("bbbbbbbbbbbb".into())).is_none());
+    }
+
+    #[test]
+    fn test_apply_incoming_dry_run() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = HistoryStore::new(&conn, &interruptee);
+
+        let mut inbound = IncomingChangeset::new("history".into(), ServerTimestamp(1000));
+        inbound.changes.push((
+            Payload::from_json(serde_json::json!({
+                "id": "aaaaaaaaaaaa",
+                "histUri": "https://example.com/",
+                "title": "Example",
+                "visits": [{ "date": Timestamp::now().as_millis() * 1000, "type": 1 }],
+            }))
+            .unwrap(),
+            ServerTimestamp(1000),
+        ));
+        let mut telem = telemetry::Engine::new("history");
+        store
+            .apply_incoming_dry_run(inbound, &mut telem)
+            .expect("dry run should work");
+
+        // The visit was applied, but then rolled back.
+        assert_eq!(conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places")?, 0);
+        assert_eq!(store.get_meta::<i64>(LAST_SYNC_META_KEY)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backfill_requests() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let mut store = HistoryStore::new(&conn, &interruptee);
+
+        // Nothing to backfill until we've synced.
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        let now = Timestamp::now().as_millis() as i64;
+        let low_water = now - 24 * 60 * 60 * 1000;
+        store.put_backfill_position(low_water, Some(&"opaque:token".to_owned()))?;
+        let request = store
+            .do_get_backfill_request()?
+            .expect("should want to backfill");
+        assert_eq!(request.older, Some(ServerTimestamp(low_water + 1)));
+        assert_eq!(request.order, Some(RequestOrder::Newest));
+        assert_eq!(request.limit, MAX_BACKFILL_PLACES);
+        assert_eq!(request.offset, Some("opaque:token".into()));
+        assert!(request.newer.expect("should have a cutoff").as_millis() < low_water);
+
+        // Disabling backfill means no more requests.
+        store.set_config(HistorySyncConfig {
+            backfill_max_age: Duration::default(),
+            ..HistorySyncConfig::default()
+        });
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        // As does reaching the maximum age...
+        store.set_config(HistorySyncConfig {
+            backfill_max_age: Duration::from_secs(60 * 60),
+            ..HistorySyncConfig::default()
+        });
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        // ...or the maximum record age, even if the backfill age is longer.
+        store.set_config(HistorySyncConfig {
+            max_record_age: Some(Duration::from_secs(60 * 60)),
+            backfill_max_age: DEFAULT_BACKFILL_MAX_AGE,
+            ..HistorySyncConfig::default()
+        });
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        // Applying the last page finishes the backfill, and counts the
+        // backfilled records as incoming.
+        store.set_config(HistorySyncConfig::default());
+        assert!(store.do_get_backfill_request()?.is_some());
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        store.do_apply_backfill(
+            changeset_with_modified(&[low_water - 1]),
+            &mut incoming_telemetry,
+        )?;
+        assert_eq!(incoming_telemetry.get_applied(), 1);
+        assert_eq!(store.get_meta::<i64>(BACKFILL_LOW_WATER_META_KEY)?, Some(0));
+        assert_eq!(
+            store.get_meta::<String>(BACKFILL_NEXT_OFFSET_META_KEY)?,
+            None
+        );
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        // We only make a few requests on each sync.
+        store.put_backfill_position(low_water, Some(&"opaque:token".to_owned()))?;
+        store.backfill_batches.set(MAX_BACKFILL_BATCHES_PER_SYNC);
+        assert_eq!(store.do_get_backfill_request()?, None);
+
+        // And resetting forgets about it entirely.
+        store.do_reset(&StoreSyncAssociation::Disconnected)?;
+        assert_eq!(store.get_meta::<i64>(BACKFILL_LOW_WATER_META_KEY)?, None);
+        assert_eq!(
+            store.get_meta::<String>(BACKFILL_NEXT_OFFSET_META_KEY)?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -19,6 +19,7 @@ pub mod history_sync;
 pub mod import;
 pub mod match_impl;
 pub mod observation;
+pub mod reading_list_sync;
 pub mod storage;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,33 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::storage::reading_list::ReadingListStatus;
+use crate::types::Timestamp;
+use serde_derive::*;
+use sync_guid::Guid as SyncGuid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadingListRecord {
+    pub id: SyncGuid,
+
+    pub url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub excerpt: Option<String>,
+
+    #[serde(default)]
+    pub status: ReadingListStatus,
+
+    // Both in milliseconds. If a record doesn't have these, we use the server
+    // modified time instead.
+    #[serde(default)]
+    pub date_added: Timestamp,
+
+    #[serde(default)]
+    pub last_modified: Timestamp,
+}
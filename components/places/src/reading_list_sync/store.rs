@@ -0,0 +1,849 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::record::ReadingListRecord;
+use crate::api::places_api::ConnectionType;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::reading_list::{ReadingListItem, ReadingListStatus};
+use crate::storage::{delete_meta, get_meta, put_meta};
+use crate::types::{SyncStatus, Timestamp};
+use rusqlite::{Row, NO_PARAMS};
+use sql_support::{self, ConnExt, SqlInterruptScope};
+use std::result;
+use sync15::telemetry;
+use sync15::{
+    CollSyncIds, CollectionRequest, IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp,
+    Store, StoreSyncAssociation,
+};
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+pub const LAST_SYNC_META_KEY: &str = "reading_list_last_sync_time";
+// Note that all engines in this crate should use a *different* meta key
+// for the global sync ID, because engines are reset individually.
+const GLOBAL_SYNCID_META_KEY: &str = "reading_list_global_sync_id";
+const COLLECTION_SYNCID_META_KEY: &str = "reading_list_sync_id";
+
+/// A local reading list item, along with its sync metadata.
+struct LocalItem {
+    item: ReadingListItem,
+    sync_status: SyncStatus,
+    sync_change_counter: u32,
+}
+
+impl LocalItem {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            item: ReadingListItem::from_row(row)?,
+            sync_status: SyncStatus::from_u8(row.get("syncStatus")?),
+            sync_change_counter: row.get("syncChangeCounter")?,
+        })
+    }
+}
+
+/// An incoming item or tombstone, staged in `moz_reading_list_synced`.
+struct RemoteItem {
+    guid: SyncGuid,
+    is_deleted: bool,
+    url: Option<String>,
+    title: Option<String>,
+    excerpt: Option<String>,
+    status: ReadingListStatus,
+    date_added: Timestamp,
+    last_modified: Timestamp,
+}
+
+impl RemoteItem {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            guid: row.get("guid")?,
+            is_deleted: row.get("isDeleted")?,
+            url: row.get("url")?,
+            title: row.get("title")?,
+            excerpt: row.get("excerpt")?,
+            status: row.get("status")?,
+            date_added: row.get("dateAdded")?,
+            last_modified: row.get("lastModified")?,
+        })
+    }
+}
+
+/// What we did with an incoming item.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MergeResult {
+    /// We took the remote item, and don't need to upload anything.
+    TookRemote,
+    /// We kept the local item or tombstone, which we'll upload.
+    KeptLocal,
+}
+
+// Like the other stores, a ReadingListStore is short-lived and constructed
+// each sync by something which owns the connection.
+pub struct ReadingListStore<'a> {
+    pub db: &'a PlacesDb,
+    interruptee: &'a SqlInterruptScope,
+}
+
+impl<'a> ReadingListStore<'a> {
+    pub fn new(db: &'a PlacesDb, interruptee: &'a SqlInterruptScope) -> Self {
+        assert_eq!(db.conn_type(), ConnectionType::Sync);
+        Self { db, interruptee }
+    }
+
+    /// Writes all incoming records and tombstones to the mirror, flagged for
+    /// merging.
+    fn stage_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<()> {
+        let mut tx = self.db.begin_transaction()?;
+        for (payload, modified) in inbound.changes {
+            self.interruptee.err_if_interrupted()?;
+            if payload.is_tombstone() {
+                self.db.execute_named_cached(
+                    "REPLACE INTO moz_reading_list_synced(guid, serverModified,
+                                                          needsMerge, isDeleted)
+                     VALUES(:guid, :modified, 1, 1)",
+                    &[(":guid", &payload.id), (":modified", &modified.as_millis())],
+                )?;
+                tx.maybe_commit()?;
+                continue;
+            }
+            let record: ReadingListRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Failed to deserialize reading list record: {}", e);
+                    incoming_telemetry.failed(1);
+                    continue;
+                }
+            };
+            if let Err(e) = Url::parse(&record.url) {
+                log::warn!("Ignoring reading list record with invalid URL: {}", e);
+                incoming_telemetry.failed(1);
+                continue;
+            }
+            let server_modified = Timestamp(modified.as_millis().max(0) as u64);
+            let or_server_modified = |t: Timestamp| {
+                if t == Timestamp::default() {
+                    server_modified
+                } else {
+                    t
+                }
+            };
+            self.db.execute_named_cached(
+                "REPLACE INTO moz_reading_list_synced(guid, serverModified,
+                                                      needsMerge, isDeleted,
+                                                      url, title, excerpt,
+                                                      status, dateAdded,
+                                                      lastModified)
+                 VALUES(:guid, :modified, 1, 0, :url, :title, :excerpt,
+                        :status, :date_added, :last_modified)",
+                &[
+                    (":guid", &record.id),
+                    (":modified", &modified.as_millis()),
+                    (":url", &record.url),
+                    (":title", &record.title),
+                    (":excerpt", &record.excerpt),
+                    (":status", &record.status),
+                    (":date_added", &or_server_modified(record.date_added)),
+                    (":last_modified", &or_server_modified(record.last_modified)),
+                ],
+            )?;
+            tx.maybe_commit()?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Merges all staged items into `moz_reading_list`.
+    fn merge(&self, incoming_telemetry: &mut telemetry::EngineIncoming) -> Result<()> {
+        let tx = self.db.begin_transaction()?;
+        let remote_items: Vec<RemoteItem> = self.db.query_rows_and_then_named(
+            "SELECT guid, isDeleted, url, title, excerpt, status, dateAdded,
+                    lastModified
+             FROM moz_reading_list_synced
+             WHERE needsMerge",
+            &[],
+            RemoteItem::from_row,
+        )?;
+        for remote in remote_items {
+            self.interruptee.err_if_interrupted()?;
+            let result = if remote.is_deleted {
+                self.apply_remote_deletion(&remote.guid)?
+            } else {
+                self.merge_item(&remote)?
+            };
+            match result {
+                MergeResult::TookRemote => incoming_telemetry.applied(1),
+                MergeResult::KeptLocal => incoming_telemetry.reconciled(1),
+            };
+            self.db.execute_named_cached(
+                "UPDATE moz_reading_list_synced SET needsMerge = 0 WHERE guid = :guid",
+                &[(":guid", &remote.guid)],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn apply_remote_deletion(&self, guid: &SyncGuid) -> Result<MergeResult> {
+        // Deletions always win, even if the item changed locally. We don't
+        // need a tombstone, since the server already has one.
+        self.db.execute_named_cached(
+            "DELETE FROM moz_reading_list WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        self.db.execute_named_cached(
+            "DELETE FROM moz_reading_list_deleted WHERE guid = :guid",
+            &[(":guid", guid)],
+        )?;
+        Ok(MergeResult::TookRemote)
+    }
+
+    fn merge_item(&self, remote: &RemoteItem) -> Result<MergeResult> {
+        let has_tombstone = self.db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM moz_reading_list_deleted
+                           WHERE guid = :guid)",
+            &[(":guid", &remote.guid)],
+            |row| row.get::<_, bool>(0),
+        )?;
+        if has_tombstone {
+            // We deleted the item locally, so we'll upload our tombstone.
+            return Ok(MergeResult::KeptLocal);
+        }
+        let url = match &remote.url {
+            Some(url) => Url::parse(url)?,
+            None => return Err(InvalidPlaceInfo::NoUrl.into()),
+        };
+        let local = match self.fetch_local_item(&remote.guid)? {
+            Some(local) => Some(local),
+            None => self.dedupe_local_item(&remote.guid, &url)?,
+        };
+        match local {
+            None => {
+                self.db.execute_named_cached(
+                    "INSERT INTO moz_reading_list(guid, url, title, excerpt,
+                                                  status, dateAdded,
+                                                  lastModified, syncStatus,
+                                                  syncChangeCounter)
+                     VALUES(:guid, :url, :title, :excerpt, :status,
+                            :date_added, :last_modified, :sync_status, 0)",
+                    &[
+                        (":guid", &remote.guid),
+                        (":url", &url.as_str()),
+                        (":title", &remote.title),
+                        (":excerpt", &remote.excerpt),
+                        (":status", &remote.status),
+                        (":date_added", &remote.date_added),
+                        (":last_modified", &remote.last_modified),
+                        (":sync_status", &SyncStatus::Normal),
+                    ],
+                )?;
+                Ok(MergeResult::TookRemote)
+            }
+            Some(ref local)
+                if local.sync_change_counter == 0
+                    || remote.last_modified >= local.item.last_modified =>
+            {
+                // The item either didn't change locally, or changed on both
+                // sides and the remote change is newer. The URL identifies
+                // the item, so we never change it.
+                self.db.execute_named_cached(
+                    "UPDATE moz_reading_list SET
+                       title = :title,
+                       excerpt = :excerpt,
+                       status = :status,
+                       dateAdded = MIN(dateAdded, :date_added),
+                       lastModified = :last_modified,
+                       syncStatus = :sync_status,
+                       syncChangeCounter = 0
+                     WHERE guid = :guid",
+                    &[
+                        (":guid", &remote.guid),
+                        (":title", &remote.title),
+                        (":excerpt", &remote.excerpt),
+                        (":status", &remote.status),
+                        (":date_added", &remote.date_added),
+                        (":last_modified", &remote.last_modified),
+                        (":sync_status", &SyncStatus::Normal),
+                    ],
+                )?;
+                Ok(MergeResult::TookRemote)
+            }
+            Some(_) => {
+                // The local change is newer, so we'll upload it. The item
+                // exists on the server now, though, so deleting it later
+                // needs a tombstone.
+                self.db.execute_named_cached(
+                    "UPDATE moz_reading_list SET
+                       syncStatus = :sync_status,
+                       syncChangeCounter = MAX(syncChangeCounter, 1)
+                     WHERE guid = :guid",
+                    &[
+                        (":guid", &remote.guid),
+                        (":sync_status", &SyncStatus::Normal),
+                    ],
+                )?;
+                Ok(MergeResult::KeptLocal)
+            }
+        }
+    }
+
+    fn fetch_local_item(&self, guid: &SyncGuid) -> Result<Option<LocalItem>> {
+        Ok(self.db.try_query_row(
+            "SELECT guid, url, title, excerpt, status, dateAdded, lastModified,
+                    syncStatus, syncChangeCounter
+             FROM moz_reading_list
+             WHERE guid = :guid",
+            &[(":guid", guid)],
+            LocalItem::from_row,
+            true,
+        )?)
+    }
+
+    /// Looks for a local item with the same URL as an incoming item, but a
+    /// different GUID. If there is one, we change its GUID to match the
+    /// incoming item, so that we don't end up with duplicates.
+    fn dedupe_local_item(&self, remote_guid: &SyncGuid, url: &Url) -> Result<Option<LocalItem>> {
+        let local = self.db.try_query_row(
+            "SELECT guid, url, title, excerpt, status, dateAdded, lastModified,
+                    syncStatus, syncChangeCounter
+             FROM moz_reading_list
+             WHERE url = :url",
+            &[(":url", &url.as_str())],
+            LocalItem::from_row,
+            true,
+        )?;
+        let mut local: LocalItem = match local {
+            Some(local) => local,
+            None => return Ok(None),
+        };
+        log::debug!(
+            "Deduping local reading list item {} to {}",
+            local.item.guid,
+            remote_guid
+        );
+        if local.sync_status == SyncStatus::Normal {
+            // The old item is on the server, too, so we need to delete it
+            // there.
+            self.db.execute_named_cached(
+                "REPLACE INTO moz_reading_list_deleted(guid, dateRemoved)
+                 VALUES(:guid, :now)",
+                &[(":guid", &local.item.guid), (":now", &Timestamp::now())],
+            )?;
+        }
+        self.db.execute_named_cached(
+            "UPDATE moz_reading_list SET guid = :new_guid WHERE guid = :old_guid",
+            &[(":new_guid", remote_guid), (":old_guid", &local.item.guid)],
+        )?;
+        local.item.guid = remote_guid.clone();
+        Ok(Some(local))
+    }
+
+    /// Stages all changed items and tombstones for upload, and returns the
+    /// outgoing records.
+    fn fetch_outgoing_records(&self, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+        let tx = self.db.begin_transaction()?;
+        self.db.execute_batch(&format!(
+            "DELETE FROM readingListItemsToUpload;
+
+             INSERT INTO readingListItemsToUpload(guid, syncChangeCounter)
+             SELECT guid, syncChangeCounter FROM moz_reading_list
+             WHERE syncChangeCounter > 0 OR syncStatus <> {sync_status};
+
+             INSERT OR IGNORE INTO readingListItemsToUpload(guid,
+                                                            syncChangeCounter,
+                                                            isDeleted)
+             SELECT guid, 0, 1 FROM moz_reading_list_deleted;",
+            sync_status = SyncStatus::Normal as u8
+        ))?;
+        tx.commit()?;
+
+        let mut outgoing = OutgoingChangeset::new(self.collection_name().into(), timestamp);
+        let mut stmt = self.db.prepare(
+            "SELECT u.guid, u.isDeleted, r.url, r.title, r.excerpt, r.status,
+                    r.dateAdded, r.lastModified
+             FROM readingListItemsToUpload u
+             LEFT JOIN moz_reading_list r ON r.guid = u.guid",
+        )?;
+        let mut results = stmt.query(NO_PARAMS)?;
+        while let Some(row) = results.next()? {
+            self.interruptee.err_if_interrupted()?;
+            let guid = row.get::<_, SyncGuid>("guid")?;
+            if row.get::<_, bool>("isDeleted")? {
+                outgoing
+                    .changes
+                    .push(Payload::new_tombstone(guid.into_string()));
+                continue;
+            }
+            let record = ReadingListRecord {
+                id: guid,
+                url: row.get("url")?,
+                title: row.get("title")?,
+                excerpt: row.get("excerpt")?,
+                status: row.get("status")?,
+                date_added: row.get("dateAdded")?,
+                last_modified: row.get("lastModified")?,
+            };
+            outgoing.changes.push(Payload::from_record(record)?);
+        }
+        Ok(outgoing)
+    }
+
+    /// Clears the change counters for uploaded items, removes uploaded
+    /// tombstones, and updates the mirror to match the server.
+    fn push_synced_items(
+        &self,
+        uploaded_at: ServerTimestamp,
+        records_synced: Vec<SyncGuid>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin_transaction()?;
+        sql_support::each_chunk(&records_synced, |chunk, _| -> Result<()> {
+            self.db.execute(
+                &format!(
+                    "UPDATE readingListItemsToUpload SET
+                         uploadedAt = {uploaded_at}
+                         WHERE guid IN ({values})",
+                    uploaded_at = uploaded_at.as_millis(),
+                    values = sql_support::repeat_sql_values(chunk.len())
+                ),
+                chunk,
+            )?;
+            tx.maybe_commit()?;
+            self.interruptee.err_if_interrupted()?;
+            Ok(())
+        })?;
+
+        self.db.execute_batch(&format!(
+            "UPDATE moz_reading_list SET
+               syncChangeCounter = MAX(syncChangeCounter - (
+                 SELECT u.syncChangeCounter FROM readingListItemsToUpload u
+                 WHERE u.guid = moz_reading_list.guid
+               ), 0),
+               syncStatus = {sync_status}
+             WHERE guid IN (SELECT guid FROM readingListItemsToUpload
+                            WHERE uploadedAt >= 0 AND NOT isDeleted);
+
+             REPLACE INTO moz_reading_list_synced(guid, serverModified,
+                                                  needsMerge, isDeleted, url,
+                                                  title, excerpt, status,
+                                                  dateAdded, lastModified)
+             SELECT u.guid, u.uploadedAt, 0, u.isDeleted, r.url, r.title,
+                    r.excerpt, IFNULL(r.status, 0), IFNULL(r.dateAdded, 0),
+                    IFNULL(r.lastModified, 0)
+             FROM readingListItemsToUpload u
+             LEFT JOIN moz_reading_list r ON r.guid = u.guid
+             WHERE u.uploadedAt >= 0 AND
+                   (u.isDeleted OR r.guid NOT NULL);
+
+             DELETE FROM moz_reading_list_deleted
+             WHERE guid IN (SELECT guid FROM readingListItemsToUpload
+                            WHERE uploadedAt >= 0 AND isDeleted);
+
+             DELETE FROM readingListItemsToUpload;",
+            sync_status = SyncStatus::Normal as u8
+        ))?;
+
+        // Fast-forward the last sync time, so that we don't download the
+        // records we just uploaded on the next sync.
+        put_meta(
+            self.db,
+            LAST_SYNC_META_KEY,
+            &(uploaded_at.as_millis() as i64),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn do_reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
+        let tx = self.db.begin_transaction()?;
+        self.db.execute_batch(&format!(
+            "DELETE FROM moz_reading_list_synced;
+
+             DELETE FROM moz_reading_list_deleted;
+
+             UPDATE moz_reading_list
+             SET syncChangeCounter = 1,
+                 syncStatus = {}",
+            (SyncStatus::New as u8)
+        ))?;
+        put_meta(self.db, LAST_SYNC_META_KEY, &0)?;
+        match assoc {
+            StoreSyncAssociation::Disconnected => {
+                delete_meta(self.db, GLOBAL_SYNCID_META_KEY)?;
+                delete_meta(self.db, COLLECTION_SYNCID_META_KEY)?;
+            }
+            StoreSyncAssociation::Connected(ids) => {
+                put_meta(self.db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
+                put_meta(self.db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+            }
+        };
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl<'a> Store for ReadingListStore<'a> {
+    #[inline]
+    fn collection_name(&self) -> &'static str {
+        "readinglist"
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        let timestamp = inbound.timestamp;
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        self.stage_incoming(inbound, &mut incoming_telemetry)?;
+
+        // write the timestamp now, so if we are interrupted merging or
+        // creating outgoing changesets we don't need to re-download the same
+        // records.
+        put_meta(self.db, LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+
+        self.merge(&mut incoming_telemetry)?;
+        telem.incoming(incoming_telemetry);
+
+        Ok(self.fetch_outgoing_records(timestamp)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<SyncGuid>,
+    ) -> result::Result<(), failure::Error> {
+        self.push_synced_items(new_timestamp, records_synced)?;
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        let since = get_meta::<i64>(self.db, LAST_SYNC_META_KEY)?.unwrap_or_default();
+        Ok(CollectionRequest::new(self.collection_name())
+            .full()
+            .newer_than(ServerTimestamp(since)))
+    }
+
+    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+        let global = get_meta(self.db, GLOBAL_SYNCID_META_KEY)?;
+        let coll = get_meta(self.db, COLLECTION_SYNCID_META_KEY)?;
+        Ok(if let (Some(global), Some(coll)) = (global, coll) {
+            StoreSyncAssociation::Connected(CollSyncIds { global, coll })
+        } else {
+            StoreSyncAssociation::Disconnected
+        })
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+        self.do_reset(assoc)?;
+        Ok(())
+    }
+
+    /// Erases all local items, writing tombstones for the ones we've synced
+    /// before, so that they're deleted from the server on the next sync.
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        let tx = self.db.begin_transaction()?;
+        self.db.execute_batch(&format!(
+            "INSERT OR REPLACE INTO moz_reading_list_deleted(guid, dateRemoved)
+             SELECT guid, now()
+             FROM moz_reading_list
+             WHERE syncStatus = {sync_status};
+
+             DELETE FROM moz_reading_list;",
+            sync_status = SyncStatus::Normal as u8
+        ))?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_api;
+    use crate::storage::reading_list::{add_item, delete_item, get_item, get_items, set_status};
+    use serde_json::json;
+
+    fn apply_incoming(
+        store: &ReadingListStore<'_>,
+        modified: ServerTimestamp,
+        records: Vec<serde_json::Value>,
+    ) -> OutgoingChangeset {
+        let mut incoming = IncomingChangeset::new(store.collection_name().into(), modified);
+        for record in records {
+            let payload = Payload::from_json(record).expect("should be a payload");
+            incoming.changes.push((payload, modified));
+        }
+        let mut telem = telemetry::Engine::new(store.collection_name());
+        store
+            .apply_incoming(incoming, &mut telem)
+            .expect("should apply incoming records")
+    }
+
+    fn outgoing_ids(outgoing: &OutgoingChangeset) -> Vec<String> {
+        let mut ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.to_string())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn count_rows(db: &PlacesDb, table: &str) -> u32 {
+        db.query_one(&format!("SELECT COUNT(*) FROM {}", table))
+            .expect("should count rows")
+    }
+
+    #[test]
+    fn test_first_sync() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+
+        let local_guid = add_item(
+            &conn,
+            &Url::parse("https://example.com/local")?,
+            Some("Local"),
+            None,
+        )?;
+        let outgoing = apply_incoming(
+            &store,
+            ServerTimestamp(10_000),
+            vec![json!({
+                "id": "remoteaaaaaa",
+                "url": "https://example.com/remote",
+                "title": "Remote",
+                "excerpt": "Read me",
+                "status": "read",
+                "dateAdded": 1000,
+                "lastModified": 2000,
+            })],
+        );
+        assert_eq!(outgoing_ids(&outgoing), vec![local_guid.to_string()]);
+
+        let remote = get_item(&conn, &SyncGuid::new("remoteaaaaaa"))?.expect("should exist");
+        assert_eq!(remote.title, Some("Remote".to_string()));
+        assert_eq!(remote.excerpt, Some("Read me".to_string()));
+        assert_eq!(remote.status, ReadingListStatus::Read);
+        assert_eq!(remote.date_added, Timestamp(1000));
+
+        store
+            .sync_finished(ServerTimestamp(11_000), vec![local_guid.clone()])
+            .expect("should finish sync");
+        let (counter, status): (u32, u8) = conn.query_row_named(
+            "SELECT syncChangeCounter, syncStatus FROM moz_reading_list
+             WHERE guid = :guid",
+            &[(":guid", &local_guid)],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!(counter, 0);
+        assert_eq!(status, SyncStatus::Normal as u8);
+        assert_eq!(count_rows(&conn, "moz_reading_list_synced"), 2);
+        assert_eq!(
+            get_meta::<i64>(&conn, LAST_SYNC_META_KEY)?,
+            Some(11_000),
+            "should fast-forward the last sync time"
+        );
+
+        // Nothing changed, so there's nothing to upload.
+        let outgoing = apply_incoming(&store, ServerTimestamp(11_000), vec![]);
+        assert!(outgoing.changes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicts() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+
+        apply_incoming(
+            &store,
+            ServerTimestamp(10_000),
+            vec![
+                json!({
+                    "id": "olderaaaaaaa",
+                    "url": "https://example.com/a",
+                    "status": "unread",
+                    "dateAdded": 1000,
+                    "lastModified": 1000,
+                }),
+                json!({
+                    "id": "neweraaaaaaa",
+                    "url": "https://example.com/b",
+                    "status": "unread",
+                    "dateAdded": 1000,
+                    "lastModified": 1000,
+                }),
+            ],
+        );
+        store
+            .sync_finished(ServerTimestamp(10_000), vec![])
+            .expect("should finish sync");
+
+        // Change both items locally.
+        let older_guid = SyncGuid::new("olderaaaaaaa");
+        let newer_guid = SyncGuid::new("neweraaaaaaa");
+        set_status(&conn, &older_guid, ReadingListStatus::Read)?;
+        set_status(&conn, &newer_guid, ReadingListStatus::Read)?;
+
+        // The remote change to the first item is older than ours, so we keep
+        // our change. The second is newer, so we take it.
+        let now = Timestamp::now().as_millis();
+        let outgoing = apply_incoming(
+            &store,
+            ServerTimestamp(20_000),
+            vec![
+                json!({
+                    "id": "olderaaaaaaa",
+                    "url": "https://example.com/a",
+                    "status": "archived",
+                    "dateAdded": 1000,
+                    "lastModified": 1500,
+                }),
+                json!({
+                    "id": "neweraaaaaaa",
+                    "url": "https://example.com/b",
+                    "status": "archived",
+                    "dateAdded": 1000,
+                    "lastModified": now + 60_000,
+                }),
+            ],
+        );
+        assert_eq!(outgoing_ids(&outgoing), vec!["olderaaaaaaa".to_string()]);
+        assert_eq!(
+            get_item(&conn, &older_guid)?.expect("should exist").status,
+            ReadingListStatus::Read
+        );
+        assert_eq!(
+            get_item(&conn, &newer_guid)?.expect("should exist").status,
+            ReadingListStatus::Archived
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+
+        apply_incoming(
+            &store,
+            ServerTimestamp(10_000),
+            vec![
+                json!({
+                    "id": "deletelocal1",
+                    "url": "https://example.com/a",
+                }),
+                json!({
+                    "id": "deleteremote",
+                    "url": "https://example.com/b",
+                }),
+            ],
+        );
+        store
+            .sync_finished(ServerTimestamp(10_000), vec![])
+            .expect("should finish sync");
+
+        // Deleting a synced item locally uploads a tombstone.
+        assert!(delete_item(&conn, &SyncGuid::new("deletelocal1"))?);
+        let outgoing = apply_incoming(
+            &store,
+            ServerTimestamp(20_000),
+            vec![json!({
+                "id": "deleteremote",
+                "deleted": true,
+            })],
+        );
+        assert_eq!(outgoing_ids(&outgoing), vec!["deletelocal1".to_string()]);
+        assert!(outgoing.changes[0].is_tombstone());
+        assert!(get_items(&conn, None)?.is_empty());
+
+        store
+            .sync_finished(ServerTimestamp(21_000), vec![SyncGuid::new("deletelocal1")])
+            .expect("should finish sync");
+        assert_eq!(count_rows(&conn, "moz_reading_list_deleted"), 0);
+        let deleted: u32 =
+            conn.query_one("SELECT COUNT(*) FROM moz_reading_list_synced WHERE isDeleted")?;
+        assert_eq!(deleted, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+
+        let url = Url::parse("https://example.com/dupe")?;
+        let local_guid = add_item(&conn, &url, Some("Local"), None)?;
+        let outgoing = apply_incoming(
+            &store,
+            ServerTimestamp(10_000),
+            vec![json!({
+                "id": "dupeaaaaaaaa",
+                "url": url.as_str(),
+                "title": "Remote",
+                "lastModified": 1000,
+            })],
+        );
+
+        // We never uploaded the local item, so we don't need a tombstone for
+        // its old GUID. Our local change is newer, so we upload it with the
+        // remote GUID.
+        assert_eq!(outgoing_ids(&outgoing), vec!["dupeaaaaaaaa".to_string()]);
+        assert_eq!(count_rows(&conn, "moz_reading_list_deleted"), 0);
+        assert_eq!(get_item(&conn, &local_guid)?, None);
+        let items = get_items(&conn, None)?;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].guid, SyncGuid::new("dupeaaaaaaaa"));
+        assert_eq!(items[0].title, Some("Local".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_and_wipe() -> Result<()> {
+        let api = new_mem_api();
+        let conn = api.open_sync_connection()?;
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+
+        apply_incoming(
+            &store,
+            ServerTimestamp(10_000),
+            vec![json!({
+                "id": "remoteaaaaaa",
+                "url": "https://example.com/remote",
+            })],
+        );
+        store
+            .sync_finished(ServerTimestamp(10_000), vec![])
+            .expect("should finish sync");
+
+        store
+            .reset(&StoreSyncAssociation::Disconnected)
+            .expect("should reset");
+        assert_eq!(count_rows(&conn, "moz_reading_list_synced"), 0);
+        assert_eq!(get_meta::<i64>(&conn, LAST_SYNC_META_KEY)?, Some(0));
+        let outgoing = apply_incoming(&store, ServerTimestamp(20_000), vec![]);
+        assert_eq!(outgoing_ids(&outgoing), vec!["remoteaaaaaa".to_string()]);
+        store
+            .sync_finished(ServerTimestamp(20_000), vec![SyncGuid::new("remoteaaaaaa")])
+            .expect("should finish sync");
+
+        store.wipe().expect("should wipe");
+        assert!(get_items(&conn, None)?.is_empty());
+        assert_eq!(count_rows(&conn, "moz_reading_list_deleted"), 1);
+        Ok(())
+    }
+}
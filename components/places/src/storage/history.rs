@@ -528,7 +528,7 @@ fn cleanup_pages(db: &PlacesDb, pages: &[PageToClean]) -> Result<()> {
 pub mod history_sync {
     use super::*;
     use crate::history_sync::record::{HistoryRecord, HistoryRecordVisit};
-    use crate::history_sync::HISTORY_TTL;
+    use crate::history_sync::{HistorySyncConfig, HISTORY_TTL};
     use std::collections::{HashMap, HashSet};
 
     #[derive(Debug, Clone, PartialEq)]
@@ -769,11 +769,26 @@ pub mod history_sync {
         Tombstone,
     }
 
+    /// Fetches the records we should upload, limited by `config`. Also
+    /// returns the number of changed pages that we skipped because they're
+    /// too old, because all their visits are excluded, or because there were
+    /// too many.
     pub fn fetch_outgoing(
         db: &PlacesDb,
-        max_places: usize,
+        config: &HistorySyncConfig,
         max_visits: usize,
-    ) -> Result<HashMap<SyncGuid, OutgoingInfo>> {
+    ) -> Result<(HashMap<SyncGuid, OutgoingInfo>, usize)> {
+        let max_places = config.max_outgoing_records;
+        let oldest_allowed = config.oldest_allowed();
+        // Pages without any visits aren't uploaded anyway, so we don't count
+        // them as too old.
+        let too_old = "(:oldest_allowed NOT NULL AND
+                        MAX(last_visit_date_local, last_visit_date_remote) > 0 AND
+                        MAX(last_visit_date_local, last_visit_date_remote) < :oldest_allowed)";
+        let changed = format!(
+            "(sync_change_counter > 0 OR sync_status != {})",
+            (SyncStatus::Normal as u8)
+        );
         // Note that we want *all* "new" regardless of change counter,
         // so that we do the right thing after a "reset".
         let places_sql = format!(
@@ -783,19 +798,22 @@ pub mod history_sync {
                 last_visit_date_local, last_visit_date_remote,
                 sync_status, sync_change_counter
             FROM moz_places
-            WHERE (sync_change_counter > 0 OR sync_status != {})
+            WHERE {changed} AND NOT {too_old}
             ORDER BY frecency DESC
             LIMIT :max_places",
-            (SyncStatus::Normal as u8)
+            changed = changed,
+            too_old = too_old,
         );
         let visits_sql = "
             SELECT visit_date as date, visit_type as transition
             FROM moz_historyvisits
             WHERE place_id = :place_id
+              AND ((1 << visit_type) & :allowed_types) != 0
             ORDER BY visit_date DESC
             LIMIT :max_visits";
         // tombstones
         let tombstones_sql = "SELECT guid FROM moz_places_tombstones LIMIT :max_places";
+        let allowed_types = config.excluded_transitions().complement();
 
         let mut result: HashMap<SyncGuid, OutgoingInfo> = HashMap::new();
 
@@ -835,9 +853,28 @@ pub mod history_sync {
 
         let rows = db.query_rows_and_then_named(
             &places_sql,
-            &[(":max_places", &(max_places_left as u32))],
+            &[
+                (":max_places", &(max_places_left as u32)),
+                (":oldest_allowed", &oldest_allowed),
+            ],
             PageInfo::from_row,
         )?;
+        // Any changed pages we didn't fetch, either because they're too old
+        // or because we hit the limit, are skipped - `finish_outgoing` will
+        // mark them as synced.
+        let (num_changed, num_too_old) = db.query_row_named(
+            &format!(
+                "SELECT COUNT(*), IFNULL(SUM({too_old}), 0)
+                 FROM moz_places
+                 WHERE {changed}",
+                changed = changed,
+                too_old = too_old,
+            ),
+            &[(":oldest_allowed", &oldest_allowed)],
+            |row| -> RusqliteResult<_> { Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)) },
+        )?;
+        let mut num_skipped =
+            (num_too_old + (num_changed - num_too_old - rows.len() as i64).max(0)) as usize;
         let mut ids_to_update = Vec::with_capacity(rows.len());
         for page in rows {
             let visits = db.query_rows_and_then_named_cached(
@@ -845,6 +882,7 @@ pub mod history_sync {
                 &[
                     (":max_visits", &(max_visits as u32)),
                     (":place_id", &page.row_id),
+                    (":allowed_types", &allowed_types),
                 ],
                 |row| -> RusqliteResult<_> {
                     Ok(HistoryRecordVisit {
@@ -859,6 +897,12 @@ pub mod history_sync {
                 continue;
             }
             if visits.is_empty() {
+                if page.last_visit_date_local.0 > 0 || page.last_visit_date_remote.0 > 0 {
+                    // The page has visits, but `config` excludes all of them.
+                    log::trace!("Page {:?} only has excluded visits - skipping", &page.guid);
+                    num_skipped += 1;
+                    continue;
+                }
                 // This will be true for things like bookmarks which haven't
                 // had visits locally applied, and if we later prune old visits
                 // we'll also hit it, so don't make much log noise.
@@ -908,7 +952,10 @@ pub mod history_sync {
             Ok(())
         })?;
 
-        Ok(result)
+        if num_skipped > 0 {
+            log::debug!("Skipping {} outgoing pages", num_skipped);
+        }
+        Ok((result, num_skipped))
     }
 
     pub fn finish_outgoing(db: &PlacesDb) -> Result<()> {
@@ -1126,6 +1173,7 @@ mod tests {
     use super::*;
     use crate::api::places_api::ConnectionType;
     use crate::history_sync::record::{HistoryRecord, HistoryRecordVisit};
+    use crate::history_sync::HistorySyncConfig;
     use crate::types::Timestamp;
     use pretty_assertions::assert_eq;
     use std::time::{Duration, SystemTime};
@@ -1617,8 +1665,13 @@ mod tests {
             &[(":status", &(SyncStatus::New as u8)), (":id", &pi3.row_id)],
         )?;
 
-        let mut outgoing = fetch_outgoing(&conn, 2, 3)?;
+        let config = HistorySyncConfig {
+            max_outgoing_records: 2,
+            ..HistorySyncConfig::default()
+        };
+        let (mut outgoing, num_skipped) = fetch_outgoing(&conn, &config, 3)?;
         assert_eq!(outgoing.len(), 2, "should have restricted to the limit");
+        assert_eq!(num_skipped, 1, "should have skipped the third page");
         // I'm sure there's a shorter way to express this...
         let mut records: Vec<HistoryRecord> = Vec::with_capacity(outgoing.len());
         for (_, outgoing) in outgoing.drain() {
@@ -1715,11 +1768,53 @@ mod tests {
         assert_eq!(pi.sync_change_counter, 0);
         assert_eq!(pi.sync_status, SyncStatus::New);
         // Ensure we are going to do a full re-upload after a reset.
-        let outgoing = fetch_outgoing(&conn, 100, 100)?;
+        let (outgoing, _) = fetch_outgoing(&conn, &HistorySyncConfig::default(), 100)?;
         assert_eq!(outgoing.len(), 1);
         Ok(())
     }
 
+    #[test]
+    fn test_fetch_outgoing_config() -> Result<()> {
+        let _ = env_logger::try_init();
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now();
+        let recent = get_observed_page(&mut conn, "http://example.com/recent")?;
+        let old = get_custom_observed_page(&mut conn, "http://example.com/old", |o| {
+            o.with_at(Timestamp(now.as_millis() - 60 * 24 * 60 * 60 * 1000))
+        })?;
+        let download = get_custom_observed_page(&mut conn, "http://example.com/download", |o| {
+            o.with_visit_type(VisitTransition::Download)
+        })?;
+
+        // By default, we upload everything.
+        let (outgoing, num_skipped) = fetch_outgoing(&conn, &HistorySyncConfig::default(), 20)?;
+        assert_eq!(outgoing.len(), 3);
+        assert_eq!(num_skipped, 0);
+        // Forget about the first fetch, as if the sync was interrupted.
+        conn.execute("DELETE FROM temp_sync_updated_meta", NO_PARAMS)?;
+
+        let config = HistorySyncConfig {
+            max_record_age: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+            sync_downloads: false,
+            ..HistorySyncConfig::default()
+        };
+        let (outgoing, num_skipped) = fetch_outgoing(&conn, &config, 20)?;
+        assert!(outgoing.contains_key(&recent.guid));
+        assert!(
+            !outgoing.contains_key(&old.guid),
+            "should skip pages that are too old"
+        );
+        assert!(
+            !outgoing.contains_key(&download.guid),
+            "should skip pages with only excluded visits"
+        );
+        assert_eq!(
+            num_skipped, 2,
+            "should count the page that's too old, and the download"
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_fetch_visits() -> Result<()> {
         let _ = env_logger::try_init();
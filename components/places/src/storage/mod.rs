@@ -7,6 +7,8 @@
 
 pub mod bookmarks;
 pub mod history;
+pub mod query;
+pub mod reading_list;
 pub mod tags;
 
 use crate::db::PlacesDb;
@@ -0,0 +1,664 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Support for desktop's `place:` query URLs, which are how "smart bookmarks"
+// (eg, "Most Visited", "Recently Bookmarked" or the contents of a tag) are
+// represented in the bookmarks tree. We only support a subset of the params
+// desktop understands - see `nsINavHistoryQueryOptions` and
+// `PlacesQueryToQueryString` in desktop's nsNavHistory.cpp for the full set.
+
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use crate::storage::bookmarks::PublicNode;
+use crate::storage::tags::{validate_tag, ValidatedTag};
+use crate::types::{BookmarkType, Timestamp};
+use rusqlite::types::ToSql;
+use sql_support::ConnExt;
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+/// The `queryType` param, from `nsINavHistoryQueryOptions`. This determines
+/// whether the query finds history entries or bookmarks.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryType {
+    History = 0,
+    Bookmarks = 1,
+}
+
+impl QueryType {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(QueryType::History),
+            1 => Some(QueryType::Bookmarks),
+            _ => None,
+        }
+    }
+}
+
+/// The `type` param, from `nsINavHistoryQueryOptions`. This determines the
+/// shape of the results. Note that we only support some of these - the
+/// others are parsed so we can report a sensible error when executing them.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryResultType {
+    Uri = 0,
+    Visit = 1,
+    FullVisit = 2,
+    DateQuery = 3,
+    SiteQuery = 4,
+    DateSiteQuery = 5,
+    TagsRoot = 6,
+    TagContents = 7,
+    RootsQuery = 8,
+    LeftPaneQuery = 9,
+}
+
+impl QueryResultType {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(QueryResultType::Uri),
+            1 => Some(QueryResultType::Visit),
+            2 => Some(QueryResultType::FullVisit),
+            3 => Some(QueryResultType::DateQuery),
+            4 => Some(QueryResultType::SiteQuery),
+            5 => Some(QueryResultType::DateSiteQuery),
+            6 => Some(QueryResultType::TagsRoot),
+            7 => Some(QueryResultType::TagContents),
+            8 => Some(QueryResultType::RootsQuery),
+            9 => Some(QueryResultType::LeftPaneQuery),
+            _ => None,
+        }
+    }
+}
+
+/// The `sort` param, from `nsINavHistoryQueryOptions`. The gaps in the
+/// discriminants are for sort orders desktop has since removed.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QuerySort {
+    None = 0,
+    TitleAscending = 1,
+    TitleDescending = 2,
+    DateAscending = 3,
+    DateDescending = 4,
+    UriAscending = 5,
+    UriDescending = 6,
+    VisitCountAscending = 7,
+    VisitCountDescending = 8,
+    DateAddedAscending = 11,
+    DateAddedDescending = 12,
+    LastModifiedAscending = 13,
+    LastModifiedDescending = 14,
+    TagsAscending = 17,
+    TagsDescending = 18,
+    FrecencyAscending = 21,
+    FrecencyDescending = 22,
+}
+
+impl QuerySort {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(QuerySort::None),
+            1 => Some(QuerySort::TitleAscending),
+            2 => Some(QuerySort::TitleDescending),
+            3 => Some(QuerySort::DateAscending),
+            4 => Some(QuerySort::DateDescending),
+            5 => Some(QuerySort::UriAscending),
+            6 => Some(QuerySort::UriDescending),
+            7 => Some(QuerySort::VisitCountAscending),
+            8 => Some(QuerySort::VisitCountDescending),
+            11 => Some(QuerySort::DateAddedAscending),
+            12 => Some(QuerySort::DateAddedDescending),
+            13 => Some(QuerySort::LastModifiedAscending),
+            14 => Some(QuerySort::LastModifiedDescending),
+            17 => Some(QuerySort::TagsAscending),
+            18 => Some(QuerySort::TagsDescending),
+            21 => Some(QuerySort::FrecencyAscending),
+            22 => Some(QuerySort::FrecencyDescending),
+            _ => None,
+        }
+    }
+
+    // The ORDER BY clause for this sort. This refers to the column aliases
+    // used by both `BOOKMARKS_QUERY_SQL` and `HISTORY_QUERY_SQL`.
+    fn order_by(self) -> &'static str {
+        match self {
+            QuerySort::None => "ORDER BY _id",
+            QuerySort::TitleAscending => "ORDER BY title COLLATE NOCASE ASC, _id",
+            QuerySort::TitleDescending => "ORDER BY title COLLATE NOCASE DESC, _id",
+            QuerySort::DateAscending => "ORDER BY lastVisitDate ASC, _id",
+            QuerySort::DateDescending => "ORDER BY lastVisitDate DESC, _id",
+            QuerySort::UriAscending => "ORDER BY url ASC, _id",
+            QuerySort::UriDescending => "ORDER BY url DESC, _id",
+            QuerySort::VisitCountAscending => "ORDER BY visitCount ASC, _id",
+            QuerySort::VisitCountDescending => "ORDER BY visitCount DESC, _id",
+            QuerySort::DateAddedAscending => "ORDER BY dateAdded ASC, _id",
+            QuerySort::DateAddedDescending => "ORDER BY dateAdded DESC, _id",
+            QuerySort::LastModifiedAscending => "ORDER BY lastModified ASC, _id",
+            QuerySort::LastModifiedDescending => "ORDER BY lastModified DESC, _id",
+            QuerySort::TagsAscending => "ORDER BY tags ASC, _id",
+            QuerySort::TagsDescending => "ORDER BY tags DESC, _id",
+            QuerySort::FrecencyAscending => "ORDER BY frecency ASC, _id",
+            QuerySort::FrecencyDescending => "ORDER BY frecency DESC, _id",
+        }
+    }
+}
+
+/// A parsed `place:` query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaceQuery {
+    pub query_type: QueryType,
+    pub result_type: QueryResultType,
+    pub sort: QuerySort,
+    pub max_results: Option<u32>,
+    /// Results must have all of these tags, or, if `tags_are_not` is set,
+    /// none of them. Like desktop, tags are matched case-insensitively, so
+    /// these are lowercase, and each tag is only listed once.
+    pub tags: Vec<String>,
+    pub tags_are_not: bool,
+}
+
+impl Default for PlaceQuery {
+    fn default() -> Self {
+        Self {
+            query_type: QueryType::History,
+            result_type: QueryResultType::Uri,
+            sort: QuerySort::None,
+            max_results: None,
+            tags: Vec::new(),
+            tags_are_not: false,
+        }
+    }
+}
+
+impl PlaceQuery {
+    /// Parses a `place:` URL. Like desktop, we ignore params we don't
+    /// understand and params with invalid values, so this only fails if the
+    /// URL isn't a `place:` URL at all.
+    pub fn from_url(url: &Url) -> Result<Self> {
+        if url.scheme() != "place" {
+            return Err(InvalidPlaceInfo::InvalidQuery("not a place: URL").into());
+        }
+        let mut query = PlaceQuery::default();
+        // As in `IncomingApplicator`, the "params" are actually the path of
+        // the URL, so we can't use `url.query_pairs()`.
+        for (key, value) in url::form_urlencoded::parse(url.path().as_bytes()) {
+            match key.as_ref() {
+                "tag" => match validate_tag(&value) {
+                    ValidatedTag::Normalized(t) | ValidatedTag::Original(t) => {
+                        let t = t.to_lowercase();
+                        if !query.tags.contains(&t) {
+                            query.tags.push(t);
+                        }
+                    }
+                    ValidatedTag::Invalid(_) => log::warn!("Ignoring invalid tag in query"),
+                },
+                "!tags" => query.tags_are_not = value == "1",
+                "sort" => match value.parse().ok().and_then(QuerySort::from_primitive) {
+                    Some(sort) => query.sort = sort,
+                    None => log::warn!("Ignoring invalid query sort {:?}", value),
+                },
+                "type" => match value.parse().ok().and_then(QueryResultType::from_primitive) {
+                    Some(result_type) => query.result_type = result_type,
+                    None => log::warn!("Ignoring invalid query result type {:?}", value),
+                },
+                "queryType" => match value.parse().ok().and_then(QueryType::from_primitive) {
+                    Some(query_type) => query.query_type = query_type,
+                    None => log::warn!("Ignoring invalid query type {:?}", value),
+                },
+                // Desktop treats 0 as "no limit".
+                "maxResults" => match value.parse::<u32>() {
+                    Ok(0) => query.max_results = None,
+                    Ok(max) => query.max_results = Some(max),
+                    Err(_) => log::warn!("Ignoring invalid query maxResults {:?}", value),
+                },
+                _ => log::trace!("Ignoring unsupported query param {:?}", key),
+            }
+        }
+        Ok(query)
+    }
+}
+
+/// Parses and executes a `place:` URL. See `fetch_query_results`.
+pub fn fetch_query_results_for_url(db: &PlacesDb, url: &Url) -> Result<Vec<PublicNode>> {
+    fetch_query_results(db, &PlaceQuery::from_url(url)?)
+}
+
+/// Executes a query against history and bookmarks.
+///
+/// Note that the nodes returned aren't always bookmarks in the tree:
+/// - For bookmark queries, the nodes are the matching bookmarks, with their
+///   GUIDs, parents and positions.
+/// - For history queries, the nodes are `BookmarkType::Bookmark` nodes for
+///   the matching pages. They have the page's GUID, no parent, and both
+///   `date_added` and `last_modified` are the date of the last visit.
+/// - For the tags root (`type=6`), each node is a `BookmarkType::Bookmark`
+///   whose URL is the `place:tag=...` query for that tag. Tags aren't items,
+///   so these nodes have a synthetic GUID, and no parent.
+pub fn fetch_query_results(db: &PlacesDb, query: &PlaceQuery) -> Result<Vec<PublicNode>> {
+    match query.result_type {
+        QueryResultType::TagsRoot => fetch_tags_root(db, query),
+        QueryResultType::TagContents if query.tags.is_empty() => {
+            Err(InvalidPlaceInfo::InvalidQuery("tag contents query without a tag").into())
+        }
+        QueryResultType::Uri | QueryResultType::Visit | QueryResultType::TagContents => {
+            fetch_uri_results(db, query)
+        }
+        _ => Err(InvalidPlaceInfo::InvalidQuery("unsupported result type").into()),
+    }
+}
+
+// Both of these queries produce the same set of column aliases, so that
+// `QuerySort::order_by` works for either. Note the aliases are explicit, as
+// SQLite only resolves ORDER BY terms against explicit aliases.
+// `{tags_filter}` is replaced with an `AND ...` expression, and `{order_by}`
+// with the ORDER BY clause.
+const BOOKMARKS_QUERY_SQL: &str = "
+    SELECT
+        b.id AS _id,
+        b.guid AS guid,
+        p.guid AS parentGuid,
+        b.position AS position,
+        b.dateAdded AS dateAdded,
+        b.lastModified AS lastModified,
+        -- Note we return null for titles with an empty string.
+        NULLIF(b.title, '') AS title,
+        h.url AS url,
+        h.visit_count_local + h.visit_count_remote AS visitCount,
+        MAX(h.last_visit_date_local, h.last_visit_date_remote) AS lastVisitDate,
+        h.frecency AS frecency,
+        (SELECT GROUP_CONCAT(t.tag, ',') FROM moz_tags t
+         JOIN moz_tags_relation r ON r.tag_id = t.id
+         WHERE r.place_id = h.id) AS tags
+    FROM moz_bookmarks b
+    JOIN moz_bookmarks p ON p.id = b.parent
+    JOIN moz_places h ON h.id = b.fk
+    WHERE b.type = {bookmark_type}
+    {tags_filter}
+    {order_by}
+    LIMIT :limit";
+
+const HISTORY_QUERY_SQL: &str = "
+    SELECT
+        h.id AS _id,
+        h.guid AS guid,
+        NULL AS parentGuid,
+        0 AS position,
+        MAX(h.last_visit_date_local, h.last_visit_date_remote) AS dateAdded,
+        MAX(h.last_visit_date_local, h.last_visit_date_remote) AS lastModified,
+        NULLIF(h.title, '') AS title,
+        h.url AS url,
+        h.visit_count_local + h.visit_count_remote AS visitCount,
+        MAX(h.last_visit_date_local, h.last_visit_date_remote) AS lastVisitDate,
+        h.frecency AS frecency,
+        (SELECT GROUP_CONCAT(t.tag, ',') FROM moz_tags t
+         JOIN moz_tags_relation r ON r.tag_id = t.id
+         WHERE r.place_id = h.id) AS tags
+    FROM moz_places h
+    WHERE {history_filter}
+    {tags_filter}
+    {order_by}
+    LIMIT :limit";
+
+fn fetch_uri_results(db: &PlacesDb, query: &PlaceQuery) -> Result<Vec<PublicNode>> {
+    let scope = db.begin_interrupt_scope();
+
+    // As in desktop's `nsNavHistory::QueryToSelectClause`, results must
+    // have every tag, so we count the distinct matching tags for each place,
+    // unless `tags_are_not` is set, in which case they must have none of them.
+    // A place can have several tags that only differ by case, so we count
+    // them once. Tags are private, so we bind them rather than formatting
+    // them into the SQL.
+    let tag_params = (0..query.tags.len())
+        .map(|i| format!(":tag{}", i))
+        .collect::<Vec<_>>();
+    let tags_filter = if query.tags.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "AND h.id {} (SELECT r.place_id FROM moz_tags_relation r
+                          JOIN moz_tags t ON t.id = r.tag_id
+                          WHERE lower_unicode(t.tag) IN ({})
+                          GROUP BY r.place_id {})",
+            if query.tags_are_not { "NOT IN" } else { "IN" },
+            tag_params.join(","),
+            if query.tags_are_not {
+                String::new()
+            } else {
+                format!(
+                    "HAVING COUNT(DISTINCT lower_unicode(t.tag)) >= {}",
+                    query.tags.len()
+                )
+            }
+        )
+    };
+    let sql = match query.query_type {
+        QueryType::Bookmarks => BOOKMARKS_QUERY_SQL.replace(
+            "{bookmark_type}",
+            &(BookmarkType::Bookmark as u8).to_string(),
+        ),
+        QueryType::History => HISTORY_QUERY_SQL.replace(
+            "{history_filter}",
+            // Tag queries find tagged pages, even if they haven't been
+            // visited. Otherwise, we only want visible pages with visits.
+            if query.tags.is_empty() || query.tags_are_not {
+                "h.hidden = 0 AND h.visit_count_local + h.visit_count_remote > 0"
+            } else {
+                "1"
+            },
+        ),
+    }
+    .replace("{tags_filter}", &tags_filter)
+    .replace("{order_by}", query.sort.order_by());
+
+    // A negative limit means "no limit" to SQLite.
+    let limit = query.max_results.map_or(-1, i64::from);
+    let mut params: Vec<(&str, &dyn ToSql)> = vec![(":limit", &limit)];
+    for (name, tag) in tag_params.iter().zip(query.tags.iter()) {
+        params.push((name.as_str(), tag));
+    }
+
+    Ok(db.query_rows_into(&sql, &params, |row| -> Result<_> {
+        scope.err_if_interrupted()?;
+        Ok(PublicNode {
+            node_type: BookmarkType::Bookmark,
+            guid: row.get("guid")?,
+            parent_guid: row.get("parentGuid")?,
+            position: row.get("position")?,
+            date_added: row.get("dateAdded")?,
+            last_modified: row.get("lastModified")?,
+            title: row.get("title")?,
+            url: Some(Url::parse(&row.get::<_, String>("url")?)?),
+            child_guids: None,
+            child_nodes: None,
+        })
+    })?)
+}
+
+// Tags aren't bookmarks, so they don't have GUIDs. We give each tag node a
+// synthetic one based on the tag's ID, so that it's valid, and stays the same
+// between queries.
+fn tag_node_guid(tag_id: i64) -> SyncGuid {
+    SyncGuid::from(format!("tag{:09}", tag_id))
+}
+
+fn fetch_tags_root(db: &PlacesDb, query: &PlaceQuery) -> Result<Vec<PublicNode>> {
+    let scope = db.begin_interrupt_scope();
+    // Most sorts don't make sense for tags, so, like desktop, anything other
+    // than a title or last modified sort is by tag name.
+    let order_by = match query.sort {
+        QuerySort::TitleDescending => "ORDER BY t.tag COLLATE NOCASE DESC",
+        QuerySort::LastModifiedAscending => "ORDER BY t.lastModified ASC, t.tag",
+        QuerySort::LastModifiedDescending => "ORDER BY t.lastModified DESC, t.tag",
+        _ => "ORDER BY t.tag COLLATE NOCASE ASC",
+    };
+    let sql = format!(
+        "SELECT t.id, t.tag, t.lastModified
+         FROM moz_tags t
+         WHERE EXISTS(SELECT 1 FROM moz_tags_relation r WHERE r.tag_id = t.id)
+         {}
+         LIMIT :limit",
+        order_by
+    );
+    let limit = query.max_results.map_or(-1, i64::from);
+    Ok(
+        db.query_rows_into(&sql, &[(":limit", &limit)], |row| -> Result<_> {
+            scope.err_if_interrupted()?;
+            let tag_id = row.get::<_, i64>("id")?;
+            let tag = row.get::<_, String>("tag")?;
+            let last_modified = row.get::<_, Timestamp>("lastModified")?;
+            let url = url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("tag", &tag)
+                .finish();
+            Ok(PublicNode {
+                node_type: BookmarkType::Bookmark,
+                guid: tag_node_guid(tag_id),
+                parent_guid: None,
+                position: 0,
+                date_added: last_modified,
+                last_modified,
+                url: Some(Url::parse(&format!("place:{}", url))?),
+                title: Some(tag),
+                child_guids: None,
+                child_nodes: None,
+            })
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::storage::history::apply_observation;
+    use crate::storage::tags::tag_url;
+    use crate::tests::insert_json_tree;
+    use crate::types::VisitTransition;
+    use serde_json::json;
+
+    fn parse(s: &str) -> PlaceQuery {
+        PlaceQuery::from_url(&Url::parse(s).unwrap()).expect("should parse")
+    }
+
+    fn urls(nodes: &[PublicNode]) -> Vec<&str> {
+        nodes
+            .iter()
+            .map(|n| n.url.as_ref().unwrap().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("place:"), PlaceQuery::default());
+        assert_eq!(
+            parse("place:sort=8&maxResults=10"),
+            PlaceQuery {
+                sort: QuerySort::VisitCountDescending,
+                max_results: Some(10),
+                ..PlaceQuery::default()
+            }
+        );
+        assert_eq!(
+            parse("place:queryType=1&sort=12&maxResults=0"),
+            PlaceQuery {
+                query_type: QueryType::Bookmarks,
+                sort: QuerySort::DateAddedDescending,
+                ..PlaceQuery::default()
+            }
+        );
+        assert_eq!(
+            parse("place:type=7&tag=foo&tag=%20bar%20&tag=&tag=FOO"),
+            PlaceQuery {
+                result_type: QueryResultType::TagContents,
+                tags: vec!["foo".to_string(), "bar".to_string()],
+                ..PlaceQuery::default()
+            }
+        );
+        assert_eq!(
+            parse("place:tag=Foo&!tags=1"),
+            PlaceQuery {
+                tags: vec!["foo".to_string()],
+                tags_are_not: true,
+                ..PlaceQuery::default()
+            }
+        );
+        // Invalid and unknown values are ignored.
+        assert_eq!(
+            parse("place:sort=9&type=99&queryType=x&maxResults=-1&folder=5&excludeItems=1"),
+            PlaceQuery::default()
+        );
+        assert!(PlaceQuery::from_url(&Url::parse("https://example.com/").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_most_visited() -> Result<()> {
+        let conn = new_mem_connection();
+        for (url, visits) in &[
+            ("https://www.example1.com/", 2),
+            ("https://www.example2.com/", 5),
+            ("https://www.example3.com/", 1),
+            ("https://www.example4.com/", 3),
+        ] {
+            for _ in 0..*visits {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(Url::parse(url)?).with_visit_type(VisitTransition::Link),
+                )?;
+            }
+        }
+        // Hidden pages aren't included.
+        apply_observation(
+            &conn,
+            VisitObservation::new(Url::parse("https://www.example5.com/")?)
+                .with_visit_type(VisitTransition::Embed),
+        )?;
+        let results =
+            fetch_query_results_for_url(&conn, &Url::parse("place:sort=8&maxResults=3")?)?;
+        assert_eq!(
+            urls(&results),
+            vec![
+                "https://www.example2.com/",
+                "https://www.example4.com/",
+                "https://www.example1.com/",
+            ]
+        );
+        assert!(results.iter().all(|n| n.parent_guid.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookmarks_and_tags() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": String::from(BookmarkRootGuid::Unfiled.as_str()),
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "url": "https://www.example1.com/",
+                        "title": "c",
+                    },
+                    {
+                        "guid": "bookmark2___",
+                        "url": "https://www.example2.com/",
+                        "title": "a",
+                    },
+                    {
+                        "guid": "bookmark3___",
+                        "url": "https://www.example3.com/",
+                        "title": "b",
+                    },
+                ]
+            }),
+        );
+        let url1 = Url::parse("https://www.example1.com/")?;
+        let url2 = Url::parse("https://www.example2.com/")?;
+        tag_url(&conn, &url1, "foo")?;
+        tag_url(&conn, &url1, "bar")?;
+        tag_url(&conn, &url2, "foo")?;
+
+        let results = fetch_query_results_for_url(&conn, &Url::parse("place:queryType=1&sort=1")?)?;
+        assert_eq!(
+            results.iter().map(|n| n.guid.as_str()).collect::<Vec<_>>(),
+            vec!["bookmark2___", "bookmark3___", "bookmark1___"]
+        );
+        assert!(results
+            .iter()
+            .all(|n| n.parent_guid.as_ref().unwrap() == BookmarkRootGuid::Unfiled));
+
+        let results =
+            fetch_query_results_for_url(&conn, &Url::parse("place:type=7&tag=foo&sort=5")?)?;
+        assert_eq!(
+            urls(&results),
+            vec!["https://www.example1.com/", "https://www.example2.com/"]
+        );
+        let results = fetch_query_results_for_url(
+            &conn,
+            &Url::parse("place:type=7&tag=foo&tag=bar&sort=5")?,
+        )?;
+        assert_eq!(urls(&results), vec!["https://www.example1.com/"]);
+        // Like desktop, tags are case-insensitive, and repeating a tag
+        // doesn't change the results.
+        let results = fetch_query_results_for_url(
+            &conn,
+            &Url::parse("place:type=7&tag=FOO&tag=bar&tag=Bar&sort=5")?,
+        )?;
+        assert_eq!(urls(&results), vec!["https://www.example1.com/"]);
+        // `!tags` finds bookmarks without any of the tags.
+        let results = fetch_query_results_for_url(
+            &conn,
+            &Url::parse("place:queryType=1&tag=bar&!tags=1&sort=5")?,
+        )?;
+        assert_eq!(
+            urls(&results),
+            vec!["https://www.example2.com/", "https://www.example3.com/"]
+        );
+        fetch_query_results_for_url(&conn, &Url::parse("place:type=7")?)
+            .expect_err("tag contents without a tag should fail");
+
+        let results = fetch_query_results_for_url(&conn, &Url::parse("place:type=6&sort=1")?)?;
+        assert_eq!(
+            results
+                .iter()
+                .map(|n| n.title.as_ref().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["bar", "foo"]
+        );
+        assert_eq!(urls(&results), vec!["place:tag=bar", "place:tag=foo"]);
+        assert!(results.iter().all(|n| n.guid.is_valid_for_places()));
+        assert_ne!(results[0].guid, results[1].guid);
+
+        fetch_query_results_for_url(&conn, &Url::parse("place:type=4")?)
+            .expect_err("site queries aren't supported");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tags_case() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": String::from(BookmarkRootGuid::Unfiled.as_str()),
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "url": "https://www.example1.com/",
+                    },
+                    {
+                        "guid": "bookmark2___",
+                        "url": "https://www.example2.com/",
+                    },
+                ]
+            }),
+        );
+        let url1 = Url::parse("https://www.example1.com/")?;
+        let url2 = Url::parse("https://www.example2.com/")?;
+        // Tags are stored as they're given, so we need to lowercase non-ASCII
+        // characters ourselves to match them.
+        tag_url(&conn, &url1, "Äpfel")?;
+        tag_url(&conn, &url1, "bar")?;
+        let results =
+            fetch_query_results_for_url(&conn, &Url::parse("place:type=7&tag=äPFEL&sort=5")?)?;
+        assert_eq!(urls(&results), vec!["https://www.example1.com/"]);
+
+        // A page with two tags that only differ by case has one of the tags,
+        // not both of them.
+        tag_url(&conn, &url2, "foo")?;
+        tag_url(&conn, &url2, "FOO")?;
+        let results = fetch_query_results_for_url(
+            &conn,
+            &Url::parse("place:type=7&tag=foo&tag=bar&sort=5")?,
+        )?;
+        assert!(results.is_empty());
+        let results =
+            fetch_query_results_for_url(&conn, &Url::parse("place:type=7&tag=Foo&sort=5")?)?;
+        assert_eq!(urls(&results), vec!["https://www.example2.com/"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Storage for the reading list: pages the user has saved to read later.
+// Reading list items are keyed by URL, and live in their own tables instead
+// of `moz_bookmarks` or `moz_places`.
+
+use super::{TITLE_LENGTH_MAX, URL_LENGTH_MAX};
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use crate::types::{SyncStatus, Timestamp};
+use crate::util::slice_up_to;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Result as RusqliteResult;
+use rusqlite::Row;
+use serde_derive::*;
+use sql_support::ConnExt;
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+/// Excerpts longer than this are truncated.
+pub const EXCERPT_LENGTH_MAX: usize = 4096;
+
+/// Whether a reading list item has been read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum ReadingListStatus {
+    Unread = 0,
+    Read = 1,
+    Archived = 2,
+}
+
+impl ReadingListStatus {
+    #[inline]
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(ReadingListStatus::Unread),
+            1 => Some(ReadingListStatus::Read),
+            2 => Some(ReadingListStatus::Archived),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ReadingListStatus {
+    fn default() -> Self {
+        ReadingListStatus::Unread
+    }
+}
+
+impl ToSql for ReadingListStatus {
+    fn to_sql(&self) -> RusqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as u8))
+    }
+}
+
+impl FromSql for ReadingListStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let v = value.as_i64()?;
+        if v < 0 || v > i64::from(u8::max_value()) {
+            return Err(FromSqlError::OutOfRange(v));
+        }
+        ReadingListStatus::from_u8(v as u8).ok_or(FromSqlError::OutOfRange(v))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadingListItem {
+    pub guid: SyncGuid,
+    pub url: Url,
+    pub title: Option<String>,
+    pub excerpt: Option<String>,
+    pub status: ReadingListStatus,
+    pub date_added: Timestamp,
+    pub last_modified: Timestamp,
+}
+
+impl ReadingListItem {
+    pub(crate) fn from_row(row: &Row<'_>) -> Result<Self> {
+        Ok(Self {
+            guid: row.get("guid")?,
+            url: Url::parse(&row.get::<_, String>("url")?)?,
+            title: row.get("title")?,
+            excerpt: row.get("excerpt")?,
+            status: row.get("status")?,
+            date_added: row.get("dateAdded")?,
+            last_modified: row.get("lastModified")?,
+        })
+    }
+}
+
+const ITEM_COLUMNS: &str = "guid, url, title, excerpt, status, dateAdded, lastModified";
+
+/// Adds a page to the reading list, returning the GUID of the new item. If
+/// the page is already in the list, this returns the existing item's GUID,
+/// without changing it.
+pub fn add_item(
+    db: &PlacesDb,
+    url: &Url,
+    title: Option<&str>,
+    excerpt: Option<&str>,
+) -> Result<SyncGuid> {
+    if url.as_str().len() > URL_LENGTH_MAX {
+        return Err(InvalidPlaceInfo::UrlTooLong.into());
+    }
+    let tx = db.begin_transaction()?;
+    if let Some(item) = get_item_for_url(db, url)? {
+        return Ok(item.guid);
+    }
+    let guid = SyncGuid::random();
+    let now = Timestamp::now();
+    db.execute_named_cached(
+        "INSERT INTO moz_reading_list(guid, url, title, excerpt, status,
+                                      dateAdded, lastModified, syncStatus,
+                                      syncChangeCounter)
+         VALUES(:guid, :url, :title, :excerpt, :status, :now, :now,
+                :sync_status, 1)",
+        &[
+            (":guid", &guid),
+            (":url", &url.as_str()),
+            (":title", &title.map(|t| slice_up_to(t, TITLE_LENGTH_MAX))),
+            (
+                ":excerpt",
+                &excerpt.map(|e| slice_up_to(e, EXCERPT_LENGTH_MAX)),
+            ),
+            (":status", &ReadingListStatus::Unread),
+            (":now", &now),
+            (":sync_status", &SyncStatus::New),
+        ],
+    )?;
+    tx.commit()?;
+    Ok(guid)
+}
+
+pub fn get_item(db: &PlacesDb, guid: &SyncGuid) -> Result<Option<ReadingListItem>> {
+    Ok(db.try_query_row(
+        &format!(
+            "SELECT {columns} FROM moz_reading_list WHERE guid = :guid",
+            columns = ITEM_COLUMNS
+        ),
+        &[(":guid", guid)],
+        ReadingListItem::from_row,
+        true,
+    )?)
+}
+
+pub fn get_item_for_url(db: &PlacesDb, url: &Url) -> Result<Option<ReadingListItem>> {
+    Ok(db.try_query_row(
+        &format!(
+            "SELECT {columns} FROM moz_reading_list WHERE url = :url",
+            columns = ITEM_COLUMNS
+        ),
+        &[(":url", &url.as_str())],
+        ReadingListItem::from_row,
+        true,
+    )?)
+}
+
+/// Returns all reading list items, or only the ones with the given status,
+/// most recently added first.
+pub fn get_items(db: &PlacesDb, status: Option<ReadingListStatus>) -> Result<Vec<ReadingListItem>> {
+    Ok(db.query_rows_and_then_named_cached(
+        &format!(
+            "SELECT {columns} FROM moz_reading_list
+             WHERE :status IS NULL OR status = :status
+             ORDER BY dateAdded DESC, id DESC",
+            columns = ITEM_COLUMNS
+        ),
+        &[(":status", &status)],
+        ReadingListItem::from_row,
+    )?)
+}
+
+/// Marks an item as unread, read, or archived.
+pub fn set_status(db: &PlacesDb, guid: &SyncGuid, status: ReadingListStatus) -> Result<()> {
+    let changes = db.execute_named_cached(
+        "UPDATE moz_reading_list SET
+           status = :status,
+           lastModified = :now,
+           syncChangeCounter = syncChangeCounter + 1
+         WHERE guid = :guid AND status <> :status",
+        &[
+            (":guid", guid),
+            (":status", &status),
+            (":now", &Timestamp::now()),
+        ],
+    )?;
+    if changes == 0 && get_item(db, guid)?.is_none() {
+        return Err(InvalidPlaceInfo::NoSuchGuid(guid.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Replaces the title and excerpt of an item, typically after the page has
+/// been fetched and parsed.
+pub fn update_metadata(
+    db: &PlacesDb,
+    guid: &SyncGuid,
+    title: Option<&str>,
+    excerpt: Option<&str>,
+) -> Result<()> {
+    let changes = db.execute_named_cached(
+        "UPDATE moz_reading_list SET
+           title = :title,
+           excerpt = :excerpt,
+           lastModified = :now,
+           syncChangeCounter = syncChangeCounter + 1
+         WHERE guid = :guid",
+        &[
+            (":guid", guid),
+            (":title", &title.map(|t| slice_up_to(t, TITLE_LENGTH_MAX))),
+            (
+                ":excerpt",
+                &excerpt.map(|e| slice_up_to(e, EXCERPT_LENGTH_MAX)),
+            ),
+            (":now", &Timestamp::now()),
+        ],
+    )?;
+    if changes == 0 {
+        return Err(InvalidPlaceInfo::NoSuchGuid(guid.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Removes an item from the reading list. Returns `false` if the item
+/// doesn't exist.
+pub fn delete_item(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
+    let tx = db.begin_transaction()?;
+    // Only items we've uploaded before need tombstones.
+    db.execute_named_cached(
+        "INSERT OR REPLACE INTO moz_reading_list_deleted(guid, dateRemoved)
+         SELECT guid, :now FROM moz_reading_list
+         WHERE guid = :guid AND syncStatus = :sync_status",
+        &[
+            (":guid", guid),
+            (":now", &Timestamp::now()),
+            (":sync_status", &SyncStatus::Normal),
+        ],
+    )?;
+    let changes = db.execute_named_cached(
+        "DELETE FROM moz_reading_list WHERE guid = :guid",
+        &[(":guid", guid)],
+    )?;
+    tx.commit()?;
+    Ok(changes > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+
+    fn count_tombstones(db: &PlacesDb) -> u32 {
+        db.query_one("SELECT COUNT(*) FROM moz_reading_list_deleted")
+            .expect("should count tombstones")
+    }
+
+    #[test]
+    fn test_add_and_get() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("https://example.com/article")?;
+        let guid = add_item(&conn, &url, Some("An article"), Some("Once upon a time"))?;
+
+        let item = get_item(&conn, &guid)?.expect("should exist");
+        assert_eq!(item.url, url);
+        assert_eq!(item.title, Some("An article".to_string()));
+        assert_eq!(item.excerpt, Some("Once upon a time".to_string()));
+        assert_eq!(item.status, ReadingListStatus::Unread);
+        assert_eq!(get_item_for_url(&conn, &url)?, Some(item));
+
+        // Adding the same URL again returns the existing item.
+        assert_eq!(add_item(&conn, &url, Some("Other title"), None)?, guid);
+        assert_eq!(get_items(&conn, None)?.len(), 1);
+
+        assert_eq!(
+            get_item(&conn, &SyncGuid::new("aaaaaaaaaaaa"))?,
+            None,
+            "unknown guids shouldn't exist"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let conn = new_mem_connection();
+        let a = add_item(&conn, &Url::parse("https://example.com/a")?, None, None)?;
+        let b = add_item(&conn, &Url::parse("https://example.com/b")?, None, None)?;
+        set_status(&conn, &a, ReadingListStatus::Read)?;
+        set_status(&conn, &b, ReadingListStatus::Archived)?;
+
+        let read = get_items(&conn, Some(ReadingListStatus::Read))?;
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].guid, a);
+        assert!(get_items(&conn, Some(ReadingListStatus::Unread))?.is_empty());
+        assert_eq!(get_items(&conn, None)?.len(), 2);
+
+        // Setting the same status again is fine.
+        set_status(&conn, &a, ReadingListStatus::Read)?;
+
+        match set_status(
+            &conn,
+            &SyncGuid::new("aaaaaaaaaaaa"),
+            ReadingListStatus::Read,
+        ) {
+            Err(_) => {}
+            Ok(_) => panic!("shouldn't set status of unknown items"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_metadata() -> Result<()> {
+        let conn = new_mem_connection();
+        let guid = add_item(&conn, &Url::parse("https://example.com/a")?, None, None)?;
+        let excerpt = "x".repeat(EXCERPT_LENGTH_MAX + 10);
+        update_metadata(&conn, &guid, Some("Title"), Some(&excerpt))?;
+        let item = get_item(&conn, &guid)?.expect("should exist");
+        assert_eq!(item.title, Some("Title".to_string()));
+        assert_eq!(item.excerpt.map(|e| e.len()), Some(EXCERPT_LENGTH_MAX));
+        assert!(update_metadata(&conn, &SyncGuid::new("aaaaaaaaaaaa"), None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete() -> Result<()> {
+        let conn = new_mem_connection();
+        let new_guid = add_item(&conn, &Url::parse("https://example.com/a")?, None, None)?;
+        let synced_guid = add_item(&conn, &Url::parse("https://example.com/b")?, None, None)?;
+        conn.execute_named(
+            "UPDATE moz_reading_list SET syncStatus = :sync_status WHERE guid = :guid",
+            &[
+                (":sync_status", &SyncStatus::Normal),
+                (":guid", &synced_guid),
+            ],
+        )?;
+
+        // Items that were never synced don't need tombstones...
+        assert!(delete_item(&conn, &new_guid)?);
+        assert_eq!(count_tombstones(&conn), 0);
+
+        // ...But synced ones do.
+        assert!(delete_item(&conn, &synced_guid)?);
+        assert_eq!(count_tombstones(&conn), 1);
+        assert!(get_items(&conn, None)?.is_empty());
+
+        assert!(!delete_item(&conn, &synced_guid)?);
+        Ok(())
+    }
+}
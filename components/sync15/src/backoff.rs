@@ -0,0 +1,188 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Handling for the backoff headers sent by the tokenserver and the storage
+// servers when they're overloaded, or down for maintenance.
+//
+// Any response may carry `X-Weave-Backoff` or `X-Backoff`, which ask us to
+// finish what we're doing, but not to sync again for that many seconds. A
+// failed response may carry `Retry-After`, which asks us to stop now. Either
+// way, we remember the latest time we've been asked to wait until, and
+// `sync_multiple` persists it so that later syncs don't touch the network
+// before then.
+
+use crate::error::{ErrorKind, Result};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use viaduct::{header_names, Headers, Response};
+
+// The backoff to use if a `Retry-After` header isn't a number of seconds.
+const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
+
+fn seconds_after(now: SystemTime, seconds: f64) -> Option<SystemTime> {
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(now + Duration::from_millis((seconds * 1000.0) as u64))
+    } else {
+        None
+    }
+}
+
+/// Returns when the `X-Weave-Backoff` and `X-Backoff` headers ask us to wait
+/// until, if either is present and valid.
+fn backoff_from_headers(headers: &Headers, now: SystemTime) -> Option<SystemTime> {
+    [header_names::X_WEAVE_BACKOFF, header_names::X_BACKOFF]
+        .iter()
+        .filter_map(|name| headers.try_get::<f64, _>(name.clone()))
+        .filter_map(|seconds| seconds_after(now, seconds))
+        .max()
+}
+
+/// Returns when the `Retry-After` header asks us to retry, if it's present.
+fn retry_after_from_headers(headers: &Headers, now: SystemTime) -> Option<SystemTime> {
+    headers
+        .get_as::<f64, _>(header_names::RETRY_AFTER)
+        .map(|seconds| {
+            seconds
+                .ok()
+                .and_then(|seconds| seconds_after(now, seconds))
+                .unwrap_or_else(|| now + Duration::from_millis(RETRY_AFTER_DEFAULT_MS))
+        })
+}
+
+/// Remembers the latest time that a server has asked us to back off until.
+#[derive(Debug, Default)]
+pub(crate) struct BackoffListener {
+    until: Mutex<Option<SystemTime>>,
+}
+
+impl BackoffListener {
+    pub fn note(&self, until: SystemTime) {
+        let mut current = self.until.lock().unwrap();
+        if current.map_or(true, |existing| until > existing) {
+            log::warn!("Server requested backoff until {:?}", until);
+            *current = Some(until);
+        }
+    }
+
+    pub fn get(&self) -> Option<SystemTime> {
+        *self.until.lock().unwrap()
+    }
+
+    /// Notes any backoff requested by `resp`. Returns a `BackoffError` if it's
+    /// a failed response with a `Retry-After` header.
+    pub fn check_response(&self, resp: &Response, now: SystemTime) -> Result<()> {
+        if let Some(until) = backoff_from_headers(&resp.headers, now) {
+            self.note(until);
+        }
+        if !resp.is_success() {
+            if let Some(until) = retry_after_from_headers(&resp.headers, now) {
+                self.note(until);
+                return Err(ErrorKind::BackoffError(until).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// We persist times in milliseconds since the epoch.
+pub(crate) fn system_time_to_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0)
+}
+
+pub(crate) fn system_time_from_ms(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use viaduct::Method;
+
+    fn response(status: u16, headers: &[(&'static str, &'static str)]) -> Response {
+        let mut h = Headers::new();
+        for (name, value) in headers {
+            h.insert(*name, *value).unwrap();
+        }
+        Response {
+            request_method: Method::Get,
+            url: "https://example.com/1.5/123/info/collections"
+                .parse()
+                .unwrap(),
+            status,
+            headers: h,
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn test_backoff_headers() {
+        let now = SystemTime::now();
+        let listener = BackoffListener::default();
+        listener
+            .check_response(&response(200, &[("X-Weave-Backoff", "60")]), now)
+            .unwrap();
+        assert_eq!(listener.get(), Some(now + Duration::from_secs(60)));
+
+        // We keep the latest time we've been asked to wait until.
+        listener
+            .check_response(&response(200, &[("X-Backoff", "30")]), now)
+            .unwrap();
+        assert_eq!(listener.get(), Some(now + Duration::from_secs(60)));
+        listener
+            .check_response(
+                &response(200, &[("X-Weave-Backoff", "10"), ("X-Backoff", "120")]),
+                now,
+            )
+            .unwrap();
+        assert_eq!(listener.get(), Some(now + Duration::from_secs(120)));
+
+        // Invalid values are ignored.
+        let listener = BackoffListener::default();
+        listener
+            .check_response(&response(200, &[("X-Weave-Backoff", "soon")]), now)
+            .unwrap();
+        assert_eq!(listener.get(), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = SystemTime::now();
+        let listener = BackoffListener::default();
+        // Retry-After only matters for failed responses.
+        listener
+            .check_response(&response(200, &[("Retry-After", "60")]), now)
+            .unwrap();
+        assert_eq!(listener.get(), None);
+
+        let err = listener
+            .check_response(&response(503, &[("Retry-After", "60")]), now)
+            .unwrap_err();
+        let expected = now + Duration::from_secs(60);
+        match err.kind() {
+            ErrorKind::BackoffError(until) => assert_eq!(*until, expected),
+            kind => panic!("Wrong error: {:?}", kind),
+        }
+        assert_eq!(listener.get(), Some(expected));
+
+        let listener = BackoffListener::default();
+        assert!(listener
+            .check_response(&response(429, &[("Retry-After", "later")]), now)
+            .is_err());
+        assert_eq!(
+            listener.get(),
+            Some(now + Duration::from_millis(RETRY_AFTER_DEFAULT_MS))
+        );
+
+        // Failures without the header aren't backoffs.
+        assert!(listener.check_response(&response(500, &[]), now).is_ok());
+    }
+
+    #[test]
+    fn test_ms_roundtrip() {
+        let time = system_time_from_ms(1_500_000_000_123);
+        assert_eq!(system_time_to_ms(time), 1_500_000_000_123);
+    }
+}
@@ -18,6 +18,9 @@ pub struct RecordChangeset<Payload> {
     /// For POSTs, this is the XIUS timestamp.
     pub timestamp: ServerTimestamp,
     pub collection: String,
+    /// For GETs that stopped at the request's `limit`, the server's opaque
+    /// token for fetching the next page with `CollectionRequest::offset`.
+    pub next_offset: Option<String>,
 }
 
 pub type IncomingChangeset = RecordChangeset<(Payload, ServerTimestamp)>;
@@ -31,6 +34,7 @@ impl<T> RecordChangeset<T> {
             changes: vec![],
             timestamp,
             collection,
+            next_offset: None,
         }
     }
 }
@@ -65,17 +69,20 @@ impl IncomingChangeset {
         collection: String,
         collection_request: &CollectionRequest,
     ) -> Result<IncomingChangeset> {
-        let (records, timestamp) = match client.get_encrypted_records(collection_request)? {
-            Sync15ClientResponse::Success {
-                record,
-                last_modified,
-                ..
-            } => (record, last_modified),
-            other => return Err(other.create_storage_error().into()),
-        };
+        let (records, timestamp, next_offset) =
+            match client.get_encrypted_records(collection_request)? {
+                Sync15ClientResponse::Success {
+                    record,
+                    last_modified,
+                    next_offset,
+                    ..
+                } => (record, last_modified, next_offset),
+                other => return Err(other.create_storage_error().into()),
+            };
         // xxx - duplication below of `timestamp` smells wrong
         state.last_modified = timestamp;
         let mut result = IncomingChangeset::new(collection, timestamp);
+        result.next_offset = next_offset;
         result.changes.reserve(records.len());
         for record in records {
             // if we see a HMAC error, we've made an explicit decision to
@@ -96,7 +103,7 @@ pub struct CollectionUpdate<'a> {
     state: &'a CollState,
     collection: String,
     xius: ServerTimestamp,
-    to_update: Vec<EncryptedBso>,
+    to_update: Vec<Payload>,
     fully_atomic: bool,
 }
 
@@ -106,7 +113,7 @@ impl<'a> CollectionUpdate<'a> {
         state: &'a CollState,
         collection: String,
         xius: ServerTimestamp,
-        records: Vec<EncryptedBso>,
+        records: Vec<Payload>,
         fully_atomic: bool,
     ) -> CollectionUpdate<'a> {
         CollectionUpdate {
@@ -136,21 +143,30 @@ impl<'a> CollectionUpdate<'a> {
                 .into(),
             );
         }
-        let to_update = changeset.encrypt(&state.key)?;
         Ok(CollectionUpdate::new(
             client,
             state,
             collection,
             xius,
-            to_update,
+            changeset.changes,
             fully_atomic,
         ))
     }
 
     /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
-    /// returns an empty vec.
+    /// returns an empty vec. Records that are too large to upload are skipped, and returned
+    /// in `too_large_ids`, even if the upload is fully atomic, since they'd never succeed.
     pub fn upload(self) -> error::Result<UploadInfo> {
-        let mut failed = vec![];
+        self.upload_with_shrinker(|_| Ok(None))
+    }
+
+    /// Like `upload`, but calls `shrink` with records that are too large to upload. If it
+    /// returns a smaller record, we try that instead; otherwise, the record is skipped.
+    pub fn upload_with_shrinker(
+        self,
+        shrink: impl Fn(Payload) -> std::result::Result<Option<Payload>, failure::Error>,
+    ) -> error::Result<UploadInfo> {
+        let mut too_large = vec![];
         let mut q = self.client.new_post_queue(
             &self.collection,
             &self.state.config,
@@ -158,16 +174,43 @@ impl<'a> CollectionUpdate<'a> {
             NormalResponseHandler::new(!self.fully_atomic),
         )?;
 
-        for record in self.to_update.into_iter() {
-            let enqueued = q.enqueue(&record)?;
-            if !enqueued && self.fully_atomic {
-                return Err(ErrorKind::RecordTooLargeError.into());
+        for mut record in self.to_update.into_iter() {
+            let id = record.id.clone();
+            let mut last_len = None;
+            loop {
+                let bso = record
+                    .clone()
+                    .into_bso(self.collection.clone())
+                    .encrypt(&self.state.key)?;
+                if q.enqueue(&bso)? {
+                    break;
+                }
+                let len = bso.payload.serialized_len();
+                // Give up if the last shrink didn't make the record any
+                // smaller, instead of asking the store forever.
+                let smaller = if last_len.map_or(false, |last_len| len >= last_len) {
+                    None
+                } else {
+                    shrink(record)?
+                };
+                match smaller {
+                    Some(smaller) => {
+                        log::info!("Retrying record {} after shrinking", id);
+                        record = smaller;
+                        last_len = Some(len);
+                    }
+                    None => {
+                        log::warn!("Skipping record {} that's too large ({} b)", id, len);
+                        too_large.push(id);
+                        break;
+                    }
+                }
             }
         }
 
         q.flush(true)?;
         let mut info = q.completed_upload_info();
-        info.failed_ids.append(&mut failed);
+        info.too_large_ids.append(&mut too_large);
         if self.fully_atomic {
             assert_eq!(
                 info.failed_ids.len(),
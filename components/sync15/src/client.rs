@@ -2,6 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::backoff::BackoffListener;
 use crate::bso_record::{BsoRecord, EncryptedBso};
 use crate::error::{self, ErrorKind, ErrorResponse};
 use crate::record_types::MetaGlobalRecord;
@@ -13,6 +14,7 @@ use crate::token;
 use crate::util::ServerTimestamp;
 use serde_json::Value;
 use std::str::FromStr;
+use std::time::SystemTime;
 use url::Url;
 use viaduct::{
     header_names::{self, AUTHORIZATION},
@@ -28,6 +30,9 @@ pub enum Sync15ClientResponse<T> {
         record: T,
         last_modified: ServerTimestamp,
         route: String,
+        /// The opaque `X-Weave-Next-Offset` token, if the server has more
+        /// records than the request's `limit`.
+        next_offset: Option<String>,
     },
     Error(ErrorResponse),
 }
@@ -45,11 +50,16 @@ impl<T> Sync15ClientResponse<T> {
                 .get(header_names::X_LAST_MODIFIED)
                 .and_then(|s| ServerTimestamp::from_str(s).ok())
                 .ok_or_else(|| ErrorKind::MissingServerTimestamp)?;
+            let next_offset = resp
+                .headers
+                .get(header_names::X_WEAVE_NEXT_OFFSET)
+                .map(String::from);
             Sync15ClientResponse::Success {
                 status: resp.status,
                 record,
                 last_modified,
                 route,
+                next_offset,
             }
         } else {
             let status = resp.status;
@@ -57,7 +67,6 @@ impl<T> Sync15ClientResponse<T> {
                 404 => Sync15ClientResponse::Error(ErrorResponse::NotFound { route }),
                 401 => Sync15ClientResponse::Error(ErrorResponse::Unauthorized { route }),
                 412 => Sync15ClientResponse::Error(ErrorResponse::PreconditionFailed { route }),
-                // / TODO: 5XX errors should parse backoff etc.
                 500..=600 => {
                     Sync15ClientResponse::Error(ErrorResponse::ServerError { route, status })
                 }
@@ -109,6 +118,8 @@ pub trait SetupStorageClient {
 #[derive(Debug)]
 pub struct Sync15StorageClient {
     tsc: token::TokenProvider,
+    // Any backoff requested by the storage servers.
+    backoff: BackoffListener,
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -130,11 +141,13 @@ impl SetupStorageClient for Sync15StorageClient {
                 last_modified,
                 route,
                 status,
+                next_offset,
             } => Sync15ClientResponse::Success {
                 record: record.payload,
                 last_modified,
                 route,
                 status,
+                next_offset,
             },
             Sync15ClientResponse::Error(e) => Sync15ClientResponse::Error(e),
         })
@@ -179,7 +192,10 @@ impl Sync15StorageClient {
             init_params.access_token,
             init_params.key_id,
         )?;
-        Ok(Sync15StorageClient { tsc })
+        Ok(Sync15StorageClient {
+            tsc,
+            backoff: BackoffListener::default(),
+        })
     }
 
     pub fn get_encrypted_records(
@@ -231,6 +247,7 @@ impl Sync15StorageClient {
         );
         let resp = req.send()?;
         log::trace!("response: {}", resp.status);
+        self.backoff.check_response(&resp, SystemTime::now())?;
 
         let result = Sync15ClientResponse::from_response(resp)?;
         match result {
@@ -292,6 +309,16 @@ impl Sync15StorageClient {
     pub fn hashed_uid(&self) -> error::Result<String> {
         self.tsc.hashed_uid()
     }
+
+    /// Returns the latest time the tokenserver or the storage servers have
+    /// asked us to back off until, if any.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        self.backoff
+            .get()
+            .into_iter()
+            .chain(self.tsc.backoff_until())
+            .max()
+    }
 }
 
 pub struct PostWrapper<'a> {
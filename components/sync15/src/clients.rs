@@ -0,0 +1,512 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The clients engine, which syncs a record for each of the user's devices
+//! to the `clients` collection.
+//!
+//! Other clients use these records to show the user's devices, like in
+//! desktop's "Connected Devices" list, and to send commands to them by adding
+//! the commands to the device's record. Commands we understand are passed to
+//! the app's `CommandProcessor`, then removed from our record.
+//!
+//! Records are uploaded with a TTL, so that the server expires the records of
+//! devices that stop syncing. We upload our own record whenever it changes,
+//! and at least every `CLIENTS_TTL_REFRESH` so that it doesn't expire.
+//!
+//! The engine doesn't keep any local data, so it always downloads the whole
+//! collection, and resetting it doesn't do anything.
+//!
+//! Apps sync this engine through the sync manager, which builds a
+//! `ClientsStore` from the device settings the app passes with each sync, and
+//! applies the commands to the other engines it syncs once the sync is done.
+
+use crate::bso_record::Payload;
+use crate::changeset::{IncomingChangeset, OutgoingChangeset};
+use crate::coll_state::StoreSyncAssociation;
+use crate::request::CollectionRequest;
+use crate::sync::Store;
+use crate::telemetry;
+use crate::util::ServerTimestamp;
+use serde_derive::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sync_guid::Guid;
+
+const COLLECTION_NAME: &str = "clients";
+
+/// The TTL for client records, in seconds. This matches desktop.
+pub const CLIENTS_TTL: u32 = 21 * 24 * 60 * 60;
+
+/// How often we upload our own record even if it hasn't changed, so that the
+/// server doesn't expire it.
+pub const CLIENTS_TTL_REFRESH: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The kind of device a client runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    Vr,
+    Tv,
+}
+
+impl DeviceType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::Vr => "vr",
+            DeviceType::Tv => "tv",
+        }
+    }
+
+    /// Returns the device type for a client record's `type`, or `None` if we
+    /// don't know it.
+    pub fn from_record_type(s: &str) -> Option<DeviceType> {
+        Some(match s {
+            "desktop" => DeviceType::Desktop,
+            "mobile" => DeviceType::Mobile,
+            "tablet" => DeviceType::Tablet,
+            "vr" => DeviceType::Vr,
+            "tv" => DeviceType::Tv,
+            _ => return None,
+        })
+    }
+}
+
+/// Describes this device, for our client record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// This device's FxA device ID, which we also use as our client ID.
+    pub fxa_device_id: String,
+    pub device_name: String,
+    pub device_type: DeviceType,
+}
+
+/// A command sent to this device by another client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Command {
+    /// Deletes all local data for the named engine, and resets it.
+    Wipe(String),
+    /// Resets the named engine, so that its next sync is a first sync.
+    Reset(String),
+    /// Resets all engines.
+    ResetAll,
+    /// Disconnects this device from the user's account.
+    Logout,
+}
+
+/// Applies commands sent to this device. This is implemented by the app,
+/// which knows about all the engines on the device.
+pub trait CommandProcessor {
+    fn settings(&self) -> &Settings;
+
+    /// Applies an incoming command. The command is removed from our record
+    /// once this returns, so it should ignore commands it doesn't support,
+    /// like wiping an engine it doesn't have. If it fails, the sync fails,
+    /// and the command is retried on the next sync.
+    fn apply_incoming_command(&self, command: Command) -> Result<(), failure::Error>;
+}
+
+/// Another of the user's devices, as described by its client record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteClient {
+    pub id: Guid,
+    pub fxa_device_id: Option<String>,
+    pub device_name: String,
+    /// `None` if the client has a device type we don't know about.
+    pub device_type: Option<DeviceType>,
+    /// When the client last uploaded its record.
+    pub last_modified: ServerTimestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientRecord {
+    id: Guid,
+    name: String,
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(default)]
+    commands: Vec<CommandRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fxa_device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    protocols: Vec<String>,
+}
+
+impl ClientRecord {
+    fn from_settings(settings: &Settings) -> ClientRecord {
+        ClientRecord {
+            id: settings.fxa_device_id.as_str().into(),
+            name: settings.device_name.clone(),
+            typ: settings.device_type.as_str().into(),
+            commands: Vec::new(),
+            fxa_device_id: Some(settings.fxa_device_id.clone()),
+            protocols: vec!["1.5".into()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CommandRecord {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(rename = "flowID", default, skip_serializing_if = "Option::is_none")]
+    flow_id: Option<String>,
+}
+
+impl CommandRecord {
+    fn as_command(&self) -> Option<Command> {
+        match (self.command.as_str(), self.args.as_slice()) {
+            ("wipeEngine", [engine]) => Some(Command::Wipe(engine.clone())),
+            ("resetEngine", [engine]) => Some(Command::Reset(engine.clone())),
+            ("resetAll", []) => Some(Command::ResetAll),
+            ("logout", []) => Some(Command::Logout),
+            _ => None,
+        }
+    }
+}
+
+/// A `Store` for the clients collection.
+pub struct ClientsStore<'a> {
+    processor: &'a dyn CommandProcessor,
+    // We don't persist anything, so we're reset on the first sync after
+    // we're created, which is harmless.
+    assoc: RefCell<StoreSyncAssociation>,
+    remote_clients: RefCell<HashMap<Guid, RemoteClient>>,
+}
+
+impl<'a> ClientsStore<'a> {
+    pub fn new(processor: &'a dyn CommandProcessor) -> ClientsStore<'a> {
+        ClientsStore {
+            processor,
+            assoc: RefCell::new(StoreSyncAssociation::Disconnected),
+            remote_clients: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the user's other devices, as of the last sync, sorted by ID.
+    pub fn remote_clients(&self) -> Vec<RemoteClient> {
+        let mut clients = self
+            .remote_clients
+            .borrow()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        clients.sort_by(|a, b| a.id.cmp(&b.id));
+        clients
+    }
+
+    /// Applies the commands in our record on the server, and returns whether
+    /// we need to upload our record.
+    fn process_own_record(
+        &self,
+        record: ClientRecord,
+        modified: ServerTimestamp,
+        now: ServerTimestamp,
+    ) -> Result<bool, failure::Error> {
+        let mut seen = HashSet::with_capacity(record.commands.len());
+        for command_record in &record.commands {
+            // Several clients might have sent us the same command.
+            if !seen.insert(command_record) {
+                continue;
+            }
+            match command_record.as_command() {
+                Some(command) => {
+                    log::info!("Applying command {:?}", command);
+                    self.processor.apply_incoming_command(command)?;
+                }
+                None => log::warn!("Ignoring unsupported command {}", command_record.command),
+            }
+        }
+        let expected = ClientRecord::from_settings(self.processor.settings());
+        let is_stale = now
+            .duration_since(modified)
+            .map_or(false, |age| age >= CLIENTS_TTL_REFRESH);
+        Ok(record != expected || is_stale)
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+        now: ServerTimestamp,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        let settings = self.processor.settings();
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let mut remote_clients = HashMap::new();
+        let mut upload_own_record = true;
+        for (payload, modified) in inbound.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let record: ClientRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Ignoring invalid client record: {}", e);
+                    incoming_telemetry.failed(1);
+                    continue;
+                }
+            };
+            if record.id == settings.fxa_device_id.as_str() {
+                upload_own_record = self.process_own_record(record, modified, now)?;
+                continue;
+            }
+            incoming_telemetry.applied(1);
+            let client = RemoteClient {
+                id: record.id.clone(),
+                fxa_device_id: record.fxa_device_id,
+                device_name: record.name,
+                device_type: DeviceType::from_record_type(&record.typ),
+                last_modified: modified,
+            };
+            remote_clients.insert(record.id, client);
+        }
+        telem.incoming(incoming_telemetry);
+        self.remote_clients.replace(remote_clients);
+
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), inbound.timestamp);
+        if upload_own_record {
+            let record = ClientRecord::from_settings(settings);
+            outgoing
+                .changes
+                .push(Payload::from_record(record)?.with_ttl(CLIENTS_TTL));
+        }
+        Ok(outgoing)
+    }
+}
+
+impl<'a> Store for ClientsStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| ServerTimestamp(d.as_millis() as i64))
+            .unwrap_or_default();
+        self.do_apply_incoming(inbound, telem, now)
+    }
+
+    fn sync_finished(
+        &self,
+        _new_timestamp: ServerTimestamp,
+        _records_synced: Vec<Guid>,
+    ) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+        // We always fetch every record, since we don't keep the ones we
+        // fetched last time.
+        Ok(CollectionRequest::new(COLLECTION_NAME).full())
+    }
+
+    fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.assoc.borrow().clone())
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> Result<(), failure::Error> {
+        self.assoc.replace(assoc.clone());
+        Ok(())
+    }
+
+    fn wipe(&self) -> Result<(), failure::Error> {
+        self.remote_clients.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct TestProcessor {
+        settings: Settings,
+        commands: RefCell<Vec<Command>>,
+    }
+
+    impl TestProcessor {
+        fn new() -> Self {
+            TestProcessor {
+                settings: Settings {
+                    fxa_device_id: "deviceAAAAAA".into(),
+                    device_name: "Laptop".into(),
+                    device_type: DeviceType::Desktop,
+                },
+                commands: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl CommandProcessor for TestProcessor {
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
+        fn apply_incoming_command(&self, command: Command) -> Result<(), failure::Error> {
+            self.commands.borrow_mut().push(command);
+            Ok(())
+        }
+    }
+
+    fn inbound(records: Vec<(serde_json::Value, i64)>) -> IncomingChangeset {
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0));
+        for (json, modified) in records {
+            inbound
+                .changes
+                .push((Payload::from_json(json).unwrap(), ServerTimestamp(modified)));
+        }
+        inbound
+    }
+
+    fn own_record(commands: serde_json::Value) -> serde_json::Value {
+        json!({
+            "id": "deviceAAAAAA",
+            "name": "Laptop",
+            "type": "desktop",
+            "commands": commands,
+            "fxaDeviceId": "deviceAAAAAA",
+            "protocols": ["1.5"],
+        })
+    }
+
+    const NOW: i64 = 1_600_000_000_000;
+
+    #[test]
+    fn test_first_sync() {
+        let processor = TestProcessor::new();
+        let store = ClientsStore::new(&processor);
+        let mut telem = telemetry::Engine::new("clients");
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(
+                    json!({
+                        "id": "deviceBBBBBB",
+                        "name": "Phone",
+                        "type": "mobile",
+                        "commands": [],
+                        "fxaDeviceId": "fxaBBBBBB",
+                    }),
+                    NOW - 1000,
+                )]),
+                &mut telem,
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.remote_clients(),
+            vec![RemoteClient {
+                id: "deviceBBBBBB".into(),
+                fxa_device_id: Some("fxaBBBBBB".into()),
+                device_name: "Phone".into(),
+                device_type: Some(DeviceType::Mobile),
+                last_modified: ServerTimestamp(NOW - 1000),
+            }]
+        );
+
+        // We don't have a record on the server yet, so we upload one.
+        assert_eq!(outgoing.changes.len(), 1);
+        let payload = &outgoing.changes[0];
+        assert_eq!(payload.id, "deviceAAAAAA");
+        assert_eq!(payload.data["ttl"], json!(CLIENTS_TTL));
+        let bso = payload.clone().into_bso(COLLECTION_NAME.into());
+        assert_eq!(bso.ttl, Some(CLIENTS_TTL));
+        assert_eq!(
+            serde_json::to_value(bso.payload).unwrap(),
+            own_record(json!([]))
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        let processor = TestProcessor::new();
+        let store = ClientsStore::new(&processor);
+        let mut telem = telemetry::Engine::new("clients");
+        let commands = json!([
+            { "command": "wipeEngine", "args": ["bookmarks"], "flowID": "flow1" },
+            { "command": "resetAll", "args": [] },
+            // Sent twice, so we only apply it once.
+            { "command": "logout", "args": [] },
+            { "command": "logout", "args": [] },
+            // We don't support these.
+            { "command": "displayURI", "args": ["https://example.com", "deviceBBBBBB"] },
+            { "command": "resetEngine", "args": [] },
+        ]);
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(own_record(commands), NOW - 1000)]),
+                &mut telem,
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert_eq!(
+            *processor.commands.borrow(),
+            vec![
+                Command::Wipe("bookmarks".into()),
+                Command::ResetAll,
+                Command::Logout
+            ]
+        );
+        // We upload our record without the commands.
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(
+            outgoing.changes[0].data["commands"],
+            serde_json::Value::Array(vec![])
+        );
+        assert!(store.remote_clients().is_empty());
+    }
+
+    #[test]
+    fn test_refresh_own_record() {
+        let processor = TestProcessor::new();
+        let store = ClientsStore::new(&processor);
+
+        // Our record is up to date.
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(own_record(json!([])), NOW - 1000)]),
+                &mut telemetry::Engine::new("clients"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+
+        // Our record is about to expire.
+        let refresh_ms = CLIENTS_TTL_REFRESH.as_millis() as i64;
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(own_record(json!([])), NOW - refresh_ms)]),
+                &mut telemetry::Engine::new("clients"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+
+        // We were renamed.
+        let mut record = own_record(json!([]));
+        record["name"] = json!("Old name");
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(record, NOW - 1000)]),
+                &mut telemetry::Engine::new("clients"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].data["name"], json!("Laptop"));
+    }
+}
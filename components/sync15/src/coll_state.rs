@@ -3,11 +3,11 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::collection_keys::CollectionKeys;
-use crate::error;
+use crate::error::{self, ErrorKind};
 use crate::key_bundle::KeyBundle;
 use crate::request::InfoConfiguration;
 use crate::state::GlobalState;
-use crate::sync::Store;
+use crate::sync::{Store, SyncMode};
 use crate::util::ServerTimestamp;
 use sync_guid::Guid;
 
@@ -61,6 +61,7 @@ pub enum LocalCollState {
 pub struct LocalCollStateMachine<'state> {
     global_state: &'state GlobalState,
     root_key: &'state KeyBundle,
+    mode: SyncMode,
 }
 
 impl<'state> LocalCollStateMachine<'state> {
@@ -108,6 +109,9 @@ impl<'state> LocalCollStateMachine<'state> {
             LocalCollState::NoSuchCollection => unreachable!("the collection is unknown"),
 
             LocalCollState::SyncIdChanged { ids } => {
+                if let SyncMode::DryRun { .. } = self.mode {
+                    return Err(ErrorKind::DryRunResetRequired(store.collection_name()).into());
+                }
                 let assoc = StoreSyncAssociation::Connected(ids);
                 log::info!("Resetting {} store", store.collection_name());
                 store.reset(&assoc)?;
@@ -167,10 +171,12 @@ impl<'state> LocalCollStateMachine<'state> {
         store: &dyn Store,
         global_state: &'state GlobalState,
         root_key: &'state KeyBundle,
+        mode: SyncMode,
     ) -> error::Result<Option<CollState>> {
         let mut gingerbread_man = Self {
             global_state,
             root_key,
+            mode,
         };
         gingerbread_man.run_and_run_as_farst_as_you_can(store)
     }
@@ -280,7 +286,8 @@ mod tests {
         let root_key = KeyBundle::new_random().expect("should work");
         let gs = get_global_state(&root_key);
         let store = TestStore::new("unknown", StoreSyncAssociation::Disconnected);
-        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key).expect("should work");
+        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key, SyncMode::Normal)
+            .expect("should work");
         assert!(cs.is_none(), "unknown collection name can't sync");
         assert_eq!(store.get_num_resets(), 0);
     }
@@ -290,7 +297,8 @@ mod tests {
         let root_key = KeyBundle::new_random().expect("should work");
         let gs = get_global_state(&root_key);
         let store = TestStore::new("bookmarks", StoreSyncAssociation::Disconnected);
-        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key).expect("should work");
+        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key, SyncMode::Normal)
+            .expect("should work");
         assert!(cs.is_some(), "collection can sync");
         assert_eq!(
             store.assoc.replace(StoreSyncAssociation::Disconnected),
@@ -313,7 +321,8 @@ mod tests {
                 coll: "syncIDYYYYYY".into(),
             }),
         );
-        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key).expect("should work");
+        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key, SyncMode::Normal)
+            .expect("should work");
         assert!(cs.is_some(), "collection can sync");
         assert_eq!(
             store.assoc.replace(StoreSyncAssociation::Disconnected),
@@ -325,6 +334,31 @@ mod tests {
         assert_eq!(store.get_num_resets(), 1);
     }
 
+    #[test]
+    fn test_dry_run_wrong_state() {
+        let root_key = KeyBundle::new_random().expect("should work");
+        let gs = get_global_state(&root_key);
+        let store = TestStore::new(
+            "bookmarks",
+            StoreSyncAssociation::Connected(CollSyncIds {
+                global: "syncIDXXXXXX".into(),
+                coll: "syncIDYYYYYY".into(),
+            }),
+        );
+        let err = LocalCollStateMachine::get_state(
+            &store,
+            &gs,
+            &root_key,
+            SyncMode::DryRun { include_ids: false },
+        )
+        .expect_err("dry runs can't reset the store");
+        match err.kind() {
+            ErrorKind::DryRunResetRequired(name) => assert_eq!(*name, "bookmarks"),
+            e => panic!("Unexpected error {}", e),
+        }
+        assert_eq!(store.get_num_resets(), 0);
+    }
+
     #[test]
     fn test_known_good_state() {
         let root_key = KeyBundle::new_random().expect("should work");
@@ -336,7 +370,8 @@ mod tests {
                 coll: "syncIDBBBBBB".into(),
             }),
         );
-        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key).expect("should work");
+        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key, SyncMode::Normal)
+            .expect("should work");
         assert!(cs.is_some(), "collection can sync");
         assert_eq!(store.get_num_resets(), 0);
     }
@@ -353,9 +388,9 @@ mod tests {
                 coll: "syncIDBBBBBB".into(),
             }),
         );
-        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key).expect("should work");
+        let cs = LocalCollStateMachine::get_state(&store, &gs, &root_key, SyncMode::Normal)
+            .expect("should work");
         assert!(cs.is_none(), "declined collection can sync");
         assert_eq!(store.get_num_resets(), 0);
     }
-
 }
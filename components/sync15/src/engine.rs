@@ -0,0 +1,665 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A higher-level, typed alternative to implementing `Store` directly.
+//!
+//! A `SyncEngine` only needs to merge incoming records with its local data,
+//! and return the records it wants to upload. Wrapping it in an `EngineStore`
+//! takes care of everything else: parsing payloads into the engine's record
+//! type, tracking the last sync time and sync IDs, and keeping a mirror of the
+//! records on the server, so that engines can do three-way merges.
+//!
+//! All of this state lives in the engine's own database, in these tables,
+//! which are created by `EngineStore::new`. Each is keyed by collection name,
+//! so several engines can share a database.
+//!
+//! - `sync15_engine_meta`: A key-value table for the last sync time and sync
+//!   IDs.
+//! - `sync15_engine_mirror`: The last version of each record that we
+//!   downloaded from, or uploaded to, the server.
+//! - `temp.sync15_engine_incoming`: The records downloaded in the current
+//!   sync. Engines that would rather merge in SQL can join against this table
+//!   in `SyncEngine::apply_incoming`.
+//! - `temp.sync15_engine_outgoing`: The records we're about to upload, which
+//!   are moved into the mirror once the server accepts them.
+
+use crate::bso_record::Payload;
+use crate::changeset::{IncomingChangeset, OutgoingChangeset};
+use crate::coll_state::{CollSyncIds, StoreSyncAssociation};
+use crate::request::CollectionRequest;
+use crate::sync::Store;
+use crate::telemetry;
+use crate::util::ServerTimestamp;
+use rusqlite::types::{FromSql, ToSql};
+use rusqlite::{named_params, Connection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sql_support::ConnExt;
+use sync_guid::Guid;
+
+const CREATE_TABLES_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS sync15_engine_meta (
+        collection TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value NOT NULL,
+        PRIMARY KEY (collection, key)
+    ) WITHOUT ROWID;
+
+    CREATE TABLE IF NOT EXISTS sync15_engine_mirror (
+        collection TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        server_modified INTEGER NOT NULL,
+        payload TEXT NOT NULL,
+        PRIMARY KEY (collection, guid)
+    ) WITHOUT ROWID;
+
+    CREATE TEMP TABLE IF NOT EXISTS sync15_engine_incoming (
+        collection TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        server_modified INTEGER NOT NULL,
+        is_deleted TINYINT NOT NULL,
+        payload TEXT NOT NULL,
+        PRIMARY KEY (collection, guid)
+    ) WITHOUT ROWID;
+
+    CREATE TEMP TABLE IF NOT EXISTS sync15_engine_outgoing (
+        collection TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        is_deleted TINYINT NOT NULL,
+        payload TEXT NOT NULL,
+        PRIMARY KEY (collection, guid)
+    ) WITHOUT ROWID;
+";
+
+const LAST_SYNC_META_KEY: &str = "last_sync_time";
+const GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
+const COLLECTION_SYNCID_META_KEY: &str = "collection_sync_id";
+
+/// A record downloaded from the server, along with the last version of it
+/// that we saw.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncomingRecord<T> {
+    pub id: Guid,
+    pub modified: ServerTimestamp,
+    /// The incoming record, or `None` if it's a tombstone.
+    pub record: Option<T>,
+    /// The mirrored copy of this record from a previous sync, or `None` if
+    /// this is the first time we've seen it.
+    pub mirror: Option<T>,
+}
+
+/// A record that an engine wants to upload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutgoingRecord<T> {
+    Record(T),
+    Tombstone(Guid),
+}
+
+/// An engine that syncs records of type `T`. `T` must serialize to, and
+/// deserialize from, a JSON object with an `id` field; that object is the
+/// cleartext payload of the record on the server.
+///
+/// Like `Store`, engines return `failure::Error`s, which we expose as
+/// `ErrorKind::StoreError`.
+pub trait SyncEngine<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn collection_name(&self) -> &'static str;
+
+    /// The connection that holds the engine's sync state.
+    fn conn(&self) -> &Connection;
+
+    /// Merges incoming records with local data, and returns the local records
+    /// that should be uploaded. Records that fail to parse are reported in
+    /// `telem`, and aren't passed to the engine.
+    fn apply_incoming(
+        &self,
+        incoming: Vec<IncomingRecord<T>>,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<Vec<OutgoingRecord<T>>, failure::Error>;
+
+    /// Called after the records in `records_synced` were uploaded, so that
+    /// the engine can mark them as synced.
+    fn sync_finished(&self, records_synced: &[Guid]) -> Result<(), failure::Error>;
+
+    /// Resets local sync state, so that every local record is uploaded on
+    /// the next sync. The last sync time, sync IDs and mirror are reset by
+    /// the `EngineStore`.
+    fn reset(&self) -> Result<(), failure::Error>;
+
+    fn wipe(&self) -> Result<(), failure::Error>;
+}
+
+/// Implements `Store` for a `SyncEngine`.
+pub struct EngineStore<'a, T> {
+    engine: &'a dyn SyncEngine<T>,
+}
+
+impl<'a, T> EngineStore<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Wraps `engine`, creating the tables for its sync state if they don't
+    /// exist yet.
+    pub fn new(engine: &'a dyn SyncEngine<T>) -> rusqlite::Result<Self> {
+        engine.conn().execute_batch(CREATE_TABLES_SQL)?;
+        Ok(EngineStore { engine })
+    }
+
+    fn put_meta(&self, key: &str, value: &dyn ToSql) -> rusqlite::Result<()> {
+        self.engine.conn().execute_named_cached(
+            "REPLACE INTO sync15_engine_meta (collection, key, value)
+             VALUES (:collection, :key, :value)",
+            named_params! {
+                ":collection": self.engine.collection_name(),
+                ":key": key,
+                ":value": value,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn get_meta<V: FromSql>(&self, key: &str) -> rusqlite::Result<Option<V>> {
+        self.engine.conn().try_query_row(
+            "SELECT value FROM sync15_engine_meta
+             WHERE collection = :collection AND key = :key",
+            named_params! {
+                ":collection": self.engine.collection_name(),
+                ":key": key,
+            },
+            |row| row.get(0),
+            true,
+        )
+    }
+
+    fn stage_incoming(&self, changes: Vec<(Payload, ServerTimestamp)>) -> rusqlite::Result<()> {
+        let conn = self.engine.conn();
+        let collection = self.engine.collection_name();
+        let tx = conn.unchecked_transaction()?;
+        for table in &["sync15_engine_incoming", "sync15_engine_outgoing"] {
+            conn.execute_named_cached(
+                &format!("DELETE FROM {} WHERE collection = :collection", table),
+                named_params! { ":collection": collection },
+            )?;
+        }
+        for (payload, modified) in changes {
+            let guid = payload.id.clone();
+            let is_deleted = payload.is_tombstone();
+            conn.execute_named_cached(
+                "INSERT OR REPLACE INTO sync15_engine_incoming
+                    (collection, guid, server_modified, is_deleted, payload)
+                 VALUES (:collection, :guid, :modified, :is_deleted, :payload)",
+                named_params! {
+                    ":collection": collection,
+                    ":guid": guid,
+                    ":modified": modified.0,
+                    ":is_deleted": is_deleted,
+                    ":payload": payload.into_json_string(),
+                },
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn fetch_incoming(
+        &self,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> rusqlite::Result<Vec<IncomingRecord<T>>> {
+        let rows: Vec<(Guid, i64, String, Option<String>)> =
+            self.engine.conn().query_rows_and_then_named_cached(
+                "SELECT s.guid, s.server_modified, s.payload, m.payload
+                 FROM sync15_engine_incoming s
+                 LEFT JOIN sync15_engine_mirror m
+                    ON m.collection = s.collection AND m.guid = s.guid
+                 WHERE s.collection = :collection",
+                named_params! { ":collection": self.engine.collection_name() },
+                |row| {
+                    Ok::<_, rusqlite::Error>((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                },
+            )?;
+        let mut incoming = Vec::with_capacity(rows.len());
+        for (id, modified, payload, mirror) in rows {
+            let record = match parse_record(&payload) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Failed to parse incoming record {}: {}", id, e);
+                    telem.failed(1);
+                    continue;
+                }
+            };
+            let mirror = mirror.and_then(|mirror| match parse_record(&mirror) {
+                Ok(mirror) => mirror,
+                Err(e) => {
+                    log::warn!("Ignoring unparseable mirror record {}: {}", id, e);
+                    None
+                }
+            });
+            incoming.push(IncomingRecord {
+                id,
+                modified: ServerTimestamp(modified),
+                record,
+                mirror,
+            });
+        }
+        Ok(incoming)
+    }
+
+    fn stage_outgoing(&self, payloads: &[Payload]) -> rusqlite::Result<()> {
+        let conn = self.engine.conn();
+        let collection = self.engine.collection_name();
+        let tx = conn.unchecked_transaction()?;
+        // The incoming records were applied, so they're now the latest
+        // versions we know about.
+        conn.execute_named_cached(
+            "DELETE FROM sync15_engine_mirror
+             WHERE collection = :collection AND guid IN (
+                SELECT guid FROM sync15_engine_incoming
+                WHERE collection = :collection AND is_deleted
+             )",
+            named_params! { ":collection": collection },
+        )?;
+        conn.execute_named_cached(
+            "REPLACE INTO sync15_engine_mirror (collection, guid, server_modified, payload)
+             SELECT collection, guid, server_modified, payload
+             FROM sync15_engine_incoming
+             WHERE collection = :collection AND NOT is_deleted",
+            named_params! { ":collection": collection },
+        )?;
+        conn.execute_named_cached(
+            "DELETE FROM sync15_engine_incoming WHERE collection = :collection",
+            named_params! { ":collection": collection },
+        )?;
+        for payload in payloads {
+            conn.execute_named_cached(
+                "INSERT OR REPLACE INTO sync15_engine_outgoing
+                    (collection, guid, is_deleted, payload)
+                 VALUES (:collection, :guid, :is_deleted, :payload)",
+                named_params! {
+                    ":collection": collection,
+                    ":guid": payload.id,
+                    ":is_deleted": payload.is_tombstone(),
+                    ":payload": payload.clone().into_json_string(),
+                },
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn mirror_outgoing(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[Guid],
+    ) -> rusqlite::Result<()> {
+        let conn = self.engine.conn();
+        let collection = self.engine.collection_name();
+        let tx = conn.unchecked_transaction()?;
+        for guid in records_synced {
+            conn.execute_named_cached(
+                "DELETE FROM sync15_engine_mirror
+                 WHERE collection = :collection AND guid = :guid",
+                named_params! { ":collection": collection, ":guid": guid },
+            )?;
+            conn.execute_named_cached(
+                "INSERT INTO sync15_engine_mirror (collection, guid, server_modified, payload)
+                 SELECT collection, guid, :modified, payload
+                 FROM sync15_engine_outgoing
+                 WHERE collection = :collection AND guid = :guid AND NOT is_deleted",
+                named_params! {
+                    ":collection": collection,
+                    ":guid": guid,
+                    ":modified": new_timestamp.0,
+                },
+            )?;
+        }
+        conn.execute_named_cached(
+            "DELETE FROM sync15_engine_outgoing WHERE collection = :collection",
+            named_params! { ":collection": collection },
+        )?;
+        self.put_meta(LAST_SYNC_META_KEY, &new_timestamp.0)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn reset_sync_state(&self, assoc: &StoreSyncAssociation) -> rusqlite::Result<()> {
+        let conn = self.engine.conn();
+        let tx = conn.unchecked_transaction()?;
+        for table in &[
+            "sync15_engine_meta",
+            "sync15_engine_mirror",
+            "sync15_engine_incoming",
+            "sync15_engine_outgoing",
+        ] {
+            conn.execute_named_cached(
+                &format!("DELETE FROM {} WHERE collection = :collection", table),
+                named_params! { ":collection": self.engine.collection_name() },
+            )?;
+        }
+        if let StoreSyncAssociation::Connected(ids) = assoc {
+            self.put_meta(GLOBAL_SYNCID_META_KEY, &ids.global)?;
+            self.put_meta(COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+// Returns `None` for tombstones.
+fn parse_record<T: DeserializeOwned>(payload: &str) -> Result<Option<T>, serde_json::Error> {
+    let payload: Payload = serde_json::from_str(payload)?;
+    if payload.is_tombstone() {
+        return Ok(None);
+    }
+    Ok(Some(payload.into_record()?))
+}
+
+impl<'a, T> Store for EngineStore<'a, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn collection_name(&self) -> &'static str {
+        self.engine.collection_name()
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        let mut outgoing = OutgoingChangeset::new(inbound.collection, inbound.timestamp);
+        self.stage_incoming(inbound.changes)?;
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let result = self
+            .fetch_incoming(&mut incoming_telemetry)
+            .map_err(failure::Error::from)
+            .and_then(|incoming| {
+                self.engine
+                    .apply_incoming(incoming, &mut incoming_telemetry)
+            });
+        telem.incoming(incoming_telemetry);
+        for record in result? {
+            outgoing.changes.push(match record {
+                OutgoingRecord::Record(record) => Payload::from_record(record)?,
+                OutgoingRecord::Tombstone(id) => Payload::new_tombstone(id.into_string()),
+            });
+        }
+        self.stage_outgoing(&outgoing.changes)?;
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> Result<(), failure::Error> {
+        self.mirror_outgoing(new_timestamp, &records_synced)?;
+        self.engine.sync_finished(&records_synced)
+    }
+
+    fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+        let last_sync = self
+            .get_meta::<i64>(LAST_SYNC_META_KEY)?
+            .unwrap_or_default();
+        Ok(CollectionRequest::new(self.engine.collection_name())
+            .full()
+            .newer_than(ServerTimestamp(last_sync)))
+    }
+
+    fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error> {
+        let global = self.get_meta::<Guid>(GLOBAL_SYNCID_META_KEY)?;
+        let coll = self.get_meta::<Guid>(COLLECTION_SYNCID_META_KEY)?;
+        Ok(match (global, coll) {
+            (Some(global), Some(coll)) => {
+                StoreSyncAssociation::Connected(CollSyncIds { global, coll })
+            }
+            _ => StoreSyncAssociation::Disconnected,
+        })
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> Result<(), failure::Error> {
+        self.reset_sync_state(assoc)?;
+        self.engine.reset()
+    }
+
+    fn wipe(&self) -> Result<(), failure::Error> {
+        self.engine.wipe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_derive::*;
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct TestRecord {
+        id: Guid,
+        title: String,
+    }
+
+    fn record(id: &str, title: &str) -> TestRecord {
+        TestRecord {
+            id: id.into(),
+            title: title.into(),
+        }
+    }
+
+    struct TestEngine {
+        conn: Connection,
+        incoming: RefCell<Vec<IncomingRecord<TestRecord>>>,
+        outgoing: RefCell<Vec<OutgoingRecord<TestRecord>>>,
+        failed: RefCell<u32>,
+        synced: RefCell<Vec<Guid>>,
+    }
+
+    impl TestEngine {
+        fn new() -> Self {
+            TestEngine {
+                conn: Connection::open_in_memory().unwrap(),
+                incoming: RefCell::default(),
+                outgoing: RefCell::default(),
+                failed: RefCell::default(),
+                synced: RefCell::default(),
+            }
+        }
+    }
+
+    impl SyncEngine<TestRecord> for TestEngine {
+        fn collection_name(&self) -> &'static str {
+            "test"
+        }
+
+        fn conn(&self) -> &Connection {
+            &self.conn
+        }
+
+        fn apply_incoming(
+            &self,
+            incoming: Vec<IncomingRecord<TestRecord>>,
+            telem: &mut telemetry::EngineIncoming,
+        ) -> Result<Vec<OutgoingRecord<TestRecord>>, failure::Error> {
+            self.failed.replace(telem.get_failed());
+            self.incoming.replace(incoming);
+            Ok(self.outgoing.replace(Vec::new()))
+        }
+
+        fn sync_finished(&self, records_synced: &[Guid]) -> Result<(), failure::Error> {
+            self.synced.replace(records_synced.to_vec());
+            Ok(())
+        }
+
+        fn reset(&self) -> Result<(), failure::Error> {
+            Ok(())
+        }
+
+        fn wipe(&self) -> Result<(), failure::Error> {
+            Ok(())
+        }
+    }
+
+    fn inbound(changes: Vec<(serde_json::Value, i64)>, timestamp: i64) -> IncomingChangeset {
+        let mut inbound = IncomingChangeset::new("test".into(), ServerTimestamp(timestamp));
+        inbound.changes = changes
+            .into_iter()
+            .map(|(json, modified)| (Payload::from_json(json).unwrap(), ServerTimestamp(modified)))
+            .collect();
+        inbound
+    }
+
+    fn sorted_incoming(engine: &TestEngine) -> Vec<IncomingRecord<TestRecord>> {
+        let mut incoming = engine.incoming.replace(Vec::new());
+        incoming.sort_by(|a, b| a.id.cmp(&b.id));
+        incoming
+    }
+
+    #[test]
+    fn test_sync_assoc_and_last_sync() {
+        let engine = TestEngine::new();
+        let store = EngineStore::new(&engine).unwrap();
+        assert_eq!(
+            store.get_sync_assoc().unwrap(),
+            StoreSyncAssociation::Disconnected
+        );
+        assert_eq!(
+            store.get_collection_request().unwrap().newer,
+            Some(ServerTimestamp(0))
+        );
+
+        let ids = CollSyncIds {
+            global: Guid::random(),
+            coll: Guid::random(),
+        };
+        store
+            .reset(&StoreSyncAssociation::Connected(ids.clone()))
+            .unwrap();
+        store.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+        assert_eq!(
+            store.get_sync_assoc().unwrap(),
+            StoreSyncAssociation::Connected(ids)
+        );
+        assert_eq!(
+            store.get_collection_request().unwrap().newer,
+            Some(ServerTimestamp(1000))
+        );
+
+        store.reset(&StoreSyncAssociation::Disconnected).unwrap();
+        assert_eq!(
+            store.get_sync_assoc().unwrap(),
+            StoreSyncAssociation::Disconnected
+        );
+        assert_eq!(
+            store.get_collection_request().unwrap().newer,
+            Some(ServerTimestamp(0))
+        );
+    }
+
+    #[test]
+    fn test_apply_incoming() {
+        let engine = TestEngine::new();
+        let store = EngineStore::new(&engine).unwrap();
+
+        engine.outgoing.replace(vec![
+            OutgoingRecord::Record(record("bbbbbbbbbbbb", "local")),
+            OutgoingRecord::Tombstone("cccccccccccc".into()),
+        ]);
+        let outgoing = store
+            .apply_incoming(
+                inbound(
+                    vec![
+                        (json!({"id": "aaaaaaaaaaaa", "title": "remote"}), 1000),
+                        (json!({"id": "dddddddddddd", "deleted": true}), 1000),
+                        (json!({"id": "eeeeeeeeeeee", "title": 123}), 1000),
+                    ],
+                    1000,
+                ),
+                &mut telemetry::Engine::new("test"),
+            )
+            .unwrap();
+        assert_eq!(*engine.failed.borrow(), 1);
+        assert_eq!(
+            sorted_incoming(&engine),
+            vec![
+                IncomingRecord {
+                    id: "aaaaaaaaaaaa".into(),
+                    modified: ServerTimestamp(1000),
+                    record: Some(record("aaaaaaaaaaaa", "remote")),
+                    mirror: None,
+                },
+                IncomingRecord {
+                    id: "dddddddddddd".into(),
+                    modified: ServerTimestamp(1000),
+                    record: None,
+                    mirror: None,
+                },
+            ]
+        );
+        assert_eq!(outgoing.collection, "test");
+        assert_eq!(
+            outgoing.changes,
+            vec![
+                Payload::from_json(json!({"id": "bbbbbbbbbbbb", "title": "local"})).unwrap(),
+                Payload::new_tombstone("cccccccccccc".into()),
+            ]
+        );
+        store
+            .sync_finished(
+                ServerTimestamp(2000),
+                vec!["bbbbbbbbbbbb".into(), "cccccccccccc".into()],
+            )
+            .unwrap();
+        assert_eq!(
+            *engine.synced.borrow(),
+            vec![Guid::from("bbbbbbbbbbbb"), Guid::from("cccccccccccc")]
+        );
+
+        // The next sync sees the records we downloaded and uploaded last time
+        // as the mirror.
+        store
+            .apply_incoming(
+                inbound(
+                    vec![
+                        (json!({"id": "aaaaaaaaaaaa", "title": "remote 2"}), 3000),
+                        (json!({"id": "bbbbbbbbbbbb", "title": "remote 3"}), 3000),
+                    ],
+                    3000,
+                ),
+                &mut telemetry::Engine::new("test"),
+            )
+            .unwrap();
+        assert_eq!(
+            sorted_incoming(&engine),
+            vec![
+                IncomingRecord {
+                    id: "aaaaaaaaaaaa".into(),
+                    modified: ServerTimestamp(3000),
+                    record: Some(record("aaaaaaaaaaaa", "remote 2")),
+                    mirror: Some(record("aaaaaaaaaaaa", "remote")),
+                },
+                IncomingRecord {
+                    id: "bbbbbbbbbbbb".into(),
+                    modified: ServerTimestamp(3000),
+                    record: Some(record("bbbbbbbbbbbb", "remote 3")),
+                    mirror: Some(record("bbbbbbbbbbbb", "local")),
+                },
+            ]
+        );
+
+        // Resetting forgets the mirror.
+        store.reset(&StoreSyncAssociation::Disconnected).unwrap();
+        store
+            .apply_incoming(
+                inbound(
+                    vec![(json!({"id": "aaaaaaaaaaaa", "title": "remote 2"}), 3000)],
+                    3000,
+                ),
+                &mut telemetry::Engine::new("test"),
+            )
+            .unwrap();
+        assert_eq!(sorted_incoming(&engine)[0].mirror, None);
+    }
+}
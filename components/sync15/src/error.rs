@@ -16,8 +16,8 @@ pub enum ErrorResponse {
     Unauthorized { route: String },
     // 412
     PreconditionFailed { route: String },
-    // 5XX
-    ServerError { route: String, status: u16 }, // TODO: info for "retry-after" and backoff handling etc here.
+    // 5XX. Responses with a `Retry-After` header are `BackoffError`s instead.
+    ServerError { route: String, status: u16 },
     // Other HTTP responses.
     RequestFailed { route: String, status: u16 },
 }
@@ -77,6 +77,18 @@ pub enum ErrorKind {
     #[fail(display = "Our storage needs setting up and we can't currently do it")]
     SetupRequired,
 
+    #[fail(display = "The {} store doesn't support dry-run syncs", _0)]
+    DryRunUnsupported(&'static str),
+
+    // A dry-run sync can't reset a store whose sync IDs changed, since the
+    // store can't undo the reset afterward. This includes a store that's
+    // never been synced, which doesn't have any sync IDs yet.
+    #[fail(
+        display = "The {} store needs to be reset, which a dry run can't do",
+        _0
+    )]
+    DryRunResetRequired(&'static str),
+
     #[fail(display = "Store error: {}", _0)]
     StoreError(#[fail(cause)] failure::Error),
 
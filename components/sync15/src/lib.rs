@@ -5,11 +5,14 @@
 #![allow(unknown_lints)]
 #![warn(rust_2018_idioms)]
 
+mod backoff;
 mod bso_record;
 mod changeset;
 mod client;
+pub mod clients;
 mod coll_state;
 mod collection_keys;
+mod engine;
 mod error;
 mod key_bundle;
 mod migrate_state;
@@ -28,12 +31,15 @@ pub use crate::bso_record::{BsoRecord, CleartextBso, EncryptedBso, EncryptedPayl
 pub use crate::changeset::{IncomingChangeset, OutgoingChangeset, RecordChangeset};
 pub use crate::client::{SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit};
 pub use crate::coll_state::{CollState, CollSyncIds, StoreSyncAssociation};
+pub use crate::engine::{EngineStore, IncomingRecord, OutgoingRecord, SyncEngine};
 pub use crate::error::{Error, ErrorKind, Result};
 pub use crate::key_bundle::KeyBundle;
 pub use crate::migrate_state::extract_v1_state;
-pub use crate::request::CollectionRequest;
+pub use crate::request::{CollectionRequest, RequestOrder};
 pub use crate::state::{GlobalState, SetupStateMachine};
 pub use crate::status::{ServiceStatus, SyncResult};
-pub use crate::sync::{synchronize, Store};
-pub use crate::sync_multiple::{sync_multiple, MemoryCachedState};
+pub use crate::sync::{synchronize, PlannedChanges, Store, SyncMode};
+pub use crate::sync_multiple::{
+    sync_multiple, sync_multiple_in_parallel, MemoryCachedState, StoreFactory, SyncOptions,
+};
 pub use crate::util::{ServerTimestamp, SERVER_EPOCH};
@@ -46,6 +46,7 @@ pub fn extract_v1_state(
     };
     let pgs = PersistedGlobalState::V2 {
         declined: Some(meta_global.declined),
+        next_sync_after: None,
     };
     let new_global_state = serde_json::to_string(&pgs).ok();
 
@@ -132,6 +133,7 @@ mod tests {
         // state reflects that.
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(Vec::<String>::new()),
+            next_sync_after: None,
         })
         .expect("should stringify");
         assert_eq!(new_state, Some(expected_state));
@@ -157,6 +159,7 @@ mod tests {
         let s = get_state_with_engine_changes_and_declined("", "\\\"foo\\\"");
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(vec!["foo".to_string()]),
+            next_sync_after: None,
         })
         .unwrap();
         assert_eq!(
@@ -39,6 +39,7 @@ pub struct CollectionRequest {
     pub full: bool,
     pub ids: Option<Vec<Guid>>,
     pub limit: usize,
+    pub offset: Option<String>,
     pub older: Option<ServerTimestamp>,
     pub newer: Option<ServerTimestamp>,
     pub order: Option<RequestOrder>,
@@ -57,6 +58,7 @@ impl CollectionRequest {
             full: false,
             ids: None,
             limit: 0,
+            offset: None,
             older: None,
             newer: None,
             order: None,
@@ -104,6 +106,16 @@ impl CollectionRequest {
         self
     }
 
+    /// Continues a paged request from the `X-Weave-Next-Offset` token that
+    /// the server returned for the previous page. The token is opaque, and
+    /// only means something for the same `sort`, `limit` and filters, so we
+    /// send it back exactly as we got it.
+    #[inline]
+    pub fn offset(mut self, offset: Option<String>) -> CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
     #[inline]
     pub fn batch(mut self, batch: Option<String>) -> CollectionRequest {
         self.batch = batch;
@@ -123,6 +135,9 @@ impl CollectionRequest {
         if self.limit > 0 {
             pairs.append_pair("limit", &format!("{}", self.limit));
         }
+        if let Some(offset) = &self.offset {
+            pairs.append_pair("offset", offset);
+        }
         if let Some(ids) = &self.ids {
             pairs.append_pair(
                 "ids",
@@ -612,6 +627,8 @@ where
 pub struct UploadInfo {
     pub successful_ids: Vec<Guid>,
     pub failed_ids: Vec<Guid>,
+    /// Records that we didn't upload because they were too large.
+    pub too_large_ids: Vec<Guid>,
     pub modified_timestamp: ServerTimestamp,
 }
 
@@ -625,6 +642,7 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
                     + self.on_response.pending_failed.len()
                     + self.on_response.pending_success.len(),
             ),
+            too_large_ids: Vec::new(),
             modified_timestamp: self.last_modified,
         };
 
@@ -691,13 +709,14 @@ mod test {
         let complex = CollectionRequest::new("specific")
             .full()
             .limit(10)
+            .offset(Some("20:abc".into()))
             .sort_by(RequestOrder::Oldest)
             .older_than(ServerTimestamp(9_876_540))
             .newer_than(ServerTimestamp(1_234_560))
             .build_url(base.clone())
             .unwrap();
         assert_eq!(complex.as_str(),
-            "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
+            "https://example.com/sync/storage/specific?full=1&limit=10&offset=20%3Aabc&older=9876.54&newer=1234.56&sort=oldest");
     }
 
     #[derive(Debug, Clone)]
@@ -894,6 +913,7 @@ mod test {
                 success: vec![],
             },
             route: "test/path".into(),
+            next_offset: None,
         }
     }
 
@@ -1401,5 +1421,4 @@ mod test {
     // - mixed bytes/record limits
     //
     // A lot of these have good examples in test_postqueue.js on deskftop sync
-
 }
@@ -3,8 +3,9 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::HashMap;
-use std::mem;
+use std::time::SystemTime;
 
+use crate::backoff;
 use crate::bso_record::EncryptedBso;
 use crate::client::{SetupStorageClient, Sync15ClientResponse};
 use crate::collection_keys::CollectionKeys;
@@ -45,31 +46,75 @@ lazy_static! {
 }
 
 /// State that we require the app to persist to storage for us.
-/// It's a little unfortunate we need this, because it's only tracking
+/// It's a little unfortunate we need this, because it's mostly tracking
 /// "declined engines", and even then, only needed in practice when there's
 /// no meta/global so we need to create one. It's extra unfortunate because we
 /// want to move away from "globally declined" engines anyway, moving towards
 /// allowing engines to be enabled or disabled per client rather than globally.
+/// (It also tracks when the servers have asked us to back off, so that we
+/// honor that across syncs.)
 ///
 /// Apps are expected to treat this as opaque, so we support serializing it.
 /// Note that this structure is *not* used to *change* the declined engines
-/// list - that will be done in the future, but the API exposed for that
-/// purpose will also take a mutable PersistedGlobalState.
+/// list - that's done by passing the engines the user enabled or declined to
+/// `sync_multiple`, which updates this structure with the result.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "schema_version")]
 pub enum PersistedGlobalState {
     /// V1 was when we persisted the entire GlobalState, keys and all!
 
-    /// V2 is just tracking the globally declined list.
+    /// V2 is tracking the globally declined list.
     /// None means "I've no idea" and theoretically should only happen on the
     /// very first sync for an app.
-    V2 { declined: Option<Vec<String>> },
+    /// `next_sync_after` is the time, in milliseconds, before which the
+    /// servers asked us not to sync, if any.
+    V2 {
+        declined: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_sync_after: Option<u64>,
+    },
 }
 
 impl Default for PersistedGlobalState {
     #[inline]
     fn default() -> PersistedGlobalState {
-        PersistedGlobalState::V2 { declined: None }
+        PersistedGlobalState::V2 {
+            declined: None,
+            next_sync_after: None,
+        }
+    }
+}
+
+impl PersistedGlobalState {
+    /// Returns the time before which the servers asked us not to sync, if any.
+    pub(crate) fn next_sync_after(&self) -> Option<SystemTime> {
+        match self {
+            PersistedGlobalState::V2 {
+                next_sync_after, ..
+            } => next_sync_after.map(backoff::system_time_from_ms),
+        }
+    }
+
+    pub(crate) fn set_next_sync_after(&mut self, time: Option<SystemTime>) {
+        match self {
+            PersistedGlobalState::V2 {
+                next_sync_after, ..
+            } => *next_sync_after = time.map(backoff::system_time_to_ms),
+        }
+    }
+
+    fn set_declined(&mut self, new_declined: Vec<String>) {
+        match self {
+            PersistedGlobalState::V2 { declined, .. } => *declined = Some(new_declined),
+        }
+    }
+
+    /// Returns the declined engines from the last `meta/global` we saw, if
+    /// we've seen one.
+    pub fn get_declined(&self) -> Option<&[String]> {
+        match self {
+            PersistedGlobalState::V2 { declined, .. } => declined.as_ref().map(Vec::as_slice),
+        }
     }
 }
 
@@ -106,7 +151,9 @@ fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
     // we previously saw a meta/global then we would have updated it with what
     // it was at the time.
     let declined = match pgs {
-        PersistedGlobalState::V2 { declined: Some(d) } => d.clone(),
+        PersistedGlobalState::V2 {
+            declined: Some(d), ..
+        } => d.clone(),
         _ => {
             log::warn!("New meta/global without local app state - the list of declined engines is being reset");
             DEFAULT_DECLINED.iter().map(ToString::to_string).collect()
@@ -121,6 +168,46 @@ fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
     })
 }
 
+/// Merges the engines the user enabled (`true`) or declined (`false`) on this
+/// device into a `meta/global` record. Returns `true` if the record changed,
+/// and needs to be uploaded.
+fn apply_engine_updates(global: &mut MetaGlobalRecord, updates: &HashMap<String, bool>) -> bool {
+    let mut changed = false;
+    for (name, &enabled) in updates {
+        let is_declined = global.declined.contains(name);
+        if enabled {
+            if is_declined {
+                global.declined.retain(|declined| declined != name);
+                changed = true;
+            }
+            // Other clients treat an engine that's missing from `meta/global`
+            // as unknown, so we add one that we're enabling for the first
+            // time. Its new sync ID makes every client reset it.
+            if !global.engines.contains_key(name) {
+                let version = DEFAULT_ENGINES
+                    .iter()
+                    .find(|(default_name, _)| default_name == name)
+                    .map_or(1, |(_, version)| *version);
+                global.engines.insert(
+                    name.clone(),
+                    MetaGlobalEngine {
+                        version,
+                        sync_id: Guid::random(),
+                    },
+                );
+                changed = true;
+            }
+        } else if !is_declined {
+            global.declined.push(name.clone());
+            changed = true;
+        }
+    }
+    if changed {
+        global.declined.sort();
+    }
+    changed
+}
+
 pub struct SetupStateMachine<'a> {
     client: &'a dyn SetupStorageClient,
     root_key: &'a KeyBundle,
@@ -136,19 +223,37 @@ pub struct SetupStateMachine<'a> {
     allowed_states: Vec<&'static str>,
     sequence: Vec<&'static str>,
     interruptee: &'a dyn Interruptee,
+    // The engines the user enabled (`true`) or declined (`false`) on this
+    // device, which we merge into `meta/global`. Only full syncs upload a
+    // changed `meta/global`, so this is always `None` for the others.
+    engine_updates: Option<&'a HashMap<String, bool>>,
+    // Whether we've uploaded a `meta/global` with `engine_updates` during
+    // this run. We only try once, so that we can't loop if another client
+    // keeps changing it back.
+    uploaded_engine_updates: bool,
+    // Whether we've started over because another client changed
+    // `meta/global` while we were uploading ours. Like above, we only do this
+    // once, so that we can't keep racing.
+    retried_meta_global_upload: bool,
 }
 
 impl<'a> SetupStateMachine<'a> {
     /// Creates a state machine for a "classic" Sync 1.5 client that supports
     /// all states, including uploading a fresh `meta/global` and `crypto/keys`
     /// after a node reassignment.
+    ///
+    /// `engine_updates` maps the names of engines that the user enabled or
+    /// declined on this device to `true` or `false`. They're merged with the
+    /// declined engines in `meta/global`, which is uploaded if it changed, so
+    /// that the user's choices are used by all their devices.
     pub fn for_full_sync(
         client: &'a dyn SetupStorageClient,
         root_key: &'a KeyBundle,
         pgs: &'a mut PersistedGlobalState,
+        engine_updates: Option<&'a HashMap<String, bool>>,
         interruptee: &'a dyn Interruptee,
     ) -> SetupStateMachine<'a> {
-        SetupStateMachine::with_allowed_states(
+        let mut state_machine = SetupStateMachine::with_allowed_states(
             client,
             root_key,
             pgs,
@@ -162,7 +267,9 @@ impl<'a> SetupStateMachine<'a> {
                 "FreshStartRequired",
                 "WithPreviousState",
             ],
-        )
+        );
+        state_machine.engine_updates = engine_updates;
+        state_machine
     }
 
     /// Creates a state machine for a fast sync, which only uses locally
@@ -225,7 +332,49 @@ impl<'a> SetupStateMachine<'a> {
             sequence: Vec::new(),
             allowed_states,
             interruptee,
+            engine_updates: None,
+            uploaded_engine_updates: false,
+            retried_meta_global_upload: false,
+        }
+    }
+
+    /// Returns a copy of `global` with our `engine_updates` merged in, if
+    /// that changes it and we haven't already uploaded our changes.
+    fn updated_global(&self, global: &MetaGlobalRecord) -> Option<MetaGlobalRecord> {
+        if self.uploaded_engine_updates {
+            return None;
+        }
+        let updates = self.engine_updates?;
+        let mut new_global = global.clone();
+        if apply_engine_updates(&mut new_global, updates) {
+            Some(new_global)
+        } else {
+            None
+        }
+    }
+
+    /// Uploads `global`, if `meta/global` hasn't changed since `xius`.
+    /// Returns `false` if another client changed it first, so that we can
+    /// start over, and fetch theirs.
+    fn put_meta_global(
+        &mut self,
+        xius: ServerTimestamp,
+        global: &MetaGlobalRecord,
+    ) -> error::Result<bool> {
+        let err = match self.client.put_meta_global(xius, global) {
+            Ok(()) => return Ok(true),
+            Err(err) => err,
+        };
+        let is_conflict = match err.kind() {
+            ErrorKind::StorageHttpError(ErrorResponse::PreconditionFailed { .. }) => true,
+            _ => false,
+        };
+        if !is_conflict || self.retried_meta_global_upload {
+            return Err(err);
         }
+        log::info!("Another client changed meta/global; starting over");
+        self.retried_meta_global_upload = true;
+        Ok(false)
     }
 
     fn advance(&mut self, from: SetupState) -> error::Result<SetupState> {
@@ -285,10 +434,21 @@ impl<'a> SetupStateMachine<'a> {
                     // reupload.
                     if global.storage_version < STORAGE_VERSION {
                         Ok(FreshStartRequired { config })
+                    } else if let Some(new_global) = self.updated_global(&global) {
+                        // The user enabled or declined engines on this
+                        // device, so upload a `meta/global` that reflects
+                        // that, and start over to fetch it. If another
+                        // client changed it first, we start over to fetch
+                        // theirs, and merge our changes into that instead.
+                        log::info!(
+                            "Uploading meta/global with declined engines {:?}",
+                            new_global.declined
+                        );
+                        if self.put_meta_global(global_timestamp, &new_global)? {
+                            self.uploaded_engine_updates = true;
+                        }
+                        Ok(InitialWithConfig { config })
                     } else {
-                        // TODO: Here would be a good place to check if we've enabled
-                        // or disabled any engines locally, and update `m/g` to reflect
-                        // that.
                         Ok(InitialWithMetaGlobal {
                             config,
                             collections,
@@ -310,12 +470,7 @@ impl<'a> SetupStateMachine<'a> {
                 global_timestamp,
             } => {
                 // Update our PersistedGlobalState with the mega/global we just read.
-                mem::replace(
-                    self.pgs,
-                    PersistedGlobalState::V2 {
-                        declined: Some(global.declined.clone()),
-                    },
-                );
+                self.pgs.set_declined(global.declined.clone());
                 // Now try and get keys etc - if we fresh-start we'll re-use declined.
                 match self.client.fetch_crypto_keys()? {
                     Sync15ClientResponse::Success {
@@ -348,6 +503,8 @@ impl<'a> SetupStateMachine<'a> {
             // We've got old state that's likely to be OK.
             // We keep things simple here - if there's evidence of a new/missing
             // meta/global or new/missing keys we just restart from scratch.
+            // If the user has changed their engines since, we also start from
+            // scratch, so that we can update `meta/global`.
             WithPreviousState { old_state } => match self.client.fetch_info_collections()? {
                 Sync15ClientResponse::Success {
                     record: collections,
@@ -355,6 +512,7 @@ impl<'a> SetupStateMachine<'a> {
                 } => Ok(
                     if is_same_timestamp(old_state.global_timestamp, &collections, "meta")
                         && is_same_timestamp(old_state.keys.modified, &collections, "crypto")
+                        && self.updated_global(&old_state.global).is_none()
                     {
                         Ready {
                             state: GlobalState {
@@ -380,9 +538,15 @@ impl<'a> SetupStateMachine<'a> {
                 self.client.wipe_all_remote()?;
 
                 // Upload a fresh `meta/global`...
-                let new_global = new_global(self.pgs)?;
-                self.client
-                    .put_meta_global(ServerTimestamp::default(), &new_global)?;
+                let mut new_global = new_global(self.pgs)?;
+                if let Some(updates) = self.engine_updates {
+                    apply_engine_updates(&mut new_global, updates);
+                }
+                if !self.put_meta_global(ServerTimestamp::default(), &new_global)? {
+                    // Another client started over at the same time, and
+                    // uploaded theirs first, so we'll use that instead.
+                    return Ok(InitialWithConfig { config });
+                }
 
                 // ...And a fresh `crypto/keys`.
                 let new_keys = CollectionKeys::new_random()?.to_encrypted_bso(&self.root_key)?;
@@ -494,12 +658,16 @@ mod tests {
     use crate::bso_record::{BsoRecord, EncryptedBso, EncryptedPayload, Payload};
     use crate::record_types::CryptoKeysRecord;
     use interrupt::NeverInterrupts;
+    use std::cell::RefCell;
 
     struct InMemoryClient {
         info_configuration: error::Result<Sync15ClientResponse<InfoConfiguration>>,
         info_collections: error::Result<Sync15ClientResponse<InfoCollections>>,
         meta_global: error::Result<Sync15ClientResponse<MetaGlobalRecord>>,
         crypto_keys: error::Result<Sync15ClientResponse<BsoRecord<EncryptedPayload>>>,
+        // The `meta/global` records we've been asked to upload, if we allow
+        // uploads.
+        uploaded_meta_globals: Option<RefCell<Vec<(ServerTimestamp, MetaGlobalRecord)>>>,
     }
 
     impl SetupStorageClient for InMemoryClient {
@@ -540,8 +708,12 @@ mod tests {
         fn put_meta_global(
             &self,
             xius: ServerTimestamp,
-            _global: &MetaGlobalRecord,
+            global: &MetaGlobalRecord,
         ) -> error::Result<()> {
+            if let Some(uploaded) = &self.uploaded_meta_globals {
+                uploaded.borrow_mut().push((xius, global.clone()));
+                return Ok(());
+            }
             assert_eq!(xius, ServerTimestamp(999_900));
             Err(ErrorKind::StorageHttpError(ErrorResponse::ServerError {
                 status: 500,
@@ -585,6 +757,7 @@ mod tests {
             record: t,
             last_modified: ServerTimestamp(ts),
             route: "test/path".into(),
+            next_offset: None,
         })
     }
 
@@ -655,11 +828,12 @@ mod tests {
                     .expect("should always work in this test"),
                 888_000,
             ),
+            uploaded_meta_globals: None,
         };
-        let mut pgs = PersistedGlobalState::V2 { declined: None };
+        let mut pgs = PersistedGlobalState::default();
 
         let mut state_machine =
-            SetupStateMachine::for_full_sync(&client, &root_key, &mut pgs, &NeverInterrupts);
+            SetupStateMachine::for_full_sync(&client, &root_key, &mut pgs, None, &NeverInterrupts);
         assert!(
             state_machine.run_to_ready(None).is_ok(),
             "Should drive state machine to ready"
@@ -676,4 +850,118 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_state_machine_uploads_engine_updates() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let keys = CollectionKeys {
+            timestamp: 123_400.into(),
+            default: KeyBundle::new_random().unwrap(),
+            collections: HashMap::new(),
+        };
+        let client = InMemoryClient {
+            info_configuration: mocked_success(InfoConfiguration::default()),
+            info_collections: mocked_success(InfoCollections::new(
+                vec![("meta", 999_000), ("crypto", 888_000)]
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.into()))
+                    .collect(),
+            )),
+            meta_global: mocked_success_ts(
+                MetaGlobalRecord {
+                    sync_id: "syncIDAAAAAA".into(),
+                    storage_version: 5usize,
+                    engines: vec![(
+                        "bookmarks",
+                        MetaGlobalEngine {
+                            version: 2usize,
+                            sync_id: "syncIDBBBBBB".into(),
+                        },
+                    )]
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value))
+                    .collect(),
+                    declined: vec!["passwords".into(), "tabs".into()],
+                },
+                999_000,
+            ),
+            crypto_keys: mocked_success_ts(
+                keys.to_encrypted_bso_with_timestamp(&root_key, 888_000.into())
+                    .expect("should always work in this test"),
+                888_000,
+            ),
+            uploaded_meta_globals: Some(RefCell::new(Vec::new())),
+        };
+        let engine_updates: HashMap<String, bool> = vec![
+            ("tabs", true),
+            ("history", false),
+            ("passwords", false),
+            ("bookmarks", true),
+        ]
+        .into_iter()
+        .map(|(name, enabled)| (name.to_owned(), enabled))
+        .collect();
+        let mut pgs = PersistedGlobalState::default();
+
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            Some(&engine_updates),
+            &NeverInterrupts,
+        );
+        assert!(
+            state_machine.run_to_ready(None).is_ok(),
+            "Should drive state machine to ready"
+        );
+        // Our mock server never changes its `meta/global`, so this also
+        // checks that we only try to upload once.
+        assert_eq!(
+            state_machine.sequence,
+            vec![
+                "Initial",
+                "InitialWithConfig",
+                "InitialWithInfo",
+                "InitialWithConfig",
+                "InitialWithInfo",
+                "InitialWithMetaGlobal",
+                "Ready",
+            ],
+            "Should start over after uploading meta/global"
+        );
+
+        let uploaded = client.uploaded_meta_globals.unwrap().into_inner();
+        assert_eq!(uploaded.len(), 1);
+        let (xius, global) = &uploaded[0];
+        assert_eq!(*xius, ServerTimestamp(999_000));
+        assert_eq!(global.sync_id, "syncIDAAAAAA");
+        assert_eq!(global.declined, vec!["history", "passwords"]);
+        // We add engines we enable that aren't in `meta/global`, but leave
+        // the others alone.
+        assert_eq!(global.engines["bookmarks"].sync_id, "syncIDBBBBBB");
+        assert_eq!(global.engines["tabs"].version, 1);
+        assert!(!global.engines.contains_key("history"));
+    }
+
+    #[test]
+    fn test_apply_engine_updates() {
+        let mut global = MetaGlobalRecord {
+            sync_id: "syncIDAAAAAA".into(),
+            storage_version: 5usize,
+            engines: HashMap::new(),
+            declined: vec!["history".into()],
+        };
+        let no_changes: HashMap<String, bool> =
+            vec![("history".to_owned(), false)].into_iter().collect();
+        assert!(!apply_engine_updates(&mut global, &no_changes));
+        assert_eq!(global.declined, vec!["history"]);
+
+        let changes: HashMap<String, bool> =
+            vec![("history".to_owned(), true)].into_iter().collect();
+        assert!(apply_engine_updates(&mut global, &changes));
+        assert!(global.declined.is_empty());
+        assert_eq!(global.engines["history"].version, 1);
+        // Applying the same changes again is a no-op.
+        assert!(!apply_engine_updates(&mut global, &changes));
+    }
 }
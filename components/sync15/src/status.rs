@@ -3,8 +3,10 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::{Error, ErrorKind, ErrorResponse};
+use crate::sync::PlannedChanges;
 use crate::telemetry::SyncTelemetryPing;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 /// The general status of sync - should probably be moved to the "sync manager"
 /// once we have one!
@@ -41,8 +43,8 @@ impl ServiceStatus {
                     ServiceStatus::ServiceError
                 }
             }
-            // BackoffError is also from the tokenserver.
-            ErrorKind::BackoffError(_) => ServiceStatus::ServiceError,
+            // BackoffError comes from the tokenserver or the storage servers.
+            ErrorKind::BackoffError(_) => ServiceStatus::BackedOff,
             ErrorKind::StorageHttpError(ref e) => match e {
                 ErrorResponse::Unauthorized { .. } => ServiceStatus::AuthenticationError,
                 _ => ServiceStatus::ServiceError,
@@ -73,5 +75,16 @@ pub struct SyncResult {
     /// Note that we expect the `String` to be replaced with an enum later.
     pub engine_results: HashMap<String, Result<(), Error>>,
 
+    /// If the servers asked us to back off, the time before which we won't
+    /// sync again. Syncs before then return `ServiceStatus::BackedOff`.
+    pub next_sync_after: Option<SystemTime>,
+
+    /// The engines declined in `meta/global`, including any we were asked to
+    /// decline, if we got far enough to fetch it.
+    pub declined: Option<Vec<String>>,
+
+    /// For dry-run syncs, the changes that each store would have made.
+    pub planned_changes: HashMap<String, PlannedChanges>,
+
     pub telemetry: SyncTelemetryPing,
 }
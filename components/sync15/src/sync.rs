@@ -2,18 +2,93 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::bso_record::Payload;
 use crate::changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
 use crate::client::Sync15StorageClient;
-use crate::coll_state::{LocalCollStateMachine, StoreSyncAssociation};
-use crate::error::Error;
+use crate::coll_state::{CollState, LocalCollStateMachine, StoreSyncAssociation};
+use crate::error::{Error, ErrorKind};
 use crate::key_bundle::KeyBundle;
 use crate::request::CollectionRequest;
 use crate::state::GlobalState;
 use crate::telemetry;
 use crate::util::ServerTimestamp;
 use interrupt::Interruptee;
+use serde_derive::*;
 use sync_guid::Guid;
 
+/// Whether a sync uploads its changes, or only reports what it would do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    Normal,
+    /// Fetches and applies incoming records, but has each store roll back
+    /// its changes, and doesn't upload anything. The changes each store
+    /// would have made are returned instead, along with the IDs of the
+    /// records involved if `include_ids` is set. A store that needs to be
+    /// reset, including one that's never been synced, fails with
+    /// `DryRunResetRequired`, so its first sync can't be a dry run.
+    DryRun {
+        include_ids: bool,
+    },
+}
+
+/// The changes that a dry-run sync of a collection would make.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedChanges {
+    /// The number of incoming records that would be applied locally.
+    pub applied: u32,
+    /// The number of incoming records that would be merged with local
+    /// changes.
+    pub reconciled: u32,
+    /// The number of incoming records that would fail to apply.
+    pub failed: u32,
+    /// The number of incoming records that would be skipped.
+    pub skipped: u32,
+    /// The number of records that would be uploaded, not counting
+    /// tombstones.
+    pub uploaded: usize,
+    /// The number of tombstones that would be uploaded.
+    pub deleted: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incoming_ids: Option<Vec<Guid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outgoing_ids: Option<Vec<Guid>>,
+}
+
+impl PlannedChanges {
+    fn new(
+        incoming: Option<&telemetry::EngineIncoming>,
+        incoming_ids: Vec<Guid>,
+        outgoing: &OutgoingChangeset,
+        include_ids: bool,
+    ) -> PlannedChanges {
+        let mut planned = PlannedChanges::default();
+        if let Some(incoming) = incoming {
+            planned.applied = incoming.get_applied();
+            planned.reconciled = incoming.get_reconciled();
+            planned.failed = incoming.get_failed();
+            planned.skipped = incoming.get_skipped();
+        }
+        planned.deleted = outgoing
+            .changes
+            .iter()
+            .filter(|payload| payload.is_tombstone())
+            .count();
+        planned.uploaded = outgoing.changes.len() - planned.deleted;
+        if include_ids {
+            planned.incoming_ids = Some(incoming_ids);
+            planned.outgoing_ids = Some(
+                outgoing
+                    .changes
+                    .iter()
+                    .map(|payload| payload.id.clone())
+                    .collect(),
+            );
+        }
+        planned
+    }
+}
+
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
 /// Different stores will produce errors of different types.  To accommodate this, we force them
@@ -27,6 +102,41 @@ pub trait Store {
         telem: &mut telemetry::Engine,
     ) -> Result<OutgoingChangeset, failure::Error>;
 
+    /// Like `apply_incoming`, but for a dry-run sync, which mustn't change
+    /// anything locally. Stores typically apply `inbound` in a transaction
+    /// that they roll back after fetching the outgoing changes. The default
+    /// fails, since we don't know how to undo a store's changes.
+    fn apply_incoming_dry_run(
+        &self,
+        _inbound: IncomingChangeset,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        Err(ErrorKind::DryRunUnsupported(self.collection_name()).into())
+    }
+
+    /// Called with an outgoing record that's too large for the server to
+    /// accept. Stores that can make the record smaller - for example, by
+    /// dropping some of a history entry's visits - return the smaller record,
+    /// and we try to upload that instead. This is called again if it's still
+    /// too large, so long as each record is smaller than the last. The
+    /// default returns `None`, meaning the record is skipped: it's reported as
+    /// too large in the outgoing telemetry, and isn't included in the IDs
+    /// passed to `sync_finished`, so the store can retry it next time.
+    fn shrink_record(&self, _record: Payload) -> Result<Option<Payload>, failure::Error> {
+        Ok(None)
+    }
+
+    /// Whether this store's outgoing records must all be accepted by the
+    /// server, or none of them. If so, a record that the server rejects fails
+    /// the upload. Stores whose records don't depend on each other, like
+    /// history, can return `false` to skip those records instead: they're
+    /// reported as failed in the outgoing telemetry, and aren't included in
+    /// the IDs passed to `sync_finished`. Records that are too large to
+    /// upload are skipped either way; see `shrink_record`.
+    fn requires_atomic_upload(&self) -> bool {
+        true
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -40,6 +150,27 @@ pub trait Store {
     /// to handle "backfills" etc
     fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error>;
 
+    /// Stores that want to download records older than those fetched by
+    /// `get_collection_request` (a "backfill") return a request for the next
+    /// batch of them here. This is called after `sync_finished`, and again
+    /// after each `apply_backfill`, until it returns `None` - so the store is
+    /// responsible for limiting how much is fetched on each sync.
+    fn get_backfill_request(&self) -> Result<Option<CollectionRequest>, failure::Error> {
+        Ok(None)
+    }
+
+    /// Applies a batch of older records fetched using the request returned by
+    /// `get_backfill_request`. Nothing is uploaded as part of a backfill; any
+    /// local changes it causes should be uploaded on the next sync. Stores
+    /// should record what they applied with `telemetry::Engine::add_incoming`.
+    fn apply_backfill(
+        &self,
+        _inbound: IncomingChangeset,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
     /// Get persisted sync IDs. If they don't match the global state we'll be
     /// `reset()` with the new IDs.
     fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error>;
@@ -51,31 +182,33 @@ pub trait Store {
     fn wipe(&self) -> Result<(), failure::Error>;
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn synchronize(
     client: &Sync15StorageClient,
     global_state: &GlobalState,
     root_sync_key: &KeyBundle,
     store: &dyn Store,
     fully_atomic: bool,
+    mode: SyncMode,
     telem_engine: &mut telemetry::Engine,
     interruptee: &impl Interruptee,
-) -> Result<(), Error> {
+) -> Result<Option<PlannedChanges>, Error> {
     let collection = store.collection_name();
     log::info!("Syncing collection {}", collection);
 
     // our global state machine is ready - get the collection machine going.
-    let mut coll_state = match LocalCollStateMachine::get_state(store, global_state, root_sync_key)?
-    {
-        Some(coll_state) => coll_state,
-        None => {
-            // XXX - this is either "error" or "declined".
-            log::warn!(
-                "can't setup for the {} collection - hopefully it works later",
-                collection
-            );
-            return Ok(());
-        }
-    };
+    let mut coll_state =
+        match LocalCollStateMachine::get_state(store, global_state, root_sync_key, mode)? {
+            Some(coll_state) => coll_state,
+            None => {
+                // XXX - this is either "error" or "declined".
+                log::warn!(
+                    "can't setup for the {} collection - hopefully it works later",
+                    collection
+                );
+                return Ok(None);
+            }
+        };
 
     let collection_request = store.get_collection_request()?;
     interruptee.err_if_interrupted()?;
@@ -91,6 +224,25 @@ pub fn synchronize(
         "Downloaded {} remote changes",
         incoming_changes.changes.len()
     );
+    if let SyncMode::DryRun { include_ids } = mode {
+        let incoming_ids = incoming_changes
+            .changes
+            .iter()
+            .map(|(payload, _)| payload.id.clone())
+            .collect();
+        let outgoing = store.apply_incoming_dry_run(incoming_changes, telem_engine)?;
+        log::info!(
+            "Dry run finished; not uploading {} outgoing changes",
+            outgoing.changes.len()
+        );
+        return Ok(Some(PlannedChanges::new(
+            telem_engine.get_incoming(),
+            incoming_ids,
+            &outgoing,
+            include_ids,
+        )));
+    }
+
     let new_timestamp = incoming_changes.timestamp;
     let mut outgoing = store.apply_incoming(incoming_changes, telem_engine)?;
 
@@ -102,22 +254,59 @@ pub fn synchronize(
     log::info!("Uploading {} outgoing changes", outgoing.changes.len());
     let upload_info =
         CollectionUpdate::new_from_changeset(client, &coll_state, outgoing, fully_atomic)?
-            .upload()?;
+            .upload_with_shrinker(|record| store.shrink_record(record))?;
 
     log::info!(
-        "Upload success ({} records success, {} records failed)",
+        "Upload success ({} records success, {} records failed, {} records too large)",
         upload_info.successful_ids.len(),
-        upload_info.failed_ids.len()
+        upload_info.failed_ids.len(),
+        upload_info.too_large_ids.len()
     );
     // ideally we'd report this per-batch, but for now, let's just report it
     // as a total.
     let mut telem_outgoing = telemetry::EngineOutgoing::new();
     telem_outgoing.sent(upload_info.successful_ids.len() + upload_info.failed_ids.len());
     telem_outgoing.failed(upload_info.failed_ids.len());
+    telem_outgoing.too_large(upload_info.too_large_ids.len());
     telem_engine.outgoing(telem_outgoing);
 
     store.sync_finished(upload_info.modified_timestamp, upload_info.successful_ids)?;
 
+    // The records we've just synced are already committed, so failing to
+    // backfill shouldn't fail the sync; we'll pick up where we left off next
+    // time.
+    if let Err(e) = backfill(client, &mut coll_state, store, telem_engine, interruptee) {
+        if let ErrorKind::Interrupted(_) = e.kind() {
+            return Err(e);
+        }
+        log::warn!("Failed to backfill {}: {}", collection, e);
+        telem_engine.backfill_failed();
+    }
+
     log::info!("Sync finished!");
+    Ok(None)
+}
+
+fn backfill(
+    client: &Sync15StorageClient,
+    coll_state: &mut CollState,
+    store: &dyn Store,
+    telem_engine: &mut telemetry::Engine,
+    interruptee: &impl Interruptee,
+) -> Result<(), Error> {
+    while let Some(backfill_request) = store.get_backfill_request()? {
+        interruptee.err_if_interrupted()?;
+        let backfill_changes = IncomingChangeset::fetch(
+            client,
+            coll_state,
+            store.collection_name().into(),
+            &backfill_request,
+        )?;
+        log::info!(
+            "Downloaded {} older remote records to backfill",
+            backfill_changes.changes.len()
+        );
+        store.apply_backfill(backfill_changes, telem_engine)?;
+    }
     Ok(())
 }
@@ -6,17 +6,21 @@
 // global and local state between syncs.
 
 use crate::client::{Sync15StorageClient, Sync15StorageClientInit};
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 use crate::key_bundle::KeyBundle;
 use crate::state::{GlobalState, PersistedGlobalState, SetupStateMachine};
 use crate::status::{ServiceStatus, SyncResult};
-use crate::sync::{self, Store};
+use crate::sync::{self, PlannedChanges, Store, SyncMode};
 use crate::telemetry;
 use failure::Fail;
 use interrupt::Interruptee;
 use std::collections::HashMap;
 use std::mem;
 use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
 
 /// Info about the client to use. We reuse the client unless
 /// we discover the client_init has changed, in which case we re-create one.
@@ -46,6 +50,51 @@ pub struct MemoryCachedState {
     last_global_state: Option<GlobalState>,
 }
 
+/// Options for `sync_multiple` and `sync_multiple_in_parallel`. The defaults
+/// are a normal sync, without any changes to the user's engines, that syncs
+/// one store at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncOptions<'a> {
+    /// The engines the user has enabled (`true`) or declined (`false`) on
+    /// this device since the last sync, if any. These are merged with the
+    /// declined engines in `meta/global`, which we upload if it changed, so
+    /// that all the user's devices use them. The resulting list is returned
+    /// in the `SyncResult`, and kept in the persisted global state. These are
+    /// ignored for dry-run syncs.
+    pub engine_updates: Option<&'a HashMap<String, bool>>,
+    /// Whether to sync normally, or do a dry run. A dry run never uploads
+    /// anything, including `meta/global` and `crypto/keys`, so it fails if
+    /// the server needs setting up, or a store needs to be reset. The
+    /// changes each store would have made are returned in the `SyncResult`.
+    pub mode: SyncMode,
+    /// The most stores that `sync_multiple_in_parallel` syncs at once. 0 is
+    /// treated as 1. `sync_multiple` always syncs one store at a time.
+    pub max_concurrency: usize,
+}
+
+impl<'a> Default for SyncOptions<'a> {
+    fn default() -> Self {
+        SyncOptions {
+            engine_updates: None,
+            mode: SyncMode::Normal,
+            max_concurrency: 1,
+        }
+    }
+}
+
+/// Makes the stores for `sync_multiple_in_parallel`. Each store is made, and
+/// synced, on the worker thread that syncs it, so stores don't need to be
+/// `Send` or `Sync`, but they usually need their own database connection.
+pub trait StoreFactory: Sync {
+    /// Calls `f` with a store for `collection`. Errors making the store are
+    /// reported as a failure to sync that collection.
+    fn with_store(
+        &self,
+        collection: &'static str,
+        f: &mut dyn FnMut(&dyn Store),
+    ) -> result::Result<(), failure::Error>;
+}
+
 /// Sync multiple stores
 /// * `stores` - The stores to sync
 /// * `persisted_global_state` - The global state to use, or None if never
@@ -58,6 +107,7 @@ pub struct MemoryCachedState {
 /// * `storage_init` - Information about how the sync http client should be
 ///   configured.
 /// * `root_sync_key` - The KeyBundle used for encryption.
+/// * `options` - The engines the user changed, and whether this is a dry run.
 ///
 /// Returns a map, keyed by name and holding an error value - if any store
 /// fails, the sync will continue on to other stores, but the error will be
@@ -69,20 +119,107 @@ pub fn sync_multiple(
     mem_cached_state: &mut MemoryCachedState,
     storage_init: &Sync15StorageClientInit,
     root_sync_key: &KeyBundle,
+    options: &SyncOptions<'_>,
+    interruptee: &impl Interruptee,
+) -> SyncResult {
+    sync_multiple_with(
+        stores.len(),
+        &|client, global_state| {
+            sync_stores_serially(
+                stores,
+                client,
+                global_state,
+                root_sync_key,
+                options.mode,
+                interruptee,
+            )
+        },
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        options,
+        interruptee,
+    )
+}
+
+/// Like `sync_multiple`, but once the global state is ready, syncs up to
+/// `options.max_concurrency` of `collections` at a time, each on its own
+/// worker thread, with a store from `factory`. Each store's download, apply
+/// and upload still happen in order on one thread, and the stores are
+/// reported in the `SyncResult` and telemetry in the order their collections
+/// are given, not the order they finished in.
+///
+/// No more stores are started once `interruptee` is interrupted, or once a
+/// store fails with an error that would stop `sync_multiple`. Stores that are
+/// already syncing finish, and are reported along with the failure, even if
+/// they come after the failed store; `service_status` comes from the first
+/// such failure.
+#[allow(clippy::too_many_arguments)]
+pub fn sync_multiple_in_parallel(
+    collections: &[&'static str],
+    factory: &dyn StoreFactory,
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    options: &SyncOptions<'_>,
+    interruptee: &(impl Interruptee + Sync),
+) -> SyncResult {
+    sync_multiple_with(
+        collections.len(),
+        &|client, global_state| {
+            sync_stores_in_parallel(
+                collections,
+                factory,
+                options.max_concurrency,
+                client,
+                global_state,
+                root_sync_key,
+                options.mode,
+                interruptee,
+            )
+        },
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        options,
+        interruptee,
+    )
+}
+
+/// Syncs all the stores, once the global state is ready, returning the
+/// outcome for each store that we synced, in the order the stores were given.
+type SyncStores<'a> = dyn Fn(&Sync15StorageClient, &GlobalState) -> Vec<EngineOutcome> + 'a;
+
+#[allow(clippy::too_many_arguments)]
+fn sync_multiple_with(
+    num_stores: usize,
+    sync_stores: &SyncStores<'_>,
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    options: &SyncOptions<'_>,
     interruptee: &impl Interruptee,
 ) -> SyncResult {
     let mut sync_result = SyncResult {
         service_status: ServiceStatus::OtherError,
         result: Ok(()),
-        engine_results: HashMap::with_capacity(stores.len()),
+        engine_results: HashMap::with_capacity(num_stores),
+        next_sync_after: None,
+        declined: None,
+        planned_changes: HashMap::new(),
         telemetry: telemetry::SyncTelemetryPing::new(),
     };
     match do_sync_multiple(
-        stores,
+        sync_stores,
         persisted_global_state,
         mem_cached_state,
         storage_init,
         root_sync_key,
+        options,
         interruptee,
         &mut sync_result,
     ) {
@@ -106,12 +243,14 @@ pub fn sync_multiple(
 }
 
 /// The actual worker for sync_multiple.
+#[allow(clippy::too_many_arguments)]
 fn do_sync_multiple(
-    stores: &[&dyn Store],
+    sync_stores: &SyncStores<'_>,
     persisted_global_state: &mut Option<String>,
     mem_cached_state: &mut MemoryCachedState,
     storage_init: &Sync15StorageClientInit,
     root_sync_key: &KeyBundle,
+    options: &SyncOptions<'_>,
     interruptee: &impl Interruptee,
     sync_result: &mut SyncResult,
 ) -> result::Result<(), Error> {
@@ -120,6 +259,37 @@ fn do_sync_multiple(
         return Ok(());
     }
 
+    let mut pgs = match persisted_global_state {
+        Some(persisted_string) => {
+            match serde_json::from_str::<PersistedGlobalState>(&persisted_string) {
+                Ok(state) => state,
+                _ => {
+                    // Don't log the error since it might contain sensitive
+                    // info (although currently it only contains the declined engines list)
+                    log::error!(
+                        "Failed to parse PersistedGlobalState from JSON! Falling back to default"
+                    );
+                    PersistedGlobalState::default()
+                }
+            }
+        }
+        None => {
+            log::info!("The application didn't give us persisted state - this is only expected on the very first run for a given user.");
+            PersistedGlobalState::default()
+        }
+    };
+
+    // If the servers asked us to back off, don't touch the network until
+    // they said we could.
+    if let Some(until) = pgs.next_sync_after() {
+        if until > SystemTime::now() {
+            log::info!("Not syncing - backing off until {:?}", until);
+            sync_result.service_status = ServiceStatus::BackedOff;
+            sync_result.next_sync_after = Some(until);
+            return Ok(());
+        }
+    }
+
     // We put None back into last_client_info now so if we fail entirely,
     // reinitialize everything related to the client.
     let client_info = match mem::replace(&mut mem_cached_state.last_client_info, None) {
@@ -145,42 +315,79 @@ fn do_sync_multiple(
         }
     };
 
-    let mut pgs = match persisted_global_state {
-        Some(persisted_string) => {
-            match serde_json::from_str::<PersistedGlobalState>(&persisted_string) {
-                Ok(state) => state,
-                _ => {
-                    // Don't log the error since it might contain sensitive
-                    // info (although currently it only contains the declined engines list)
-                    log::error!(
-                        "Failed to parse PersistedGlobalState from JSON! Falling back to default"
-                    );
-                    PersistedGlobalState::default()
-                }
-            }
-        }
-        None => {
-            log::info!("The application didn't give us persisted state - this is only expected on the very first run for a given user.");
-            PersistedGlobalState::default()
-        }
-    };
-
     if interruptee.was_interrupted() {
         sync_result.service_status = ServiceStatus::Interrupted;
         return Ok(());
     }
 
+    let result = sync_with_client(
+        sync_stores,
+        &client_info,
+        &mut pgs,
+        mem_cached_state,
+        root_sync_key,
+        options,
+        interruptee,
+        sync_result,
+    );
+
+    // Remember any backoff the servers asked for, even if the sync failed.
+    let next_sync_after = client_info
+        .client
+        .backoff_until()
+        .filter(|until| *until > SystemTime::now());
+    pgs.set_next_sync_after(next_sync_after);
+    sync_result.next_sync_after = next_sync_after;
+    // The state machine might also have updated our persisted_global_state,
+    // so update the callers repr of it.
+    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+
+    if let Some(global_state) = result? {
+        log::info!("Updating persisted global state");
+        mem_cached_state.last_client_info = Some(client_info);
+        mem_cached_state.last_global_state = Some(global_state);
+    }
+    Ok(())
+}
+
+/// Runs the state machine and syncs each store. Returns the global state to
+/// reuse next time, if every store synced successfully.
+#[allow(clippy::too_many_arguments)]
+fn sync_with_client(
+    sync_stores: &SyncStores<'_>,
+    client_info: &ClientInfo,
+    pgs: &mut PersistedGlobalState,
+    mem_cached_state: &mut MemoryCachedState,
+    root_sync_key: &KeyBundle,
+    options: &SyncOptions<'_>,
+    interruptee: &impl Interruptee,
+    sync_result: &mut SyncResult,
+) -> result::Result<Option<GlobalState>, Error> {
     // Advance the state machine to the point where it can perform a full
     // sync. This may involve uploading meta/global, crypto/keys etc.
     let global_state = {
         let last_state = mem::replace(&mut mem_cached_state.last_global_state, None);
-        let mut state_machine = SetupStateMachine::for_full_sync(
-            &client_info.client,
-            &root_sync_key,
-            &mut pgs,
-            interruptee,
-        );
-        log::info!("Advancing state machine to ready (full)");
+        let mut state_machine = match options.mode {
+            SyncMode::Normal => {
+                log::info!("Advancing state machine to ready (full)");
+                SetupStateMachine::for_full_sync(
+                    &client_info.client,
+                    &root_sync_key,
+                    pgs,
+                    options.engine_updates,
+                    interruptee,
+                )
+            }
+            SyncMode::DryRun { .. } => {
+                log::info!("Advancing state machine to ready (read-only)");
+                SetupStateMachine::for_readonly_sync(
+                    &client_info.client,
+                    &root_sync_key,
+                    pgs,
+                    interruptee,
+                )
+            }
+        };
         let state = match state_machine.run_to_ready(last_state) {
             Err(e) => {
                 sync_result.service_status = ServiceStatus::from_err(&e);
@@ -188,10 +395,8 @@ fn do_sync_multiple(
             }
             Ok(state) => state,
         };
-        // The state machine might have updated our persisted_global_state, so
-        // update the callers repr of it.
-        mem::replace(persisted_global_state, Some(serde_json::to_string(&pgs)?));
         sync_result.telemetry.uid(client_info.client.hashed_uid()?);
+        sync_result.declined = Some(state.global.declined.clone());
         // As for client_info, put None back now so we start from scratch on error.
         mem_cached_state.last_global_state = None;
         state
@@ -203,20 +408,18 @@ fn do_sync_multiple(
 
     let mut num_failures = 0;
     let mut telem_sync = telemetry::SyncTelemetry::new();
-    for store in stores {
-        let name = store.collection_name();
-        log::info!("Syncing {} engine!", name);
-
-        let mut telem_engine = telemetry::Engine::new(name);
-        let result = sync::synchronize(
-            &client_info.client,
-            &global_state,
-            root_sync_key,
-            *store,
-            true,
-            &mut telem_engine,
-            interruptee,
-        );
+    telem_sync.declined(global_state.global.declined.clone());
+    for outcome in sync_stores(&client_info.client, &global_state) {
+        let EngineOutcome {
+            name,
+            result,
+            telemetry: mut telem_engine,
+        } = outcome;
+        let result = result.map(|planned| {
+            if let Some(planned) = planned {
+                sync_result.planned_changes.insert(name.into(), planned);
+            }
+        });
 
         match result {
             Ok(()) => log::info!("Sync of {} was successful!", name),
@@ -232,30 +435,237 @@ fn do_sync_multiple(
                 let this_status = ServiceStatus::from_err(&e);
                 telem_engine.failure(e);
                 // If the failure from the store looks like anything other than
-                // a "store error" we don't bother trying the others.
-                if this_status != ServiceStatus::OtherError {
-                    telem_sync.engine(telem_engine);
-                    sync_result.engine_results.insert(name.into(), result);
+                // a "store error", no more stores were started after it. The
+                // ones that had already started still synced, so we report
+                // them too, but the first such failure decides the status.
+                if this_status != ServiceStatus::OtherError
+                    && sync_result.service_status == ServiceStatus::Ok
+                {
                     sync_result.service_status = this_status;
-                    break;
                 }
             }
         }
         telem_sync.engine(telem_engine);
         sync_result.engine_results.insert(name.into(), result);
-        if interruptee.was_interrupted() {
-            sync_result.service_status = ServiceStatus::Interrupted;
-            return Ok(());
-        }
+    }
+    if sync_result.service_status == ServiceStatus::Ok && interruptee.was_interrupted() {
+        sync_result.service_status = ServiceStatus::Interrupted;
+        return Ok(None);
     }
 
     sync_result.telemetry.sync(telem_sync);
-    if num_failures == 0 {
-        // XXX - not clear if we should really only do this on full success,
-        // particularly if it's just a network error. See XXX above for more.
-        log::info!("Updating persisted global state");
-        mem_cached_state.last_client_info = Some(client_info);
-        mem_cached_state.last_global_state = Some(global_state);
+    // XXX - not clear if we should really only reuse the state on full
+    // success, particularly if it's just a network error. See XXX above for
+    // more.
+    Ok(if num_failures == 0 {
+        Some(global_state)
+    } else {
+        None
+    })
+}
+
+/// The result of syncing one store.
+struct EngineOutcome {
+    name: &'static str,
+    result: result::Result<Option<PlannedChanges>, Error>,
+    telemetry: telemetry::Engine,
+}
+
+impl EngineOutcome {
+    /// The outcome for a store that we couldn't sync, because we couldn't
+    /// make it.
+    fn store_error(name: &'static str, e: failure::Error) -> Self {
+        EngineOutcome {
+            name,
+            result: Err(ErrorKind::StoreError(e).into()),
+            telemetry: telemetry::Engine::new(name),
+        }
+    }
+
+    /// Whether this store failed in a way that means we shouldn't bother
+    /// syncing the others.
+    fn is_fatal(&self) -> bool {
+        match &self.result {
+            Ok(_) => false,
+            Err(e) => ServiceStatus::from_err(e) != ServiceStatus::OtherError,
+        }
+    }
+}
+
+fn sync_engine(
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    store: &dyn Store,
+    mode: SyncMode,
+    interruptee: &impl Interruptee,
+) -> EngineOutcome {
+    let name = store.collection_name();
+    log::info!("Syncing {} engine!", name);
+
+    let mut telem_engine = telemetry::Engine::new(name);
+    let result = sync::synchronize(
+        client,
+        global_state,
+        root_sync_key,
+        store,
+        store.requires_atomic_upload(),
+        mode,
+        &mut telem_engine,
+        interruptee,
+    );
+    EngineOutcome {
+        name,
+        result,
+        telemetry: telem_engine,
+    }
+}
+
+fn sync_stores_serially(
+    stores: &[&dyn Store],
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    mode: SyncMode,
+    interruptee: &impl Interruptee,
+) -> Vec<EngineOutcome> {
+    let mut outcomes = Vec::with_capacity(stores.len());
+    for store in stores {
+        let outcome = sync_engine(
+            client,
+            global_state,
+            root_sync_key,
+            *store,
+            mode,
+            interruptee,
+        );
+        let is_fatal = outcome.is_fatal();
+        outcomes.push(outcome);
+        if is_fatal || interruptee.was_interrupted() {
+            break;
+        }
+    }
+    outcomes
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sync_stores_in_parallel(
+    collections: &[&'static str],
+    factory: &dyn StoreFactory,
+    max_concurrency: usize,
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    mode: SyncMode,
+    interruptee: &(impl Interruptee + Sync),
+) -> Vec<EngineOutcome> {
+    // Workers take the next collection that hasn't been started yet, until
+    // there are none left, or we're stopped.
+    let next_store = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let outcomes = Mutex::new(Vec::with_capacity(collections.len()));
+    let num_workers = max_concurrency.max(1).min(collections.len());
+    log::info!(
+        "Syncing {} stores on {} threads",
+        collections.len(),
+        num_workers
+    );
+    thread::scope(|scope| {
+        for _ in 0..num_workers {
+            scope.spawn(|| loop {
+                if stopped.load(Ordering::SeqCst) || interruptee.was_interrupted() {
+                    break;
+                }
+                let index = next_store.fetch_add(1, Ordering::SeqCst);
+                let name = match collections.get(index) {
+                    Some(name) => *name,
+                    None => break,
+                };
+                let mut outcome = None;
+                let result = factory.with_store(name, &mut |store| {
+                    outcome = Some(sync_engine(
+                        client,
+                        global_state,
+                        root_sync_key,
+                        store,
+                        mode,
+                        interruptee,
+                    ));
+                });
+                let outcome = match (result, outcome) {
+                    (Ok(()), Some(outcome)) => outcome,
+                    (Err(e), _) => EngineOutcome::store_error(name, e),
+                    (Ok(()), None) => EngineOutcome::store_error(
+                        name,
+                        failure::format_err!("No store for {}", name),
+                    ),
+                };
+                if outcome.is_fatal() {
+                    stopped.store(true, Ordering::SeqCst);
+                }
+                outcomes.lock().unwrap().push((index, outcome));
+            });
+        }
+    });
+    // Report the outcomes in the order the stores were given, not the order
+    // they finished in.
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backoff;
+    use interrupt::NeverInterrupts;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn test_backed_off() {
+        let until = SystemTime::now() + Duration::from_secs(60);
+        let mut pgs = PersistedGlobalState::default();
+        pgs.set_next_sync_after(Some(until));
+        let persisted = serde_json::to_string(&pgs).unwrap();
+        let mut persisted_global_state = Some(persisted.clone());
+        let mut mem_cached_state = MemoryCachedState::default();
+        // This server doesn't exist, so we'd fail if we tried to use it.
+        let storage_init = Sync15StorageClientInit {
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            tokenserver_url: Url::parse("https://token.example.invalid/").unwrap(),
+        };
+        let result = sync_multiple(
+            &[],
+            &mut persisted_global_state,
+            &mut mem_cached_state,
+            &storage_init,
+            &KeyBundle::new_random().unwrap(),
+            &SyncOptions::default(),
+            &NeverInterrupts,
+        );
+        assert_eq!(result.service_status, ServiceStatus::BackedOff);
+        assert!(result.result.is_ok());
+        // We persist milliseconds.
+        let expected = backoff::system_time_from_ms(backoff::system_time_to_ms(until));
+        assert_eq!(result.next_sync_after, Some(expected));
+        assert_eq!(persisted_global_state, Some(persisted));
+        assert!(mem_cached_state.last_client_info.is_none());
+    }
+
+    #[test]
+    fn test_persisted_backoff() {
+        // State from before we persisted backoffs still parses.
+        let pgs: PersistedGlobalState =
+            serde_json::from_str(r#"{"schema_version":"V2","declined":["tabs"]}"#).unwrap();
+        assert_eq!(pgs.next_sync_after(), None);
+
+        let mut pgs = PersistedGlobalState::default();
+        pgs.set_next_sync_after(Some(backoff::system_time_from_ms(1_500_000_000_000)));
+        assert_eq!(
+            serde_json::to_string(&pgs).unwrap(),
+            r#"{"schema_version":"V2","declined":null,"next_sync_after":1500000000000}"#
+        );
     }
-    Ok(())
 }
@@ -338,6 +338,9 @@ pub struct EngineIncoming {
 
     #[serde(skip_serializing_if = "skip_if_default")]
     reconciled: u32,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    skipped: u32,
 }
 
 impl EngineIncoming {
@@ -350,7 +353,13 @@ impl EngineIncoming {
     // A helper used via skip_serializing_if
     fn is_empty(inc: &Option<Self>) -> bool {
         match inc {
-            Some(a) => a.applied == 0 && a.failed == 0 && a.new_failed == 0 && a.reconciled == 0,
+            Some(a) => {
+                a.applied == 0
+                    && a.failed == 0
+                    && a.new_failed == 0
+                    && a.reconciled == 0
+                    && a.skipped == 0
+            }
             None => true,
         }
     }
@@ -379,6 +388,13 @@ impl EngineIncoming {
         self.reconciled += n;
     }
 
+    /// Increment the value of `skipped` by `n`. Skipped records are valid,
+    /// but weren't applied because of limits set by the engine.
+    #[inline]
+    pub fn skipped(&mut self, n: u32) {
+        self.skipped += n;
+    }
+
     /// Get the value of `applied`. Mostly useful for testing.
     #[inline]
     pub fn get_applied(&self) -> u32 {
@@ -402,6 +418,12 @@ impl EngineIncoming {
     pub fn get_reconciled(&self) -> u32 {
         self.reconciled
     }
+
+    /// Get the value of `skipped`. Mostly useful for testing.
+    #[inline]
+    pub fn get_skipped(&self) -> u32 {
+        self.skipped
+    }
 }
 
 /// Outgoing record for an engine's sync
@@ -412,6 +434,12 @@ pub struct EngineOutgoing {
 
     #[serde(skip_serializing_if = "skip_if_default")]
     failed: usize,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    skipped: usize,
+
+    #[serde(rename = "tooLarge", skip_serializing_if = "skip_if_default")]
+    too_large: usize,
 }
 
 impl EngineOutgoing {
@@ -430,6 +458,32 @@ impl EngineOutgoing {
     pub fn failed(&mut self, n: usize) {
         self.failed += n;
     }
+
+    /// Records that weren't staged for upload because of limits set by the
+    /// engine.
+    #[inline]
+    pub fn skipped(&mut self, n: usize) {
+        self.skipped += n;
+    }
+
+    /// Get the value of `skipped`.
+    #[inline]
+    pub fn get_skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Records that were too large for the server to accept, even after the
+    /// store tried to shrink them, and so weren't uploaded.
+    #[inline]
+    pub fn too_large(&mut self, n: usize) {
+        self.too_large += n;
+    }
+
+    /// Get the value of `too_large`.
+    #[inline]
+    pub fn get_too_large(&self) -> usize {
+        self.too_large
+    }
 }
 
 /// One engine's sync.
@@ -444,7 +498,11 @@ pub struct Engine {
     incoming: Option<EngineIncoming>,
 
     #[serde(skip_serializing_if = "Vec::is_empty")]
-    outgoing: Vec<EngineOutgoing>, // one for each batch posted.
+    outgoing: Vec<EngineOutgoing>, // one for each batch posted, and any skipped records.
+
+    #[serde(rename = "backfillFailed")]
+    #[serde(skip_serializing_if = "skip_if_default")]
+    backfill_failed: u32,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "failureReason")]
@@ -461,6 +519,7 @@ impl Engine {
             when_took: Stopwatch::new(),
             incoming: None,
             outgoing: Vec::new(),
+            backfill_failed: 0,
             failure: None,
             validation: None,
         }
@@ -471,10 +530,36 @@ impl Engine {
         self.incoming = Some(inc);
     }
 
+    /// Adds `inc` to the engine's incoming counts. Unlike `incoming`, this
+    /// can be called more than once, for example, to record each batch of
+    /// records applied during a backfill.
+    pub fn add_incoming(&mut self, inc: EngineIncoming) {
+        match &mut self.incoming {
+            Some(existing) => {
+                existing.applied += inc.applied;
+                existing.failed += inc.failed;
+                existing.new_failed += inc.new_failed;
+                existing.reconciled += inc.reconciled;
+                existing.skipped += inc.skipped;
+            }
+            None => self.incoming = Some(inc),
+        }
+    }
+
+    pub(crate) fn get_incoming(&self) -> Option<&EngineIncoming> {
+        self.incoming.as_ref()
+    }
+
     pub fn outgoing(&mut self, out: EngineOutgoing) {
         self.outgoing.push(out);
     }
 
+    /// Records that we failed to backfill older records. This isn't an
+    /// engine failure, since the records we synced are already committed.
+    pub fn backfill_failed(&mut self) {
+        self.backfill_failed += 1;
+    }
+
     pub fn failure(&mut self, err: impl Into<SyncFailure>) {
         // Currently we take the first error, under the assumption that the
         // first is the most important and all others stem from that.
@@ -566,17 +651,46 @@ mod engine_tests {
         );
     }
 
+    #[test]
+    fn test_add_incoming() {
+        let mut i = EngineIncoming::new();
+        i.applied(1);
+        let mut e = Engine::new("TestEngine");
+        e.incoming(i);
+        let mut backfilled = EngineIncoming::new();
+        backfilled.applied(2);
+        backfilled.failed(1);
+        e.add_incoming(backfilled);
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine", "when": 0.0, "incoming": {"applied": 3, "failed": 1}}),
+        );
+    }
+
+    #[test]
+    fn test_backfill_failed() {
+        let mut e = Engine::new("TestEngine");
+        e.backfill_failed();
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine", "when": 0.0, "backfillFailed": 1}),
+        );
+    }
+
     #[test]
     fn test_outgoing() {
         let mut o = EngineOutgoing::new();
         o.sent(2);
         o.failed(1);
+        o.too_large(1);
         let mut e = Engine::new("TestEngine");
         e.outgoing(o);
         e.finished();
         assert_json(
             &e,
-            json!({"name": "TestEngine", "when": 0.0, "outgoing": [{"sent": 2, "failed": 1}]}),
+            json!({"name": "TestEngine", "when": 0.0, "outgoing": [{"sent": 2, "failed": 1, "tooLarge": 1}]}),
         );
     }
 
@@ -626,6 +740,15 @@ pub struct SyncTelemetry {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "failureReason")]
     failure: Option<SyncFailure>,
+
+    // Why we synced, like "user" or "schedule".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    why: Option<String>,
+
+    // The engines declined in `meta/global` at the end of the sync,
+    // including any the user just declined on this device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    declined: Option<Vec<String>>,
 }
 
 impl SyncTelemetry {
@@ -643,6 +766,10 @@ impl SyncTelemetry {
         self.failure = Some(failure);
     }
 
+    pub fn declined(&mut self, declined: Vec<String>) {
+        self.declined = Some(declined);
+    }
+
     // Note that unlike other 'finished' methods, this isn't private - someone
     // needs to explicitly call this before handling the json payload to
     // whatever ends up submitting it.
@@ -705,11 +832,13 @@ mod sync_tests {
         s.engine(e1);
         s.engine(e2);
         s.failure(SyncFailure::Http { code: 500 });
+        s.declined(vec!["history".into()]);
         s.finished();
         assert_json(
             &s,
             json!({
                 "when": 0.0,
+                "declined": ["history"],
                 "engines": [{
                     "name": "test_engine",
                     "when": 0.0,
@@ -785,6 +914,13 @@ impl SyncTelemetryPing {
     pub fn event(&mut self, e: Event) {
         self.events.push(e);
     }
+
+    /// Records why we synced, for all the syncs in the ping.
+    pub fn why(&mut self, why: &str) {
+        for s in &mut self.syncs {
+            s.why = Some(why.to_owned());
+        }
+    }
 }
 
 implement_into_ffi_by_json!(SyncTelemetryPing);
@@ -802,6 +938,7 @@ mod ping_tests {
         p.sync(s);
         let event = Event::new("foo", "bar");
         p.event(event);
+        p.why("user");
         assert_json(
             &p,
             json!({
@@ -812,7 +949,8 @@ mod ping_tests {
                     "engines": [{
                         "name": "test", "when": 0.0
                     }],
-                    "when": 0.0
+                    "when": 0.0,
+                    "why": "user"
                 }],
                 "uid": "user-id",
                 "version": 1
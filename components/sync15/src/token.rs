@@ -2,19 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::backoff::BackoffListener;
 use crate::error::{self, ErrorKind, Result};
 use crate::util::ServerTimestamp;
 use rc_crypto::hawk;
 use serde_derive::*;
 use std::borrow::{Borrow, Cow};
-use std::cell::RefCell;
 use std::fmt;
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 use url::Url;
 use viaduct::{header_names, Request};
 
-const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
-
 // The TokenserverToken is the token as received directly from the token server
 // and deserialized from JSON.
 #[derive(Deserialize, Clone, PartialEq, Eq)]
@@ -61,6 +60,8 @@ struct TokenServerFetcher {
     server_url: Url,
     access_token: String,
     key_id: String,
+    // Any backoff requested by the tokenserver.
+    backoff: BackoffListener,
 }
 
 fn fixup_server_url(mut url: Url) -> Result<Url> {
@@ -89,6 +90,7 @@ impl TokenServerFetcher {
             server_url: fixup_server_url(base_url)?,
             access_token,
             key_id,
+            backoff: BackoffListener::default(),
         })
     }
 }
@@ -104,19 +106,13 @@ impl TokenFetcher for TokenServerFetcher {
             .header(header_names::X_KEYID, self.key_id.clone())?
             .send()?;
 
+        // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
+        // have a TokenserverHttpError as its cause?
+        self.backoff.check_response(&resp, self.now())?;
         if !resp.is_success() {
             log::warn!("Non-success status when fetching token: {}", resp.status);
             // TODO: the body should be JSON and contain a status parameter we might need?
             log::trace!("  Response body {}", resp.text());
-            // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
-            // have a TokenserverHttpError as its cause?
-            if let Some(res) = resp.headers.get_as::<f64, _>(header_names::RETRY_AFTER) {
-                let ms = res
-                    .ok()
-                    .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
-                let when = self.now() + Duration::from_millis(ms);
-                return Err(ErrorKind::BackoffError(when).into());
-            }
             let status = resp.status;
             return Err(ErrorKind::TokenserverHttpError(status).into());
         }
@@ -236,8 +232,10 @@ enum TokenState {
 #[derive(Debug)]
 struct TokenProviderImpl<TF: TokenFetcher> {
     fetcher: TF,
-    // Our token state (ie, whether we have a token, and if not, why not)
-    current_state: RefCell<TokenState>,
+    // Our token state (ie, whether we have a token, and if not, why not).
+    // This is behind a mutex so that stores syncing in parallel share one
+    // token, instead of each fetching their own.
+    current_state: Mutex<TokenState>,
 }
 
 impl<TF: TokenFetcher> TokenProviderImpl<TF> {
@@ -247,7 +245,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         rc_crypto::ensure_initialized();
         TokenProviderImpl {
             fetcher,
-            current_state: RefCell::new(TokenState::NoToken),
+            current_state: Mutex::new(TokenState::NoToken),
         }
     }
 
@@ -347,7 +345,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     {
         // first get a mutable ref to our existing state, advance to the
         // state we will use, then re-stash that state for next time.
-        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        let state: &mut TokenState = &mut self.current_state.lock().unwrap();
         if let Some(new_state) = self.advance_state(state) {
             *state = new_state;
         }
@@ -418,6 +416,12 @@ impl TokenProvider {
     pub fn api_endpoint(&self) -> Result<String> {
         self.imp.api_endpoint()
     }
+
+    /// Returns the latest time the tokenserver has asked us to back off
+    /// until, if any.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        self.imp.fetcher.backoff.get()
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,99 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+use ffi_support::{define_string_destructor, ExternError, FfiStr, HandleError};
+use interrupt::NeverInterrupts;
+use std::os::raw::c_char;
+use sync15::clients::Settings;
+use sync_manager::{Result, SyncComponent, SyncParams, WithStoresFn, PASSWORDS_ENGINE};
+
+/// Syncs the passwords engine with a logins handle. The engine is looked up
+/// in the logins handle map for each sync, so it's locked the same way as for
+/// any other logins call, and isn't synced once the handle's been closed.
+struct LoginsHandleComponent(u64);
+
+impl SyncComponent for LoginsHandleComponent {
+    fn engines(&self) -> &'static [&'static str] {
+        &[PASSWORDS_ENGINE]
+    }
+
+    fn with_stores(
+        &self,
+        _engines: &[&str],
+        _device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()> {
+        let result = logins_ffi::ENGINES.get_u64(self.0, |engine| {
+            Ok::<_, HandleError>(
+                engine
+                    .sync_store()
+                    .map_err(Into::into)
+                    .and_then(|store| f(&[&store], &store.scope)),
+            )
+        });
+        match result {
+            Ok(result) => result,
+            Err(_) => f(&[], &NeverInterrupts),
+        }
+    }
+}
+
+/// Registers the places API with `places_api_handle` with the sync manager,
+/// so that `sync_manager_sync` syncs history, bookmarks and the reading list.
+#[no_mangle]
+pub extern "C" fn sync_manager_set_places(places_api_handle: u64, error: &mut ExternError) {
+    log::debug!("sync_manager_set_places");
+    places_ffi::APIS.call_with_output(error, places_api_handle, |api| {
+        sync_manager::set_places(api)
+    })
+}
+
+/// Registers the logins engine with `logins_handle` with the sync manager, so
+/// that `sync_manager_sync` syncs passwords.
+#[no_mangle]
+pub extern "C" fn sync_manager_set_logins(logins_handle: u64, error: &mut ExternError) {
+    log::debug!("sync_manager_set_logins");
+    ffi_support::call_with_result(error, || -> std::result::Result<(), HandleError> {
+        // Check the handle now, but don't hold the engine's lock while we
+        // wait for the manager's, since syncing takes them in the other order.
+        logins_ffi::ENGINES.get_u64(logins_handle, |_| Ok::<_, HandleError>(()))?;
+        sync_manager::register(Box::new(LoginsHandleComponent(logins_handle)));
+        Ok(())
+    })
+}
+
+/// Registers the tabs engine with `tabs_handle` with the sync manager, so that
+/// `sync_manager_sync` syncs tabs. Tabs are only synced if the sync params
+/// include the device settings.
+#[no_mangle]
+pub extern "C" fn sync_manager_set_tabs(tabs_handle: u64, error: &mut ExternError) {
+    log::debug!("sync_manager_set_tabs");
+    tabs_ffi::ENGINES.call_with_output(error, tabs_handle, sync_manager::set_tabs)
+}
+
+#[no_mangle]
+pub extern "C" fn sync_manager_disconnect(error: &mut ExternError) {
+    log::debug!("sync_manager_disconnect");
+    ffi_support::call_with_output(error, sync_manager::disconnect)
+}
+
+/// Syncs the registered engines. `params_json` is a JSON `SyncParams`, and
+/// the result is a JSON `SyncResult`.
+#[no_mangle]
+pub extern "C" fn sync_manager_sync(
+    params_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync_manager_sync");
+    ffi_support::call_with_result(error, || -> Result<String> {
+        let params: SyncParams = serde_json::from_str(params_json.as_str())?;
+        let result = sync_manager::sync(params)?;
+        Ok(serde_json::to_string(&result)?)
+    })
+}
+
+define_string_destructor!(sync_manager_destroy_string);
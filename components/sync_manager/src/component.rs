@@ -0,0 +1,206 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::error::*;
+use interrupt::{Interruptee, NeverInterrupts};
+use logins::PasswordEngine;
+use places::bookmark_sync::store::BookmarksStore;
+use places::history_sync::store::HistoryStore;
+use places::reading_list_sync::ReadingListStore;
+use places::PlacesApi;
+use std::cell::RefCell;
+use std::sync::{Mutex, Weak};
+use sync15::clients::{ClientsStore, Command, CommandProcessor, RemoteClient, Settings};
+use sync15::Store;
+use tabs::{TabsEngine, TabsStore};
+
+pub const CLIENTS_ENGINE: &str = "clients";
+pub const HISTORY_ENGINE: &str = "history";
+pub const BOOKMARKS_ENGINE: &str = "bookmarks";
+pub const PASSWORDS_ENGINE: &str = "passwords";
+pub const READING_LIST_ENGINE: &str = "readinglist";
+pub const TABS_ENGINE: &str = "tabs";
+
+/// Called with the stores to sync, and something that's interrupted when any
+/// of them are.
+pub type WithStoresFn<'a> = dyn FnMut(&[&dyn Store], &dyn Interruptee) -> Result<()> + 'a;
+
+/// A component that syncs one or more engines through the sync manager.
+///
+/// Stores usually borrow a connection, or a lock, that's only held for the
+/// duration of the sync, so instead of returning its stores, a component
+/// passes them to a callback.
+pub trait SyncComponent {
+    /// The names of the engines this component syncs, which are also the
+    /// names of their collections, in the order they're synced.
+    fn engines(&self) -> &'static [&'static str];
+
+    /// Calls `f` with a store for each of `engines`, which are some of the
+    /// engines returned by `engines`. `device` describes this device, if the
+    /// app gave us its settings. A component that's been closed calls `f`
+    /// without any stores.
+    fn with_stores(
+        &self,
+        engines: &[&str],
+        device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()>;
+}
+
+/// Syncs the history, bookmarks and reading list engines. We only keep a weak
+/// reference, so places is unregistered when it's closed.
+pub struct PlacesComponent(pub Weak<PlacesApi>);
+
+impl SyncComponent for PlacesComponent {
+    fn engines(&self) -> &'static [&'static str] {
+        &[HISTORY_ENGINE, BOOKMARKS_ENGINE, READING_LIST_ENGINE]
+    }
+
+    fn with_stores(
+        &self,
+        engines: &[&str],
+        _device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()> {
+        let api = match self.0.upgrade() {
+            Some(api) => api,
+            None => return f(&[], &NeverInterrupts),
+        };
+        let conn = api.open_sync_connection()?;
+        // This must be called before syncing history or bookmarks, to
+        // migrate their old sync IDs.
+        HistoryStore::migrate_v1_global_state(&conn)?;
+        let scope = conn.begin_interrupt_scope();
+        let history_store = HistoryStore::new(&conn, &scope);
+        let bookmarks_store = BookmarksStore::new(&conn, &scope);
+        let reading_list_store = ReadingListStore::new(&conn, &scope);
+        let mut stores: Vec<&dyn Store> = Vec::with_capacity(engines.len());
+        if engines.contains(&HISTORY_ENGINE) {
+            stores.push(&history_store);
+        }
+        if engines.contains(&BOOKMARKS_ENGINE) {
+            stores.push(&bookmarks_store);
+        }
+        if engines.contains(&READING_LIST_ENGINE) {
+            stores.push(&reading_list_store);
+        }
+        f(&stores, &scope)
+    }
+}
+
+/// Syncs the passwords engine. Like places, we only keep a weak reference.
+pub struct LoginsComponent(pub Weak<Mutex<PasswordEngine>>);
+
+impl SyncComponent for LoginsComponent {
+    fn engines(&self) -> &'static [&'static str] {
+        &[PASSWORDS_ENGINE]
+    }
+
+    fn with_stores(
+        &self,
+        _engines: &[&str],
+        _device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()> {
+        let engine = match self.0.upgrade() {
+            Some(engine) => engine,
+            None => return f(&[], &NeverInterrupts),
+        };
+        let engine = engine
+            .lock()
+            .map_err(|_| ErrorKind::EnginePoisoned(PASSWORDS_ENGINE))?;
+        let store = engine.sync_store()?;
+        f(&[&store], &store.scope)
+    }
+}
+
+/// Syncs the tabs engine. Our tabs record is identified by this device's
+/// client ID, so the tabs engine is only synced if the app gave us the
+/// device settings.
+pub struct TabsComponent(pub Weak<Mutex<TabsEngine>>);
+
+impl SyncComponent for TabsComponent {
+    fn engines(&self) -> &'static [&'static str] {
+        &[TABS_ENGINE]
+    }
+
+    fn with_stores(
+        &self,
+        _engines: &[&str],
+        device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()> {
+        let (engine, device) = match (self.0.upgrade(), device) {
+            (Some(engine), Some(device)) => (engine, device),
+            _ => return f(&[], &NeverInterrupts),
+        };
+        let engine = engine
+            .lock()
+            .map_err(|_| ErrorKind::EnginePoisoned(TABS_ENGINE))?;
+        let store = TabsStore::new(&engine.storage, &device.fxa_device_id, &device.device_name);
+        f(&[&store], &NeverInterrupts)
+    }
+}
+
+/// Queues the commands that other devices send us, so that we can apply them
+/// once the sync is done, and the stores they affect are no longer in use.
+/// It also keeps the user's other devices from the clients store, which only
+/// lives for the duration of the sync.
+pub(crate) struct CommandQueue {
+    settings: Settings,
+    commands: RefCell<Vec<Command>>,
+    remote_clients: RefCell<Vec<RemoteClient>>,
+}
+
+impl CommandQueue {
+    pub fn new(settings: Settings) -> Self {
+        CommandQueue {
+            settings,
+            commands: RefCell::default(),
+            remote_clients: RefCell::default(),
+        }
+    }
+
+    pub fn take(&self) -> Vec<Command> {
+        self.commands.replace(Vec::new())
+    }
+
+    pub fn take_remote_clients(&self) -> Vec<RemoteClient> {
+        self.remote_clients.replace(Vec::new())
+    }
+}
+
+impl CommandProcessor for CommandQueue {
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    fn apply_incoming_command(&self, command: Command) -> std::result::Result<(), failure::Error> {
+        self.commands.borrow_mut().push(command);
+        Ok(())
+    }
+}
+
+/// Syncs the clients engine, so that this device shows up in the user's
+/// other devices, and receives the commands they send us. The manager
+/// registers this itself whenever the app gives us the device settings.
+pub(crate) struct ClientsComponent<'a>(pub &'a CommandQueue);
+
+impl<'a> SyncComponent for ClientsComponent<'a> {
+    fn engines(&self) -> &'static [&'static str] {
+        &[CLIENTS_ENGINE]
+    }
+
+    fn with_stores(
+        &self,
+        _engines: &[&str],
+        _device: Option<&Settings>,
+        f: &mut WithStoresFn<'_>,
+    ) -> Result<()> {
+        let store = ClientsStore::new(self.0);
+        let result = f(&[&store], &NeverInterrupts);
+        self.0.remote_clients.replace(store.remote_clients());
+        result
+    }
+}
@@ -0,0 +1,39 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Error from sync15: {}", _0)]
+    Sync15Error(#[fail(cause)] sync15::Error),
+
+    #[fail(display = "Error from places: {}", _0)]
+    PlacesError(#[fail(cause)] places::Error),
+
+    #[fail(display = "Error from logins: {}", _0)]
+    LoginsError(#[fail(cause)] logins::Error),
+
+    #[fail(display = "URL parse error: {}", _0)]
+    UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "Error parsing JSON data: {}", _0)]
+    JsonError(#[fail(cause)] serde_json::Error),
+
+    #[fail(display = "The {} engine panicked in an earlier call", _0)]
+    EnginePoisoned(&'static str),
+
+    #[fail(display = "A component returned without passing its stores to the sync")]
+    ComponentDidNotSync,
+}
+
+error_support::define_error! {
+    ErrorKind {
+        (Sync15Error, sync15::Error),
+        (PlacesError, places::Error),
+        (LoginsError, logins::Error),
+        (UrlParseError, url::ParseError),
+        (JsonError, serde_json::Error),
+    }
+}
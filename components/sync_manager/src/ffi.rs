@@ -0,0 +1,59 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// This module implement the traits that make the FFI code easier to manage.
+
+use crate::{Error, ErrorKind};
+use ffi_support::{ErrorCode, ExternError};
+use sync15::ErrorKind as Sync15ErrorKind;
+
+pub mod error_codes {
+    /// An unexpected error occurred which likely cannot be meaningfully handled
+    /// by the application.
+    pub const UNEXPECTED: i32 = -2;
+
+    // Note: -1 and 0 (panic and success) codes are reserved by the ffi-support library
+
+    /// Indicates the FxA credentials are invalid, and should be refreshed.
+    pub const AUTH_INVALID: i32 = 1;
+
+    /// A request to the sync server failed.
+    pub const NETWORK: i32 = 2;
+
+    /// The sync params couldn't be parsed.
+    pub const INVALID_PARAMS: i32 = 4;
+}
+
+fn get_code(err: &Error) -> ErrorCode {
+    match err.kind() {
+        ErrorKind::Sync15Error(e) => {
+            log::error!("Sync error {:?}", e);
+            match e.kind() {
+                Sync15ErrorKind::TokenserverHttpError(401) | Sync15ErrorKind::BadKeyLength(..) => {
+                    ErrorCode::new(error_codes::AUTH_INVALID)
+                }
+                Sync15ErrorKind::RequestError(_) => ErrorCode::new(error_codes::NETWORK),
+                _ => ErrorCode::new(error_codes::UNEXPECTED),
+            }
+        }
+        ErrorKind::JsonError(e) => {
+            log::error!("Invalid sync params: {}", e);
+            ErrorCode::new(error_codes::INVALID_PARAMS)
+        }
+        ErrorKind::UrlParseError(e) => {
+            log::error!("Invalid tokenserver URL: {}", e);
+            ErrorCode::new(error_codes::INVALID_PARAMS)
+        }
+        err => {
+            log::error!("Unexpected error: {:?}", err);
+            ErrorCode::new(error_codes::UNEXPECTED)
+        }
+    }
+}
+
+impl From<Error> for ExternError {
+    fn from(e: Error) -> ExternError {
+        ExternError::new_error(get_code(&e), e.to_string())
+    }
+}
@@ -0,0 +1,61 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+//! The sync manager syncs every component in the app with one call to
+//! `sync15::sync_multiple`, instead of each component syncing separately.
+//!
+//! Components register with `set_places`, `set_logins` and `set_tabs`, or
+//! implement `SyncComponent` and call `register`. The clients engine is
+//! built in, and syncs whenever the app passes the device settings. The app
+//! then calls `sync` with the account, the reason for the sync, and the
+//! engines to sync, and gets back one `SyncResult`, with the state to
+//! persist in case the app restarts before the next sync.
+
+mod component;
+mod error;
+mod ffi;
+mod manager;
+
+pub use crate::component::{
+    LoginsComponent, PlacesComponent, SyncComponent, TabsComponent, WithStoresFn, BOOKMARKS_ENGINE,
+    CLIENTS_ENGINE, HISTORY_ENGINE, PASSWORDS_ENGINE, READING_LIST_ENGINE, TABS_ENGINE,
+};
+pub use crate::error::*;
+pub use crate::manager::*;
+
+use logins::PasswordEngine;
+use places::PlacesApi;
+use std::sync::{Arc, Mutex};
+use tabs::TabsEngine;
+
+lazy_static::lazy_static! {
+    static ref MANAGER: Mutex<SyncManager> = Mutex::new(SyncManager::new());
+}
+
+pub fn set_places(places: &Arc<PlacesApi>) {
+    MANAGER.lock().unwrap().set_places(Arc::downgrade(places));
+}
+
+pub fn set_logins(logins: &Arc<Mutex<PasswordEngine>>) {
+    MANAGER.lock().unwrap().set_logins(Arc::downgrade(logins));
+}
+
+pub fn set_tabs(tabs: &Arc<Mutex<TabsEngine>>) {
+    MANAGER.lock().unwrap().set_tabs(Arc::downgrade(tabs));
+}
+
+pub fn register(component: Box<dyn SyncComponent + Send>) {
+    MANAGER.lock().unwrap().register(component);
+}
+
+pub fn disconnect() {
+    MANAGER.lock().unwrap().disconnect();
+}
+
+pub fn sync(params: SyncParams) -> Result<SyncResult> {
+    MANAGER.lock().unwrap().sync(params)
+}
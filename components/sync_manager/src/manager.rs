@@ -0,0 +1,760 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::component::*;
+use crate::error::*;
+use interrupt::Interruptee;
+use logins::PasswordEngine;
+use places::PlacesApi;
+use serde::de::{Deserializer, Error as _};
+use serde::Serializer;
+use serde_derive::*;
+use std::collections::HashMap;
+use std::sync::{Mutex, Weak};
+use std::time::UNIX_EPOCH;
+use sync15::clients::{self, Command, DeviceType, Settings};
+use sync15::{
+    telemetry::SyncTelemetryPing, KeyBundle, MemoryCachedState, ServiceStatus, Store,
+    StoreSyncAssociation, Sync15StorageClientInit, SyncOptions,
+};
+use tabs::TabsEngine;
+use url::Url;
+
+/// Why we're syncing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncReason {
+    /// A periodic sync in the background.
+    Scheduled,
+    /// The user asked us to sync, like by tapping "Sync now".
+    User,
+    /// The first sync after this device connected to an account. We sync
+    /// every registered engine, even if only some were requested.
+    FirstSync,
+}
+
+impl SyncReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SyncReason::Scheduled => "scheduled",
+            SyncReason::User => "user",
+            SyncReason::FirstSync => "firstSync",
+        }
+    }
+}
+
+/// What to sync, and the account to sync it with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncParams {
+    pub reason: SyncReason,
+    /// The names of the engines to sync, or `None` to sync every registered
+    /// engine.
+    #[serde(default)]
+    pub engines: Option<Vec<String>>,
+    /// The engines the user enabled (`true`) or declined (`false`) since the
+    /// last sync. These are synced to all the user's devices.
+    #[serde(default)]
+    pub engines_to_change_state: Option<HashMap<String, bool>>,
+    /// The `persisted_state` from the last `SyncResult`, or `None` if we've
+    /// never synced. The manager keeps its own copy between syncs, so this is
+    /// only used for the first sync after the app starts.
+    #[serde(default)]
+    pub persisted_state: Option<String>,
+    /// Describes this device, for the clients and tabs engines, which are
+    /// only synced if this is given.
+    #[serde(default)]
+    pub device_settings: Option<DeviceSettings>,
+    pub key_id: String,
+    pub access_token: String,
+    pub tokenserver_url: String,
+    /// The base64-encoded sync key.
+    pub sync_key: String,
+}
+
+/// This device's client ID, name, and type, for its client record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSettings {
+    pub fxa_device_id: String,
+    pub name: String,
+    #[serde(
+        rename = "type",
+        serialize_with = "serialize_device_type",
+        deserialize_with = "deserialize_device_type"
+    )]
+    pub device_type: DeviceType,
+}
+
+fn serialize_device_type<S: Serializer>(
+    device_type: &DeviceType,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(device_type.as_str())
+}
+
+fn deserialize_device_type<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<DeviceType, D::Error> {
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    DeviceType::from_record_type(&s)
+        .ok_or_else(|| D::Error::custom(format!("unknown device type {:?}", s)))
+}
+
+impl From<DeviceSettings> for Settings {
+    fn from(device: DeviceSettings) -> Settings {
+        Settings {
+            fxa_device_id: device.fxa_device_id,
+            device_name: device.name,
+            device_type: device.device_type,
+        }
+    }
+}
+
+/// Another of the user's devices, from its client record.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteClient {
+    /// The client ID, which is also the FxA device ID for clients that
+    /// set one.
+    pub id: String,
+    pub fxa_device_id: Option<String>,
+    pub name: String,
+    /// `None` if the client has a device type we don't know about.
+    #[serde(rename = "type", serialize_with = "serialize_remote_device_type")]
+    pub device_type: Option<DeviceType>,
+    /// When the client last uploaded its record, in milliseconds.
+    pub last_modified: i64,
+}
+
+fn serialize_remote_device_type<S: Serializer>(
+    device_type: &Option<DeviceType>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match device_type {
+        Some(device_type) => s.serialize_some(device_type.as_str()),
+        None => s.serialize_none(),
+    }
+}
+
+impl From<clients::RemoteClient> for RemoteClient {
+    fn from(client: clients::RemoteClient) -> RemoteClient {
+        RemoteClient {
+            id: client.id.into_string(),
+            fxa_device_id: client.fxa_device_id,
+            name: client.device_name,
+            device_type: client.device_type,
+            last_modified: client.last_modified.into(),
+        }
+    }
+}
+
+fn serialize_status<S: Serializer>(
+    status: &ServiceStatus,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_str(match status {
+        ServiceStatus::Ok => "ok",
+        ServiceStatus::NetworkError => "networkError",
+        ServiceStatus::ServiceError => "serviceError",
+        ServiceStatus::AuthenticationError => "authenticationError",
+        ServiceStatus::BackedOff => "backedOff",
+        ServiceStatus::Interrupted => "interrupted",
+        ServiceStatus::OtherError => "otherError",
+    })
+}
+
+/// The result of a sync.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    /// The general health of sync.
+    #[serde(serialize_with = "serialize_status")]
+    pub status: ServiceStatus,
+    /// The engines that synced successfully.
+    pub successful: Vec<String>,
+    /// The engines that failed to sync, with the error for each.
+    pub failures: HashMap<String, String>,
+    /// If the servers asked us to back off, the time, in milliseconds, before
+    /// which we won't sync again.
+    pub next_sync_after: Option<u64>,
+    /// The engines the user has declined on any of their devices, if we got
+    /// far enough to know.
+    pub declined: Option<Vec<String>>,
+    /// The user's other devices, sorted by ID, if the clients engine synced.
+    pub remote_clients: Option<Vec<RemoteClient>>,
+    /// State that the app must persist, and pass back in the `SyncParams` for
+    /// the next sync.
+    pub persisted_state: Option<String>,
+    /// The telemetry ping for all the engines we synced.
+    pub telemetry: SyncTelemetryPing,
+}
+
+// A sync is interrupted if any of the stores we're syncing are.
+struct AnyInterruptee<'a>(Vec<&'a dyn Interruptee>);
+
+impl<'a> Interruptee for AnyInterruptee<'a> {
+    fn was_interrupted(&self) -> bool {
+        self.0.iter().any(|i| i.was_interrupted())
+    }
+}
+
+/// Syncs the stores of all the components that are registered with it, using
+/// one `sync_multiple`, so that they share a tokenserver token, `meta/global`,
+/// and the rest of the global state.
+#[derive(Default)]
+pub struct SyncManager {
+    mem_cached_state: MemoryCachedState,
+    // The global state that `sync_multiple` persists. We keep it between
+    // syncs, so the app only needs to give it to us after it restarts.
+    persisted_state: Option<String>,
+    // In the order they were registered, which is the order we sync them.
+    components: Vec<Box<dyn SyncComponent + Send>>,
+}
+
+impl SyncManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a component, replacing any registered components that sync
+    /// the same engines.
+    pub fn register(&mut self, component: Box<dyn SyncComponent + Send>) {
+        let engines = component.engines();
+        self.components
+            .retain(|c| !c.engines().iter().any(|name| engines.contains(name)));
+        self.components.push(component);
+    }
+
+    /// Registers places, which syncs the history, bookmarks and reading list
+    /// engines.
+    pub fn set_places(&mut self, places: Weak<PlacesApi>) {
+        self.register(Box::new(PlacesComponent(places)));
+    }
+
+    /// Registers logins, which syncs the passwords engine.
+    pub fn set_logins(&mut self, logins: Weak<Mutex<PasswordEngine>>) {
+        self.register(Box::new(LoginsComponent(logins)));
+    }
+
+    /// Registers tabs, which syncs the tabs engine.
+    pub fn set_tabs(&mut self, tabs: Weak<Mutex<TabsEngine>>) {
+        self.register(Box::new(TabsComponent(tabs)));
+    }
+
+    /// Forgets the state we've cached in memory, and the persisted state,
+    /// for when the user signs out. The app should also forget its copy of
+    /// the persisted state.
+    pub fn disconnect(&mut self) {
+        self.mem_cached_state = MemoryCachedState::default();
+        self.persisted_state = None;
+    }
+
+    pub fn sync(&mut self, params: SyncParams) -> Result<SyncResult> {
+        let storage_init = Sync15StorageClientInit {
+            key_id: params.key_id.clone(),
+            access_token: params.access_token.clone(),
+            tokenserver_url: Url::parse(&params.tokenserver_url)?,
+        };
+        let root_sync_key = KeyBundle::from_ksync_base64(&params.sync_key)?;
+
+        let device = params.device_settings.clone().map(Settings::from);
+        let commands = device.clone().map(CommandQueue::new);
+        let clients = commands.as_ref().map(ClientsComponent);
+        // Like desktop, we sync the clients engine first, so that we see
+        // any commands before syncing the engines they're for.
+        let mut components: Vec<&dyn SyncComponent> = Vec::with_capacity(self.components.len() + 1);
+        components.extend(clients.as_ref().map(|c| c as &dyn SyncComponent));
+        components.extend(self.components.iter().map(|c| &**c as &dyn SyncComponent));
+        let registered = components
+            .iter()
+            .flat_map(|c| c.engines().iter().cloned())
+            .collect::<Vec<_>>();
+        let (engines, mut unavailable) = choose_engines(&params, &registered);
+
+        if params.reason == SyncReason::FirstSync {
+            // Nothing we've cached is useful for a device that just connected.
+            self.mem_cached_state = MemoryCachedState::default();
+        }
+        if self.persisted_state.is_none() {
+            self.persisted_state = params.persisted_state.clone();
+        }
+        log::info!("Syncing {:?} ({})", engines, params.reason.as_str());
+        let mem_cached_state = &mut self.mem_cached_state;
+        let persisted_state = &mut self.persisted_state;
+        let mut synced = Vec::with_capacity(engines.len());
+        let mut result = None;
+        with_all_stores(
+            &components,
+            &engines,
+            device.as_ref(),
+            &[],
+            &[],
+            &mut |stores, interruptee| {
+                synced.extend(stores.iter().map(|store| store.collection_name()));
+                result = Some(sync15::sync_multiple(
+                    stores,
+                    persisted_state,
+                    mem_cached_state,
+                    &storage_init,
+                    &root_sync_key,
+                    &SyncOptions {
+                        engine_updates: params.engines_to_change_state.as_ref(),
+                        ..SyncOptions::default()
+                    },
+                    // `sync_multiple` wants a sized `Interruptee`.
+                    &AnyInterruptee(vec![interruptee]),
+                ));
+                Ok(())
+            },
+        )?;
+        // Every component should call `f`, even if it's closed, but one that
+        // doesn't would otherwise skip the sync for all of them.
+        let result = result.ok_or(ErrorKind::ComponentDidNotSync)?;
+
+        // Components that have been closed, or can't sync without the device
+        // settings, don't give us stores for their engines.
+        let (engines, closed): (Vec<_>, Vec<_>) =
+            engines.into_iter().partition(|name| synced.contains(name));
+        unavailable.extend(closed.into_iter().map(String::from));
+
+        let clients_synced = result
+            .engine_results
+            .get(CLIENTS_ENGINE)
+            .map_or(false, std::result::Result::is_ok);
+        let remote_clients = commands
+            .as_ref()
+            .filter(|_| clients_synced)
+            .map(|commands| {
+                commands
+                    .take_remote_clients()
+                    .into_iter()
+                    .map(RemoteClient::from)
+                    .collect()
+            });
+
+        if let Some(commands) = &commands {
+            for command in commands.take() {
+                if let Err(e) = apply_command(&components, device.as_ref(), &command) {
+                    log::warn!("Failed to apply command {:?}: {}", command, e);
+                }
+            }
+        }
+
+        Ok(SyncResult::new(
+            result,
+            &engines,
+            unavailable,
+            remote_clients,
+            self.persisted_state.clone(),
+            params.reason,
+        ))
+    }
+}
+
+// Calls `f` with the stores for `engines` from all `components`, by nesting
+// their `with_stores` calls. `stores` and `interruptees` are the ones from
+// the components we've already visited.
+fn with_all_stores(
+    components: &[&dyn SyncComponent],
+    engines: &[&str],
+    device: Option<&Settings>,
+    stores: &[&dyn Store],
+    interruptees: &[&dyn Interruptee],
+    f: &mut WithStoresFn<'_>,
+) -> Result<()> {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => return f(stores, &AnyInterruptee(interruptees.to_vec())),
+    };
+    let component_engines = component
+        .engines()
+        .iter()
+        .cloned()
+        .filter(|name| engines.contains(name))
+        .collect::<Vec<_>>();
+    if component_engines.is_empty() {
+        return with_all_stores(rest, engines, device, stores, interruptees, f);
+    }
+    component.with_stores(
+        &component_engines,
+        device,
+        &mut |new_stores, interruptee| {
+            let mut all_stores = stores.to_vec();
+            all_stores.extend_from_slice(new_stores);
+            let mut all_interruptees = interruptees.to_vec();
+            all_interruptees.push(interruptee);
+            with_all_stores(rest, engines, device, &all_stores, &all_interruptees, f)
+        },
+    )
+}
+
+/// Applies a command that another device sent us through the clients
+/// engine. Commands for engines that aren't registered are ignored.
+fn apply_command(
+    components: &[&dyn SyncComponent],
+    device: Option<&Settings>,
+    command: &Command,
+) -> Result<()> {
+    let (names, wipe) = match command {
+        Command::Wipe(name) => (vec![name.as_str()], true),
+        Command::Reset(name) => (vec![name.as_str()], false),
+        Command::ResetAll => (
+            components
+                .iter()
+                .flat_map(|c| c.engines().iter().cloned())
+                .collect(),
+            false,
+        ),
+        Command::Logout => {
+            // Signing out is up to the app, which knows about the account.
+            log::warn!("Ignoring logout command");
+            return Ok(());
+        }
+    };
+    for component in components {
+        let engines = component
+            .engines()
+            .iter()
+            .cloned()
+            .filter(|name| names.contains(name))
+            .collect::<Vec<_>>();
+        if engines.is_empty() {
+            continue;
+        }
+        component.with_stores(&engines, device, &mut |stores, _| {
+            for store in stores {
+                if wipe {
+                    store.wipe().map_err(sync15::Error::from)?;
+                }
+                store
+                    .reset(&StoreSyncAssociation::Disconnected)
+                    .map_err(sync15::Error::from)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the engines to sync, in the order they're registered, and the
+/// names of any requested engines that aren't registered.
+fn choose_engines(
+    params: &SyncParams,
+    registered: &[&'static str],
+) -> (Vec<&'static str>, Vec<String>) {
+    let requested = match (&params.engines, params.reason) {
+        (Some(names), reason) if reason != SyncReason::FirstSync => names,
+        _ => return (registered.to_vec(), Vec::new()),
+    };
+    let unavailable = requested
+        .iter()
+        .filter(|name| !registered.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    let engines = registered
+        .iter()
+        .cloned()
+        .filter(|name| requested.iter().any(|r| r == name))
+        .collect();
+    (engines, unavailable)
+}
+
+impl SyncResult {
+    fn new(
+        mut result: sync15::SyncResult,
+        engines: &[&str],
+        unavailable: Vec<String>,
+        remote_clients: Option<Vec<RemoteClient>>,
+        persisted_state: Option<String>,
+        reason: SyncReason,
+    ) -> Self {
+        let mut successful = Vec::new();
+        let mut failures = HashMap::new();
+        for &name in engines {
+            match result.engine_results.remove(name) {
+                Some(Ok(())) => successful.push(name.to_owned()),
+                Some(Err(e)) => {
+                    failures.insert(name.to_owned(), e.to_string());
+                }
+                // We didn't get to this engine - if that's because the whole
+                // sync failed, it failed too.
+                None => {
+                    if let Err(e) = &result.result {
+                        failures.insert(name.to_owned(), e.to_string());
+                    }
+                }
+            }
+        }
+        for name in unavailable {
+            let message = format!("The {} engine isn't available", name);
+            failures.insert(name, message);
+        }
+        result.telemetry.why(reason.as_str());
+        SyncResult {
+            status: result.service_status,
+            successful,
+            failures,
+            next_sync_after: result.next_sync_after.and_then(|time| {
+                time.duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            }),
+            declined: result.declined,
+            remote_clients,
+            persisted_state,
+            telemetry: result.telemetry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use sync15::{
+        telemetry, CollectionRequest, IncomingChangeset, OutgoingChangeset, ServerTimestamp,
+    };
+    use sync_guid::Guid;
+
+    fn params(reason: SyncReason, engines: Option<&[&str]>) -> SyncParams {
+        SyncParams {
+            reason,
+            engines: engines.map(|names| names.iter().map(ToString::to_string).collect()),
+            engines_to_change_state: None,
+            persisted_state: None,
+            device_settings: None,
+            key_id: "key-id".into(),
+            access_token: "access-token".into(),
+            // This server doesn't exist, so syncs that touch the network fail.
+            tokenserver_url: "https://token.example.invalid/".into(),
+            // 64 zero bytes.
+            sync_key: "A".repeat(86),
+        }
+    }
+
+    #[test]
+    fn test_choose_engines() {
+        let registered = &[HISTORY_ENGINE, PASSWORDS_ENGINE];
+        let (engines, unavailable) =
+            choose_engines(&params(SyncReason::Scheduled, None), registered);
+        assert_eq!(engines, vec![HISTORY_ENGINE, PASSWORDS_ENGINE]);
+        assert!(unavailable.is_empty());
+
+        let (engines, unavailable) = choose_engines(
+            &params(SyncReason::User, Some(&["passwords", "bookmarks"])),
+            registered,
+        );
+        assert_eq!(engines, vec![PASSWORDS_ENGINE]);
+        assert_eq!(unavailable, vec!["bookmarks"]);
+
+        // First syncs sync everything.
+        let (engines, unavailable) = choose_engines(
+            &params(SyncReason::FirstSync, Some(&["passwords", "bookmarks"])),
+            registered,
+        );
+        assert_eq!(engines, vec![HISTORY_ENGINE, PASSWORDS_ENGINE]);
+        assert!(unavailable.is_empty());
+
+        let (engines, unavailable) =
+            choose_engines(&params(SyncReason::User, Some(&["tabs"])), registered);
+        assert!(engines.is_empty());
+        assert_eq!(unavailable, vec!["tabs"]);
+    }
+
+    #[test]
+    fn test_params_json() {
+        let params: SyncParams = serde_json::from_str(
+            r#"{
+                "reason": "firstSync",
+                "enginesToChangeState": {"history": false},
+                "keyId": "key-id",
+                "accessToken": "access-token",
+                "tokenserverUrl": "https://token.services.mozilla.com/",
+                "syncKey": "key",
+                "deviceSettings": {
+                    "fxaDeviceId": "device-id",
+                    "name": "My phone",
+                    "type": "mobile"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(params.reason, SyncReason::FirstSync);
+        assert_eq!(params.engines, None);
+        assert_eq!(
+            params.engines_to_change_state,
+            Some(vec![("history".to_owned(), false)].into_iter().collect())
+        );
+        assert_eq!(params.persisted_state, None);
+        assert_eq!(
+            params.device_settings,
+            Some(DeviceSettings {
+                fxa_device_id: "device-id".into(),
+                name: "My phone".into(),
+                device_type: DeviceType::Mobile,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sync_backed_off() {
+        let places = PlacesApi::new_memory("test_sync_backed_off").unwrap();
+        let logins = Arc::new(Mutex::new(PasswordEngine::new_in_memory(None).unwrap()));
+        let mut manager = SyncManager::new();
+        manager.set_places(Arc::downgrade(&places));
+        manager.set_logins(Arc::downgrade(&logins));
+
+        // Pretend the servers asked us to back off during the last sync, so
+        // that we don't touch the network.
+        let until = SystemTime::now() + Duration::from_secs(60);
+        let until_ms = until.duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000;
+        let persisted_state = format!(
+            r#"{{"schema_version":"V2","declined":null,"next_sync_after":{}}}"#,
+            until_ms
+        );
+        let mut sync_params = params(SyncReason::User, Some(&["history", "passwords"]));
+        sync_params.persisted_state = Some(persisted_state.clone());
+        let result = manager.sync(sync_params).unwrap();
+        assert_eq!(result.status, ServiceStatus::BackedOff);
+        assert_eq!(result.next_sync_after, Some(until_ms));
+        assert_eq!(result.persisted_state, Some(persisted_state));
+        assert!(result.successful.is_empty());
+        assert!(result.failures.is_empty());
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["status"], "backedOff");
+
+        // Engines that were closed are reported as failures. We kept the
+        // persisted state, so we're still backed off.
+        drop(logins);
+        let result = manager
+            .sync(params(SyncReason::User, Some(&["passwords"])))
+            .unwrap();
+        assert_eq!(result.status, ServiceStatus::BackedOff);
+        assert!(result.failures.contains_key("passwords"));
+
+        // The tabs and clients engines need the device settings.
+        let tabs = Arc::new(Mutex::new(TabsEngine::new_in_memory()));
+        manager.set_tabs(Arc::downgrade(&tabs));
+        let result = manager
+            .sync(params(SyncReason::User, Some(&["tabs", "clients"])))
+            .unwrap();
+        assert!(result.failures.contains_key("tabs"));
+        assert!(result.failures.contains_key("clients"));
+    }
+
+    // Records the commands applied to it.
+    #[derive(Default)]
+    struct TestStore {
+        calls: RefCell<Vec<&'static str>>,
+    }
+
+    impl Store for TestStore {
+        fn collection_name(&self) -> &'static str {
+            "test"
+        }
+
+        fn apply_incoming(
+            &self,
+            inbound: IncomingChangeset,
+            _telem: &mut telemetry::Engine,
+        ) -> std::result::Result<OutgoingChangeset, failure::Error> {
+            Ok(OutgoingChangeset::new("test".into(), inbound.timestamp))
+        }
+
+        fn sync_finished(
+            &self,
+            _new_timestamp: ServerTimestamp,
+            _records_synced: Vec<Guid>,
+        ) -> std::result::Result<(), failure::Error> {
+            Ok(())
+        }
+
+        fn get_collection_request(&self) -> std::result::Result<CollectionRequest, failure::Error> {
+            Ok(CollectionRequest::new("test").full())
+        }
+
+        fn get_sync_assoc(&self) -> std::result::Result<StoreSyncAssociation, failure::Error> {
+            Ok(StoreSyncAssociation::Disconnected)
+        }
+
+        fn reset(&self, _assoc: &StoreSyncAssociation) -> std::result::Result<(), failure::Error> {
+            self.calls.borrow_mut().push("reset");
+            Ok(())
+        }
+
+        fn wipe(&self) -> std::result::Result<(), failure::Error> {
+            self.calls.borrow_mut().push("wipe");
+            Ok(())
+        }
+    }
+
+    impl SyncComponent for TestStore {
+        fn engines(&self) -> &'static [&'static str] {
+            &["test"]
+        }
+
+        fn with_stores(
+            &self,
+            _engines: &[&str],
+            _device: Option<&Settings>,
+            f: &mut WithStoresFn<'_>,
+        ) -> Result<()> {
+            f(&[self], &interrupt::NeverInterrupts)
+        }
+    }
+
+    #[test]
+    fn test_apply_command() {
+        let component = TestStore::default();
+        let components: &[&dyn SyncComponent] = &[&component];
+        for command in &[
+            Command::Wipe("test".into()),
+            Command::Reset("test".into()),
+            Command::Reset("other".into()),
+            Command::ResetAll,
+            Command::Logout,
+        ] {
+            apply_command(components, None, command).unwrap();
+        }
+        assert_eq!(
+            *component.calls.borrow(),
+            vec!["wipe", "reset", "reset", "reset"]
+        );
+    }
+
+    // Returns without giving the manager its stores.
+    struct SkippingComponent;
+
+    impl SyncComponent for SkippingComponent {
+        fn engines(&self) -> &'static [&'static str] {
+            &["test"]
+        }
+
+        fn with_stores(
+            &self,
+            _engines: &[&str],
+            _device: Option<&Settings>,
+            _f: &mut WithStoresFn<'_>,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_component_did_not_sync() {
+        let mut manager = SyncManager::new();
+        manager.register(Box::new(SkippingComponent));
+        let err = manager
+            .sync(params(SyncReason::User, None))
+            .expect_err("Should fail if a component skips the sync");
+        match err.kind() {
+            ErrorKind::ComponentDidNotSync => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+    }
+}
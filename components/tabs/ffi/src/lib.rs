@@ -0,0 +1,115 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+// Let's allow these in the FFI code, since it's usually just a coincidence if
+// the closure is small.
+#![allow(clippy::redundant_closure)]
+
+use ffi_support::{
+    define_handle_map_deleter, define_string_destructor, ConcurrentHandleMap, ExternError, FfiStr,
+};
+use std::os::raw::c_char;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tabs::{ErrorKind, RemoteTab, Result, TabsEngine};
+
+// indirection to help `?` figure out the target error type
+fn parse_url(url: &str) -> Result<url::Url> {
+    Ok(url::Url::parse(url)?)
+}
+
+// Fails instead of panicking if the engine panicked while another call held
+// its lock.
+fn lock(engine: &Mutex<TabsEngine>) -> Result<MutexGuard<'_, TabsEngine>> {
+    Ok(engine.lock().map_err(|_| ErrorKind::EnginePoisoned)?)
+}
+
+lazy_static::lazy_static! {
+    // Like the logins engines, these are shared with the sync manager.
+    pub static ref ENGINES: ConcurrentHandleMap<Arc<Mutex<TabsEngine>>> = ConcurrentHandleMap::new();
+}
+
+/// Instantiate a tabs engine, which keeps its tabs in the file at `path`.
+/// If `path` is null, the tabs are only kept in memory. Returned engine must
+/// be freed with `tabs_destroy`.
+#[no_mangle]
+pub extern "C" fn tabs_new(path: FfiStr<'_>, error: &mut ExternError) -> u64 {
+    log::debug!("tabs_new");
+    ENGINES.insert_with_result(error, || -> Result<_> {
+        let engine = match path.as_opt_str() {
+            Some(path) => TabsEngine::new(path)?,
+            None => TabsEngine::new_in_memory(),
+        };
+        Ok(Arc::new(Mutex::new(engine)))
+    })
+}
+
+/// Replaces this device's open tabs with `local_state_json`, a JSON array of
+/// tabs, each with a `title`, `urlHistory`, optional `icon`, and `lastUsed`
+/// time in milliseconds.
+#[no_mangle]
+pub extern "C" fn tabs_update_local_state(
+    handle: u64,
+    local_state_json: FfiStr<'_>,
+    error: &mut ExternError,
+) {
+    log::debug!("tabs_update_local_state");
+    ENGINES.call_with_result(error, handle, |engine| -> Result<_> {
+        let local_tabs: Vec<RemoteTab> = serde_json::from_str(local_state_json.as_str())?;
+        lock(engine)?.update_local_state(local_tabs)
+    })
+}
+
+/// Returns the tabs on the user's other devices, as of the last sync, as a
+/// JSON array with an entry for each device.
+#[no_mangle]
+pub extern "C" fn tabs_get_remote_tabs(handle: u64, error: &mut ExternError) -> *mut c_char {
+    log::debug!("tabs_get_remote_tabs");
+    ENGINES.call_with_result(error, handle, |engine| -> Result<_> {
+        let remote_tabs = lock(engine)?.get_remote_tabs();
+        Ok(serde_json::to_string(&remote_tabs)?)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn tabs_reset(handle: u64, error: &mut ExternError) {
+    log::debug!("tabs_reset");
+    ENGINES.call_with_result(error, handle, |engine| -> Result<_> {
+        lock(engine)?.reset()
+    })
+}
+
+/// Syncs the tabs, and returns the sync telemetry ping as JSON. `local_id`
+/// is this device's client ID, and `local_name` is its name.
+#[allow(clippy::too_many_arguments)]
+#[no_mangle]
+pub extern "C" fn tabs_sync(
+    handle: u64,
+    key_id: FfiStr<'_>,
+    access_token: FfiStr<'_>,
+    sync_key: FfiStr<'_>,
+    tokenserver_url: FfiStr<'_>,
+    local_id: FfiStr<'_>,
+    local_name: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("tabs_sync");
+    ENGINES.call_with_result(error, handle, |engine| -> Result<_> {
+        let ping = lock(engine)?.sync(
+            &sync15::Sync15StorageClientInit {
+                key_id: key_id.into_string(),
+                access_token: access_token.into_string(),
+                tokenserver_url: parse_url(tokenserver_url.as_str())?,
+            },
+            &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+            local_id.as_str(),
+            local_name.as_str(),
+        )?;
+        Ok(ping)
+    })
+}
+
+define_string_destructor!(tabs_destroy_string);
+define_handle_map_deleter!(ENGINES, tabs_destroy);
@@ -0,0 +1,87 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::error::*;
+use crate::storage::{ClientRemoteTabs, RemoteTab, TabsStorage};
+use crate::store::TabsStore;
+use interrupt::NeverInterrupts;
+use std::cell::Cell;
+use std::path::Path;
+use sync15::{
+    sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
+    Sync15StorageClientInit, SyncOptions,
+};
+
+// Like `PasswordEngine`, this is a bundle of the tabs storage and the sync
+// state, rather than an engine in the desktop sense.
+pub struct TabsEngine {
+    pub storage: TabsStorage,
+    pub mem_cached_state: Cell<MemoryCachedState>,
+}
+
+impl TabsEngine {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self {
+            storage: TabsStorage::new(path)?,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
+    pub fn new_in_memory() -> Self {
+        Self {
+            storage: TabsStorage::new_in_memory(),
+            mem_cached_state: Cell::default(),
+        }
+    }
+
+    pub fn update_local_state(&self, local_tabs: Vec<RemoteTab>) -> Result<()> {
+        self.storage.update_local_state(local_tabs)
+    }
+
+    pub fn get_remote_tabs(&self) -> Vec<ClientRemoteTabs> {
+        self.storage.get_remote_tabs()
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        self.storage
+            .set_sync_assoc(&StoreSyncAssociation::Disconnected)
+    }
+
+    /// Uploads our tabs, and downloads the tabs from the user's other
+    /// devices. `local_id` and `local_name` identify this device; see
+    /// `TabsStore::new`.
+    pub fn sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+        local_id: &str,
+        local_name: &str,
+    ) -> Result<telemetry::SyncTelemetryPing> {
+        let mut disk_cached_state = self.storage.get_global_state();
+        let mut mem_cached_state = self.mem_cached_state.take();
+        let store = TabsStore::new(&self.storage, local_id, local_name);
+
+        let mut result = sync_multiple(
+            &[&store],
+            &mut disk_cached_state,
+            &mut mem_cached_state,
+            storage_init,
+            root_sync_key,
+            &SyncOptions::default(),
+            &NeverInterrupts,
+        );
+        // We always update the state - sync_multiple does the right thing
+        // if it needs to be dropped.
+        self.storage.set_global_state(disk_cached_state)?;
+        self.mem_cached_state.replace(mem_cached_state);
+
+        if let Err(e) = result.result {
+            return Err(e.into());
+        }
+        match result.engine_results.remove("tabs") {
+            None | Some(Ok(())) => Ok(result.telemetry),
+            Some(Err(e)) => Err(e.into()),
+        }
+    }
+}
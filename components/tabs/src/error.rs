@@ -0,0 +1,32 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use failure::Fail;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Error synchronizing: {}", _0)]
+    SyncAdapterError(#[fail(cause)] sync15::Error),
+
+    #[fail(display = "Error parsing JSON data: {}", _0)]
+    JsonError(#[fail(cause)] serde_json::Error),
+
+    #[fail(display = "Error reading or writing the tabs file: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
+
+    #[fail(display = "Error parsing URL: {}", _0)]
+    UrlParseError(#[fail(cause)] url::ParseError),
+
+    #[fail(display = "The tabs engine panicked in an earlier call")]
+    EnginePoisoned,
+}
+
+error_support::define_error! {
+    ErrorKind {
+        (SyncAdapterError, sync15::Error),
+        (JsonError, serde_json::Error),
+        (IoError, std::io::Error),
+        (UrlParseError, url::ParseError),
+    }
+}
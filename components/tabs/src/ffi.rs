@@ -0,0 +1,72 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// This module implement the traits that make the FFI code easier to manage.
+
+use crate::error::{Error, ErrorKind};
+use ffi_support::{ErrorCode, ExternError};
+
+pub mod error_codes {
+    /// An unexpected error occurred which likely cannot be meaningfully handled
+    /// by the application.
+    pub const UNEXPECTED: i32 = -2;
+
+    // Note: -1 and 0 (panic and success) codes are reserved by the ffi-support library
+
+    /// Indicates the FxA credentials are invalid, and should be refreshed.
+    pub const AUTH_INVALID: i32 = 1;
+
+    /// A request to the sync server failed.
+    pub const NETWORK: i32 = 2;
+
+    /// The local tabs passed to `tabs_update_local_state` weren't valid JSON.
+    pub const INVALID_TABS: i32 = 3;
+}
+
+fn get_code(err: &Error) -> ErrorCode {
+    match err.kind() {
+        ErrorKind::SyncAdapterError(e) => {
+            use sync15::ErrorKind;
+            match e.kind() {
+                ErrorKind::TokenserverHttpError(401) | ErrorKind::BadKeyLength(..) => {
+                    log::error!("Sync auth error: {:?}", e);
+                    ErrorCode::new(error_codes::AUTH_INVALID)
+                }
+                ErrorKind::RequestError(_) => {
+                    log::error!("Sync network error: {:?}", e);
+                    ErrorCode::new(error_codes::NETWORK)
+                }
+                ErrorKind::StoreError(store_error) => {
+                    // If it's a type-erased version of one of our errors, try
+                    // and resolve it.
+                    if let Some(tabs_err) = store_error.downcast_ref::<Error>() {
+                        log::info!("Recursing to resolve tabs error");
+                        get_code(tabs_err)
+                    } else {
+                        log::error!("Unexpected sync error: {:?}", err);
+                        ErrorCode::new(error_codes::UNEXPECTED)
+                    }
+                }
+                _ => {
+                    log::error!("Unexpected sync error: {:?}", err);
+                    ErrorCode::new(error_codes::UNEXPECTED)
+                }
+            }
+        }
+        ErrorKind::JsonError(e) => {
+            log::error!("Invalid tabs JSON: {}", e);
+            ErrorCode::new(error_codes::INVALID_TABS)
+        }
+        err => {
+            log::error!("Unexpected error: {:?}", err);
+            ErrorCode::new(error_codes::UNEXPECTED)
+        }
+    }
+}
+
+impl From<Error> for ExternError {
+    fn from(e: Error) -> ExternError {
+        ExternError::new_error(get_code(&e), e.to_string())
+    }
+}
@@ -0,0 +1,17 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+mod engine;
+mod error;
+mod ffi;
+mod storage;
+mod store;
+
+pub use crate::engine::TabsEngine;
+pub use crate::error::*;
+pub use crate::storage::{ClientRemoteTabs, RemoteTab, TabsStorage};
+pub use crate::store::TabsStore;
@@ -0,0 +1,234 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keeps this device's open tabs, as supplied by the app, and the tabs on
+//! the user's other devices, as of the last sync.
+//!
+//! There's not much data, and it's always read and written as a whole, so
+//! instead of a database we keep it in memory, and write it all to a JSON
+//! file whenever it changes. The remote tabs are replaced on every sync, so
+//! losing the file only means the app has nothing to show until the next
+//! sync; we start over if it's unreadable.
+
+use crate::error::*;
+use serde_derive::*;
+use std::cell::RefCell;
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::path::{Path, PathBuf};
+use sync15::{CollSyncIds, StoreSyncAssociation};
+use sync_guid::Guid;
+
+/// An open tab, either on this device or another one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteTab {
+    pub title: String,
+    /// The URLs the tab has visited, most recent first. The first is the
+    /// URL the tab is showing.
+    pub url_history: Vec<String>,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// When the tab was last used, in milliseconds since the Unix epoch.
+    pub last_used: u64,
+}
+
+/// The open tabs on another of the user's devices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRemoteTabs {
+    /// The device's client ID, which is also the ID of its record in the
+    /// clients collection.
+    pub client_id: String,
+    pub client_name: String,
+    pub remote_tabs: Vec<RemoteTab>,
+    /// When the device last uploaded its tabs, in milliseconds since the
+    /// Unix epoch.
+    pub last_modified: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabsState {
+    #[serde(default)]
+    local_tabs: Vec<RemoteTab>,
+    #[serde(default)]
+    remote_tabs: Vec<ClientRemoteTabs>,
+    #[serde(default)]
+    global_sync_id: Option<Guid>,
+    #[serde(default)]
+    coll_sync_id: Option<Guid>,
+    #[serde(default)]
+    global_state: Option<String>,
+}
+
+pub struct TabsStorage {
+    /// `None` if we only keep the tabs in memory.
+    path: Option<PathBuf>,
+    state: RefCell<TabsState>,
+}
+
+impl TabsStorage {
+    /// Opens the tabs file at `path`, which is created the first time the
+    /// tabs change.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let state = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid tabs file: {}", e);
+                TabsState::default()
+            }),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => TabsState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            state: RefCell::new(state),
+        })
+    }
+
+    pub fn new_in_memory() -> Self {
+        Self {
+            path: None,
+            state: RefCell::default(),
+        }
+    }
+
+    /// Replaces this device's open tabs. They're uploaded on the next sync.
+    pub fn update_local_state(&self, local_tabs: Vec<RemoteTab>) -> Result<()> {
+        self.state.borrow_mut().local_tabs = local_tabs;
+        self.persist()
+    }
+
+    pub fn get_local_tabs(&self) -> Vec<RemoteTab> {
+        self.state.borrow().local_tabs.clone()
+    }
+
+    /// Returns the open tabs on each of the user's other devices, as of the
+    /// last sync, sorted by client ID.
+    pub fn get_remote_tabs(&self) -> Vec<ClientRemoteTabs> {
+        self.state.borrow().remote_tabs.clone()
+    }
+
+    pub(crate) fn replace_remote_tabs(&self, mut remote_tabs: Vec<ClientRemoteTabs>) -> Result<()> {
+        remote_tabs.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        self.state.borrow_mut().remote_tabs = remote_tabs;
+        self.persist()
+    }
+
+    pub(crate) fn get_sync_assoc(&self) -> StoreSyncAssociation {
+        let state = self.state.borrow();
+        match (&state.global_sync_id, &state.coll_sync_id) {
+            (Some(global), Some(coll)) => StoreSyncAssociation::Connected(CollSyncIds {
+                global: global.clone(),
+                coll: coll.clone(),
+            }),
+            _ => StoreSyncAssociation::Disconnected,
+        }
+    }
+
+    pub(crate) fn set_sync_assoc(&self, assoc: &StoreSyncAssociation) -> Result<()> {
+        {
+            let mut state = self.state.borrow_mut();
+            match assoc {
+                StoreSyncAssociation::Connected(ids) => {
+                    state.global_sync_id = Some(ids.global.clone());
+                    state.coll_sync_id = Some(ids.coll.clone());
+                }
+                StoreSyncAssociation::Disconnected => {
+                    state.global_sync_id = None;
+                    state.coll_sync_id = None;
+                }
+            }
+        }
+        self.persist()
+    }
+
+    pub(crate) fn get_global_state(&self) -> Option<String> {
+        self.state.borrow().global_state.clone()
+    }
+
+    pub(crate) fn set_global_state(&self, global_state: Option<String>) -> Result<()> {
+        self.state.borrow_mut().global_state = global_state;
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let contents = serde_json::to_vec(&*self.state.borrow())?;
+        // Write to a temporary file first, so that we don't leave a partly
+        // written file behind if we're killed.
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tab(url: &str, last_used: u64) -> RemoteTab {
+        RemoteTab {
+            title: "Example".into(),
+            url_history: vec![url.into()],
+            icon: None,
+            last_used,
+        }
+    }
+
+    #[test]
+    fn test_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tabs.json");
+
+        let storage = TabsStorage::new(&path).unwrap();
+        assert!(storage.get_local_tabs().is_empty());
+        assert!(!path.exists());
+
+        storage
+            .update_local_state(vec![tab("https://example.com", 1000)])
+            .unwrap();
+        storage
+            .replace_remote_tabs(vec![ClientRemoteTabs {
+                client_id: "deviceBBBBBB".into(),
+                client_name: "Phone".into(),
+                remote_tabs: vec![tab("https://example.org", 2000)],
+                last_modified: 3000,
+            }])
+            .unwrap();
+        let ids = CollSyncIds {
+            global: "globalAAAAAA".into(),
+            coll: "collAAAAAAAA".into(),
+        };
+        storage
+            .set_sync_assoc(&StoreSyncAssociation::Connected(ids.clone()))
+            .unwrap();
+        storage.set_global_state(Some("{}".into())).unwrap();
+
+        let reopened = TabsStorage::new(&path).unwrap();
+        assert_eq!(reopened.get_local_tabs(), storage.get_local_tabs());
+        assert_eq!(reopened.get_remote_tabs(), storage.get_remote_tabs());
+        assert_eq!(
+            reopened.get_sync_assoc(),
+            StoreSyncAssociation::Connected(ids)
+        );
+        assert_eq!(reopened.get_global_state(), Some("{}".into()));
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tabs.json");
+        fs::write(&path, "not json").unwrap();
+
+        let storage = TabsStorage::new(&path).unwrap();
+        assert!(storage.get_local_tabs().is_empty());
+        assert_eq!(storage.get_sync_assoc(), StoreSyncAssociation::Disconnected);
+    }
+}
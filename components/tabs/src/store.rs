@@ -0,0 +1,412 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Syncs the `tabs` collection, which has a record for each of the user's
+//! devices, listing the device's open tabs. The record's ID is the device's
+//! client ID, so that other clients can match it up with its client record.
+//!
+//! Each device only writes its own record, so there's nothing to reconcile:
+//! we upload our record whenever our tabs change, and replace the remote
+//! tabs with whatever's on the server. Like client records, tab records are
+//! uploaded with a TTL, so that the records of devices that stop syncing
+//! expire, and we refresh ours before it does.
+
+use crate::error::*;
+use crate::storage::{ClientRemoteTabs, RemoteTab, TabsStorage};
+use serde::de::{Deserializer, Error as _};
+use serde_derive::*;
+use std::result;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sync15::clients::{CLIENTS_TTL, CLIENTS_TTL_REFRESH};
+use sync15::{
+    telemetry, CollectionRequest, IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp,
+    Store, StoreSyncAssociation,
+};
+use sync_guid::Guid;
+
+const COLLECTION_NAME: &str = "tabs";
+
+/// The TTL for tab records, in seconds. A device's tabs are only useful for
+/// as long as the device itself is, so this matches the clients engine.
+const TABS_TTL: u32 = CLIENTS_TTL;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabsRecord {
+    id: Guid,
+    client_name: String,
+    tabs: Vec<TabsRecordTab>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabsRecordTab {
+    title: String,
+    url_history: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    /// In seconds, unlike `RemoteTab::last_used`.
+    #[serde(deserialize_with = "deserialize_last_used")]
+    last_used: u64,
+}
+
+// Older desktop versions upload `lastUsed` as a string.
+fn deserialize_last_used<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> result::Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum LastUsed {
+        Number(u64),
+        String(String),
+    }
+    match serde::Deserialize::deserialize(deserializer)? {
+        LastUsed::Number(n) => Ok(n),
+        LastUsed::String(s) => s.parse().map_err(D::Error::custom),
+    }
+}
+
+impl TabsRecordTab {
+    fn from_tab(tab: &RemoteTab) -> TabsRecordTab {
+        TabsRecordTab {
+            title: tab.title.clone(),
+            url_history: tab.url_history.clone(),
+            icon: tab.icon.clone(),
+            last_used: tab.last_used / 1000,
+        }
+    }
+
+    fn into_tab(self) -> RemoteTab {
+        RemoteTab {
+            title: self.title,
+            url_history: self.url_history,
+            icon: self.icon,
+            last_used: self.last_used * 1000,
+        }
+    }
+}
+
+/// A `Store` for the tabs collection.
+pub struct TabsStore<'a> {
+    storage: &'a TabsStorage,
+    local_id: &'a str,
+    local_name: &'a str,
+}
+
+impl<'a> TabsStore<'a> {
+    /// `local_id` is this device's client ID, which should be the same as
+    /// the ID of its client record, and `local_name` is the name other
+    /// devices show for it.
+    pub fn new(storage: &'a TabsStorage, local_id: &'a str, local_name: &'a str) -> Self {
+        TabsStore {
+            storage,
+            local_id,
+            local_name,
+        }
+    }
+
+    /// Builds the record for our tabs, with the most recently used first.
+    /// Tabs without a URL aren't worth sending.
+    fn local_record(&self) -> TabsRecord {
+        let mut local_tabs = self.storage.get_local_tabs();
+        local_tabs.retain(|tab| !tab.url_history.is_empty());
+        local_tabs.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        TabsRecord {
+            id: self.local_id.into(),
+            client_name: self.local_name.into(),
+            tabs: local_tabs.iter().map(TabsRecordTab::from_tab).collect(),
+        }
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+        now: ServerTimestamp,
+    ) -> Result<OutgoingChangeset> {
+        let local_record = self.local_record();
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let mut remote_tabs = Vec::with_capacity(inbound.changes.len());
+        let mut upload_local_record = true;
+        for (payload, modified) in inbound.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let record: TabsRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Ignoring invalid tabs record: {}", e);
+                    incoming_telemetry.failed(1);
+                    continue;
+                }
+            };
+            if record.id == self.local_id {
+                let is_stale = now
+                    .duration_since(modified)
+                    .map_or(false, |age| age >= CLIENTS_TTL_REFRESH);
+                upload_local_record = record != local_record || is_stale;
+                continue;
+            }
+            incoming_telemetry.applied(1);
+            remote_tabs.push(ClientRemoteTabs {
+                client_id: record.id.into_string(),
+                client_name: record.client_name,
+                remote_tabs: record
+                    .tabs
+                    .into_iter()
+                    .map(TabsRecordTab::into_tab)
+                    .collect(),
+                last_modified: modified.as_millis(),
+            });
+        }
+        telem.incoming(incoming_telemetry);
+        self.storage.replace_remote_tabs(remote_tabs)?;
+
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), inbound.timestamp);
+        if upload_local_record {
+            outgoing
+                .changes
+                .push(Payload::from_record(local_record)?.with_ttl(TABS_TTL));
+        }
+        Ok(outgoing)
+    }
+}
+
+/// Drops the least recently used half of the tabs in our record, which are
+/// at the end.
+fn shrink_record(mut record: Payload) -> Option<Payload> {
+    let tabs = record.data.get_mut("tabs")?.as_array_mut()?;
+    if tabs.len() <= 1 {
+        return None;
+    }
+    let keep = tabs.len() / 2;
+    tabs.truncate(keep);
+    Some(record)
+}
+
+impl<'a> Store for TabsStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| ServerTimestamp(d.as_millis() as i64))
+            .unwrap_or_default();
+        Ok(self.do_apply_incoming(inbound, telem, now)?)
+    }
+
+    fn shrink_record(&self, record: Payload) -> result::Result<Option<Payload>, failure::Error> {
+        Ok(shrink_record(record))
+    }
+
+    fn sync_finished(
+        &self,
+        _new_timestamp: ServerTimestamp,
+        _records_synced: Vec<Guid>,
+    ) -> result::Result<(), failure::Error> {
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        // We always fetch every record, since we replace all the remote
+        // tabs on each sync.
+        Ok(CollectionRequest::new(COLLECTION_NAME).full())
+    }
+
+    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.storage.get_sync_assoc())
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+        self.storage.set_sync_assoc(assoc)?;
+        Ok(())
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        // Our own tabs are the app's, so we only forget the remote ones.
+        self.storage.replace_remote_tabs(Vec::new())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NOW: i64 = 1_600_000_000_000;
+
+    fn inbound(records: Vec<(serde_json::Value, i64)>) -> IncomingChangeset {
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0));
+        for (json, modified) in records {
+            inbound
+                .changes
+                .push((Payload::from_json(json).unwrap(), ServerTimestamp(modified)));
+        }
+        inbound
+    }
+
+    fn storage_with_local_tabs() -> TabsStorage {
+        let storage = TabsStorage::new_in_memory();
+        storage
+            .update_local_state(vec![
+                RemoteTab {
+                    title: "Older".into(),
+                    url_history: vec!["https://example.org".into()],
+                    icon: None,
+                    last_used: 1_000_000,
+                },
+                RemoteTab {
+                    title: "Newer".into(),
+                    url_history: vec![
+                        "https://example.com/2".into(),
+                        "https://example.com/1".into(),
+                    ],
+                    icon: Some("https://example.com/favicon.ico".into()),
+                    last_used: 2_000_000,
+                },
+                RemoteTab {
+                    title: "New tab".into(),
+                    url_history: vec![],
+                    icon: None,
+                    last_used: 3_000_000,
+                },
+            ])
+            .unwrap();
+        storage
+    }
+
+    fn local_record() -> serde_json::Value {
+        json!({
+            "id": "deviceAAAAAA",
+            "clientName": "Laptop",
+            "tabs": [{
+                "title": "Newer",
+                "urlHistory": ["https://example.com/2", "https://example.com/1"],
+                "icon": "https://example.com/favicon.ico",
+                "lastUsed": 2000,
+            }, {
+                "title": "Older",
+                "urlHistory": ["https://example.org"],
+                "lastUsed": 1000,
+            }],
+        })
+    }
+
+    #[test]
+    fn test_first_sync() {
+        let storage = storage_with_local_tabs();
+        let store = TabsStore::new(&storage, "deviceAAAAAA", "Laptop");
+        let mut telem = telemetry::Engine::new("tabs");
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![
+                    (
+                        json!({
+                            "id": "deviceBBBBBB",
+                            "clientName": "Phone",
+                            "tabs": [{
+                                "title": "Example",
+                                "urlHistory": ["https://example.net"],
+                                "lastUsed": "1500",
+                            }],
+                        }),
+                        NOW - 1000,
+                    ),
+                    (json!({ "id": "deviceCCCCCC", "deleted": true }), NOW),
+                ]),
+                &mut telem,
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+
+        assert_eq!(
+            storage.get_remote_tabs(),
+            vec![ClientRemoteTabs {
+                client_id: "deviceBBBBBB".into(),
+                client_name: "Phone".into(),
+                remote_tabs: vec![RemoteTab {
+                    title: "Example".into(),
+                    url_history: vec!["https://example.net".into()],
+                    icon: None,
+                    last_used: 1_500_000,
+                }],
+                last_modified: NOW - 1000,
+            }]
+        );
+
+        // We don't have a record on the server yet, so we upload one.
+        assert_eq!(outgoing.changes.len(), 1);
+        let bso = outgoing.changes[0].clone().into_bso(COLLECTION_NAME.into());
+        assert_eq!(bso.ttl, Some(TABS_TTL));
+        assert_eq!(serde_json::to_value(bso.payload).unwrap(), local_record());
+    }
+
+    #[test]
+    fn test_refresh_local_record() {
+        let storage = storage_with_local_tabs();
+        let store = TabsStore::new(&storage, "deviceAAAAAA", "Laptop");
+
+        // Our record is up to date.
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(local_record(), NOW - 1000)]),
+                &mut telemetry::Engine::new("tabs"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+        assert!(storage.get_remote_tabs().is_empty());
+
+        // Our record is about to expire.
+        let refresh_ms = CLIENTS_TTL_REFRESH.as_millis() as i64;
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(local_record(), NOW - refresh_ms)]),
+                &mut telemetry::Engine::new("tabs"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+
+        // We closed a tab.
+        let mut local_tabs = storage.get_local_tabs();
+        local_tabs.remove(0);
+        storage.update_local_state(local_tabs).unwrap();
+        let outgoing = store
+            .do_apply_incoming(
+                inbound(vec![(local_record(), NOW - 1000)]),
+                &mut telemetry::Engine::new("tabs"),
+                ServerTimestamp(NOW),
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(
+            outgoing.changes[0].data["tabs"].as_array().unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_shrink_record() {
+        let record = Payload::from_json(local_record()).unwrap();
+        let shrunk = shrink_record(record).unwrap();
+        assert_eq!(
+            shrunk.data["tabs"],
+            json!([{
+                "title": "Newer",
+                "urlHistory": ["https://example.com/2", "https://example.com/1"],
+                "icon": "https://example.com/favicon.ico",
+                "lastUsed": 2000,
+            }])
+        );
+        assert!(shrink_record(shrunk).is_none());
+    }
+}
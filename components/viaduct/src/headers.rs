@@ -375,10 +375,12 @@ pub mod consts {
         (USER_AGENT, "user-agent"),
         // non-standard, but it's convenient to have these.
         (RETRY_AFTER, "retry-after"),
+        (X_BACKOFF, "x-backoff"),
         (X_IF_UNMODIFIED_SINCE, "x-if-unmodified-since"),
         (X_KEYID, "x-keyid"),
         (X_LAST_MODIFIED, "x-last-modified"),
         (X_TIMESTAMP, "x-timestamp"),
+        (X_WEAVE_BACKOFF, "x-weave-backoff"),
         (X_WEAVE_NEXT_OFFSET, "x-weave-next-offset"),
         (X_WEAVE_RECORDS, "x-weave-records"),
         (X_WEAVE_TIMESTAMP, "x-weave-timestamp"),
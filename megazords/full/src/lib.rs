@@ -13,6 +13,8 @@ pub use logins_ffi;
 pub use places_ffi;
 pub use push_ffi;
 pub use rc_log_ffi;
+pub use sync_manager_ffi;
+pub use tabs_ffi;
 pub use viaduct;
 
 /// In order to support the use case of consumers who don't know about megazords
@@ -0,0 +1,206 @@
+/* Any copyright is dedicated to the Public Domain.
+http://creativecommons.org/publicdomain/zero/1.0/ */
+
+// Just enough HTTP/1.1 to serve the requests our sync client makes. Every
+// response closes its connection, so we never need to handle pipelining or
+// keep-alive, and our client always sends a `Content-Length` with its bodies.
+
+use std::io::{self, BufRead, Write};
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Request {
+    /// Reads a request, or returns `None` if the connection was closed
+    /// before it sent one.
+    pub fn read_from(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.trim_end().split(' ');
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+            _ => return Err(invalid_data("Malformed request line")),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(invalid_data("Connection closed in headers"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut split = header.splitn(2, ':');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) => {
+                    headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+                }
+                _ => return Err(invalid_data("Malformed header")),
+            }
+        }
+
+        let mut request = Request {
+            method,
+            path: String::new(),
+            query: Vec::new(),
+            headers,
+            body: Vec::new(),
+        };
+        let content_length = match request.header("content-length") {
+            Some(length) => length
+                .parse::<usize>()
+                .map_err(|_| invalid_data("Malformed Content-Length"))?,
+            None => 0,
+        };
+        request.body.resize(content_length, 0);
+        reader.read_exact(&mut request.body)?;
+
+        // The target is only a path and query, so we need a base to parse it.
+        let url = Url::parse("http://127.0.0.1")
+            .and_then(|base| base.join(&target))
+            .map_err(|_| invalid_data("Malformed request target"))?;
+        request.path = url.path().to_owned();
+        request.query = url.query_pairs().into_owned().collect();
+        Ok(Some(request))
+    }
+
+    /// Returns the value of the header `name`, which must be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param_name, _)| param_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Response::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.to_string().into_bytes())
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_owned(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        )?;
+        for (name, value) in &self.headers {
+            write!(writer, "{}: {}\r\n", name, value)?;
+        }
+        write!(
+            writer,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /1/1.5/1/storage/bookmarks?batch=true&commit=true HTTP/1.1\r\n\
+                   Host: 127.0.0.1\r\n\
+                   X-If-Unmodified-Since: 123.45\r\n\
+                   Content-Length: 2\r\n\
+                   \r\n\
+                   []";
+        let request = Request::read_from(&mut raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/1/1.5/1/storage/bookmarks");
+        assert_eq!(request.query_param("batch"), Some("true"));
+        assert_eq!(request.query_param("commit"), Some("true"));
+        assert_eq!(request.header("x-if-unmodified-since"), Some("123.45"));
+        assert_eq!(request.body, b"[]");
+
+        assert!(Request::read_from(&mut "".as_bytes()).unwrap().is_none());
+        assert!(Request::read_from(&mut "GET\r\n\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut written = Vec::new();
+        Response::json(200, &serde_json::json!({"a": 1}))
+            .header("X-Last-Modified", "1.00")
+            .write_to(&mut written)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             X-Last-Modified: 1.00\r\n\
+             Content-Length: 7\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {\"a\":1}"
+        );
+    }
+}
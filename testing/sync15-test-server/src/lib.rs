@@ -0,0 +1,445 @@
+/* Any copyright is dedicated to the Public Domain.
+http://creativecommons.org/publicdomain/zero/1.0/ */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+//! A tokenserver and Sync 1.5 storage server that runs in-process on
+//! 127.0.0.1, for testing the sync15 client end to end without an FxA
+//! account or live servers.
+//!
+//! ```no_run
+//! let server = sync15_test_server::TestServer::start();
+//! // Use `server.tokenserver_url()` as the `tokenserver_url` in the
+//! // `Sync15StorageClientInit`. Any key ID and access token will do.
+//! server.inject_fault(
+//!     sync15_test_server::Endpoint::storage("info/collections"),
+//!     sync15_test_server::Fault::ServiceUnavailable { retry_after: 60 },
+//! );
+//! ```
+//!
+//! The server doesn't check Hawk signatures, or decrypt anything - tests see
+//! the records exactly as the client uploaded them.
+
+mod http;
+mod storage;
+
+pub use crate::storage::ServerBso;
+
+use crate::http::{Request, Response};
+use crate::storage::Storage;
+use serde_json::json;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+// The path of the tokenserver endpoint. The sync15 client adds the
+// `1.0/sync/1.5` to the URL from `tokenserver_url`.
+const TOKENSERVER_PATH: &str = "/token/1.0/sync/1.5";
+
+// Our one and only user.
+const UID: u64 = 1;
+
+// How long, in seconds, the tokens we hand out are valid for.
+const TOKEN_DURATION: u64 = 3600;
+
+/// Where to inject a fault.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// The tokenserver.
+    Tokenserver,
+    /// A route on the storage server, relative to the storage endpoint, like
+    /// `info/collections` or `storage/meta/global`. Routes that start with
+    /// this one also match, so `storage` matches any collection.
+    Storage(String),
+    /// Like `Storage`, but only matches requests that write to the route,
+    /// so that we can fail an upload after the client fetched the same
+    /// route.
+    StorageWrite(String),
+}
+
+impl Endpoint {
+    pub fn storage(route: &str) -> Self {
+        Endpoint::Storage(route.to_owned())
+    }
+
+    pub fn storage_write(route: &str) -> Self {
+        Endpoint::StorageWrite(route.to_owned())
+    }
+
+    fn matches(&self, method: &str, route: Option<&str>) -> bool {
+        match (self, route) {
+            (Endpoint::Tokenserver, None) => true,
+            (Endpoint::Storage(prefix), Some(route)) => route.starts_with(prefix.as_str()),
+            (Endpoint::StorageWrite(prefix), Some(route)) => {
+                method != "GET" && route.starts_with(prefix.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A fault to inject into the response to the next matching request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Fail with a 401, like when a token has expired, or the credentials
+    /// are invalid.
+    Unauthorized,
+    /// Fail with a 412, like when another client wrote first.
+    PreconditionFailed,
+    /// Fail with a 503 and a `Retry-After` header, in seconds.
+    ServiceUnavailable { retry_after: u64 },
+    /// Handle the request as usual, but ask the client to back off for
+    /// `seconds`, with `X-Backoff` from the tokenserver, or `X-Weave-Backoff`
+    /// from storage.
+    Backoff { seconds: u64 },
+}
+
+// Everything the server knows, behind the server's lock.
+#[derive(Debug, Default)]
+struct ServerState {
+    // The port we're listening on, for building the storage endpoint URL.
+    port: u16,
+    // The storage node the user is assigned to. Reassigning the user moves
+    // them to a new, empty node, and requests to the old one fail with a
+    // 401.
+    node: u32,
+    storage: Storage,
+    faults: Vec<(Endpoint, Fault)>,
+    requests: Vec<String>,
+}
+
+impl ServerState {
+    fn api_endpoint(&self) -> String {
+        format!("http://127.0.0.1:{}/{}/1.5/{}", self.port, self.node, UID)
+    }
+
+    fn take_fault(&mut self, method: &str, route: Option<&str>) -> Option<Fault> {
+        let index = self
+            .faults
+            .iter()
+            .position(|(endpoint, _)| endpoint.matches(method, route))?;
+        Some(self.faults.remove(index).1)
+    }
+
+    fn handle(&mut self, request: &Request) -> Response {
+        self.requests
+            .push(format!("{} {}", request.method, request.path));
+        if request.path == TOKENSERVER_PATH {
+            return self.handle_tokenserver(request);
+        }
+
+        // Storage paths are `/{node}/1.5/{uid}/{route}`.
+        let segments: Vec<&str> = request.path.splitn(5, '/').skip(1).collect();
+        let (node, route) = match segments.as_slice() {
+            [node, "1.5", uid] if *uid == UID.to_string() => (*node, ""),
+            [node, "1.5", uid, route] if *uid == UID.to_string() => (*node, *route),
+            _ => return Response::new(404),
+        };
+        if node != self.node.to_string() {
+            log::info!("Request to old node {}", node);
+            return Response::new(401);
+        }
+        if !request
+            .header("authorization")
+            .map_or(false, |auth| auth.starts_with("Hawk "))
+        {
+            return Response::new(401);
+        }
+        match self.take_fault(&request.method, Some(route)) {
+            Some(Fault::Backoff { seconds }) => self
+                .storage
+                .handle(route, request)
+                .header("X-Weave-Backoff", seconds.to_string()),
+            Some(fault) => fault_response(&fault),
+            None => self.storage.handle(route, request),
+        }
+    }
+
+    fn handle_tokenserver(&mut self, request: &Request) -> Response {
+        let response = match self.take_fault(&request.method, None) {
+            Some(Fault::Backoff { seconds }) => self
+                .token_response(request)
+                .header("X-Backoff", seconds.to_string()),
+            Some(fault) => fault_response(&fault),
+            None => self.token_response(request),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        response.header("X-Timestamp", now.to_string())
+    }
+
+    fn token_response(&self, request: &Request) -> Response {
+        let has_credentials = request
+            .header("authorization")
+            .map_or(false, |auth| auth.starts_with("Bearer "))
+            && request.header("x-keyid").is_some();
+        if !has_credentials {
+            return Response::json(
+                401,
+                &json!({ "status": "invalid-credentials", "errors": [] }),
+            );
+        }
+        Response::json(
+            200,
+            &json!({
+                "id": format!("token-{}", self.node),
+                "key": "key",
+                "api_endpoint": self.api_endpoint(),
+                "uid": UID,
+                "duration": TOKEN_DURATION,
+                "hashed_fxa_uid": "hashed-fxa-uid",
+            }),
+        )
+    }
+}
+
+fn fault_response(fault: &Fault) -> Response {
+    match fault {
+        Fault::Unauthorized => Response::new(401),
+        Fault::PreconditionFailed => Response::new(412),
+        Fault::ServiceUnavailable { retry_after } => {
+            Response::new(503).header("Retry-After", retry_after.to_string())
+        }
+        Fault::Backoff { .. } => unreachable!("backoffs aren't failures"),
+    }
+}
+
+/// A tokenserver and storage server, listening on 127.0.0.1 on a port of
+/// its own. The server shuts down when this is dropped.
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Starts a server with empty storage.
+    pub fn start() -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+        let addr = listener.local_addr().expect("Test server has no address");
+        let state = Arc::new(Mutex::new(ServerState {
+            port: addr.port(),
+            node: 1,
+            ..ServerState::default()
+        }));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || serve(&listener, &state, &shutdown))
+        };
+        TestServer {
+            addr,
+            state,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// The URL to use as the `tokenserver_url`.
+    pub fn tokenserver_url(&self) -> Url {
+        Url::parse(&format!("http://{}/token/", self.addr)).unwrap()
+    }
+
+    /// The storage endpoint that the tokenserver currently hands out.
+    pub fn api_endpoint(&self) -> Url {
+        Url::parse(&self.state.lock().unwrap().api_endpoint()).unwrap()
+    }
+
+    /// Fails, or adds backoff headers to, the next request to `endpoint`.
+    /// Faults are used in the order they were injected.
+    pub fn inject_fault(&self, endpoint: Endpoint, fault: Fault) {
+        self.state.lock().unwrap().faults.push((endpoint, fault));
+    }
+
+    /// Moves the user to a new, empty, storage node. Requests to the old
+    /// node fail with a 401, and the tokenserver hands out the new one.
+    pub fn reassign_node(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.node += 1;
+        state.storage.reset();
+    }
+
+    /// Replaces the server limits returned from `info/configuration`.
+    pub fn set_info_configuration(&self, config: serde_json::Value) {
+        self.state.lock().unwrap().storage.info_configuration = config;
+    }
+
+    /// Returns the record `id` in `collection`, if it exists.
+    pub fn bso(&self, collection: &str, id: &str) -> Option<ServerBso> {
+        self.state
+            .lock()
+            .unwrap()
+            .storage
+            .bso(collection, id)
+            .cloned()
+    }
+
+    /// Returns the IDs of all the records in `collection`, in order.
+    pub fn ids(&self, collection: &str) -> Vec<String> {
+        self.state.lock().unwrap().storage.ids(collection)
+    }
+
+    /// Returns and forgets the requests we've handled so far, like
+    /// `GET /1/1.5/1/info/collections`.
+    pub fn take_requests(&self) -> Vec<String> {
+        std::mem::replace(&mut self.state.lock().unwrap().requests, Vec::new())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the server thread, which is blocked waiting for a
+        // connection.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(listener: &TcpListener, state: &Mutex<ServerState>, shutdown: &AtomicBool) {
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let result = stream.and_then(|stream| {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let request = match Request::read_from(&mut reader)? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            log::trace!("test server request: {} {}", request.method, request.path);
+            let response = state.lock().unwrap().handle(&request);
+            log::trace!("test server response: {}", response.status);
+            response.write_to(&mut &stream)
+        });
+        if let Err(e) = result {
+            log::warn!("Test server connection failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: Vec::new(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    fn state() -> ServerState {
+        ServerState {
+            port: 8080,
+            node: 1,
+            ..ServerState::default()
+        }
+    }
+
+    const HAWK: (&str, &str) = ("authorization", "Hawk id=\"token-1\"");
+
+    #[test]
+    fn test_tokenserver() {
+        let mut state = state();
+        let response = state.handle(&request("GET", TOKENSERVER_PATH, &[]));
+        assert_eq!(response.status, 401);
+
+        let credentials = [("authorization", "Bearer token"), ("x-keyid", "key-id")];
+        let response = state.handle(&request("GET", TOKENSERVER_PATH, &credentials));
+        assert_eq!(response.status, 200);
+        let token: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(token["api_endpoint"], "http://127.0.0.1:8080/1/1.5/1");
+        assert!(response
+            .headers
+            .iter()
+            .any(|(name, _)| name == "X-Timestamp"));
+
+        state
+            .faults
+            .push((Endpoint::Tokenserver, Fault::Backoff { seconds: 30 }));
+        let response = state.handle(&request("GET", TOKENSERVER_PATH, &credentials));
+        assert_eq!(response.status, 200);
+        assert!(response
+            .headers
+            .contains(&("X-Backoff".to_owned(), "30".to_owned())));
+    }
+
+    #[test]
+    fn test_storage_faults() {
+        let mut state = state();
+        let path = "/1/1.5/1/info/collections";
+        assert_eq!(state.handle(&request("GET", path, &[])).status, 401);
+        assert_eq!(state.handle(&request("GET", path, &[HAWK])).status, 200);
+
+        state.faults.push((
+            Endpoint::storage("storage"),
+            Fault::ServiceUnavailable { retry_after: 60 },
+        ));
+        state
+            .faults
+            .push((Endpoint::storage("info"), Fault::PreconditionFailed));
+        let response = state.handle(&request("GET", path, &[HAWK]));
+        assert_eq!(response.status, 412);
+        // Faults are only used once.
+        assert_eq!(state.handle(&request("GET", path, &[HAWK])).status, 200);
+        let response = state.handle(&request("GET", "/1/1.5/1/storage/tabs", &[HAWK]));
+        assert_eq!(response.status, 503);
+        assert!(response
+            .headers
+            .contains(&("Retry-After".to_owned(), "60".to_owned())));
+
+        assert_eq!(
+            state.requests,
+            vec![
+                "GET /1/1.5/1/info/collections",
+                "GET /1/1.5/1/info/collections",
+                "GET /1/1.5/1/info/collections",
+                "GET /1/1.5/1/info/collections",
+                "GET /1/1.5/1/storage/tabs",
+            ]
+        );
+
+        // Write faults skip reads of the same route.
+        state.faults.push((
+            Endpoint::storage_write("storage/meta/global"),
+            Fault::PreconditionFailed,
+        ));
+        let path = "/1/1.5/1/storage/meta/global";
+        assert_eq!(state.handle(&request("GET", path, &[HAWK])).status, 404);
+        assert_eq!(state.handle(&request("PUT", path, &[HAWK])).status, 412);
+    }
+
+    #[test]
+    fn test_reassign_node() {
+        let server = TestServer::start();
+        let old_endpoint = server.api_endpoint();
+        server.reassign_node();
+        let new_endpoint = server.api_endpoint();
+        assert_ne!(old_endpoint, new_endpoint);
+
+        let mut state = server.state.lock().unwrap();
+        let path = format!("{}/info/collections", old_endpoint.path());
+        assert_eq!(state.handle(&request("GET", &path, &[HAWK])).status, 401);
+        let path = format!("{}/info/collections", new_endpoint.path());
+        assert_eq!(state.handle(&request("GET", &path, &[HAWK])).status, 200);
+    }
+}
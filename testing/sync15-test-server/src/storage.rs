@@ -0,0 +1,587 @@
+/* Any copyright is dedicated to the Public Domain.
+http://creativecommons.org/publicdomain/zero/1.0/ */
+
+// An in-memory implementation of the Sync 1.5 storage API, as described at
+// https://mozilla-services.readthedocs.io/en/latest/storage/apis-1.5.html.
+// It handles requests relative to a user's storage endpoint, like
+// `info/collections` or `storage/bookmarks`.
+
+use crate::http::{Request, Response};
+use serde_derive::*;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A record, as the server stores it. Timestamps are in milliseconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerBso {
+    pub id: String,
+    pub modified: i64,
+    pub sortindex: Option<i64>,
+    pub payload: String,
+}
+
+impl ServerBso {
+    fn to_json(&self) -> Value {
+        let mut json = json!({
+            "id": self.id,
+            "modified": seconds(self.modified),
+            "payload": self.payload,
+        });
+        if let Some(sortindex) = self.sortindex {
+            json["sortindex"] = sortindex.into();
+        }
+        json
+    }
+}
+
+// A record as the client uploads it. Every field but the ID is optional,
+// and missing fields keep their old values.
+#[derive(Debug, Clone, Deserialize)]
+struct IncomingBso {
+    id: String,
+    #[serde(default)]
+    sortindex: Option<i64>,
+    #[serde(default)]
+    payload: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Collection {
+    modified: i64,
+    bsos: BTreeMap<String, ServerBso>,
+}
+
+// The records we've been sent for an uncommitted batch.
+#[derive(Debug)]
+struct Batch {
+    collection: String,
+    bsos: Vec<IncomingBso>,
+}
+
+/// Formats a timestamp in milliseconds as the server does, in seconds with
+/// two decimal places.
+pub fn format_timestamp(ms: i64) -> String {
+    format!("{:.2}", seconds(ms))
+}
+
+fn seconds(ms: i64) -> f64 {
+    ms as f64 / 1000.0
+}
+
+fn parse_timestamp(s: &str) -> Option<i64> {
+    s.parse::<f64>()
+        .ok()
+        .map(|secs| (secs * 1000.0).round() as i64)
+}
+
+#[derive(Debug)]
+pub struct Storage {
+    collections: BTreeMap<String, Collection>,
+    batches: HashMap<String, Batch>,
+    next_batch_id: u64,
+    // The last timestamp we handed out, so that each write gets a later one.
+    last_timestamp: i64,
+    pub(crate) info_configuration: Value,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            collections: BTreeMap::new(),
+            batches: HashMap::new(),
+            next_batch_id: 1,
+            last_timestamp: 0,
+            info_configuration: json!({
+                "max_request_bytes": 2_101_248,
+                "max_post_records": 100,
+                "max_post_bytes": 2_097_152,
+                "max_total_records": 10_000,
+                "max_total_bytes": 104_857_600,
+                "max_record_payload_bytes": 2_097_152,
+            }),
+        }
+    }
+}
+
+impl Storage {
+    /// Returns the current server time. Like the real server, timestamps
+    /// have a resolution of 10 milliseconds, and writes always get a later
+    /// timestamp than the last one.
+    fn next_timestamp(&mut self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64 / 10 * 10)
+            .unwrap_or(0);
+        self.last_timestamp = now.max(self.last_timestamp + 10);
+        self.last_timestamp
+    }
+
+    fn last_modified(&self) -> i64 {
+        self.collections
+            .values()
+            .map(|collection| collection.modified)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Deletes everything, like on a new storage node, but keeps our
+    /// configuration.
+    pub fn reset(&mut self) {
+        let info_configuration = self.info_configuration.take();
+        *self = Storage {
+            info_configuration,
+            ..Storage::default()
+        };
+    }
+
+    pub fn bso(&self, collection: &str, id: &str) -> Option<&ServerBso> {
+        self.collections
+            .get(collection)
+            .and_then(|collection| collection.bsos.get(id))
+    }
+
+    pub fn ids(&self, collection: &str) -> Vec<String> {
+        self.collections
+            .get(collection)
+            .map(|collection| collection.bsos.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Handles a request for `route`, which is relative to the storage
+    /// endpoint.
+    pub fn handle(&mut self, route: &str, request: &Request) -> Response {
+        let segments: Vec<&str> = route.split('/').filter(|s| !s.is_empty()).collect();
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["info", "collections"]) => self.info_collections(),
+            ("GET", ["info", "configuration"]) => Response::json(200, &self.info_configuration)
+                .header("X-Last-Modified", format_timestamp(self.last_modified())),
+            ("DELETE", []) | ("DELETE", ["storage"]) => self.delete_all(),
+            ("GET", ["storage", collection]) => self.get_collection(collection, request),
+            ("POST", ["storage", collection]) => self.post_collection(collection, request),
+            ("DELETE", ["storage", collection]) => self.delete_collection(collection, request),
+            ("GET", ["storage", collection, id]) => self.get_bso(collection, id),
+            ("PUT", ["storage", collection, id]) => self.put_bso(collection, id, request),
+            ("DELETE", ["storage", collection, id]) => self.delete_bso(collection, id, request),
+            _ => match segments.first() {
+                Some(&"info") | Some(&"storage") => Response::new(405),
+                _ => Response::new(404),
+            },
+        };
+        response.header("X-Weave-Timestamp", format_timestamp(self.next_timestamp()))
+    }
+
+    fn info_collections(&self) -> Response {
+        let collections: serde_json::Map<String, Value> = self
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.modified > 0)
+            .map(|(name, collection)| (name.clone(), seconds(collection.modified).into()))
+            .collect();
+        Response::json(200, &collections.into())
+            .header("X-Last-Modified", format_timestamp(self.last_modified()))
+    }
+
+    // Returns a 412 if `modified` is later than the request's
+    // `X-If-Unmodified-Since`.
+    fn check_unmodified(&self, request: &Request, modified: i64) -> Option<Response> {
+        let since = request
+            .header("x-if-unmodified-since")
+            .and_then(parse_timestamp)?;
+        if modified > since {
+            Some(Response::new(412).header("X-Last-Modified", format_timestamp(modified)))
+        } else {
+            None
+        }
+    }
+
+    fn delete_all(&mut self) -> Response {
+        self.collections.clear();
+        self.batches.clear();
+        Response::json(200, &json!({}))
+            .header("X-Last-Modified", format_timestamp(self.next_timestamp()))
+    }
+
+    fn get_collection(&self, name: &str, request: &Request) -> Response {
+        let collection_modified = self.collections.get(name).map_or(0, |c| c.modified);
+        let newer = request.query_param("newer").and_then(parse_timestamp);
+        let older = request.query_param("older").and_then(parse_timestamp);
+        let ids = request
+            .query_param("ids")
+            .map(|ids| ids.split(',').collect::<Vec<_>>());
+        let mut bsos: Vec<&ServerBso> = self
+            .collections
+            .get(name)
+            .map(|collection| collection.bsos.values().collect())
+            .unwrap_or_default();
+        bsos.retain(|bso| {
+            newer.map_or(true, |newer| bso.modified > newer)
+                && older.map_or(true, |older| bso.modified < older)
+                && ids
+                    .as_ref()
+                    .map_or(true, |ids| ids.iter().any(|id| *id == bso.id))
+        });
+        match request.query_param("sort") {
+            Some("newest") => bsos.sort_by(|a, b| b.modified.cmp(&a.modified)),
+            Some("oldest") => bsos.sort_by(|a, b| a.modified.cmp(&b.modified)),
+            Some("index") => bsos.sort_by(|a, b| b.sortindex.cmp(&a.sortindex)),
+            _ => {}
+        }
+
+        // `offset` is the index of the first record to return, and we return
+        // the next one in `X-Weave-Next-Offset` if we hit the `limit`.
+        let offset = request
+            .query_param("offset")
+            .and_then(|offset| offset.parse::<usize>().ok())
+            .unwrap_or(0);
+        let limit = request
+            .query_param("limit")
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .filter(|limit| *limit > 0);
+        let remaining = bsos.len().saturating_sub(offset);
+        let count = limit.map_or(remaining, |limit| limit.min(remaining));
+        let page = bsos.into_iter().skip(offset).take(count);
+        let body: Vec<Value> = if request.query_param("full").is_some() {
+            page.map(ServerBso::to_json).collect()
+        } else {
+            page.map(|bso| bso.id.clone().into()).collect()
+        };
+
+        let mut response = Response::json(200, &body.into())
+            .header("X-Last-Modified", format_timestamp(collection_modified))
+            .header("X-Weave-Records", count.to_string());
+        if count < remaining {
+            response = response.header("X-Weave-Next-Offset", (offset + count).to_string());
+        }
+        response
+    }
+
+    fn get_bso(&self, collection: &str, id: &str) -> Response {
+        match self.bso(collection, id) {
+            Some(bso) => Response::json(200, &bso.to_json())
+                .header("X-Last-Modified", format_timestamp(bso.modified)),
+            None => Response::new(404),
+        }
+    }
+
+    fn put_bso(&mut self, collection: &str, id: &str, request: &Request) -> Response {
+        let modified = self.bso(collection, id).map_or(0, |bso| bso.modified);
+        if let Some(response) = self.check_unmodified(request, modified) {
+            return response;
+        }
+        let mut incoming: IncomingBso = match serde_json::from_slice(&request.body) {
+            Ok(incoming) => incoming,
+            Err(_) => return Response::new(400),
+        };
+        incoming.id = id.to_owned();
+        let timestamp = self.next_timestamp();
+        if !self.apply(collection, vec![incoming], timestamp) {
+            return Response::new(400);
+        }
+        Response::json(200, &seconds(timestamp).into())
+            .header("X-Last-Modified", format_timestamp(timestamp))
+    }
+
+    fn delete_bso(&mut self, collection: &str, id: &str, request: &Request) -> Response {
+        let modified = match self.bso(collection, id) {
+            Some(bso) => bso.modified,
+            None => return Response::new(404),
+        };
+        if let Some(response) = self.check_unmodified(request, modified) {
+            return response;
+        }
+        let timestamp = self.next_timestamp();
+        let collection = self.collections.get_mut(collection).unwrap();
+        collection.bsos.remove(id);
+        collection.modified = timestamp;
+        Response::json(200, &json!({ "modified": seconds(timestamp) }))
+            .header("X-Last-Modified", format_timestamp(timestamp))
+    }
+
+    fn delete_collection(&mut self, name: &str, request: &Request) -> Response {
+        let modified = self.collections.get(name).map_or(0, |c| c.modified);
+        if let Some(response) = self.check_unmodified(request, modified) {
+            return response;
+        }
+        let timestamp = self.next_timestamp();
+        match request.query_param("ids") {
+            Some(ids) => {
+                if let Some(collection) = self.collections.get_mut(name) {
+                    for id in ids.split(',') {
+                        collection.bsos.remove(id);
+                    }
+                    collection.modified = timestamp;
+                }
+            }
+            None => {
+                self.collections.remove(name);
+            }
+        }
+        Response::json(200, &json!({ "modified": seconds(timestamp) }))
+            .header("X-Last-Modified", format_timestamp(timestamp))
+    }
+
+    // Handles a POST, which might start, add to, or commit a batch, or be a
+    // plain upload.
+    fn post_collection(&mut self, name: &str, request: &Request) -> Response {
+        let modified = self.collections.get(name).map_or(0, |c| c.modified);
+        if let Some(response) = self.check_unmodified(request, modified) {
+            return response;
+        }
+        let incoming: Vec<Value> = match serde_json::from_slice(&request.body) {
+            Ok(incoming) => incoming,
+            Err(_) => return Response::new(400),
+        };
+        let mut success = Vec::new();
+        let mut failed = serde_json::Map::new();
+        let mut valid = Vec::with_capacity(incoming.len());
+        for value in incoming {
+            let id = value["id"].as_str().map(ToOwned::to_owned);
+            match serde_json::from_value::<IncomingBso>(value) {
+                Ok(bso) => {
+                    success.push(bso.id.clone());
+                    valid.push(bso);
+                }
+                Err(e) => {
+                    failed.insert(id.unwrap_or_default(), e.to_string().into());
+                }
+            }
+        }
+
+        let commit = request.query_param("commit") == Some("true");
+        let bsos = match request.query_param("batch") {
+            None => valid,
+            Some(batch_id) => {
+                let mut batch = if batch_id == "true" {
+                    Batch {
+                        collection: name.to_owned(),
+                        bsos: Vec::new(),
+                    }
+                } else if self.batches.get(batch_id).map(|b| b.collection.as_str()) == Some(name) {
+                    self.batches.remove(batch_id).unwrap()
+                } else {
+                    return Response::new(400);
+                };
+                batch.bsos.extend(valid);
+                if !commit {
+                    let batch_id = if batch_id == "true" {
+                        let id = self.next_batch_id.to_string();
+                        self.next_batch_id += 1;
+                        id
+                    } else {
+                        batch_id.to_owned()
+                    };
+                    self.batches.insert(batch_id.clone(), batch);
+                    // Nothing's been written yet, so the collection hasn't
+                    // changed.
+                    return Response::json(
+                        202,
+                        &json!({ "batch": batch_id, "success": success, "failed": failed }),
+                    )
+                    .header("X-Last-Modified", format_timestamp(modified));
+                }
+                batch.bsos
+            }
+        };
+
+        let timestamp = self.next_timestamp();
+        if !self.apply(name, bsos, timestamp) {
+            return Response::new(400);
+        }
+        Response::json(
+            200,
+            &json!({ "modified": seconds(timestamp), "success": success, "failed": failed }),
+        )
+        .header("X-Last-Modified", format_timestamp(timestamp))
+    }
+
+    // Writes records to a collection, all with the same timestamp. Returns
+    // false, without writing anything, if a new record doesn't have a
+    // payload.
+    fn apply(&mut self, name: &str, bsos: Vec<IncomingBso>, timestamp: i64) -> bool {
+        let collection = self.collections.entry(name.to_owned()).or_default();
+        let is_valid = bsos
+            .iter()
+            .all(|bso| bso.payload.is_some() || collection.bsos.contains_key(&bso.id));
+        if !is_valid {
+            return false;
+        }
+        for incoming in bsos {
+            let existing = collection.bsos.get(&incoming.id);
+            let bso = ServerBso {
+                sortindex: incoming
+                    .sortindex
+                    .or_else(|| existing.and_then(|bso| bso.sortindex)),
+                payload: match incoming.payload {
+                    Some(payload) => payload,
+                    None => existing.unwrap().payload.clone(),
+                },
+                id: incoming.id,
+                modified: timestamp,
+            };
+            collection.bsos.insert(bso.id.clone(), bso);
+        }
+        collection.modified = timestamp;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(
+        method: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: String::new(),
+            query: query
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn body(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let mut storage = Storage::default();
+        let response = storage.handle("storage/meta/global", &request("GET", &[], &[], ""));
+        assert_eq!(response.status, 404);
+
+        let response = storage.handle(
+            "storage/meta/global",
+            &request(
+                "PUT",
+                &[],
+                &[("x-if-unmodified-since", "0")],
+                r#"{"id": "global", "payload": "{}"}"#,
+            ),
+        );
+        assert_eq!(response.status, 200);
+        let modified = header(&response, "X-Last-Modified").unwrap().to_owned();
+
+        let response = storage.handle("storage/meta/global", &request("GET", &[], &[], ""));
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response)["payload"], "{}");
+        assert_eq!(
+            header(&response, "X-Last-Modified"),
+            Some(modified.as_str())
+        );
+
+        let response = storage.handle("info/collections", &request("GET", &[], &[], ""));
+        assert_eq!(body(&response)["meta"].as_f64(), modified.parse().ok());
+
+        // Writing with an older timestamp fails.
+        let response = storage.handle(
+            "storage/meta/global",
+            &request(
+                "PUT",
+                &[],
+                &[("x-if-unmodified-since", "0")],
+                r#"{"id": "global", "payload": "{}"}"#,
+            ),
+        );
+        assert_eq!(response.status, 412);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut storage = Storage::default();
+        let response = storage.handle(
+            "storage/bookmarks",
+            &request(
+                "POST",
+                &[("batch", "true")],
+                &[],
+                r#"[{"id": "a", "payload": "1"}, {"payload": "2"}]"#,
+            ),
+        );
+        assert_eq!(response.status, 202);
+        let json = body(&response);
+        assert_eq!(json["success"], json!(["a"]));
+        assert!(json["failed"].as_object().unwrap().contains_key(""));
+        let batch = json["batch"].as_str().unwrap().to_owned();
+        // Nothing's visible until the batch is committed.
+        assert!(storage.ids("bookmarks").is_empty());
+
+        let response = storage.handle(
+            "storage/bookmarks",
+            &request(
+                "POST",
+                &[("batch", &batch), ("commit", "true")],
+                &[("x-if-unmodified-since", "0")],
+                r#"[{"id": "b", "payload": "2", "sortindex": 5}]"#,
+            ),
+        );
+        assert_eq!(response.status, 200);
+        assert_eq!(storage.ids("bookmarks"), vec!["a", "b"]);
+        assert_eq!(storage.bso("bookmarks", "b").unwrap().sortindex, Some(5));
+
+        // The batch is gone once it's committed.
+        let response = storage.handle(
+            "storage/bookmarks",
+            &request("POST", &[("batch", &batch)], &[], "[]"),
+        );
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn test_get_collection() {
+        let mut storage = Storage::default();
+        for (id, sortindex) in &[("a", 1), ("b", 3), ("c", 2)] {
+            let body = format!(
+                r#"[{{"id": "{}", "payload": "x", "sortindex": {}}}]"#,
+                id, sortindex
+            );
+            storage.handle("storage/tabs", &request("POST", &[], &[], &body));
+        }
+        let response = storage.handle(
+            "storage/tabs",
+            &request("GET", &[("sort", "index"), ("limit", "2")], &[], ""),
+        );
+        assert_eq!(body(&response), json!(["b", "c"]));
+        assert_eq!(header(&response, "X-Weave-Next-Offset"), Some("2"));
+
+        let newer = format_timestamp(storage.bso("tabs", "a").unwrap().modified);
+        let response = storage.handle(
+            "storage/tabs",
+            &request(
+                "GET",
+                &[("full", "1"), ("newer", &newer), ("sort", "oldest")],
+                &[],
+                "",
+            ),
+        );
+        let json = body(&response);
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[0]["id"], "b");
+        assert_eq!(json[0]["sortindex"], 3);
+
+        let response = storage.handle("storage/tabs", &request("GET", &[("ids", "a,c")], &[], ""));
+        assert_eq!(body(&response), json!(["a", "c"]));
+
+        storage.handle("storage", &request("DELETE", &[], &[], ""));
+        assert!(storage.ids("tabs").is_empty());
+    }
+}
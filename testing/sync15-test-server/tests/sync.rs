@@ -0,0 +1,881 @@
+/* Any copyright is dedicated to the Public Domain.
+http://creativecommons.org/publicdomain/zero/1.0/ */
+
+// Runs the sync15 client against the test server.
+
+use interrupt::NeverInterrupts;
+use logins::PasswordEngine;
+use places::bookmark_sync::store::BookmarksStore;
+use places::reading_list_sync::ReadingListStore;
+use places::storage::bookmarks::{
+    self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark, InsertableFolder,
+};
+use places::storage::reading_list;
+use places::PlacesApi;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{mpsc, Mutex};
+use sync15::clients::DeviceType;
+use sync15::{
+    sync_multiple, sync_multiple_in_parallel, telemetry, CollectionRequest, IncomingChangeset,
+    KeyBundle, MemoryCachedState, OutgoingChangeset, Payload, ServerTimestamp, ServiceStatus,
+    Store, StoreFactory, StoreSyncAssociation, Sync15StorageClientInit, SyncMode, SyncOptions,
+};
+use sync15_test_server::{Endpoint, Fault, TestServer};
+use sync_guid::Guid;
+use sync_manager::{DeviceSettings, SyncManager, SyncParams, SyncReason};
+
+// A store that uploads the records it's given, and remembers the ones it
+// downloads. It's `Sync`, so that `TestStores` can hand it out to the
+// threads that sync it in parallel.
+struct TestStore {
+    collection: &'static str,
+    assoc: Mutex<StoreSyncAssociation>,
+    last_sync: Mutex<ServerTimestamp>,
+    outgoing: Mutex<Vec<Payload>>,
+    incoming: Mutex<Vec<Payload>>,
+    synced: Mutex<Vec<Guid>>,
+    backfill: Mutex<Vec<CollectionRequest>>,
+    atomic: bool,
+}
+
+impl TestStore {
+    fn new(outgoing: Vec<Payload>) -> Self {
+        TestStore::for_collection("tabs", outgoing)
+    }
+
+    fn for_collection(collection: &'static str, outgoing: Vec<Payload>) -> Self {
+        TestStore {
+            collection,
+            assoc: Mutex::new(StoreSyncAssociation::Disconnected),
+            last_sync: Mutex::new(ServerTimestamp::default()),
+            outgoing: Mutex::new(outgoing),
+            incoming: Mutex::new(Vec::new()),
+            synced: Mutex::new(Vec::new()),
+            backfill: Mutex::new(Vec::new()),
+            atomic: true,
+        }
+    }
+}
+
+impl Store for TestStore {
+    fn collection_name(&self) -> &'static str {
+        self.collection
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        let mut outgoing = OutgoingChangeset::new(self.collection.into(), inbound.timestamp);
+        self.incoming
+            .lock()
+            .unwrap()
+            .extend(inbound.changes.into_iter().map(|(payload, _)| payload));
+        outgoing.changes = std::mem::replace(&mut *self.outgoing.lock().unwrap(), Vec::new());
+        Ok(outgoing)
+    }
+
+    fn apply_incoming_dry_run(
+        &self,
+        inbound: IncomingChangeset,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        let mut outgoing = OutgoingChangeset::new(self.collection.into(), inbound.timestamp);
+        outgoing.changes = self.outgoing.lock().unwrap().clone();
+        Ok(outgoing)
+    }
+
+    // Halves records with a `text` field; other records can't be shrunk.
+    fn shrink_record(&self, mut record: Payload) -> Result<Option<Payload>, failure::Error> {
+        let text = match record.data.get("text").and_then(|text| text.as_str()) {
+            Some(text) => text[..text.len() / 2].to_owned(),
+            None => return Ok(None),
+        };
+        record.data.insert("text".into(), text.into());
+        Ok(Some(record))
+    }
+
+    fn requires_atomic_upload(&self) -> bool {
+        self.atomic
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> Result<(), failure::Error> {
+        *self.last_sync.lock().unwrap() = new_timestamp;
+        self.synced.lock().unwrap().extend(records_synced);
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+        Ok(CollectionRequest::new(self.collection)
+            .full()
+            .newer_than(*self.last_sync.lock().unwrap()))
+    }
+
+    fn get_backfill_request(&self) -> Result<Option<CollectionRequest>, failure::Error> {
+        Ok(self.backfill.lock().unwrap().pop())
+    }
+
+    fn apply_backfill(
+        &self,
+        inbound: IncomingChangeset,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<(), failure::Error> {
+        self.incoming
+            .lock()
+            .unwrap()
+            .extend(inbound.changes.into_iter().map(|(payload, _)| payload));
+        Ok(())
+    }
+
+    fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.assoc.lock().unwrap().clone())
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> Result<(), failure::Error> {
+        *self.assoc.lock().unwrap() = assoc.clone();
+        *self.last_sync.lock().unwrap() = ServerTimestamp::default();
+        Ok(())
+    }
+
+    fn wipe(&self) -> Result<(), failure::Error> {
+        Ok(())
+    }
+}
+
+// Makes stores for parallel syncs from the ones it holds.
+struct TestStores(Vec<TestStore>);
+
+impl StoreFactory for TestStores {
+    fn with_store(
+        &self,
+        collection: &'static str,
+        f: &mut dyn FnMut(&dyn Store),
+    ) -> Result<(), failure::Error> {
+        let store = self
+            .0
+            .iter()
+            .find(|store| store.collection == collection)
+            .ok_or_else(|| failure::format_err!("No {} store", collection))?;
+        f(store);
+        Ok(())
+    }
+}
+
+// Only starts syncing the `waiting` store once the `first` store has
+// finished, so that we can fail a store after another has uploaded.
+struct OrderedStores {
+    stores: TestStores,
+    first: &'static str,
+    waiting: &'static str,
+    first_done: Mutex<mpsc::Sender<()>>,
+    first_done_rx: Mutex<mpsc::Receiver<()>>,
+}
+
+impl OrderedStores {
+    fn new(stores: TestStores, first: &'static str, waiting: &'static str) -> Self {
+        let (tx, rx) = mpsc::channel();
+        OrderedStores {
+            stores,
+            first,
+            waiting,
+            first_done: Mutex::new(tx),
+            first_done_rx: Mutex::new(rx),
+        }
+    }
+}
+
+impl StoreFactory for OrderedStores {
+    fn with_store(
+        &self,
+        collection: &'static str,
+        f: &mut dyn FnMut(&dyn Store),
+    ) -> Result<(), failure::Error> {
+        if collection == self.waiting {
+            self.first_done_rx.lock().unwrap().recv()?;
+        }
+        self.stores.with_store(collection, f)?;
+        if collection == self.first {
+            self.first_done.lock().unwrap().send(())?;
+        }
+        Ok(())
+    }
+}
+
+struct Client {
+    storage_init: Sync15StorageClientInit,
+    root_sync_key: KeyBundle,
+    persisted_state: Option<String>,
+    mem_cached_state: MemoryCachedState,
+}
+
+impl Client {
+    fn new(server: &TestServer, root_sync_key: KeyBundle) -> Self {
+        Client {
+            storage_init: Sync15StorageClientInit {
+                key_id: "key-id".into(),
+                access_token: "access-token".into(),
+                tokenserver_url: server.tokenserver_url(),
+            },
+            root_sync_key,
+            persisted_state: None,
+            mem_cached_state: MemoryCachedState::default(),
+        }
+    }
+
+    fn sync(
+        &mut self,
+        stores: &[&dyn Store],
+        engine_updates: Option<&HashMap<String, bool>>,
+    ) -> sync15::SyncResult {
+        self.sync_with_mode(stores, engine_updates, SyncMode::Normal)
+    }
+
+    fn sync_with_mode(
+        &mut self,
+        stores: &[&dyn Store],
+        engine_updates: Option<&HashMap<String, bool>>,
+        mode: SyncMode,
+    ) -> sync15::SyncResult {
+        sync_multiple(
+            stores,
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            &self.storage_init,
+            &self.root_sync_key,
+            &SyncOptions {
+                engine_updates,
+                mode,
+                ..SyncOptions::default()
+            },
+            &NeverInterrupts,
+        )
+    }
+}
+
+impl Client {
+    fn sync_in_parallel(
+        &mut self,
+        collections: &[&'static str],
+        stores: &dyn StoreFactory,
+        max_concurrency: usize,
+    ) -> sync15::SyncResult {
+        sync_multiple_in_parallel(
+            collections,
+            stores,
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            &self.storage_init,
+            &self.root_sync_key,
+            &SyncOptions {
+                max_concurrency,
+                ..SyncOptions::default()
+            },
+            &NeverInterrupts,
+        )
+    }
+}
+
+fn payloads(count: usize) -> Vec<Payload> {
+    (0..count)
+        .map(|i| Payload::from_json(json!({ "id": format!("record{:05}", i), "i": i })).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_fresh_start() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(server.bso("meta", "global").is_some());
+    assert!(server.bso("crypto", "keys").is_some());
+
+    // The next sync reuses our cached state, and doesn't need `meta/global`
+    // or `crypto/keys` again.
+    server.take_requests();
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert_eq!(
+        server.take_requests(),
+        vec![format!(
+            "GET {}/info/collections",
+            server.api_endpoint().path()
+        )]
+    );
+}
+
+#[test]
+fn test_batch_upload() {
+    let server = TestServer::start();
+    // Small limits, so that we need several POSTs, and several batches.
+    server.set_info_configuration(json!({
+        "max_post_records": 3,
+        "max_total_records": 5,
+    }));
+    let root_sync_key = KeyBundle::new_random().unwrap();
+    let mut client = Client::new(&server, root_sync_key.clone());
+    let store = TestStore::new(payloads(12));
+    let result = client.sync(&[&store], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.engine_results["tabs"].is_ok());
+    assert_eq!(server.ids("tabs").len(), 12);
+    let posts = server
+        .take_requests()
+        .into_iter()
+        .filter(|request| request.starts_with("POST"))
+        .count();
+    assert_eq!(posts, 5);
+
+    // Another device with the same account downloads them all.
+    let mut other_client = Client::new(&server, root_sync_key);
+    let other_store = TestStore::new(Vec::new());
+    let result = other_client.sync(&[&other_store], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert_eq!(other_store.incoming.lock().unwrap().len(), 12);
+}
+
+#[test]
+fn test_oversized_records() {
+    let server = TestServer::start();
+    server.set_info_configuration(json!({
+        "max_record_payload_bytes": 2000,
+    }));
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let big = "x".repeat(3000);
+    let mut store = TestStore::new(vec![
+        Payload::from_json(json!({ "id": "small", "i": 0 })).unwrap(),
+        // Too large, but the store can shrink it...
+        Payload::from_json(json!({ "id": "shrinkable", "text": big })).unwrap(),
+        // ...but not this one.
+        Payload::from_json(json!({ "id": "unshrinkable", "blob": big })).unwrap(),
+    ]);
+    store.atomic = false;
+    let result = client.sync(&[&store], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.engine_results["tabs"].is_ok());
+
+    let mut ids = server.ids("tabs");
+    ids.sort();
+    assert_eq!(ids, vec!["shrinkable".to_owned(), "small".to_owned()]);
+    let mut synced = store.synced.lock().unwrap().clone();
+    synced.sort();
+    assert_eq!(synced, vec![Guid::from("shrinkable"), Guid::from("small")]);
+
+    let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+    let outgoing = &telemetry["syncs"][0]["engines"][0]["outgoing"];
+    assert_eq!(outgoing, &json!([{ "sent": 2, "tooLarge": 1 }]));
+}
+
+#[test]
+fn test_oversized_records_atomic() {
+    let server = TestServer::start();
+    server.set_info_configuration(json!({
+        "max_record_payload_bytes": 2000,
+    }));
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let big = "x".repeat(3000);
+    // Atomic uploads shrink and skip records that are too large, too,
+    // instead of failing the whole upload.
+    let store = TestStore::new(vec![
+        Payload::from_json(json!({ "id": "small", "i": 0 })).unwrap(),
+        Payload::from_json(json!({ "id": "shrinkable", "text": big })).unwrap(),
+        Payload::from_json(json!({ "id": "unshrinkable", "blob": big })).unwrap(),
+    ]);
+    let result = client.sync(&[&store], None);
+    assert!(result.engine_results["tabs"].is_ok());
+    let mut ids = server.ids("tabs");
+    ids.sort();
+    assert_eq!(ids, vec!["shrinkable".to_owned(), "small".to_owned()]);
+    assert!(!store
+        .synced
+        .lock()
+        .unwrap()
+        .contains(&Guid::from("unshrinkable")));
+
+    let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+    let outgoing = &telemetry["syncs"][0]["engines"][0]["outgoing"];
+    assert_eq!(outgoing, &json!([{ "sent": 2, "tooLarge": 1 }]));
+}
+
+#[test]
+fn test_oversized_bookmark_folder() {
+    let server = TestServer::start();
+    server.set_info_configuration(json!({
+        "max_record_payload_bytes": 2000,
+    }));
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let api = PlacesApi::new_memory("test_oversized_bookmark_folder").unwrap();
+    let conn = api.open_sync_connection().unwrap();
+    let folder_guid = bookmarks::insert_bookmark(
+        &conn,
+        &InsertableFolder {
+            parent_guid: BookmarkRootGuid::Unfiled.into(),
+            position: BookmarkPosition::Append,
+            date_added: None,
+            last_modified: None,
+            guid: None,
+            title: Some("Big folder".into()),
+        }
+        .into(),
+    )
+    .unwrap();
+    // Enough children that the folder's list of them is too large to
+    // upload, but each bookmark is small enough.
+    let child_guids = (0..200)
+        .map(|i| {
+            bookmarks::insert_bookmark(
+                &conn,
+                &InsertableBookmark {
+                    parent_guid: folder_guid.clone(),
+                    position: BookmarkPosition::Append,
+                    date_added: None,
+                    last_modified: None,
+                    guid: None,
+                    url: url::Url::parse(&format!("https://example.com/{}", i)).unwrap(),
+                    title: None,
+                }
+                .into(),
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    {
+        let interruptee = conn.begin_interrupt_scope();
+        let store = BookmarksStore::new(&conn, &interruptee);
+        let result = client.sync(&[&store], None);
+        assert_eq!(result.service_status, ServiceStatus::Ok);
+        assert!(result.engine_results["bookmarks"].is_ok());
+
+        let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+        let engine = &telemetry["syncs"][0]["engines"][0];
+        assert_eq!(engine["name"], json!("bookmarks"));
+        assert_eq!(engine["outgoing"][0]["tooLarge"], json!(1));
+    }
+
+    // Everything but the folder is uploaded...
+    let ids = server.ids("bookmarks");
+    assert!(child_guids
+        .iter()
+        .all(|guid| ids.contains(&guid.to_string())));
+    assert!(ids.contains(&"unfiled".to_owned()));
+    assert!(!ids.contains(&folder_guid.to_string()));
+
+    // ...and the folder is still waiting to be uploaded on the next sync.
+    let changed: i64 = conn
+        .query_row(
+            "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = ?",
+            &[folder_guid.as_str()],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(changed > 0);
+}
+
+#[test]
+fn test_parallel_sync() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let stores = TestStores(vec![
+        TestStore::for_collection("passwords", payloads(1)),
+        TestStore::for_collection("history", payloads(2)),
+        TestStore::for_collection("tabs", payloads(3)),
+        TestStore::for_collection("forms", payloads(4)),
+    ]);
+    let collections = ["passwords", "history", "tabs", "forms"];
+    let result = client.sync_in_parallel(&collections, &stores, 2);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    for (i, store) in stores.0.iter().enumerate() {
+        assert!(result.engine_results[store.collection].is_ok());
+        assert_eq!(server.ids(store.collection).len(), i + 1);
+        assert_eq!(store.synced.lock().unwrap().len(), i + 1);
+    }
+
+    // Engines are reported in the order we passed them, however long each
+    // one took.
+    let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+    let names = telemetry["syncs"][0]["engines"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|engine| engine["name"].as_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["passwords", "history", "tabs", "forms"]);
+
+    // A store that the factory can't make fails without stopping the others.
+    let result = client.sync_in_parallel(&["bookmarks", "passwords"], &stores, 2);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.engine_results["bookmarks"].is_err());
+    assert!(result.engine_results["passwords"].is_ok());
+
+    // A store failing with a server error stops us from starting the others.
+    server.inject_fault(
+        Endpoint::storage("storage/history"),
+        Fault::PreconditionFailed,
+    );
+    let result = client.sync_in_parallel(&collections, &stores, 1);
+    assert_eq!(result.service_status, ServiceStatus::ServiceError);
+    assert!(result.engine_results["passwords"].is_ok());
+    assert!(result.engine_results["history"].is_err());
+    assert!(!result.engine_results.contains_key("tabs"));
+    assert!(!result.engine_results.contains_key("forms"));
+}
+
+#[test]
+fn test_parallel_fatal_error_after_upload() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let stores = OrderedStores::new(
+        TestStores(vec![
+            TestStore::for_collection("history", payloads(1)),
+            TestStore::for_collection("passwords", payloads(2)),
+        ]),
+        "passwords",
+        "history",
+    );
+    server.inject_fault(
+        Endpoint::storage("storage/history"),
+        Fault::PreconditionFailed,
+    );
+
+    // History comes first, but only fails once passwords has uploaded, so
+    // both are reported.
+    let result = client.sync_in_parallel(&["history", "passwords"], &stores, 2);
+    assert_eq!(result.service_status, ServiceStatus::ServiceError);
+    assert!(result.engine_results["history"].is_err());
+    assert!(result.engine_results["passwords"].is_ok());
+    assert_eq!(server.ids("passwords").len(), 2);
+    assert_eq!(stores.stores.0[1].synced.lock().unwrap().len(), 2);
+
+    let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+    let engines = telemetry["syncs"][0]["engines"].as_array().unwrap();
+    assert_eq!(engines.len(), 2);
+    assert_eq!(engines[0]["name"], "history");
+    assert!(engines[0]["failureReason"].is_object());
+    assert_eq!(engines[1]["name"], "passwords");
+    assert!(engines[1].get("failureReason").is_none());
+}
+
+#[test]
+fn test_storage_error() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let store = TestStore::new(payloads(1));
+    server.inject_fault(Endpoint::storage("storage/tabs"), Fault::PreconditionFailed);
+    let result = client.sync(&[&store], None);
+    assert!(result.engine_results["tabs"].is_err());
+    assert!(server.ids("tabs").is_empty());
+}
+
+#[test]
+fn test_backfill_error() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let store = TestStore::new(payloads(2));
+    store
+        .backfill
+        .lock()
+        .unwrap()
+        .push(CollectionRequest::new("backfill").full());
+    server.inject_fault(
+        Endpoint::storage("storage/backfill"),
+        Fault::PreconditionFailed,
+    );
+
+    // Failing to backfill is counted, but doesn't fail the sync, or the
+    // engine.
+    let result = client.sync(&[&store], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.engine_results["tabs"].is_ok());
+    assert_eq!(server.ids("tabs").len(), 2);
+    assert_eq!(store.synced.lock().unwrap().len(), 2);
+    let telemetry = serde_json::to_value(&result.telemetry).unwrap();
+    let engine = &telemetry["syncs"][0]["engines"][0];
+    assert_eq!(engine["backfillFailed"], 1);
+    assert!(engine.get("failureReason").is_none());
+}
+
+#[test]
+fn test_retry_after() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    server.inject_fault(
+        Endpoint::storage("info/collections"),
+        Fault::ServiceUnavailable { retry_after: 60 },
+    );
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::BackedOff);
+    assert!(result.next_sync_after.is_some());
+
+    // We don't touch the server again until the backoff expires.
+    server.take_requests();
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::BackedOff);
+    assert!(server.take_requests().is_empty());
+}
+
+#[test]
+fn test_tokenserver_unauthorized() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    server.inject_fault(Endpoint::Tokenserver, Fault::Unauthorized);
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::AuthenticationError);
+    assert!(server.bso("meta", "global").is_none());
+}
+
+#[test]
+fn test_node_reassignment() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    assert_eq!(client.sync(&[], None).service_status, ServiceStatus::Ok);
+
+    // Our cached token points at the old node, so the next sync fails...
+    server.reassign_node();
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::AuthenticationError);
+
+    // ...but the one after that fetches a new token, and starts over on the
+    // new node.
+    let result = client.sync(&[], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(server.bso("meta", "global").is_some());
+}
+
+#[test]
+fn test_declined_engines() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    assert_eq!(client.sync(&[], None).service_status, ServiceStatus::Ok);
+
+    let mut engine_updates = HashMap::new();
+    engine_updates.insert("tabs".to_owned(), false);
+    let result = client.sync(&[], Some(&engine_updates));
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert_eq!(result.declined, Some(vec!["tabs".to_owned()]));
+
+    let global: serde_json::Value =
+        serde_json::from_str(&server.bso("meta", "global").unwrap().payload).unwrap();
+    assert_eq!(global["declined"], json!(["tabs"]));
+}
+
+#[test]
+fn test_meta_global_conflict() {
+    let server = TestServer::start();
+    let mut client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let store = TestStore::for_collection("history", payloads(1));
+    let result = client.sync(&[&store], None);
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+
+    // Another client changes `meta/global` while we're declining tabs, so we
+    // fetch it again, and retry.
+    server.inject_fault(
+        Endpoint::storage_write("storage/meta/global"),
+        Fault::PreconditionFailed,
+    );
+    server.take_requests();
+    let mut engine_updates = HashMap::new();
+    engine_updates.insert("tabs".to_owned(), false);
+    let result = client.sync(&[&store], Some(&engine_updates));
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.result.is_ok());
+    assert_eq!(result.declined, Some(vec!["tabs".to_owned()]));
+    let global: serde_json::Value =
+        serde_json::from_str(&server.bso("meta", "global").unwrap().payload).unwrap();
+    assert_eq!(global["declined"], json!(["tabs"]));
+    let meta_global = format!("{}/storage/meta/global", server.api_endpoint().path());
+    let meta_global_requests = server
+        .take_requests()
+        .into_iter()
+        .filter(|request| request.ends_with(&meta_global))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        meta_global_requests,
+        vec![
+            "GET", "PUT", // Conflicts.
+            "GET", "PUT", // Succeeds.
+            "GET",
+        ]
+        .into_iter()
+        .map(|method| format!("{} {}", method, meta_global))
+        .collect::<Vec<_>>()
+    );
+
+    // We only retry once, in case we keep racing.
+    server.inject_fault(
+        Endpoint::storage_write("storage/meta/global"),
+        Fault::PreconditionFailed,
+    );
+    server.inject_fault(
+        Endpoint::storage_write("storage/meta/global"),
+        Fault::PreconditionFailed,
+    );
+    engine_updates.insert("tabs".to_owned(), true);
+    let result = client.sync(&[&store], Some(&engine_updates));
+    assert_eq!(result.service_status, ServiceStatus::ServiceError);
+    assert!(result.result.is_err());
+}
+
+#[test]
+fn test_reading_list() {
+    let server = TestServer::start();
+    let root_sync_key = KeyBundle::new_random().unwrap();
+    let mut client = Client::new(&server, root_sync_key.clone());
+    let api = PlacesApi::new_memory("test_reading_list_1").unwrap();
+    let conn = api.open_sync_connection().unwrap();
+    let url = url::Url::parse("https://example.com/article").unwrap();
+    let guid = reading_list::add_item(&conn, &url, Some("Article"), None).unwrap();
+    {
+        let interruptee = conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&conn, &interruptee);
+        // A fresh `meta/global` doesn't include the reading list, so it
+        // needs to be enabled, like `PlacesApi::sync_reading_list` does.
+        let mut engine_updates = HashMap::new();
+        engine_updates.insert("readinglist".to_owned(), true);
+        let result = client.sync(&[&store], Some(&engine_updates));
+        assert_eq!(result.service_status, ServiceStatus::Ok);
+        assert!(result.engine_results["readinglist"].is_ok());
+    }
+    let global: serde_json::Value =
+        serde_json::from_str(&server.bso("meta", "global").unwrap().payload).unwrap();
+    assert!(global["engines"]["readinglist"].is_object());
+    assert_eq!(server.ids("readinglist"), vec![guid.to_string()]);
+
+    // Another device with the same account downloads the item.
+    let mut other_client = Client::new(&server, root_sync_key);
+    let other_api = PlacesApi::new_memory("test_reading_list_2").unwrap();
+    let other_conn = other_api.open_sync_connection().unwrap();
+    {
+        let interruptee = other_conn.begin_interrupt_scope();
+        let store = ReadingListStore::new(&other_conn, &interruptee);
+        let result = other_client.sync(&[&store], None);
+        assert!(result.engine_results["readinglist"].is_ok());
+    }
+    let item = reading_list::get_item(&other_conn, &guid)
+        .unwrap()
+        .expect("should download the item");
+    assert_eq!(item.url, url);
+    assert_eq!(item.title, Some("Article".to_owned()));
+}
+
+#[test]
+fn test_logins_dry_run_keeps_state() {
+    let server = TestServer::start();
+    let client = Client::new(&server, KeyBundle::new_random().unwrap());
+    let engine = PasswordEngine::new_in_memory(None).unwrap();
+    engine
+        .sync(&client.storage_init, &client.root_sync_key)
+        .unwrap();
+    let state = engine.db.get_global_state().unwrap();
+    assert!(state.is_some());
+
+    // A normal sync would remember to back off.
+    server.inject_fault(
+        Endpoint::storage("info/collections"),
+        Fault::ServiceUnavailable { retry_after: 60 },
+    );
+    let _ = engine.dry_run_sync(&client.storage_init, &client.root_sync_key, false);
+    assert_eq!(engine.db.get_global_state().unwrap(), state);
+
+    // So the next sync doesn't wait.
+    server.take_requests();
+    engine
+        .sync(&client.storage_init, &client.root_sync_key)
+        .unwrap();
+    assert!(!server.take_requests().is_empty());
+}
+
+#[test]
+fn test_dry_run() {
+    let server = TestServer::start();
+    let root_sync_key = KeyBundle::new_random().unwrap();
+    let mut client = Client::new(&server, root_sync_key.clone());
+    let store = TestStore::new(payloads(2));
+    assert_eq!(
+        client.sync(&[&store], None).service_status,
+        ServiceStatus::Ok
+    );
+
+    let mut outgoing = payloads(4);
+    outgoing.push(Payload::new_tombstone("record00000".into()));
+    *store.outgoing.lock().unwrap() = outgoing;
+    let result = client.sync_with_mode(&[&store], None, SyncMode::DryRun { include_ids: true });
+    assert_eq!(result.service_status, ServiceStatus::Ok);
+    assert!(result.engine_results["tabs"].is_ok());
+    let planned = &result.planned_changes["tabs"];
+    assert_eq!(planned.uploaded, 4);
+    assert_eq!(planned.deleted, 1);
+    assert_eq!(planned.incoming_ids, Some(vec![]));
+    assert_eq!(planned.outgoing_ids.as_ref().map(Vec::len), Some(5));
+    // Nothing was uploaded, or marked as synced.
+    assert_eq!(server.ids("tabs").len(), 2);
+    assert_eq!(store.outgoing.lock().unwrap().len(), 5);
+
+    // A store that hasn't synced before needs a reset, which a dry run can't
+    // do.
+    let mut other_client = Client::new(&server, root_sync_key);
+    let other_store = TestStore::new(Vec::new());
+    let result = other_client.sync_with_mode(
+        &[&other_store],
+        None,
+        SyncMode::DryRun { include_ids: false },
+    );
+    assert!(result.engine_results["tabs"].is_err());
+    assert!(result.planned_changes.is_empty());
+}
+
+#[test]
+fn test_sync_manager_remote_clients() {
+    let server = TestServer::start();
+    let sync = |manager: &mut SyncManager, id: &str, name: &str, device_type| {
+        manager
+            .sync(SyncParams {
+                reason: SyncReason::User,
+                engines: None,
+                engines_to_change_state: None,
+                persisted_state: None,
+                device_settings: Some(DeviceSettings {
+                    fxa_device_id: id.into(),
+                    name: name.into(),
+                    device_type,
+                }),
+                key_id: "key-id".into(),
+                access_token: "access-token".into(),
+                tokenserver_url: server.tokenserver_url().into_string(),
+                // 64 zero bytes.
+                sync_key: "A".repeat(86),
+            })
+            .unwrap()
+    };
+
+    let mut laptop = SyncManager::new();
+    let result = sync(&mut laptop, "laptop", "My laptop", DeviceType::Desktop);
+    assert_eq!(result.status, ServiceStatus::Ok);
+    assert_eq!(result.remote_clients, Some(Vec::new()));
+
+    // Another device sees the laptop, and the laptop sees it on its next
+    // sync.
+    let mut phone = SyncManager::new();
+    let result = sync(&mut phone, "phone", "My phone", DeviceType::Mobile);
+    assert_eq!(result.status, ServiceStatus::Ok);
+    let clients = result.remote_clients.as_ref().unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].id, "laptop");
+    assert_eq!(clients[0].fxa_device_id.as_ref().unwrap(), "laptop");
+    assert_eq!(clients[0].name, "My laptop");
+    assert_eq!(clients[0].device_type, Some(DeviceType::Desktop));
+    let json = serde_json::to_value(&result).unwrap();
+    assert_eq!(json["remoteClients"][0]["name"], "My laptop");
+    assert_eq!(json["remoteClients"][0]["type"], "desktop");
+
+    let result = sync(&mut laptop, "laptop", "My laptop", DeviceType::Desktop);
+    let clients = result.remote_clients.unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].name, "My phone");
+    assert_eq!(clients[0].device_type, Some(DeviceType::Mobile));
+}
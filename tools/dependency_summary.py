@@ -143,6 +143,12 @@ EXTRA_PACKAGE_METADATA = {
             CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
         """)
     },
+    "ext-public-suffix-list": {
+        "name": "public-suffix-list",
+        "repository": "https://github.com/publicsuffix/list",
+        "license": "MPL-2.0",
+        "license_file": "https://raw.githubusercontent.com/publicsuffix/list/master/LICENSE",
+    },
     "ext-sqlcipher": {
         "name": "sqlcipher",
         "repository": "https://github.com/sqlcipher/sqlcipher",
@@ -178,7 +184,8 @@ PACKAGES_WITH_EXTRA_DEPENDENCIES = {
     # As a special case, we know that the "logins" crate is the only thing that enables SQLCipher.
     # In a future iteration we could check the cargo build-plan output to see whether anything is
     # enabling the sqlcipher feature, but this will do for now.
-    "logins": ["ext-sqlcipher"],
+    # It also bundles a copy of the public suffix list; see `tools/update_public_suffix_list.sh`.
+    "logins": ["ext-sqlcipher", "ext-public-suffix-list"],
 }
 
 # Hand-audited tweaks to package metadata, for cases where the data given to us by cargo is insufficient.
@@ -0,0 +1,17 @@
+#!/bin/sh
+
+# Updates the copy of the public suffix list that the logins component uses
+# to find the base domains of logins. The list changes often, but adding new
+# suffixes rarely affects which logins we match, so it's fine to run this
+# every few months, or when someone reports a site we get wrong.
+#
+# Logins store the base domain of each login's hostname, so existing logins
+# keep their old base domain until their hostname changes. If an update
+# changes the base domains of existing hostnames, bump the logins schema
+# version, and recompute them in the schema upgrade.
+
+set -e
+
+curl --fail --silent --show-error \
+  https://publicsuffix.org/list/public_suffix_list.dat \
+  --output ./components/logins/src/public_suffix_list.dat